@@ -0,0 +1,216 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use futures_util::stream::Stream;
+use hyper::body::Bytes;
+use hyper::{Body, Response};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+
+
+/// Line format of the access log
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    /// Native `access.log` format of squid
+    Squid,
+}
+
+/// Result code of squid, what the proxy did with a request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Forwarded to the server, nothing is cached
+    Miss,
+    /// Forwarded, but the client connection was closed without a response
+    MissAborted,
+    /// CONNECT tunnel
+    Tunnel,
+    /// Refused by acl, authentication or rate limit
+    Denied,
+    /// Answered by the proxy itself
+    None,
+}
+
+impl Action {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Action::Miss => "TCP_MISS",
+            Action::MissAborted => "TCP_MISS_ABORTED",
+            Action::Tunnel => "TCP_TUNNEL",
+            Action::Denied => "TCP_DENIED",
+            Action::None => "NONE",
+        }
+    }
+}
+
+/// Hierarchy code of squid, where a request was sent to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hierarchy {
+    Direct,
+    /// Through `upstream_proxy`
+    Parent,
+    None,
+}
+
+impl Hierarchy {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Hierarchy::Direct => "HIER_DIRECT",
+            Hierarchy::Parent => "FIRST_UP_PARENT",
+            Hierarchy::None => "HIER_NONE",
+        }
+    }
+}
+
+/// Completed request or tunnel as written to the access log
+#[derive(Debug, Clone)]
+pub struct AccessLogEntry {
+    /// Time the request completed
+    pub time: SystemTime,
+    pub elapsed: Duration,
+    /// Address of the client, anonymized as `privacy.anonymize_client_ip` says
+    pub client: String,
+    pub action: Action,
+    pub status: u16,
+    /// Bytes sent to the client, including the response head
+    pub bytes: u64,
+    pub method: String,
+    /// Uri as logged, `host:port` for CONNECT
+    pub url: String,
+    /// Kerberos principal or client certificate identity
+    pub user: Option<String>,
+    pub hierarchy: Hierarchy,
+    /// Address of the server or parent proxy the request was sent to
+    pub server: Option<IpAddr>,
+    pub content_type: Option<String>,
+    /// Name of the matched route, appended to the line when `routes` are configured
+    pub route: Option<String>,
+}
+
+/// Renders an entry in the native format of squid,
+/// `time elapsed client action/status bytes method url user hierarchy/server content-type`:
+///
+/// ```text
+/// 1286536308.779    180 10.0.0.7 TCP_MISS/200 411 GET http://example.com/ alice HIER_DIRECT/93.184.216.34 text/html
+/// ```
+///
+/// The route name follows as an extra field when there is one.
+pub fn format_squid_line(entry: &AccessLogEntry) -> String {
+    let time = entry.time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let field = |v: &Option<String>| match v {
+        // fields are separated by spaces, squid encodes them in user names as well
+        Some(v) if !v.is_empty() => v.replace(' ', "%20"),
+        _ => String::from("-")
+    };
+    let mut line = format!("{}.{:03} {:>6} {} {}/{:03} {} {} {} {} {}/{} {}",
+                           time.as_secs(), time.subsec_millis(), entry.elapsed.as_millis(), entry.client,
+                           entry.action.as_str(), entry.status, entry.bytes, entry.method, entry.url.replace(' ', "%20"),
+                           field(&entry.user), entry.hierarchy.as_str(),
+                           entry.server.map(|a| a.to_string()).unwrap_or_else(|| String::from("-")),
+                           field(&entry.content_type));
+    if entry.route.is_some() {
+        line.push(' ');
+        line.push_str(&field(&entry.route));
+    }
+    line
+}
+
+
+/// File one line is appended to per completed request and tunnel
+pub struct AccessLog {
+    path: String,
+    /// `None` writes to stdout
+    file: Mutex<Option<File>>,
+}
+
+impl AccessLog {
+    /// Returns `None` when `log_format` is not configured
+    pub fn from_config(config: &Config) -> Result<Option<AccessLog>, String> {
+        let path = match (config.log_format, &config.access_log) {
+            (None, _) => return Ok(None),
+            (Some(_), Some(path)) => path.clone(),
+            (Some(_), None) => return Err(String::from("access_log is required with log_format"))
+        };
+        let log = AccessLog { file: Mutex::new(open(&path)?), path };
+        Ok(Some(log))
+    }
+
+    /// Opens the file again, so it can be rotated
+    pub fn reopen(&self) -> Result<(), String> {
+        *self.file.lock().unwrap() = open(&self.path)?;
+        Ok(())
+    }
+
+    pub fn write(&self, entry: &AccessLogEntry) {
+        let line = format!("{}\n", format_squid_line(entry));
+        let result = match &mut *self.file.lock().unwrap() {
+            Some(file) => file.write_all(line.as_bytes()),
+            None => std::io::stdout().write_all(line.as_bytes())
+        };
+        if let Err(e) = result {
+            warn!("can not write to access_log {:?}; err = {}", self.path, e);
+        }
+    }
+
+    /// Writes the entry of a response once its body was sent or the client went away, `bytes` and
+    /// `elapsed` of the entry are filled in then
+    pub fn log_response(self: &Arc<Self>, resp: Response<Body>, entry: AccessLogEntry, started: Instant)
+        -> Response<Body> {
+        let head = head_size(&resp);
+        let (parts, body) = resp.into_parts();
+        let body = LoggedBody { body, log: self.clone(), entry, started, bytes: head };
+        Response::from_parts(parts, Body::wrap_stream(body))
+    }
+}
+
+/// `-` is stdout
+fn open(path: &str) -> Result<Option<File>, String> {
+    if path == "-" {
+        return Ok(None);
+    }
+    OpenOptions::new().create(true).append(true).open(path)
+        .map(Some)
+        .map_err(|e| format!("can not open access_log {:?}; err = {}", path, e))
+}
+
+/// Size of the response head as sent over HTTP/1
+pub fn head_size(resp: &Response<Body>) -> u64 {
+    let status_line = "HTTP/1.1 200 \r\n".len() + resp.status().canonical_reason().unwrap_or("").len();
+    let headers: usize = resp.headers().iter().map(|(k, v)| k.as_str().len() + v.len() + 4).sum();
+    (status_line + headers + 2) as u64
+}
+
+struct LoggedBody {
+    body: Body,
+    log: Arc<AccessLog>,
+    entry: AccessLogEntry,
+    started: Instant,
+    bytes: u64,
+}
+
+impl Stream for LoggedBody {
+    type Item = Result<Bytes, hyper::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let poll = Pin::new(&mut self.body).poll_next(cx);
+        if let Poll::Ready(Some(Ok(chunk))) = &poll {
+            self.bytes += chunk.len() as u64;
+        }
+        poll
+    }
+}
+
+impl Drop for LoggedBody {
+    fn drop(&mut self) {
+        self.entry.time = SystemTime::now();
+        self.entry.elapsed = self.started.elapsed();
+        self.entry.bytes = self.bytes;
+        self.log.write(&self.entry);
+    }
+}
@@ -0,0 +1,146 @@
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use log::debug;
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio::sync::mpsc;
+
+use crate::metrics::Metrics;
+use crate::privacy::Peer;
+
+
+/// Events not yet drained by the consumer, further events are merged into later ones
+const EVENTS_CAPACITY: usize = 1024;
+
+
+/// Bytes transferred through a CONNECT tunnel in both directions
+#[derive(Debug, Clone)]
+pub struct ByteAccountingEvent {
+    pub peer: Peer,
+    pub target: String,
+    pub bytes_since_last_event: u64,
+    pub total_bytes: u64,
+}
+
+/// Publishes an event every `interval` bytes of a tunnel, the copy of the tunnel never waits for the consumer
+#[derive(Clone)]
+pub struct ByteAccounting {
+    interval: u64,
+    tx: mpsc::Sender<ByteAccountingEvent>,
+}
+
+impl ByteAccounting {
+    pub fn new(interval_kb: u64) -> (ByteAccounting, mpsc::Receiver<ByteAccountingEvent>) {
+        let (tx, rx) = mpsc::channel(EVENTS_CAPACITY);
+        (ByteAccounting { interval: interval_kb * 1024, tx }, rx)
+    }
+
+    /// Starts accounting of a tunnel, the remaining bytes are published once the meter is dropped
+    pub fn meter(&self, peer: Peer, target: String) -> Arc<TunnelMeter> {
+        Arc::new(TunnelMeter {
+            peer,
+            target,
+            interval: self.interval,
+            tx: self.tx.clone(),
+            counts: Mutex::new(Counts::default()),
+        })
+    }
+}
+
+
+#[derive(Default)]
+struct Counts {
+    total: u64,
+    /// Part of `total` already published
+    published: u64,
+}
+
+/// Counts bytes of one tunnel, shared by both directions
+pub struct TunnelMeter {
+    peer: Peer,
+    target: String,
+    interval: u64,
+    tx: mpsc::Sender<ByteAccountingEvent>,
+    counts: Mutex<Counts>,
+}
+
+impl TunnelMeter {
+    fn count(&self, bytes: u64) {
+        let mut counts = self.counts.lock().unwrap();
+        counts.total += bytes;
+        if counts.total - counts.published >= self.interval {
+            self.publish(&mut counts);
+        }
+    }
+
+    /// Bytes of an event which does not fit into the channel are published with the next one
+    fn publish(&self, counts: &mut Counts) {
+        let event = ByteAccountingEvent {
+            peer: self.peer,
+            target: self.target.clone(),
+            bytes_since_last_event: counts.total - counts.published,
+            total_bytes: counts.total,
+        };
+        if self.tx.try_send(event).is_ok() {
+            counts.published = counts.total;
+        }
+    }
+}
+
+impl Drop for TunnelMeter {
+    fn drop(&mut self) {
+        let mut counts = self.counts.lock().unwrap();
+        if counts.total > counts.published {
+            self.publish(&mut counts);
+        }
+    }
+}
+
+
+/// Reader counting the bytes read from it with a tunnel meter
+pub struct Counted<R> {
+    inner: R,
+    meter: Option<Arc<TunnelMeter>>,
+    /// Bytes read so far, also when the tunnel is cut off before the copy finished
+    total: Option<Arc<AtomicU64>>,
+}
+
+impl<R> Counted<R> {
+    pub fn new(inner: R, meter: Option<Arc<TunnelMeter>>) -> Counted<R> {
+        Counted { inner, meter, total: None }
+    }
+
+    pub fn with_total(mut self, total: Arc<AtomicU64>) -> Counted<R> {
+        self.total = Some(total);
+        self
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Counted<R> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = &poll {
+            let read = (buf.filled().len() - before) as u64;
+            if let Some(meter) = &self.meter {
+                meter.count(read);
+            }
+            if let Some(total) = &self.total {
+                total.fetch_add(read, Ordering::Relaxed);
+            }
+        }
+        poll
+    }
+}
+
+
+/// Drains accounting events, this is the place to hand them over to a billing or quota system
+pub async fn consume(mut rx: mpsc::Receiver<ByteAccountingEvent>, metrics: Arc<Metrics>) {
+    while let Some(event) = rx.recv().await {
+        debug!("client {:?}: {} - transferred {} bytes, {} bytes in total",
+               event.peer, event.target, event.bytes_since_last_event, event.total_bytes);
+        metrics.add("tunnel_bytes_total", &[], event.bytes_since_last_event as i64);
+    }
+}
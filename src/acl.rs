@@ -0,0 +1,287 @@
+use std::fmt;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+
+use crate::config::AclConfig;
+use crate::metrics::Metrics;
+use crate::target::{host_matches, Target};
+
+
+/// How often `allow_file` and `deny_file` are checked for changes
+pub const FILE_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+
+/// What happens to a destination which matches no rule
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    Allow,
+    Deny,
+}
+
+
+/// Destination rule like `*.example.com`, `example.com:443` or `[2001:db8::1]:443`
+struct Rule {
+    text: String,
+    /// Host pattern, `*` matches every host
+    host: String,
+    /// Every port when missing
+    port: Option<u16>,
+}
+
+impl Rule {
+    fn parse(text: &str) -> Result<Rule, String> {
+        let (host, port) = match text.rsplit_once(':') {
+            // a colon in the host is part of an IPv6 literal, which needs brackets to have a port
+            Some((host, port)) if !host.contains(':') || host.ends_with(']') => {
+                let port = port.parse::<u16>()
+                    .map_err(|_| format!("invalid acl rule {:?} (port must be a number)", text))?;
+                (host, Some(port))
+            },
+            _ => (text, None)
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            return Err(format!("invalid acl rule {:?} (host is empty)", text));
+        }
+        Ok(Rule { text: String::from(text), host: host.to_lowercase(), port })
+    }
+
+    fn matches(&self, target: &Target) -> bool {
+        (self.host == "*" || host_matches(&self.host, &target.host)) && self.port.is_none_or(|p| p == target.port)
+    }
+}
+
+
+/// Rules read from a file, one per line, `#` starts a comment. The file is read again when it
+/// changes, a file which does not parse keeps the previous rules.
+struct RuleFile {
+    path: String,
+    /// `allow` or `deny`
+    list: &'static str,
+    rules: RwLock<Vec<Rule>>,
+    /// Modification time and size when the file was last read
+    version: Mutex<Option<(SystemTime, u64)>>,
+}
+
+impl RuleFile {
+    fn load(path: &str, list: &'static str, metrics: &Metrics) -> Result<RuleFile, String> {
+        let version = file_version(path);
+        let file = RuleFile {
+            path: String::from(path),
+            list,
+            rules: RwLock::new(read_rules(path)?),
+            version: Mutex::new(version),
+        };
+        file.report(metrics);
+        Ok(file)
+    }
+
+    fn matches(&self, target: &Target) -> Option<String> {
+        self.rules.read().unwrap().iter().find(|r| r.matches(target)).map(|r| r.text.clone())
+    }
+
+    fn refresh(&self, metrics: &Metrics) {
+        let version = file_version(&self.path);
+        {
+            let mut current = self.version.lock().unwrap();
+            if *current == version {
+                return;
+            }
+            // a broken file is reported once, not at every check
+            *current = version;
+        }
+        match read_rules(&self.path) {
+            Ok(rules) => {
+                info!("loaded {} acl {} rules from {:?}", rules.len(), self.list, self.path);
+                *self.rules.write().unwrap() = rules;
+                self.report(metrics);
+            },
+            Err(e) => error!("can not reload acl {}_file, keeping the current rules; {}", self.list, e)
+        }
+    }
+
+    fn report(&self, metrics: &Metrics) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0);
+        let labels = [("list", self.list)];
+        metrics.set("acl_file_rules", &labels, self.rules.read().unwrap().len() as i64);
+        metrics.set("acl_file_loaded_timestamp_seconds", &labels, now);
+    }
+}
+
+/// Modification time and size of a file, `None` when it can not be read
+fn file_version(path: &str) -> Option<(SystemTime, u64)> {
+    let metadata = std::fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+/// Parses a rule file, errors name the file and line
+fn read_rules(path: &str) -> Result<Vec<Rule>, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("can not read {:?}; err = {}", path, e))?;
+    let mut rules = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if !line.is_empty() {
+            rules.push(Rule::parse(line).map_err(|e| format!("{}:{}: {}", path, i + 1, e))?);
+        }
+    }
+    Ok(rules)
+}
+
+
+/// Why a destination is denied
+#[derive(Debug)]
+pub enum Denial {
+    Rule(String),
+    Default,
+}
+
+impl Denial {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Denial::Rule(_) => "rule",
+            Denial::Default => "default",
+        }
+    }
+}
+
+impl fmt::Display for Denial {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Denial::Rule(rule) => write!(f, "denied by acl rule {:?}", rule),
+            Denial::Default => write!(f, "denied by acl default_action deny, no allow rule matches"),
+        }
+    }
+}
+
+
+/// Destinations clients may reach, deny rules take precedence over allow rules
+pub struct Acl {
+    default_action: Action,
+    allow: Vec<Rule>,
+    deny: Vec<Rule>,
+    allow_file: Option<RuleFile>,
+    deny_file: Option<RuleFile>,
+    metrics: Arc<Metrics>,
+}
+
+impl Acl {
+    pub fn from_config(config: &AclConfig, metrics: Arc<Metrics>) -> Result<Acl, String> {
+        let allow = match (&config.allow, config.default_action) {
+            (Some(v), _) => v.as_slice(),
+            (None, Action::Deny) if config.allow_file.is_none() => {
+                return Err(String::from("acl.allow or acl.allow_file is required when acl.default_action is deny"));
+            },
+            (None, _) => &[]
+        };
+        let load = |path: &Option<String>, list| match path {
+            Some(path) => RuleFile::load(path, list, &metrics).map(Some),
+            None => Ok(None)
+        };
+        Ok(Acl {
+            default_action: config.default_action,
+            allow: allow.iter().map(|v| Rule::parse(v)).collect::<Result<_, _>>()?,
+            deny: config.deny.iter().map(|v| Rule::parse(v)).collect::<Result<_, _>>()?,
+            allow_file: load(&config.allow_file, "allow")?,
+            deny_file: load(&config.deny_file, "deny")?,
+            metrics: metrics.clone(),
+        })
+    }
+
+    /// Tells whether nothing at all is allowed
+    pub fn denies_all(&self) -> bool {
+        let allow_file = self.allow_file.as_ref().map(|f| f.rules.read().unwrap().len()).unwrap_or(0);
+        self.default_action == Action::Deny && self.allow.is_empty() && allow_file == 0
+    }
+
+    /// Reads `allow_file` and `deny_file` again when they changed
+    pub fn refresh(&self) {
+        for file in self.allow_file.iter().chain(&self.deny_file) {
+            file.refresh(&self.metrics);
+        }
+    }
+
+    /// Checks a destination, the error tells which rule or the default denied it
+    pub fn check(&self, target: &Target) -> Result<(), Denial> {
+        if let Some(rule) = self.deny.iter().find(|r| r.matches(target)) {
+            return Err(Denial::Rule(rule.text.clone()));
+        }
+        if let Some(rule) = self.deny_file.as_ref().and_then(|f| f.matches(target)) {
+            return Err(Denial::Rule(rule));
+        }
+        let allow_file = || self.allow_file.as_ref().and_then(|f| f.matches(target)).is_some();
+        if self.allow.iter().any(|r| r.matches(target)) || allow_file() {
+            return Ok(());
+        }
+        match self.default_action {
+            Action::Allow => Ok(()),
+            Action::Deny => Err(Denial::Default)
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target(authority: &str) -> Target {
+        Target::from_uri(&authority.parse().unwrap(), 443).unwrap()
+    }
+
+    /// Path of a new file of its own with `text`
+    fn rule_file(text: &str) -> String {
+        static NEXT: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!("mirror-proxy-acl-{}-{}", std::process::id(),
+                                                     NEXT.fetch_add(1, std::sync::atomic::Ordering::SeqCst)));
+        std::fs::write(&path, text).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    fn acl(yaml: &str) -> Acl {
+        Acl::from_config(&serde_yaml::from_str(yaml).unwrap(), Arc::new(Metrics::new())).unwrap()
+    }
+
+    #[test]
+    fn deny_rules_take_precedence() {
+        let acl = acl("default_action: deny\nallow: [\"*.example.com\"]\ndeny: [\"admin.example.com:443\"]\n");
+
+        assert!(acl.check(&target("www.example.com:443")).is_ok());
+        assert!(acl.check(&target("admin.example.com:8443")).is_ok());
+        assert!(matches!(acl.check(&target("admin.example.com:443")),
+                         Err(Denial::Rule(rule)) if rule == "admin.example.com:443"));
+        assert!(matches!(acl.check(&target("example.org:443")), Err(Denial::Default)));
+    }
+
+    #[test]
+    fn rule_files_skip_comments_and_name_bad_lines() {
+        let path = rule_file("# ads\nads.example.com  # and its subdomains below\n\n*.ads.example.com:443\n");
+        let rules = read_rules(&path).unwrap();
+        assert_eq!(rules.iter().map(|r| r.text.as_str()).collect::<Vec<_>>(),
+                   ["ads.example.com", "*.ads.example.com:443"]);
+
+        std::fs::write(&path, "ads.example.com\n\n[2001:db8::1]:https\n").unwrap();
+        assert_eq!(read_rules(&path).err().unwrap(),
+                   format!("{}:3: invalid acl rule \"[2001:db8::1]:https\" (port must be a number)", path));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn broken_rule_file_keeps_the_previous_rules() {
+        let path = rule_file("ads.example.com\n");
+        let acl = acl(&format!("deny_file: {:?}\n", path));
+        assert!(acl.check(&target("tracker.example.com:443")).is_ok());
+
+        std::fs::write(&path, "ads.example.com\ntracker.example.com\n").unwrap();
+        acl.refresh();
+        assert!(acl.check(&target("tracker.example.com:443")).is_err());
+
+        std::fs::write(&path, "ads.example.com\ntracker.example.com\nhalf-written:\n").unwrap();
+        acl.refresh();
+        assert!(acl.check(&target("tracker.example.com:443")).is_err());
+        assert!(acl.metrics.render().contains("acl_file_rules{list=\"deny\"} 2"), "{}", acl.metrics.render());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
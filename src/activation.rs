@@ -0,0 +1,50 @@
+use std::net::TcpListener;
+
+
+/// First file descriptor passed by systemd, `SD_LISTEN_FDS_START`
+#[cfg(unix)]
+const LISTEN_FDS_START: i32 = 3;
+
+
+/// Listening sockets passed by systemd socket activation in the order of the socket unit, empty when
+/// `LISTEN_PID` does not name this process. Every one has to be a listening TCP socket.
+#[cfg(unix)]
+pub fn listeners() -> Result<Vec<TcpListener>, String> {
+    use std::os::unix::io::FromRawFd;
+    use socket2::{Socket, Type};
+
+    let pid = std::env::var("LISTEN_PID").ok().and_then(|v| v.parse::<u32>().ok());
+    if pid != Some(std::process::id()) {
+        return Ok(Vec::new());
+    }
+    let count = std::env::var("LISTEN_FDS").unwrap_or_default();
+    let count = count.parse::<i32>()
+        .map_err(|_| format!("invalid LISTEN_FDS {:?} of socket activation (must be a number)", count))?;
+    // not meant for processes started by this one
+    for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        std::env::remove_var(name);
+    }
+    let mut listeners = Vec::new();
+    for fd in LISTEN_FDS_START..LISTEN_FDS_START + count {
+        // systemd hands the descriptors over to this process, nothing else owns them
+        let socket = unsafe { Socket::from_raw_fd(fd) };
+        let invalid = |reason: String| format!("file descriptor {} of socket activation {}", fd, reason);
+        let is_tcp = socket.r#type().map_err(|e| invalid(format!("is no socket; err = {}", e)))? == Type::STREAM;
+        let addr = socket.local_addr().ok().and_then(|a| a.as_socket());
+        if !is_tcp || addr.is_none() {
+            return Err(invalid(String::from("is no TCP socket (ListenStream= must be an address or port)")));
+        }
+        if !socket.is_listener().map_err(|e| invalid(format!("can not be checked; err = {}", e)))? {
+            return Err(invalid(String::from("is not listening (Accept= must be no)")));
+        }
+        socket.set_cloexec(true).map_err(|e| invalid(format!("can not be set close-on-exec; err = {}", e)))?;
+        socket.set_nonblocking(true).map_err(|e| invalid(format!("can not be set non-blocking; err = {}", e)))?;
+        listeners.push(TcpListener::from(socket));
+    }
+    Ok(listeners)
+}
+
+#[cfg(not(unix))]
+pub fn listeners() -> Result<Vec<TcpListener>, String> {
+    Ok(Vec::new())
+}
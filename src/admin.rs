@@ -0,0 +1,161 @@
+use std::sync::Arc;
+use std::convert::Infallible;
+use log::{info, warn, error, debug};
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+use hyper::service::service_fn;
+use hyper::server::conn::Http;
+use hyper::{Body, Method, Request, Response};
+use hyper::http::{header, HeaderValue, StatusCode};
+
+use crate::State;
+use crate::privacy::Peer;
+
+
+/// Paths of admin endpoints
+const PATHS: [&str; 7] = [
+    "/metrics", "/admin/connections", "/admin/pool", "/admin/split", "/admin/backends", "/admin/certificates",
+    "/admin/requests",
+];
+
+
+pub fn is_admin_path(path: &str) -> bool {
+    PATHS.contains(&path)
+}
+
+/// Answers a request to an admin endpoint.
+///
+/// `cert_authenticated` is set for connections which presented a client certificate signed by
+/// the admin CA, then the token is not checked at all.
+pub fn handle(state: &State, req: &Request<Body>, peer: Peer, cert_authenticated: bool) -> Response<Body> {
+    if !cert_authenticated {
+        if let Some(token) = &state.config().admin_token {
+            let expected = format!("Bearer {}", token);
+            let given = req.headers().get(header::AUTHORIZATION).map(|v| v.as_bytes()).unwrap_or(b"");
+            if !constant_time_eq(given, expected.as_bytes()) {
+                warn!("admin {:?}: invalid token for {}", peer, req.uri().path());
+                let mut resp = Response::new(Body::from("invalid admin token"));
+                *resp.status_mut() = StatusCode::UNAUTHORIZED;
+                resp.headers_mut().insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+                return resp;
+            }
+        }
+    }
+
+    // only the split percentage can be changed, everything else is read-only
+    let allow = if req.uri().path() == "/admin/split" { "GET, PUT" } else { "GET" };
+    if req.method() != Method::GET && !(req.method() == Method::PUT && allow.contains("PUT")) {
+        let mut resp = Response::new(Body::from(format!("method {} is not allowed", req.method())));
+        *resp.status_mut() = StatusCode::METHOD_NOT_ALLOWED;
+        resp.headers_mut().insert(header::ALLOW, HeaderValue::from_static(allow));
+        return resp;
+    }
+
+    if req.uri().path() == "/metrics" && !state.config().prometheus {
+        let mut resp = Response::new(Body::from("prometheus metrics are disabled"));
+        *resp.status_mut() = StatusCode::NOT_FOUND;
+        return resp;
+    }
+    let (content_type, body) = match req.uri().path() {
+        "/metrics" => ("text/plain; version=0.0.4", state.metrics.render()),
+        "/admin/connections" => ("application/json", serde_json::to_string(&state.connections.list()).unwrap()),
+        "/admin/pool" => ("application/json", serde_json::to_string(&state.pool.info()).unwrap()),
+        "/admin/certificates" => ("application/json", serde_json::to_string(&crate::certificates(state)).unwrap()),
+        "/admin/backends" => match &state.balancer {
+            Some(balancer) => ("application/json", serde_json::to_string(&balancer.info()).unwrap()),
+            None => {
+                let mut resp = Response::new(Body::from("load balancing is not configured"));
+                *resp.status_mut() = StatusCode::NOT_FOUND;
+                return resp;
+            }
+        },
+        "/admin/requests" => match &state.recent {
+            Some(recent) => ("application/json", serde_json::to_string(&recent.list()).unwrap()),
+            None => {
+                let mut resp = Response::new(Body::from("recent_requests_buffer is 0"));
+                *resp.status_mut() = StatusCode::NOT_FOUND;
+                return resp;
+            }
+        },
+        "/admin/split" => match split(state, req, peer) {
+            Ok(v) => ("application/json", v),
+            Err((status, message)) => {
+                let mut resp = Response::new(Body::from(message));
+                *resp.status_mut() = status;
+                return resp;
+            }
+        },
+        _ => {
+            let mut resp = Response::new(Body::from("not found"));
+            *resp.status_mut() = StatusCode::NOT_FOUND;
+            return resp;
+        }
+    };
+    let mut resp = Response::new(Body::from(body));
+    resp.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+    resp
+}
+
+/// Shows traffic splitting, `PUT /admin/split?percent_b=N` changes the percentage routed to backend B
+fn split(state: &State, req: &Request<Body>, peer: Peer) -> Result<String, (StatusCode, &'static str)> {
+    let split = match &state.split {
+        Some(v) => v,
+        None => return Err((StatusCode::NOT_FOUND, "traffic splitting is not configured"))
+    };
+    if req.method() == Method::PUT {
+        let percent_b = req.uri().query().unwrap_or("")
+            .split('&')
+            .find_map(|p| p.strip_prefix("percent_b="))
+            .and_then(|v| v.parse::<u8>().ok())
+            .filter(|v| *v <= 100);
+        match percent_b {
+            Some(v) => {
+                info!("admin {:?}: split percent_b changed to {}", peer, v);
+                split.set_percent_b(v);
+            },
+            None => return Err((StatusCode::BAD_REQUEST, "percent_b must be given as a number from 0 to 100"))
+        }
+    }
+    Ok(serde_json::to_string(&split.info()).unwrap())
+}
+
+/// Serves admin endpoints on their own listener, with TLS when an acceptor is given
+pub async fn serve(state: Arc<State>, listener: TcpListener, acceptor: Option<TlsAcceptor>) {
+    let mtls = state.config().admin_mtls;
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok((stream, addr)) => (stream, state.privacy.peer(addr)),
+            Err(e) => {
+                error!("admin listener error; err = {:?}", e);
+                continue;
+            }
+        };
+        let state = state.clone();
+        let acceptor = acceptor.clone();
+        tokio::task::spawn(async move {
+            debug!("admin {:?}: connected", peer);
+            let service = service_fn(move |req| {
+                let resp = handle(&state, &req, peer, mtls);
+                info!("admin {:?}: {} {} {}", peer, req.method(), req.uri().path(), resp.status().as_u16());
+                async move { Ok::<_, Infallible>(resp) }
+            });
+            let result = match acceptor {
+                Some(acceptor) => match acceptor.accept(stream).await {
+                    Ok(stream) => Http::new().serve_connection(stream, service).await,
+                    Err(e) => {
+                        warn!("admin {:?}: TLS handshake failed; err = {:?}", peer, e);
+                        return;
+                    }
+                },
+                None => Http::new().serve_connection(stream, service).await
+            };
+            if let Err(e) = result {
+                debug!("admin {:?}: connection error; err = {:?}", peer, e);
+            }
+        });
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b.iter()).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -0,0 +1,301 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::sync::Mutex;
+use chrono::{SecondsFormat, Utc};
+use log::warn;
+use ring::digest;
+use serde::{Deserialize, Serialize};
+
+use crate::config::AuditLogConfig;
+use crate::error_page::ErrorKind;
+
+/// Bytes read from the end of an existing log to find its last line
+const TAIL_BYTES: u64 = 64 * 1024;
+
+
+/// Request the proxy refused, one JSON line of the audit log:
+///
+/// ```json
+/// {"time":"2024-05-02T10:15:00.123Z","request_id":"4f1c2a9e0b7d3e61","client":"10.0.0.7:51234","user":null,
+///  "method":"CONNECT","destination":"blocked.example.com:443","status":403,"category":"policy_blocked",
+///  "reason":"destination blocked.example.com:443 denied by acl","prev":"9c56cc51..."}
+/// ```
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub time: String,
+    pub request_id: String,
+    /// Address of the client
+    pub client: String,
+    /// Kerberos principal or client certificate identity
+    pub user: Option<String>,
+    pub method: String,
+    /// Uri of the request, `host:port` for CONNECT
+    pub destination: String,
+    pub status: u16,
+    /// Code of the error, e.g. `policy_blocked` or `rate_limited`
+    pub category: String,
+    /// What triggered the refusal, as told to the client
+    pub reason: String,
+    /// Hex SHA-256 of the previous line with `hash_chain`, `null` for the first line of a chain
+    #[serde(default)]
+    pub prev: Option<String>,
+}
+
+/// Time of an entry, RFC 3339 in UTC with milliseconds
+pub fn now() -> String {
+    Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+/// Whether a refusal of `kind` is audited: policy blocks, authentication failures, rate limits
+/// and loops, not malformed requests or failures of upstreams
+pub fn audited(kind: ErrorKind) -> bool {
+    matches!(kind, ErrorKind::PolicyBlocked | ErrorKind::AuthRequired | ErrorKind::RateLimited
+             | ErrorKind::LoopDetected)
+}
+
+
+/// File of every request the proxy refused, written whether or not the access log is on. With
+/// `hash_chain` every line holds the hash of the line before, so removed or edited lines break
+/// the chain; `verify-audit-log` checks it.
+pub struct AuditLog {
+    path: String,
+    hash_chain: bool,
+    /// File and hash of the last line written, the chain continues into a reopened file
+    file: Mutex<(File, Option<String>)>,
+}
+
+impl AuditLog {
+    /// Returns `None` when `audit_log` is not configured
+    pub fn from_config(config: &Option<AuditLogConfig>) -> Result<Option<AuditLog>, String> {
+        let config = match config {
+            Some(v) => v,
+            None => return Ok(None)
+        };
+        // a restart continues the chain of the lines already written
+        let last = if config.hash_chain { last_line(&config.path)?.map(|l| hash(&l)) } else { None };
+        let file = open(&config.path)?;
+        Ok(Some(AuditLog { path: config.path.clone(), hash_chain: config.hash_chain, file: Mutex::new((file, last)) }))
+    }
+
+    /// Opens the file again, so it can be rotated
+    pub fn reopen(&self) -> Result<(), String> {
+        self.file.lock().unwrap().0 = open(&self.path)?;
+        Ok(())
+    }
+
+    pub fn write(&self, mut entry: AuditEntry) {
+        let mut file = self.file.lock().unwrap();
+        if self.hash_chain {
+            entry.prev = file.1.clone();
+        }
+        let line = match serde_json::to_string(&entry) {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("can not encode audit_log entry; err = {}", e);
+                return;
+            }
+        };
+        match file.0.write_all(format!("{}\n", line).as_bytes()) {
+            Ok(()) if self.hash_chain => file.1 = Some(hash(&line)),
+            Ok(()) => {},
+            Err(e) => warn!("can not write to audit_log {:?}; err = {}", self.path, e)
+        }
+    }
+}
+
+fn open(path: &str) -> Result<File, String> {
+    OpenOptions::new().create(true).append(true).open(path)
+        .map_err(|e| format!("can not open audit_log {:?}; err = {}", path, e))
+}
+
+fn hash(line: &str) -> String {
+    digest::digest(&digest::SHA256, line.as_bytes()).as_ref().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Last line of the file at `path`, `None` when it is missing or empty
+fn last_line(path: &str) -> Result<Option<String>, String> {
+    let mut file = match File::open(path) {
+        Ok(v) => v,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("can not read audit_log {:?}; err = {}", path, e))
+    };
+    let mut tail = Vec::new();
+    let size = file.metadata().map(|m| m.len()).unwrap_or(0);
+    file.seek(SeekFrom::Start(size.saturating_sub(TAIL_BYTES)))
+        .and_then(|_| file.read_to_end(&mut tail))
+        .map_err(|e| format!("can not read audit_log {:?}; err = {}", path, e))?;
+    // the tail may start within a character, the last line is complete
+    Ok(String::from_utf8_lossy(&tail).lines().rev().find(|l| !l.is_empty()).map(String::from))
+}
+
+/// Checks the hash chain of audit log files given oldest first, as rotated; prints the result and
+/// returns the exit code, 1 when a line does not follow the one before
+pub fn command(paths: &[&str]) -> i32 {
+    let mut prev: Option<String> = None;
+    let mut lines = 0;
+    for path in paths {
+        let file = match File::open(path) {
+            Ok(v) => v,
+            Err(e) => {
+                eprintln!("can not open {:?}; err = {}", path, e);
+                return 1;
+            }
+        };
+        for (i, line) in BufReader::new(file).lines().enumerate() {
+            let line = match line {
+                Ok(v) => v,
+                Err(e) => {
+                    eprintln!("can not read {:?}; err = {}", path, e);
+                    return 1;
+                }
+            };
+            let entry: AuditEntry = match serde_json::from_str(&line) {
+                Ok(v) => v,
+                Err(e) => {
+                    eprintln!("{}:{}: not an audit_log entry; err = {}", path, i + 1, e);
+                    return 1;
+                }
+            };
+            // the first line may continue a file which is not given
+            if lines > 0 && entry.prev.is_none() {
+                eprintln!("{}:{}: line has no hash of the line before, audit_log.hash_chain was off", path, i + 1);
+                return 1;
+            }
+            if lines > 0 && entry.prev != prev {
+                eprintln!("{}:{}: hash chain broken, the line before is missing or was changed", path, i + 1);
+                return 1;
+            }
+            prev = Some(hash(&line));
+            lines += 1;
+        }
+    }
+    println!("{} lines, hash chain intact", lines);
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    /// Directory of the files of one test
+    fn dir() -> PathBuf {
+        static NEXT: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!("mirror-proxy-audit-{}-{}", std::process::id(),
+                                                    NEXT.fetch_add(1, std::sync::atomic::Ordering::SeqCst)));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn chained(path: &str) -> AuditLog {
+        let config = AuditLogConfig { path: String::from(path), hash_chain: true };
+        AuditLog::from_config(&Some(config)).unwrap().unwrap()
+    }
+
+    fn entry(destination: &str) -> AuditEntry {
+        AuditEntry {
+            time: now(),
+            request_id: String::from("4f1c2a9e0b7d3e61"),
+            client: String::from("10.0.0.7:51234"),
+            user: None,
+            method: String::from("CONNECT"),
+            destination: String::from(destination),
+            status: 403,
+            category: String::from("policy_blocked"),
+            reason: format!("destination {} denied by acl", destination),
+            prev: None,
+        }
+    }
+
+    fn lines(path: &str) -> Vec<String> {
+        std::fs::read_to_string(path).unwrap().lines().map(String::from).collect()
+    }
+
+    #[test]
+    fn intact_chain_verifies() {
+        let dir = dir();
+        let path = dir.join("audit.log").to_string_lossy().into_owned();
+        let log = chained(&path);
+        for host in ["a.example:443", "b.example:443", "c.example:443"] {
+            log.write(entry(host));
+        }
+
+        let lines = lines(&path);
+        let first: AuditEntry = serde_json::from_str(&lines[0]).unwrap();
+        let second: AuditEntry = serde_json::from_str(&lines[1]).unwrap();
+        assert_eq!(first.prev, None);
+        assert_eq!(second.prev, Some(hash(&lines[0])));
+        assert_eq!(command(&[&path]), 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn edited_or_removed_line_breaks_chain() {
+        let dir = dir();
+        let path = dir.join("audit.log").to_string_lossy().into_owned();
+        let log = chained(&path);
+        for host in ["a.example:443", "b.example:443", "c.example:443"] {
+            log.write(entry(host));
+        }
+        let lines = lines(&path);
+
+        let edited = dir.join("edited.log").to_string_lossy().into_owned();
+        std::fs::write(&edited, lines.join("\n").replacen("b.example", "x.example", 1) + "\n").unwrap();
+        assert_eq!(command(&[&edited]), 1);
+        let removed = dir.join("removed.log").to_string_lossy().into_owned();
+        std::fs::write(&removed, format!("{}\n{}\n", lines[0], lines[2])).unwrap();
+        assert_eq!(command(&[&removed]), 1);
+        // the first line may continue a file which was not given
+        let tail = dir.join("tail.log").to_string_lossy().into_owned();
+        std::fs::write(&tail, format!("{}\n{}\n", lines[1], lines[2])).unwrap();
+        assert_eq!(command(&[&tail]), 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn chain_continues_across_rotation() {
+        let dir = dir();
+        let path = dir.join("audit.log").to_string_lossy().into_owned();
+        let rotated = dir.join("audit.log.1").to_string_lossy().into_owned();
+        let log = chained(&path);
+        log.write(entry("a.example:443"));
+        log.write(entry("b.example:443"));
+        std::fs::rename(&path, &rotated).unwrap();
+        log.reopen().unwrap();
+        log.write(entry("c.example:443"));
+
+        let first: AuditEntry = serde_json::from_str(&lines(&path)[0]).unwrap();
+        assert_eq!(first.prev, Some(hash(&lines(&rotated)[1])));
+        assert_eq!(command(&[&rotated, &path]), 0);
+        // out of order the files do not follow each other
+        assert_eq!(command(&[&path, &rotated]), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn restart_continues_chain() {
+        let dir = dir();
+        let path = dir.join("audit.log").to_string_lossy().into_owned();
+        chained(&path).write(entry("a.example:443"));
+        chained(&path).write(entry("b.example:443"));
+
+        let lines = lines(&path);
+        let second: AuditEntry = serde_json::from_str(&lines[1]).unwrap();
+        assert_eq!(second.prev, Some(hash(&lines[0])));
+        assert_eq!(last_line(&path).unwrap().as_deref(), Some(lines[1].as_str()));
+        assert_eq!(command(&[&path]), 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn unchained_line_breaks_chain() {
+        let dir = dir();
+        let path = dir.join("audit.log").to_string_lossy().into_owned();
+        chained(&path).write(entry("a.example:443"));
+        let config = AuditLogConfig { path: path.clone(), hash_chain: false };
+        AuditLog::from_config(&Some(config)).unwrap().unwrap().write(entry("b.example:443"));
+
+        assert_eq!(command(&[&path]), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,251 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::hash::{Hash, Hasher};
+use std::collections::hash_map::DefaultHasher;
+use std::time::Duration;
+use log::{info, warn};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use hyper::{Body, Client, HeaderMap, Request, Uri};
+use tokio::net::TcpStream;
+
+use crate::config::{BalanceConfig, HealthCheckConfig};
+use crate::metrics::Metrics;
+use crate::target::Target;
+
+
+/// How the backend of a request is picked, both in proportion to the backend weights
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Strategy {
+    RoundRobin,
+    Random,
+}
+
+
+/// Backend of a pool with its health as seen by the health checks
+struct Backend {
+    target: Target,
+    weight: u32,
+    healthy: AtomicBool,
+    /// Consecutive probes with the same result, reset when the result changes
+    successes: AtomicU32,
+    failures: AtomicU32,
+}
+
+/// Routes requests to `target` to one of the healthy backends in proportion to their weights
+pub struct Pool {
+    target: Target,
+    backends: Vec<Backend>,
+    strategy: Strategy,
+    /// Header whose value picks the backend, requests with the same value go to the same backend
+    sticky_header: Option<String>,
+    next: AtomicU64,
+    health_check: Option<HealthCheckConfig>,
+}
+
+/// Pool as served by `/admin/backends`
+#[derive(Debug, Serialize)]
+pub struct PoolInfo {
+    pub target: String,
+    pub backends: Vec<BackendInfo>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BackendInfo {
+    pub address: String,
+    pub weight: u32,
+    pub healthy: bool,
+}
+
+/// Every configured pool, a request is balanced by the pool its destination is the target of
+pub struct Balancer {
+    pools: Vec<Arc<Pool>>,
+    metrics: Arc<Metrics>,
+}
+
+impl Balancer {
+    pub fn from_config(config: &[BalanceConfig], metrics: Arc<Metrics>) -> Result<Option<Balancer>, String> {
+        let parse = |v: &str| {
+            v.parse::<Uri>().ok()
+                .and_then(|uri| Target::from_uri(&uri, 443).ok())
+                .ok_or_else(|| format!("invalid load_balance address {:?} (must be host:port)", v))
+        };
+        let mut pools = Vec::new();
+        for pool in config {
+            if pool.backends.is_empty() {
+                return Err(format!("load_balance pool {:?} has no backends", pool.target));
+            }
+            let mut backends = Vec::new();
+            for backend in &pool.backends {
+                backends.push(Backend {
+                    target: parse(backend.address())?,
+                    weight: backend.weight(),
+                    healthy: AtomicBool::new(true),
+                    successes: AtomicU32::new(0),
+                    failures: AtomicU32::new(0),
+                });
+            }
+            pools.push(Arc::new(Pool {
+                target: parse(&pool.target)?,
+                backends,
+                strategy: pool.strategy,
+                sticky_header: pool.sticky_header.clone(),
+                next: AtomicU64::new(0),
+                health_check: pool.health_check.clone(),
+            }));
+        }
+        if pools.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(Balancer { pools, metrics }))
+    }
+
+    /// Picks a backend for a request to `target` with `headers`.
+    ///
+    /// Returns `None` when `target` is not balanced, `Some(None)` when no backend of its pool is healthy.
+    pub fn route(&self, target: &Target, headers: &HeaderMap) -> Option<Option<&Target>> {
+        let pool = self.pools.iter().find(|p| p.target == *target)?;
+        let key = pool.sticky_header.as_ref()
+            .and_then(|h| headers.get(h.as_str()))
+            .map(|v| v.as_bytes());
+        let backend = pool.pick(key);
+        if let Some(backend) = backend {
+            self.metrics.inc("balanced_requests_total",
+                             &[("pool", &pool.target.to_string()), ("backend", &backend.to_string())]);
+        }
+        Some(backend)
+    }
+
+    /// Healthy backends of the pool of `target` with a weight, they race for requests of
+    /// `race_backends` routes; `None` when `target` is not balanced
+    pub fn candidates(&self, target: &Target) -> Option<Vec<Target>> {
+        let pool = self.pools.iter().find(|p| p.target == *target)?;
+        Some(pool.backends.iter()
+            .filter(|b| b.weight > 0 && b.healthy.load(Ordering::Relaxed))
+            .map(|b| b.target.clone())
+            .collect())
+    }
+
+    /// Counts a request to `backend` which won the race of the pool of `target`
+    pub fn count_won(&self, target: &Target, backend: &Target) {
+        self.metrics.inc("balanced_requests_total",
+                         &[("pool", &target.to_string()), ("backend", &backend.to_string())]);
+    }
+
+    /// Spawns the health checks of every pool which has them configured
+    pub fn spawn_health_checks(&self) {
+        for pool in &self.pools {
+            if pool.health_check.is_some() {
+                tokio::task::spawn(health_check(pool.clone(), self.metrics.clone()));
+            }
+        }
+    }
+
+    pub fn info(&self) -> Vec<PoolInfo> {
+        self.pools.iter()
+            .map(|p| PoolInfo {
+                target: p.target.to_string(),
+                backends: p.backends.iter()
+                    .map(|b| BackendInfo {
+                        address: b.target.to_string(),
+                        weight: b.weight,
+                        healthy: b.healthy.load(Ordering::Relaxed),
+                    })
+                    .collect(),
+            })
+            .collect()
+    }
+}
+
+impl Pool {
+    /// Picks a healthy backend with a probability proportional to its weight, a sticky `key`
+    /// always picks the same one as long as the healthy backends do not change
+    fn pick(&self, key: Option<&[u8]>) -> Option<&Target> {
+        let healthy: Vec<&Backend> = self.backends.iter()
+            .filter(|b| b.weight > 0 && b.healthy.load(Ordering::Relaxed))
+            .collect();
+        let total: u64 = healthy.iter().map(|b| b.weight as u64).sum();
+        if total == 0 {
+            return None;
+        }
+        let mut n = match (key, self.strategy) {
+            (Some(key), _) => {
+                let mut hasher = DefaultHasher::new();
+                key.hash(&mut hasher);
+                hasher.finish() % total
+            },
+            (None, Strategy::RoundRobin) => self.next.fetch_add(1, Ordering::Relaxed) % total,
+            (None, Strategy::Random) => rand::thread_rng().gen_range(0..total),
+        };
+        for backend in healthy {
+            if n < backend.weight as u64 {
+                return Some(&backend.target);
+            }
+            n -= backend.weight as u64;
+        }
+        None
+    }
+}
+
+
+/// Probes the backends of a pool every interval, a backend is taken out after `consecutive_failures`
+/// failed probes and put back after `consecutive_successes` successful ones
+async fn health_check(pool: Arc<Pool>, metrics: Arc<Metrics>) {
+    let config = pool.health_check.clone().unwrap();
+    let client = Client::builder().pool_max_idle_per_host(0).build_http::<Body>();
+    let mut interval = tokio::time::interval(Duration::from_secs(config.interval_secs));
+    loop {
+        interval.tick().await;
+        for backend in &pool.backends {
+            let address = backend.target.to_string();
+            let ok = match tokio::time::timeout(Duration::from_secs(config.timeout_secs), probe(&client, &address, &config)).await {
+                Ok(Ok(())) => true,
+                Ok(Err(e)) => {
+                    warn!("health check of backend {} failed; {}", address, e);
+                    false
+                },
+                Err(_) => {
+                    warn!("health check of backend {} timed out after {}s", address, config.timeout_secs);
+                    false
+                }
+            };
+            let labels = [("pool", pool.target.to_string()), ("backend", address.clone())];
+            let labels: Vec<(&str, &str)> = labels.iter().map(|(k, v)| (*k, v.as_str())).collect();
+            metrics.inc("health_checks_total", &[labels[0], labels[1], ("result", if ok { "success" } else { "failure" })]);
+            if ok {
+                backend.failures.store(0, Ordering::Relaxed);
+                let successes = backend.successes.fetch_add(1, Ordering::Relaxed) + 1;
+                if !backend.healthy.load(Ordering::Relaxed) && successes >= config.consecutive_successes {
+                    info!("backend {} of {} is healthy again", address, pool.target);
+                    backend.healthy.store(true, Ordering::Relaxed);
+                }
+            } else {
+                backend.successes.store(0, Ordering::Relaxed);
+                let failures = backend.failures.fetch_add(1, Ordering::Relaxed) + 1;
+                if backend.healthy.load(Ordering::Relaxed) && failures >= config.consecutive_failures {
+                    warn!("backend {} of {} is unhealthy, it gets no requests", address, pool.target);
+                    backend.healthy.store(false, Ordering::Relaxed);
+                }
+            }
+            metrics.set("backend_healthy", &labels, backend.healthy.load(Ordering::Relaxed) as i64);
+        }
+    }
+}
+
+/// Sends `GET path` to the backend and expects a 2xx or 3xx status, connects only when `path` is empty
+async fn probe(client: &Client<hyper::client::HttpConnector>, address: &str, config: &HealthCheckConfig)
+    -> Result<(), String> {
+    if config.path.is_empty() {
+        return TcpStream::connect(address).await.map(|_| ()).map_err(|e| format!("err = {}", e));
+    }
+    let req = Request::get(format!("http://{}{}", address, config.path))
+        .body(Body::empty())
+        .map_err(|e| format!("invalid health check uri; err = {}", e))?;
+    let resp = client.request(req).await.map_err(|e| format!("err = {}", e))?;
+    if resp.status().is_success() || resp.status().is_redirection() {
+        Ok(())
+    } else {
+        Err(format!("status {}", resp.status()))
+    }
+}
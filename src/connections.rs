@@ -0,0 +1,110 @@
+use std::sync::{Arc, Mutex};
+use std::net::SocketAddr;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use chrono::{DateTime, Local};
+use serde::Serialize;
+
+use crate::privacy::Peer;
+
+
+/// Client connection as listed by `/admin/connections`
+#[derive(Debug, Clone, Serialize)]
+pub struct ConnectionInfo {
+    pub id: u64,
+    pub peer: Peer,
+    pub since: DateTime<Local>,
+    /// Identity of the client certificate, `None` for anonymous clients
+    pub identity: Option<String>,
+    /// Destination of the CONNECT tunnel, if the connection was upgraded to one
+    pub tunnel: Option<String>,
+    /// Address the tunnel is connected to
+    pub tunnel_addr: Option<SocketAddr>,
+}
+
+/// Registry of active client connections
+#[derive(Default)]
+pub struct Connections {
+    next_id: AtomicU64,
+    active: Mutex<BTreeMap<u64, ConnectionInfo>>,
+}
+
+impl Connections {
+    pub fn new() -> Connections {
+        Connections::default()
+    }
+
+    /// Registers a connection, it stays listed until the returned guard is dropped
+    pub fn open(self: &Arc<Self>, peer: Peer, identity: Option<String>) -> Arc<ConnectionGuard> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let info = ConnectionInfo {
+            id,
+            peer,
+            since: Local::now(),
+            identity: identity.clone(),
+            tunnel: None,
+            tunnel_addr: None,
+        };
+        self.active.lock().unwrap().insert(id, info);
+        Arc::new(ConnectionGuard {
+            id,
+            peer,
+            identity,
+            principal: Mutex::new(None),
+            opened: Instant::now(),
+            requests: AtomicU64::new(0),
+            connections: self.clone(),
+        })
+    }
+
+    pub fn list(&self) -> Vec<ConnectionInfo> {
+        self.active.lock().unwrap().values().cloned().collect()
+    }
+}
+
+/// Keeps a connection registered, shared by the connection service and its tunnel
+pub struct ConnectionGuard {
+    pub id: u64,
+    pub peer: Peer,
+    /// Identity of the client certificate, see `tls::peer_identity`
+    pub identity: Option<String>,
+    /// Kerberos principal the last request was authenticated as
+    principal: Mutex<Option<String>>,
+    opened: Instant,
+    requests: AtomicU64,
+    connections: Arc<Connections>,
+}
+
+impl ConnectionGuard {
+    /// Counts a request served over the connection, returns how many were served including it
+    pub fn count_request(&self) -> u64 {
+        self.requests.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    pub fn age(&self) -> Duration {
+        self.opened.elapsed()
+    }
+
+    pub fn set_principal(&self, principal: String) {
+        *self.principal.lock().unwrap() = Some(principal);
+    }
+
+    /// Kerberos principal or, without one, identity of the client certificate
+    pub fn user(&self) -> Option<String> {
+        self.principal.lock().unwrap().clone().or_else(|| self.identity.clone())
+    }
+
+    pub fn set_tunnel(&self, target: String, addr: SocketAddr) {
+        if let Some(info) = self.connections.active.lock().unwrap().get_mut(&self.id) {
+            info.tunnel = Some(target);
+            info.tunnel_addr = Some(addr);
+        }
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.connections.active.lock().unwrap().remove(&self.id);
+    }
+}
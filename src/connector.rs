@@ -0,0 +1,476 @@
+use std::pin::Pin;
+use std::future::Future;
+use std::sync::{Arc, Mutex, Weak};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::collections::BTreeMap;
+use std::task::{Context, Poll, Waker};
+use std::io;
+use std::fmt;
+use std::error::Error;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_rustls::TlsConnector;
+use tokio_rustls::client::TlsStream;
+use futures_util::stream::Stream;
+use hyper::{Body, Response, Uri};
+use hyper::body::Bytes;
+use hyper::header::HeaderValue;
+use hyper::client::connect::{Connected, Connection};
+use serde::Serialize;
+use tower_service::Service;
+
+use crate::dial::{order_addrs, AddressOrder, Dialer};
+use crate::metrics::Metrics;
+use crate::resolve::Resolver;
+use crate::target::strip_brackets;
+use crate::tls::{self, UpstreamTls};
+
+
+/// Longest interim response head which is read off an upstream connection
+const MAX_INTERIM_HEAD_BYTES: usize = 16 * 1024;
+
+/// Connector of the forwarding client, dials upstreams like CONNECT tunnels do so every
+/// upstream connection can be closed later through its `ConnectionHandle`.
+///
+/// The handle is attached to the extensions of every response received over the connection,
+/// it also tells whether the connection was reused and how it was established.
+#[derive(Clone)]
+pub struct Connector {
+    dialer: Dialer,
+    address_order: AddressOrder,
+    metrics: Arc<Metrics>,
+    resolver: Arc<dyn Resolver>,
+    tls: Arc<UpstreamTls>,
+    pool: Arc<UpstreamPool>,
+    /// Plain connections speak HTTP/2 with prior knowledge
+    http2_only: bool,
+}
+
+impl Connector {
+    /// Upstream hosts are resolved by `resolver`, the same one CONNECT destinations are resolved by,
+    /// every connection is registered in `pool`
+    pub fn new(dialer: Dialer, address_order: AddressOrder, metrics: Arc<Metrics>,
+               resolver: Arc<dyn Resolver>, tls: Arc<UpstreamTls>, pool: Arc<UpstreamPool>, http2_only: bool)
+        -> Connector {
+        Connector { dialer, address_order, metrics, resolver, tls, pool, http2_only }
+    }
+}
+
+/// TLS handshake with an `https` upstream failed, e.g. its certificate is not trusted
+#[derive(Debug)]
+pub struct TlsError(io::Error);
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0.get_ref().and_then(|e| e.downcast_ref::<rustls::Error>()) {
+            Some(e) if tls::is_pin_mismatch(e) => write!(f, "certificate pin mismatch"),
+            Some(e) if tls::is_client_cert_rejected(e) => write!(f, "client certificate rejected; {}", e),
+            // the message of the rustls error, like `invalid peer certificate: UnknownIssuer`
+            _ => write!(f, "{}", self.0)
+        }
+    }
+}
+
+impl Error for TlsError {}
+
+impl Service<Uri> for Connector {
+    type Response = UpstreamStream;
+    type Error = Box<dyn std::error::Error + Send + Sync>;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let host = String::from(uri.host().map(strip_brackets).unwrap_or(""));
+        let started = Instant::now();
+        let connector = self.clone();
+        Box::pin(async move {
+            let default_port = match uri.scheme_str() {
+                Some("http") if !host.is_empty() => 80,
+                Some("https") if !host.is_empty() => 443,
+                _ => return Err(format!("invalid upstream uri {} (must be http[s]://host[:port])", uri).into())
+            };
+            let addrs = connector.resolver.resolve(&host, uri.port_u16().unwrap_or(default_port)).await?;
+            let (stream, _) = connector.dialer.connect(&order_addrs(addrs, connector.address_order)).await?;
+            let (local, remote) = (stream.local_addr().ok(), stream.peer_addr().ok());
+            let stream = if default_port == 443 {
+                let name = connector.tls.server_name(&host)?;
+                let tls = TlsConnector::from(connector.tls.config(&host));
+                Io::Tls(Box::new(tls.connect(name, stream).await.map_err(TlsError)?))
+            } else {
+                Io::Plain(stream)
+            };
+            let http2 = match &stream {
+                Io::Plain(_) => connector.http2_only,
+                Io::Tls(v) => v.get_ref().1.alpn_protocol() == Some(b"h2"),
+            };
+            let info = ConnectInfo { local, remote, connect_time: started.elapsed(), host, http2 };
+            connector.metrics.inc("upstream_connections_opened_total", &[("host", &info.host)]);
+            connector.metrics.add("upstream_connect_ms_total", &[("host", &info.host)],
+                                  info.connect_time.as_millis() as i64);
+            let handle = ConnectionHandle::new(info);
+            connector.pool.register(&handle);
+            Ok(UpstreamStream { inner: stream, handle })
+        })
+    }
+}
+
+
+/// How an upstream connection was established
+#[derive(Debug, Clone, Default)]
+pub struct ConnectInfo {
+    pub host: String,
+    pub local: Option<SocketAddr>,
+    pub remote: Option<SocketAddr>,
+    /// Time spent resolving and connecting
+    pub connect_time: Duration,
+    /// Requests are multiplexed over the connection
+    pub http2: bool,
+}
+
+/// Closes an upstream connection, so it is evicted from the pool instead of being reused
+#[derive(Clone)]
+pub struct ConnectionHandle {
+    inner: Arc<HandleInner>,
+}
+
+struct HandleInner {
+    info: ConnectInfo,
+    closed: AtomicBool,
+    /// Responses received over the connection
+    responses: AtomicU64,
+    /// Waker of the last pending read, an idle pooled connection notices the close through it
+    waker: Mutex<Option<Waker>>,
+    /// Last time bytes were read or written
+    last_io: Mutex<Instant>,
+    /// Bytes were written since the last read, i.e. a request waits for its response
+    awaiting: AtomicBool,
+    /// Response bodies still passed on to clients
+    bodies: AtomicUsize,
+    interim: Mutex<Interim>,
+}
+
+impl ConnectionHandle {
+    fn new(info: ConnectInfo) -> ConnectionHandle {
+        ConnectionHandle {
+            inner: Arc::new(HandleInner {
+                info,
+                closed: AtomicBool::new(false),
+                responses: AtomicU64::new(0),
+                waker: Mutex::new(None),
+                last_io: Mutex::new(Instant::now()),
+                awaiting: AtomicBool::new(false),
+                bodies: AtomicUsize::new(0),
+                interim: Mutex::new(Interim::default()),
+            })
+        }
+    }
+
+    pub fn info(&self) -> &ConnectInfo {
+        &self.inner.info
+    }
+
+    pub fn close(&self) {
+        self.inner.closed.store(true, Ordering::SeqCst);
+        if let Some(waker) = self.inner.waker.lock().unwrap().take() {
+            waker.wake();
+        }
+    }
+
+    /// Statuses of the interim responses which preceded the last response, like `103 Early Hints`,
+    /// and the `Link` headers of its early hints
+    pub fn take_interim(&self) -> (Vec<u16>, Vec<HeaderValue>) {
+        let mut interim = self.inner.interim.lock().unwrap();
+        (std::mem::take(&mut interim.statuses), std::mem::take(&mut interim.links))
+    }
+
+    /// Counts a response received over the connection, tells whether it was reused for it
+    pub fn count_response(&self) -> bool {
+        self.inner.responses.fetch_add(1, Ordering::Relaxed) > 0
+    }
+
+    fn is_closed(&self) -> bool {
+        self.inner.closed.load(Ordering::SeqCst)
+    }
+
+    fn record_io(&self, write: bool) {
+        *self.inner.last_io.lock().unwrap() = Instant::now();
+        self.inner.awaiting.store(write, Ordering::Relaxed);
+    }
+
+    /// Tells whether no request is waiting for its response and no response body is passed on
+    fn is_idle(&self) -> bool {
+        !self.inner.awaiting.load(Ordering::Relaxed) && self.inner.bodies.load(Ordering::Relaxed) == 0
+    }
+}
+
+
+/// Interim responses of an HTTP/1 upstream, which hyper skips without telling; their heads are
+/// read off the stream before hyper parses them, until the final head of the response
+#[derive(Default)]
+struct Interim {
+    /// A request was sent and its final response head did not arrive yet
+    active: bool,
+    /// Bytes of the head read so far
+    head: Vec<u8>,
+    statuses: Vec<u16>,
+    /// `Link` headers of `103 Early Hints`
+    links: Vec<HeaderValue>,
+}
+
+impl Interim {
+    fn start(&mut self) {
+        *self = Interim { active: true, ..Interim::default() };
+    }
+
+    fn stop(&mut self) {
+        self.active = false;
+        self.head = Vec::new();
+    }
+
+    fn read(&mut self, bytes: &[u8]) {
+        if !self.active {
+            return;
+        }
+        self.head.extend_from_slice(bytes);
+        loop {
+            // anything but a response head, e.g. the rest of a request body sent after an early response
+            let prefix = &b"HTTP/1."[..self.head.len().min(7)];
+            if !self.head.starts_with(prefix) {
+                return self.stop();
+            }
+            let end = match self.head.windows(4).position(|w| w == b"\r\n\r\n") {
+                Some(v) => v + 4,
+                None if self.head.len() > MAX_INTERIM_HEAD_BYTES => return self.stop(),
+                None => return
+            };
+            let head = String::from_utf8_lossy(&self.head[..end]).into_owned();
+            let status = head.get(9..12).and_then(|v| v.parse::<u16>().ok());
+            match status {
+                Some(status) if (100..200).contains(&status) && status != 101 => {
+                    self.statuses.push(status);
+                    let links = head.split("\r\n").skip(1)
+                        .filter_map(|line| line.split_once(':'))
+                        .filter(|(name, _)| status == 103 && name.trim().eq_ignore_ascii_case("link"))
+                        .filter_map(|(_, value)| HeaderValue::from_str(value.trim()).ok());
+                    self.links.extend(links);
+                    self.head.drain(..end);
+                },
+                // the final head is left to hyper
+                _ => return self.stop()
+            }
+        }
+    }
+}
+
+
+/// Every open upstream connection of the forwarding clients, hyper does not tell about its pool
+#[derive(Default)]
+pub struct UpstreamPool {
+    connections: Mutex<Vec<Weak<HandleInner>>>,
+}
+
+/// Upstream connections as served by `/admin/pool`
+#[derive(Debug, Default, Serialize)]
+pub struct PoolInfo {
+    pub open: usize,
+    /// Connections without a request in flight, they are in the pool of hyper
+    pub idle: usize,
+    /// Open connections by host
+    pub hosts: BTreeMap<String, usize>,
+}
+
+impl UpstreamPool {
+    fn register(&self, handle: &ConnectionHandle) {
+        self.connections.lock().unwrap().push(Arc::downgrade(&handle.inner));
+    }
+
+    /// Closes HTTP/1 connections which were idle and without any traffic for `idle_after`,
+    /// returns how many were closed and the connections left open.
+    ///
+    /// HTTP/2 connections are left to hyper, their streams can not be told apart by traffic.
+    pub fn reap(&self, idle_after: Option<Duration>) -> (usize, PoolInfo) {
+        let mut connections = self.connections.lock().unwrap();
+        connections.retain(|c| c.upgrade().map(|c| !c.closed.load(Ordering::SeqCst)).unwrap_or(false));
+        let mut reaped = 0;
+        let mut info = PoolInfo::default();
+        connections.retain(|c| {
+            let handle = match c.upgrade() {
+                Some(inner) => ConnectionHandle { inner },
+                None => return false
+            };
+            let idle = handle.is_idle();
+            let silent = idle_after.map(|d| handle.inner.last_io.lock().unwrap().elapsed() >= d).unwrap_or(false);
+            if idle && silent && !handle.info().http2 {
+                handle.close();
+                reaped += 1;
+                return false;
+            }
+            info.open += 1;
+            info.idle += idle as usize;
+            *info.hosts.entry(handle.info().host.clone()).or_insert(0) += 1;
+            true
+        });
+        (reaped, info)
+    }
+
+    /// Open connections without closing any
+    pub fn info(&self) -> PoolInfo {
+        let mut info = PoolInfo::default();
+        for handle in self.connections.lock().unwrap().iter().filter_map(|c| c.upgrade()) {
+            let handle = ConnectionHandle { inner: handle };
+            if !handle.is_closed() {
+                info.open += 1;
+                info.idle += handle.is_idle() as usize;
+                *info.hosts.entry(handle.info().host.clone()).or_insert(0) += 1;
+            }
+        }
+        info
+    }
+}
+
+
+/// Upstream connection, encrypted for `https` upstreams
+enum Io {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl AsyncRead for Io {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Io::Plain(v) => Pin::new(v).poll_read(cx, buf),
+            Io::Tls(v) => Pin::new(v).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Io {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Io::Plain(v) => Pin::new(v).poll_write(cx, buf),
+            Io::Tls(v) => Pin::new(v).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Io::Plain(v) => Pin::new(v).poll_flush(cx),
+            Io::Tls(v) => Pin::new(v).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Io::Plain(v) => Pin::new(v).poll_shutdown(cx),
+            Io::Tls(v) => Pin::new(v).poll_shutdown(cx),
+        }
+    }
+}
+
+
+pub struct UpstreamStream {
+    inner: Io,
+    handle: ConnectionHandle,
+}
+
+impl AsyncRead for UpstreamStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        // a closed connection reads as EOF, so hyper drops it
+        if self.handle.is_closed() {
+            return Poll::Ready(Ok(()));
+        }
+        let filled = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        match &poll {
+            Poll::Pending => *self.handle.inner.waker.lock().unwrap() = Some(cx.waker().clone()),
+            Poll::Ready(Ok(())) if buf.filled().len() > filled => {
+                self.handle.record_io(false);
+                if !self.handle.info().http2 {
+                    self.handle.inner.interim.lock().unwrap().read(&buf.filled()[filled..]);
+                }
+            },
+            _ => {}
+        }
+        poll
+    }
+}
+
+impl AsyncWrite for UpstreamStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        if self.handle.is_closed() {
+            return Poll::Ready(Err(io::Error::new(io::ErrorKind::BrokenPipe, "upstream connection is closed")));
+        }
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = poll {
+            if n > 0 {
+                // the first bytes written after a read start a request, its response may begin with interim ones
+                if !self.handle.inner.awaiting.load(Ordering::Relaxed) && !self.handle.info().http2 {
+                    self.handle.inner.interim.lock().unwrap().start();
+                }
+                self.handle.record_io(true);
+            }
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+impl Connection for UpstreamStream {
+    fn connected(&self) -> Connected {
+        let connected = match &self.inner {
+            Io::Plain(v) => v.connected(),
+            // hyper speaks HTTP/2 once the upstream agreed to it in ALPN
+            Io::Tls(v) if v.get_ref().1.alpn_protocol() == Some(b"h2") => v.get_ref().0.connected().negotiated_h2(),
+            Io::Tls(v) => v.get_ref().0.connected(),
+        };
+        connected.extra(self.handle.clone())
+    }
+}
+
+
+/// Keeps the upstream connection of a response from being reaped as idle until its body has been
+/// passed on or dropped, then closes the connection with `close`
+pub fn track_body(resp: Response<Body>, close: bool) -> Response<Body> {
+    let handle = match resp.extensions().get::<ConnectionHandle>() {
+        Some(v) => v.clone(),
+        None => return resp
+    };
+    handle.inner.bodies.fetch_add(1, Ordering::Relaxed);
+    let (parts, body) = resp.into_parts();
+    Response::from_parts(parts, Body::wrap_stream(TrackedBody { body, handle, close }))
+}
+
+struct TrackedBody {
+    body: Body,
+    handle: ConnectionHandle,
+    close: bool,
+}
+
+impl Stream for TrackedBody {
+    type Item = Result<Bytes, hyper::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.body).poll_next(cx)
+    }
+}
+
+impl Drop for TrackedBody {
+    fn drop(&mut self) {
+        self.handle.inner.bodies.fetch_sub(1, Ordering::Relaxed);
+        if self.close {
+            self.handle.close();
+        }
+    }
+}
@@ -0,0 +1,61 @@
+use hyper::{Body, Method, Request, Response, StatusCode};
+use hyper::header::{HeaderMap, HeaderValue, ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS,
+                    ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_REQUEST_METHOD, ORIGIN, VARY};
+
+use crate::config::CorsConfig;
+
+
+/// Answers a preflight of an allowed origin with 204 and the CORS headers, the upstream never sees it;
+/// preflights of other origins are forwarded like any request
+pub fn preflight(config: &CorsConfig, req: &Request<Body>) -> Option<Response<Body>> {
+    if req.method() != Method::OPTIONS || !req.headers().contains_key(ACCESS_CONTROL_REQUEST_METHOD) {
+        return None;
+    }
+    let origin = req.headers().get(ORIGIN).and_then(|v| v.to_str().ok())?;
+    allowed_origin(config, origin)?;
+    let mut resp = Response::new(Body::empty());
+    *resp.status_mut() = StatusCode::NO_CONTENT;
+    inject(config, Some(origin), &mut resp);
+    Some(resp)
+}
+
+/// Adds the CORS headers to the response of a request of an allowed origin, replacing the ones of the upstream
+pub fn inject(config: &CorsConfig, origin: Option<&str>, resp: &mut Response<Body>) {
+    let allowed = match origin.and_then(|origin| allowed_origin(config, origin)) {
+        Some(v) => v,
+        None => return
+    };
+    let headers = resp.headers_mut();
+    let varies = headers.get_all(VARY).iter().any(|v| v.to_str().map(has_origin).unwrap_or(false));
+    if allowed != "*" && !varies {
+        // caches must not hand the answer for one origin to another
+        headers.append(VARY, HeaderValue::from_static("Origin"));
+    }
+    insert(headers, ACCESS_CONTROL_ALLOW_ORIGIN, allowed);
+    insert(headers, ACCESS_CONTROL_ALLOW_METHODS, &config.methods.join(", "));
+    insert(headers, ACCESS_CONTROL_ALLOW_HEADERS, &config.headers.join(", "));
+}
+
+/// Value of `Access-Control-Allow-Origin` for `origin`, `*` when every origin is allowed
+fn allowed_origin<'a>(config: &CorsConfig, origin: &'a str) -> Option<&'a str> {
+    if config.origins.iter().any(|o| o == "*") {
+        return Some("*");
+    }
+    config.origins.iter().any(|o| o.eq_ignore_ascii_case(origin)).then_some(origin)
+}
+
+fn insert(headers: &mut HeaderMap, name: hyper::header::HeaderName, value: &str) {
+    match HeaderValue::from_str(value) {
+        Ok(v) if !value.is_empty() => {
+            headers.insert(name, v);
+        },
+        _ => {
+            headers.remove(name);
+        }
+    }
+}
+
+/// Whether a `Vary` value names `Origin`
+fn has_origin(vary: &str) -> bool {
+    vary.split(',').any(|v| v.trim().eq_ignore_ascii_case("origin"))
+}
@@ -0,0 +1,317 @@
+use std::io;
+use std::fmt;
+use std::error::Error;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use log::debug;
+use rand::Rng;
+use std::sync::Arc;
+use serde::{Deserialize, Serialize};
+use tokio::net::{TcpSocket, TcpStream};
+use tokio::task::JoinSet;
+
+use crate::config::Config;
+use crate::resolve::Resolver;
+use crate::target::Target;
+
+
+/// Source ports tried for one address before the range counts as exhausted
+const MAX_PORT_ATTEMPTS: u32 = 32;
+
+
+/// Order in which the addresses of a destination are tried
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AddressOrder {
+    /// As returned by the resolver
+    AsReturned,
+    PreferIpv4,
+    PreferIpv6,
+}
+
+/// Sorts addresses by `order`, the order within a family is kept
+pub fn order_addrs(mut addrs: Vec<SocketAddr>, order: AddressOrder) -> Vec<SocketAddr> {
+    match order {
+        AddressOrder::AsReturned => {},
+        AddressOrder::PreferIpv4 => addrs.sort_by_key(|a| !a.is_ipv4()),
+        AddressOrder::PreferIpv6 => addrs.sort_by_key(|a| !a.is_ipv6()),
+    }
+    addrs
+}
+
+
+/// Every address of a destination failed, holds the error of every attempt
+#[derive(Debug)]
+pub struct DialError(pub Vec<(SocketAddr, io::Error)>);
+
+impl DialError {
+    /// Tells whether no address could be tried because `outbound.port_range` had no free port
+    pub fn ports_exhausted(&self) -> bool {
+        !self.0.is_empty() && self.0.iter().all(|(_, e)| e.get_ref().map(|e| e.is::<PortsExhausted>()).unwrap_or(false))
+    }
+}
+
+impl fmt::Display for DialError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let attempts: Vec<String> = self.0.iter().map(|(addr, e)| format!("{} ({})", addr, e)).collect();
+        write!(f, "{}", attempts.join(", "))
+    }
+}
+
+impl Error for DialError {}
+
+/// No free source port was found in `outbound.port_range`
+#[derive(Debug)]
+pub struct PortsExhausted(u16, u16);
+
+impl fmt::Display for PortsExhausted {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "no free source port in {}-{}", self.0, self.1)
+    }
+}
+
+impl Error for PortsExhausted {}
+
+
+/// Opens outgoing connections of CONNECT tunnels and of the forwarding client, bound to
+/// `client.local_address`, a source port of `outbound.port_range` and `outbound.bind_interface`, with
+/// `outbound.tcp_user_timeout_ms`
+#[derive(Debug, Clone)]
+pub struct Dialer {
+    timeout: Option<Duration>,
+    local_ip: Option<IpAddr>,
+    ports: Option<(u16, u16)>,
+    interface: Option<String>,
+    user_timeout: Option<Duration>,
+}
+
+impl Dialer {
+    pub fn from_config(config: &Config) -> Result<Dialer, String> {
+        let local_ip = match &config.client.local_address {
+            Some(v) => Some(v.parse()
+                .map_err(|_| format!("invalid client.local_address {:?} (must be an IP address)", v))?),
+            None => None
+        };
+        let ports = match config.outbound.port_range {
+            Some([first, last]) if first == 0 || first > last => {
+                return Err(format!("invalid outbound.port_range [{}, {}] (must be [first, last] of ports 1-65535)",
+                                   first, last));
+            },
+            Some([first, last]) => Some((first, last)),
+            None => None
+        };
+        let interface = config.outbound.bind_interface.clone();
+        if let Some(interface) = &interface {
+            check_interface(interface)?;
+        }
+        let user_timeout = config.outbound.tcp_user_timeout();
+        if let Some(timeout) = user_timeout {
+            TcpSocket::new_v4().and_then(|socket| set_user_timeout(&socket, timeout))
+                .map_err(|e| format!("can not set outbound.tcp_user_timeout_ms; err = {}", e))?;
+        }
+        Ok(Dialer { timeout: config.client.connect_timeout(), local_ip, ports, interface, user_timeout })
+    }
+
+    /// Connects to the first address accepting the connection, the timeout applies to every attempt
+    pub async fn connect(&self, addrs: &[SocketAddr]) -> Result<(TcpStream, SocketAddr), DialError> {
+        let mut errors = Vec::new();
+        for addr in addrs {
+            let connecting = self.connect_one(*addr);
+            let result = match self.timeout {
+                Some(timeout) => match tokio::time::timeout(timeout, connecting).await {
+                    Ok(v) => v,
+                    Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, format!("no connection in {:?}", timeout)))
+                },
+                None => connecting.await
+            };
+            match result {
+                Ok(v) => return Ok((v, *addr)),
+                Err(e) => {
+                    debug!("can not connect to {}, trying the next address; err = {}", addr, e);
+                    errors.push((*addr, e));
+                }
+            }
+        }
+        Err(DialError(errors))
+    }
+
+    /// Connects to all `backends` at once and returns the connection established first with its
+    /// backend; the other attempts are aborted and their connections closed
+    pub async fn race(&self, resolver: Arc<dyn Resolver>, backends: &[Target], order: AddressOrder)
+        -> Result<(TcpStream, SocketAddr, Target), String> {
+        let mut racing = JoinSet::new();
+        for backend in backends {
+            let (dialer, resolver, backend) = (self.clone(), resolver.clone(), backend.clone());
+            racing.spawn(async move {
+                let addrs = resolver.resolve(&backend.host, backend.port).await
+                    .map_err(|e| format!("{}: {}", backend, e))?;
+                // errors of connecting name the addresses tried
+                let (stream, addr) = dialer.connect(&order_addrs(addrs, order)).await.map_err(|e| e.to_string())?;
+                Ok::<_, String>((stream, addr, backend))
+            });
+        }
+        let mut errors = Vec::new();
+        while let Some(joined) = racing.join_next().await {
+            match joined {
+                // dropping the set aborts the slower attempts
+                Ok(Ok(v)) => return Ok(v),
+                Ok(Err(e)) => errors.push(e),
+                Err(e) => errors.push(e.to_string())
+            }
+        }
+        Err(errors.join(", "))
+    }
+
+    async fn connect_one(&self, addr: SocketAddr) -> io::Result<TcpStream> {
+        let interface = self.interface.as_deref();
+        let (first, last) = match (self.local_ip, self.ports) {
+            (None, None) if interface.is_none() && self.user_timeout.is_none() => return TcpStream::connect(addr).await,
+            (None, None) => return self.socket(addr, None)?.connect(addr).await,
+            (Some(ip), None) => return self.socket(addr, Some(SocketAddr::new(ip, 0)))?.connect(addr).await,
+            (_, Some(v)) => v
+        };
+        let ip = self.local_ip.unwrap_or(match addr {
+            SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        });
+        // ports are tried in turn from a random one, so concurrent connections rarely collide
+        let size = (last - first) as u32 + 1;
+        let start = rand::thread_rng().gen_range(0..size);
+        for i in 0..size.min(MAX_PORT_ATTEMPTS) {
+            let port = first + ((start + i) % size) as u16;
+            let result = match self.socket(addr, Some(SocketAddr::new(ip, port))) {
+                Ok(socket) => socket.connect(addr).await,
+                Err(e) => Err(e)
+            };
+            match result {
+                // the port is taken, or already connected to the same address
+                Err(e) if e.kind() == io::ErrorKind::AddrInUse || e.kind() == io::ErrorKind::AddrNotAvailable => {
+                    debug!("source port {} is busy for {}; err = {}", port, addr, e);
+                },
+                v => return v
+            }
+        }
+        Err(io::Error::new(io::ErrorKind::AddrInUse, PortsExhausted(first, last)))
+    }
+
+    /// Creates a socket for `addr` bound to `local` and the interface
+    fn socket(&self, addr: SocketAddr, local: Option<SocketAddr>) -> io::Result<TcpSocket> {
+        let socket = match addr {
+            SocketAddr::V4(_) => TcpSocket::new_v4()?,
+            SocketAddr::V6(_) => TcpSocket::new_v6()?,
+        };
+        if let Some(interface) = &self.interface {
+            bind_device(&socket, interface)?;
+        }
+        if let Some(timeout) = self.user_timeout {
+            // set before connecting, it bounds the retransmissions of the handshake as well
+            set_user_timeout(&socket, timeout)?;
+        }
+        if let Some(local) = local {
+            // ports of closed connections in TIME_WAIT stay usable
+            socket.set_reuseaddr(true)?;
+            socket.bind(local)?;
+        }
+        Ok(socket)
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn bind_device(socket: &TcpSocket, interface: &str) -> io::Result<()> {
+    socket2::SockRef::from(socket).bind_device(Some(interface.as_bytes()))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_device(_socket: &TcpSocket, _interface: &str) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "SO_BINDTODEVICE is only supported on Linux"))
+}
+
+#[cfg(target_os = "linux")]
+fn set_user_timeout(socket: &TcpSocket, timeout: Duration) -> io::Result<()> {
+    socket2::SockRef::from(socket).set_tcp_user_timeout(Some(timeout))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_user_timeout(_socket: &TcpSocket, _timeout: Duration) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "TCP_USER_TIMEOUT is only supported on Linux"))
+}
+
+/// Binds a socket to `outbound.bind_interface` once at startup, so a missing interface or
+/// privilege is reported before the first connection fails
+fn check_interface(interface: &str) -> Result<(), String> {
+    let result = TcpSocket::new_v4().and_then(|socket| bind_device(&socket, interface));
+    match result {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => Err(format!(
+            "can not bind outgoing connections to interface {:?}, outbound.bind_interface requires CAP_NET_RAW; \
+             err = {}", interface, e)),
+        Err(e) => Err(format!("can not bind outgoing connections to interface {:?}; err = {}", interface, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addrs(list: &[&str]) -> Vec<SocketAddr> {
+        list.iter().map(|a| a.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn orders_addresses_by_family() {
+        let returned = addrs(&["[2001:db8::1]:443", "192.0.2.1:443", "[2001:db8::2]:443", "192.0.2.2:443"]);
+
+        assert_eq!(order_addrs(returned.clone(), AddressOrder::AsReturned), returned);
+        assert_eq!(order_addrs(returned.clone(), AddressOrder::PreferIpv4),
+                   addrs(&["192.0.2.1:443", "192.0.2.2:443", "[2001:db8::1]:443", "[2001:db8::2]:443"]));
+        assert_eq!(order_addrs(returned, AddressOrder::PreferIpv6),
+                   addrs(&["[2001:db8::1]:443", "[2001:db8::2]:443", "192.0.2.1:443", "192.0.2.2:443"]));
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_the_next_address() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        // nothing listens on the port of the other loopback address
+        let refusing = SocketAddr::from(([127, 0, 0, 3], port));
+        let dialer = Dialer::from_config(&Config::default()).unwrap();
+
+        let (_, addr) = dialer.connect(&[refusing, listener.local_addr().unwrap()]).await.unwrap();
+        assert_eq!(addr, listener.local_addr().unwrap());
+
+        let error = dialer.connect(&[refusing, SocketAddr::from(([127, 0, 0, 4], port))]).await.unwrap_err();
+        assert_eq!(error.0.iter().map(|(a, _)| a.ip().to_string()).collect::<Vec<_>>(), ["127.0.0.3", "127.0.0.4"]);
+        assert!(error.to_string().starts_with(&format!("127.0.0.3:{} (", port)), "{}", error);
+        assert!(!error.ports_exhausted());
+    }
+
+    #[tokio::test]
+    async fn binds_source_ports_of_the_range() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        // below the ephemeral ports of Linux, which other tests are given
+        let mut config = Config::default();
+        config.outbound.port_range = Some([31000, 31001]);
+        let dialer = Dialer::from_config(&config).unwrap();
+
+        let (first, _) = dialer.connect(&[addr]).await.unwrap();
+        let (second, _) = dialer.connect(&[addr]).await.unwrap();
+        let mut ports = [first.local_addr().unwrap().port(), second.local_addr().unwrap().port()];
+        ports.sort_unstable();
+        assert_eq!(ports, [31000, 31001]);
+
+        let error = dialer.connect(&[addr]).await.unwrap_err();
+        assert!(error.ports_exhausted(), "{}", error);
+        assert_eq!(error.to_string(), format!("{} (no free source port in 31000-31001)", addr));
+    }
+
+    #[test]
+    fn refuses_invalid_port_ranges() {
+        for range in [[0, 10], [2000, 1000]] {
+            let mut config = Config::default();
+            config.outbound.port_range = Some(range);
+            let error = Dialer::from_config(&config).unwrap_err();
+            assert!(error.starts_with("invalid outbound.port_range"), "{}", error);
+        }
+    }
+}
@@ -0,0 +1,183 @@
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::future::Future;
+use std::convert::TryFrom;
+use std::net::{IpAddr, SocketAddr};
+use std::task::{Context, Poll};
+use serde::Deserialize;
+use tokio::net::TcpStream;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_rustls::TlsConnector;
+use tokio_rustls::client::TlsStream;
+use rustls::pki_types::ServerName;
+use hyper::{Body, Client, Request, Uri};
+use hyper::client::HttpConnector;
+use hyper::client::connect::{Connected, Connection};
+use tower_service::Service;
+
+use crate::resolve::{Resolver, Resolving};
+use crate::tls;
+
+
+/// Record types of the JSON API
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+/// Response codes of the JSON API
+const RCODE_NOERROR: u16 = 0;
+const RCODE_SERVFAIL: u16 = 2;
+const RCODE_NXDOMAIN: u16 = 3;
+
+
+/// Resolves with a DNS-over-HTTPS server speaking the JSON API (`application/dns-json`),
+/// as served by e.g. `https://cloudflare-dns.com/dns-query`.
+///
+/// The name of the DoH server itself is resolved by the system resolver.
+pub struct DohResolver {
+    url: Uri,
+    client: Client<DohConnector>,
+}
+
+impl DohResolver {
+    /// Certificates of the server are verified against the CA bundle `ca_pem`
+    pub fn new(url: &str, ca_pem: &str) -> Result<DohResolver, String> {
+        let url = match url.parse::<Uri>() {
+            Ok(v) if v.scheme_str() == Some("https") && v.host().is_some() => v,
+            _ => return Err(format!("invalid dns.doh_url {:?} (must be an https uri)", url))
+        };
+        let config = rustls::ClientConfig::builder_with_provider(tls::provider())
+            .with_safe_default_protocol_versions()
+            .map_err(|e| format!("can not setup TLS; err = {:?}", e))?
+            .with_root_certificates(tls::load_roots(ca_pem)?)
+            .with_no_client_auth();
+        let mut http = HttpConnector::new();
+        http.enforce_http(false);
+        let connector = DohConnector { http, tls: TlsConnector::from(Arc::new(config)) };
+        Ok(DohResolver { url, client: Client::builder().build(connector) })
+    }
+
+    /// Asks for records of one type, an empty list means the name has no such records
+    async fn query(&self, host: &str, record_type: u16) -> io::Result<Vec<IpAddr>> {
+        let uri = format!("{}?name={}&type={}", self.url, host, record_type);
+        let req = Request::get(uri)
+            .header(hyper::header::ACCEPT, "application/dns-json")
+            .body(Body::empty())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        // the resolver is temporarily unavailable rather than the name missing
+        let resp = self.client.request(req).await.map_err(|e| {
+            io::Error::new(io::ErrorKind::ConnectionAborted, format!("can not reach DoH server {}; {}", self.url, e))
+        })?;
+        if !resp.status().is_success() {
+            return Err(io::Error::other(format!("DoH server answered {}", resp.status())));
+        }
+        let body = hyper::body::to_bytes(resp.into_body()).await.map_err(io::Error::other)?;
+        let answer: DohAnswer = serde_json::from_slice(&body)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        match answer.status {
+            RCODE_NOERROR => {},
+            RCODE_NXDOMAIN => return Err(io::Error::new(io::ErrorKind::NotFound, format!("{} does not exist", host))),
+            // worded like getaddrinfo, so the failure is retried
+            RCODE_SERVFAIL => return Err(io::Error::other("Temporary failure in name resolution (SERVFAIL)")),
+            v => return Err(io::Error::other(format!("DoH server answered rcode {}", v)))
+        }
+        Ok(answer.answer.iter()
+            .filter(|r| r.record_type == record_type)
+            .filter_map(|r| r.data.parse().ok())
+            .collect())
+    }
+}
+
+impl Resolver for DohResolver {
+    fn resolve<'a>(&'a self, host: &'a str, port: u16) -> Resolving<'a> {
+        Box::pin(async move {
+            if let Ok(ip) = host.parse::<IpAddr>() {
+                return Ok(vec![SocketAddr::new(ip, port)]);
+            }
+            let (v4, v6) = tokio::join!(self.query(host, TYPE_A), self.query(host, TYPE_AAAA));
+            let addrs: Vec<SocketAddr> = match (v4, v6) {
+                (Err(e), Err(_)) => return Err(e),
+                (v4, v6) => v4.unwrap_or_default().into_iter()
+                    .chain(v6.unwrap_or_default())
+                    .map(|ip| SocketAddr::new(ip, port))
+                    .collect()
+            };
+            if addrs.is_empty() {
+                return Err(io::Error::new(io::ErrorKind::NotFound, format!("no addresses found for {}", host)));
+            }
+            Ok(addrs)
+        })
+    }
+}
+
+
+#[derive(Deserialize)]
+struct DohAnswer {
+    #[serde(rename = "Status")]
+    status: u16,
+    #[serde(rename = "Answer", default)]
+    answer: Vec<DohRecord>,
+}
+
+#[derive(Deserialize)]
+struct DohRecord {
+    #[serde(rename = "type")]
+    record_type: u16,
+    data: String,
+}
+
+
+/// Opens TLS connections to the DoH server
+#[derive(Clone)]
+struct DohConnector {
+    http: HttpConnector,
+    tls: TlsConnector,
+}
+
+impl Service<Uri> for DohConnector {
+    type Response = DohStream;
+    type Error = Box<dyn std::error::Error + Send + Sync>;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.http.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let host = uri.host().map(crate::target::strip_brackets).unwrap_or("").to_string();
+        let connecting = self.http.call(uri);
+        let tls = self.tls.clone();
+        Box::pin(async move {
+            let name = ServerName::try_from(host)?;
+            let stream = connecting.await?;
+            Ok(DohStream(tls.connect(name, stream).await?))
+        })
+    }
+}
+
+struct DohStream(TlsStream<TcpStream>);
+
+impl AsyncRead for DohStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for DohStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
+impl Connection for DohStream {
+    fn connected(&self) -> Connected {
+        Connected::new()
+    }
+}
@@ -0,0 +1,306 @@
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+use hyper::{Body, Response, StatusCode};
+use hyper::header::{self, HeaderValue};
+
+
+/// Header the code of an error made by the proxy is sent in, like `X-Squid-Error` of squid
+pub const PROXY_ERROR_HEADER: &str = "x-proxy-error";
+/// Retry hint of errors a retry may fix
+pub const RETRY_AFTER: Duration = Duration::from_secs(1);
+
+/// Page of statuses without a template of their own when `error_pages` has no `default`
+const BUILTIN_PAGE: &str = "<!DOCTYPE html>
+<html>
+<head><title>{{status}} {{reason}}</title></head>
+<body>
+<h1>{{status}} {{reason}}</h1>
+<p>The proxy could not complete the request to {{host}}.</p>
+<p>Request ID: {{request_id}}</p>
+</body>
+</html>
+";
+
+
+/// Why the proxy answered a request itself, its code tells automation a refusal by policy from
+/// a failing upstream and labels `proxy_errors_total`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// Refused by the acl, `allow_localhost`, `allowed_methods`, `denied_methods`, `dns.family` or the webhook
+    PolicyBlocked,
+    /// Credentials of the proxy or the parent proxy are missing or invalid
+    AuthRequired,
+    /// Over `per_user_rate_limit` or the connection limit of the destination
+    RateLimited,
+    /// The destination of the request is malformed
+    BadRequest,
+    DnsFailure,
+    /// No connection or TLS handshake with the server, the parent proxy or a healthy backend
+    UpstreamConnectFailed,
+    UpstreamTimeout,
+    /// The request points back at the proxy
+    LoopDetected,
+    /// The proxy itself failed, e.g. it has no free source port or the webhook is down
+    InternalError,
+}
+
+impl ErrorKind {
+    /// Stable code of the error in bodies, headers and metrics
+    pub fn code(&self) -> &'static str {
+        match self {
+            ErrorKind::PolicyBlocked => "policy_blocked",
+            ErrorKind::AuthRequired => "auth_required",
+            ErrorKind::RateLimited => "rate_limited",
+            ErrorKind::BadRequest => "bad_request",
+            ErrorKind::DnsFailure => "dns_failure",
+            ErrorKind::UpstreamConnectFailed => "upstream_connect_failed",
+            ErrorKind::UpstreamTimeout => "upstream_timeout",
+            ErrorKind::LoopDetected => "loop_detected",
+            ErrorKind::InternalError => "internal_error",
+        }
+    }
+
+    /// `None` for errors a retry does not fix
+    fn retry_after(&self) -> Option<Duration> {
+        match self {
+            ErrorKind::RateLimited | ErrorKind::DnsFailure | ErrorKind::UpstreamConnectFailed
+            | ErrorKind::UpstreamTimeout => Some(RETRY_AFTER),
+            _ => None
+        }
+    }
+}
+
+
+/// Error response made by the proxy, kept in the extensions of the response until it is sent
+#[derive(Debug, Clone)]
+pub struct ProxyError {
+    pub kind: ErrorKind,
+    pub message: String,
+    /// How long the client should wait before retrying
+    pub retry_after: Option<Duration>,
+}
+
+impl ProxyError {
+    pub fn new(kind: ErrorKind, message: impl Into<String>) -> ProxyError {
+        ProxyError { kind, message: message.into(), retry_after: kind.retry_after() }
+    }
+
+    /// Replaces the retry hint of the kind
+    pub fn retry_after(mut self, retry_after: Option<Duration>) -> ProxyError {
+        self.retry_after = retry_after;
+        self
+    }
+
+    /// Response with the message as plain text and the code in `x-proxy-error`
+    pub fn into_response(self, status: StatusCode) -> Response<Body> {
+        let mut resp = Response::new(Body::from(self.message.clone()));
+        *resp.status_mut() = status;
+        resp.headers_mut().insert(PROXY_ERROR_HEADER, HeaderValue::from_static(self.kind.code()));
+        resp.extensions_mut().insert(self);
+        resp
+    }
+}
+
+
+/// Templates of the error responses made by the proxy itself, answers of servers are passed on as they are
+pub struct ErrorPages {
+    pages: HashMap<u16, String>,
+    default: Option<String>,
+}
+
+impl ErrorPages {
+    /// Reads the template files of `error_pages`, returns `None` when none are configured
+    pub fn from_config(config: &BTreeMap<String, String>) -> Result<Option<ErrorPages>, String> {
+        if config.is_empty() {
+            return Ok(None);
+        }
+        let mut pages = ErrorPages { pages: HashMap::new(), default: None };
+        for (key, path) in config {
+            let template = std::fs::read_to_string(path)
+                .map_err(|e| format!("can not read error_pages template {:?} of {}; err = {}", path, key, e))?;
+            match key.parse::<u16>() {
+                Ok(status) if (400..600).contains(&status) => { pages.pages.insert(status, template); },
+                _ if key == "default" => pages.default = Some(template),
+                _ => return Err(format!("invalid error_pages key {:?} (must be a status from 400 to 599 or default)",
+                                        key))
+            }
+        }
+        Ok(Some(pages))
+    }
+
+    /// Replaces the body of an error response with its page, the other headers are kept
+    pub fn render(&self, resp: Response<Body>, request_id: &str, host: &str) -> Response<Body> {
+        let status = resp.status();
+        let error = resp.extensions().get::<ProxyError>();
+        let template = self.pages.get(&status.as_u16()).or(self.default.as_ref())
+            .map(|v| v.as_str())
+            .unwrap_or(BUILTIN_PAGE);
+        let body = template
+            .replace("{{status}}", status.as_str())
+            .replace("{{reason}}", &escape_html(status.canonical_reason().unwrap_or("Error")))
+            .replace("{{code}}", error.map(|e| e.kind.code()).unwrap_or(""))
+            .replace("{{message}}", &escape_html(error.map(|e| e.message.as_str()).unwrap_or("")))
+            .replace("{{request_id}}", &escape_html(request_id))
+            .replace("{{host}}", &escape_html(host));
+        replace_body(resp, body, "text/html; charset=utf-8")
+    }
+}
+
+/// Replaces the body of an error response with JSON, `code`, `message` and `retry_after_ms` are set
+/// for errors made by the proxy
+pub fn render_json(resp: Response<Body>, request_id: &str) -> Response<Body> {
+    let reason = resp.status().canonical_reason().unwrap_or("Error");
+    let mut body = serde_json::json!({"error": reason, "request_id": request_id});
+    if let Some(error) = resp.extensions().get::<ProxyError>() {
+        body["code"] = error.kind.code().into();
+        body["message"] = error.message.as_str().into();
+        if let Some(retry_after) = error.retry_after {
+            body["retry_after_ms"] = (retry_after.as_millis() as u64).into();
+        }
+    }
+    replace_body(resp, body.to_string(), "application/json")
+}
+
+fn replace_body(resp: Response<Body>, body: String, content_type: &'static str) -> Response<Body> {
+    let (mut parts, _) = resp.into_parts();
+    parts.headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+    parts.headers.remove(header::CONTENT_LENGTH);
+    Response::from_parts(parts, Body::from(body))
+}
+
+/// Tells whether an `Accept` header ranks `application/json` above `text/html`, browsers send the latter
+pub fn prefers_json(accept: &str) -> bool {
+    let quality = |media_type: &str| accept.split(',')
+        .filter_map(|range| {
+            let mut params = range.split(';').map(|v| v.trim());
+            let name = params.next()?;
+            if !name.eq_ignore_ascii_case(media_type) {
+                return None;
+            }
+            let q = params.find_map(|p| p.strip_prefix("q=")).and_then(|q| q.parse::<f32>().ok()).unwrap_or(1.0);
+            Some(q)
+        })
+        .next();
+    match quality("application/json") {
+        Some(json) => json > 0.0 && json > quality("text/html").unwrap_or(0.0),
+        None => false
+    }
+}
+
+fn escape_html(value: &str) -> String {
+    value.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;").replace('\'', "&#39;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KINDS: [ErrorKind; 9] = [
+        ErrorKind::PolicyBlocked, ErrorKind::AuthRequired, ErrorKind::RateLimited, ErrorKind::BadRequest,
+        ErrorKind::DnsFailure, ErrorKind::UpstreamConnectFailed, ErrorKind::UpstreamTimeout, ErrorKind::LoopDetected,
+        ErrorKind::InternalError,
+    ];
+
+    #[test]
+    fn codes_are_stable() {
+        let codes: Vec<&str> = KINDS.iter().map(|k| k.code()).collect();
+        assert_eq!(codes, ["policy_blocked", "auth_required", "rate_limited", "bad_request", "dns_failure",
+                           "upstream_connect_failed", "upstream_timeout", "loop_detected", "internal_error"]);
+    }
+
+    #[test]
+    fn only_retryable_errors_have_retry_hint() {
+        for kind in KINDS {
+            let retryable = matches!(kind, ErrorKind::RateLimited | ErrorKind::DnsFailure
+                                     | ErrorKind::UpstreamConnectFailed | ErrorKind::UpstreamTimeout);
+            let expected = if retryable { Some(RETRY_AFTER) } else { None };
+            assert_eq!(ProxyError::new(kind, "").retry_after, expected, "{:?}", kind);
+        }
+    }
+
+    #[test]
+    fn response_carries_code_and_message() {
+        let resp = ProxyError::new(ErrorKind::PolicyBlocked, "destination blocked.example:443 denied by acl")
+            .into_response(StatusCode::FORBIDDEN);
+
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        assert_eq!(resp.headers()[PROXY_ERROR_HEADER], "policy_blocked");
+        assert_eq!(resp.extensions().get::<ProxyError>().unwrap().kind, ErrorKind::PolicyBlocked);
+    }
+
+    #[tokio::test]
+    async fn json_body_has_code_message_and_retry_hint() {
+        let json = |resp: Response<Body>| async move {
+            let resp = render_json(resp, "4f1c2a9e0b7d3e61");
+            assert_eq!(resp.headers()[header::CONTENT_TYPE], "application/json");
+            let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+        };
+
+        let body = json(ProxyError::new(ErrorKind::UpstreamTimeout, "no response from remote server in 1s")
+            .into_response(StatusCode::GATEWAY_TIMEOUT)).await;
+        assert_eq!(body, serde_json::json!({"error": "Gateway Timeout", "request_id": "4f1c2a9e0b7d3e61",
+            "code": "upstream_timeout", "message": "no response from remote server in 1s", "retry_after_ms": 1000}));
+        let body = json(ProxyError::new(ErrorKind::LoopDetected, "refusing to proxy to myself")
+            .into_response(StatusCode::FORBIDDEN)).await;
+        assert_eq!(body.get("retry_after_ms"), None);
+        // answers of servers have no code
+        let mut resp = Response::new(Body::from("down"));
+        *resp.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+        let body = json(resp).await;
+        assert_eq!(body, serde_json::json!({"error": "Service Unavailable", "request_id": "4f1c2a9e0b7d3e61"}));
+    }
+
+    #[test]
+    fn json_is_preferred_only_when_ranked_above_html() {
+        let cases = [
+            ("application/json", true),
+            ("application/json, text/plain", true),
+            ("Application/JSON;q=0.5", true),
+            ("text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8", false),
+            ("text/html;q=0.5, application/json", true),
+            ("text/html, application/json", false),
+            ("application/json;q=0", false),
+            ("*/*", false),
+        ];
+        for (accept, expected) in cases {
+            assert_eq!(prefers_json(accept), expected, "{}", accept);
+        }
+    }
+
+    #[tokio::test]
+    async fn pages_are_rendered_with_escaped_variables() {
+        let pages = ErrorPages {
+            pages: HashMap::from([(403, String::from("{{status}} {{reason}} {{code}}: {{message}} ({{request_id}})"))]),
+            default: None,
+        };
+        let pages = &pages;
+        let page = |resp: Response<Body>| async move {
+            let resp = pages.render(resp, "4f1c2a9e0b7d3e61", "<b>example.com</b>");
+            assert_eq!(resp.headers()[header::CONTENT_TYPE], "text/html; charset=utf-8");
+            String::from_utf8(hyper::body::to_bytes(resp.into_body()).await.unwrap().to_vec()).unwrap()
+        };
+
+        let blocked = ProxyError::new(ErrorKind::PolicyBlocked, "denied by acl rule \"<script>\"")
+            .into_response(StatusCode::FORBIDDEN);
+        assert_eq!(page(blocked).await,
+                   "403 Forbidden policy_blocked: denied by acl rule &quot;&lt;script&gt;&quot; (4f1c2a9e0b7d3e61)");
+        // statuses without a page of their own get the built-in one
+        let builtin = page(ProxyError::new(ErrorKind::UpstreamTimeout, "").into_response(StatusCode::GATEWAY_TIMEOUT))
+            .await;
+        assert!(builtin.contains("<h1>504 Gateway Timeout</h1>"), "{}", builtin);
+        assert!(builtin.contains("request to &lt;b&gt;example.com&lt;/b&gt;."), "{}", builtin);
+        assert!(builtin.contains("Request ID: 4f1c2a9e0b7d3e61"), "{}", builtin);
+    }
+
+    #[test]
+    fn refuses_missing_templates_and_invalid_keys() {
+        let config = |key: &str, path: &str| BTreeMap::from([(String::from(key), String::from(path))]);
+
+        assert!(ErrorPages::from_config(&BTreeMap::new()).unwrap().is_none());
+        let error = ErrorPages::from_config(&config("502", "/nonexistent/502.html")).err().unwrap();
+        assert!(error.starts_with("can not read error_pages template \"/nonexistent/502.html\" of 502"), "{}", error);
+        let error = ErrorPages::from_config(&config("302", file!())).err().unwrap();
+        assert_eq!(error, "invalid error_pages key \"302\" (must be a status from 400 to 599 or default)");
+    }
+}
@@ -0,0 +1,48 @@
+use std::time::Duration;
+use std::collections::BTreeMap;
+use rand::Rng;
+
+use crate::config::{Config, Mode};
+use crate::target::host_matches;
+
+
+/// Delays requests and responses to simulate a slow network, only in `mode: development`
+pub struct Latency {
+    /// Delay of every host, `None` delays only hosts of `overrides`
+    default: Option<u64>,
+    /// Delays of host patterns, they take precedence over `default`
+    overrides: BTreeMap<String, u64>,
+    /// Up to this many milliseconds are added to every delay at random
+    jitter: u64,
+}
+
+impl Latency {
+    /// Returns `None` when nothing is delayed
+    pub fn from_config(config: &Config) -> Option<Latency> {
+        if config.mode != Mode::Development
+            || (config.simulate_latency_ms.is_none() && config.simulate_latency_overrides.is_empty()) {
+            return None;
+        }
+        Some(Latency {
+            default: config.simulate_latency_ms,
+            overrides: config.simulate_latency_overrides.clone(),
+            jitter: config.simulate_jitter_ms,
+        })
+    }
+
+    fn delay(&self, host: &str) -> Option<Duration> {
+        let ms = match self.overrides.iter().find(|(pattern, _)| host_matches(pattern, host)) {
+            Some((_, ms)) => *ms,
+            None => self.default?
+        };
+        let jitter = if self.jitter > 0 { rand::thread_rng().gen_range(0..=self.jitter) } else { 0 };
+        Some(Duration::from_millis(ms + jitter))
+    }
+
+    /// Waits the delay of `host`
+    pub async fn sleep(&self, host: &str) {
+        if let Some(delay) = self.delay(host) {
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
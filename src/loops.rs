@@ -0,0 +1,164 @@
+use std::sync::RwLock;
+use std::net::{IpAddr, SocketAddr};
+use log::{debug, warn};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use hyper::http::{HeaderMap, HeaderValue, header::VIA};
+
+
+/// How destinations pointing back at the proxy are detected
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LoopDetection {
+    /// No detection at all
+    Off,
+    /// Destination equals a listening address, and `Via` header check for multi-hop chains
+    Listen,
+    /// As `listen`, and any port on any local interface address is refused as well
+    Strict,
+}
+
+
+/// Detects requests which would make the proxy connect to itself
+pub struct LoopGuard {
+    mode: LoopDetection,
+    listen: Vec<SocketAddr>,
+    /// Addresses of local interfaces, enumerated at startup and on SIGHUP
+    interfaces: RwLock<Vec<IpAddr>>,
+    /// Name of this proxy in `Via` headers
+    pseudonym: String,
+}
+
+impl LoopGuard {
+    pub fn new(mode: LoopDetection, listen: Vec<SocketAddr>, pseudonym: Option<String>) -> LoopGuard {
+        let pseudonym = match pseudonym {
+            Some(v) => v,
+            None => format!("{}-{:08x}", env!("CARGO_PKG_NAME"), rand::thread_rng().gen::<u32>())
+        };
+        let guard = LoopGuard { mode, listen, interfaces: RwLock::new(Vec::new()), pseudonym };
+        guard.refresh();
+        guard
+    }
+
+    /// Enumerates addresses of local interfaces again
+    pub fn refresh(&self) {
+        if self.mode == LoopDetection::Off {
+            return;
+        }
+        match if_addrs::get_if_addrs() {
+            Ok(v) => {
+                let addrs: Vec<IpAddr> = v.iter().map(|i| i.ip()).collect();
+                debug!("local interface addresses {:?}", addrs);
+                *self.interfaces.write().unwrap() = addrs;
+            },
+            Err(e) => warn!("can not enumerate local interface addresses; err = {:?}", e)
+        }
+    }
+
+    /// Tells whether connecting to the address would reach this proxy
+    pub fn is_local(&self, addr: &SocketAddr) -> bool {
+        let interfaces = self.interfaces.read().unwrap();
+        let is_interface = addr.ip().is_loopback() || interfaces.contains(&addr.ip());
+        match self.mode {
+            LoopDetection::Off => false,
+            LoopDetection::Strict if is_interface => true,
+            _ => self.listen.iter().any(|l| {
+                if l.port() != addr.port() {
+                    false
+                } else if l.ip().is_unspecified() {
+                    is_interface
+                } else if l.ip().is_loopback() {
+                    addr.ip().is_loopback()
+                } else {
+                    l.ip() == addr.ip()
+                }
+            })
+        }
+    }
+
+    /// Tells whether the request already passed through this proxy
+    pub fn seen(&self, headers: &HeaderMap) -> bool {
+        if self.mode == LoopDetection::Off {
+            return false;
+        }
+        headers.get_all(VIA).iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .any(|hop| hop.split_whitespace().nth(1) == Some(self.pseudonym.as_str()))
+    }
+
+    /// Appends this proxy to the `Via` header of a forwarded request
+    pub fn add_via(&self, headers: &mut HeaderMap, version: hyper::Version) {
+        if self.mode == LoopDetection::Off {
+            return;
+        }
+        let protocol = match version {
+            hyper::Version::HTTP_10 => "1.0",
+            hyper::Version::HTTP_2 => "2",
+            _ => "1.1"
+        };
+        if let Ok(v) = HeaderValue::from_str(&format!("{} {}", protocol, self.pseudonym)) {
+            headers.append(VIA, v);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn guard(mode: LoopDetection, listen: &str) -> LoopGuard {
+        LoopGuard::new(mode, vec![listen.parse().unwrap()], Some(String::from("edge-1")))
+    }
+
+    fn addr(v: &str) -> SocketAddr {
+        v.parse().unwrap()
+    }
+
+    #[test]
+    fn listening_address_is_local() {
+        let guard = guard(LoopDetection::Listen, "127.0.0.1:3128");
+
+        assert!(guard.is_local(&addr("127.0.0.1:3128")));
+        // any loopback address reaches a listener on one
+        assert!(guard.is_local(&addr("127.0.0.2:3128")));
+        assert!(guard.is_local(&addr("[::1]:3128")));
+        assert!(!guard.is_local(&addr("127.0.0.1:8080")));
+        assert!(!guard.is_local(&addr("192.0.2.1:3128")));
+    }
+
+    #[test]
+    fn unspecified_listener_covers_every_interface() {
+        let guard = guard(LoopDetection::Listen, "0.0.0.0:3128");
+        guard.interfaces.write().unwrap().push(addr("192.0.2.7:0").ip());
+
+        assert!(guard.is_local(&addr("192.0.2.7:3128")));
+        assert!(guard.is_local(&addr("127.0.0.1:3128")));
+        assert!(!guard.is_local(&addr("192.0.2.8:3128")));
+    }
+
+    #[test]
+    fn strict_refuses_every_port_of_local_interfaces() {
+        let strict = guard(LoopDetection::Strict, "127.0.0.1:3128");
+        let off = guard(LoopDetection::Off, "127.0.0.1:3128");
+
+        assert!(strict.is_local(&addr("127.0.0.1:8080")));
+        assert!(!strict.is_local(&addr("192.0.2.1:8080")));
+        assert!(!off.is_local(&addr("127.0.0.1:3128")));
+    }
+
+    #[test]
+    fn own_via_entry_is_seen() {
+        let guard = guard(LoopDetection::Listen, "127.0.0.1:3128");
+        let mut headers = HeaderMap::new();
+        headers.insert(VIA, HeaderValue::from_static("1.1 edge-2, 1.0 edge-10"));
+        assert!(!guard.seen(&headers));
+
+        guard.add_via(&mut headers, hyper::Version::HTTP_11);
+
+        assert_eq!(headers.get_all(VIA).iter().next_back().unwrap(), "1.1 edge-1");
+        assert!(guard.seen(&headers));
+        assert!(!LoopGuard::new(LoopDetection::Off, Vec::new(), Some(String::from("edge-1"))).seen(&headers));
+    }
+}
@@ -1,20 +1,178 @@
 use std::process::exit;
 use std::format;
-use std::convert::{TryFrom,Infallible};
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::future::Future;
+use std::pin::Pin;
+use std::time::{Duration, Instant, SystemTime};
 use std::io::Write;
-use std::net::{ToSocketAddrs, SocketAddr};
+use std::net::SocketAddr;
 use log::{info, warn, error, debug};
-use futures_util::future::try_join;
-use clap::{App, Arg};
-use http;
+use futures_util::future::poll_fn;
+use clap::{App, Arg, ArgMatches, SubCommand};
 use tokio::net::TcpStream;
-use hyper::service::{make_service_fn, service_fn};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{watch, Semaphore};
+use hyper::service::service_fn;
 use hyper::upgrade::Upgraded;
-use hyper::{Body, Client, Method, Request, Response, Server};
-use hyper::server::conn::AddrStream;
+use hyper::{Body, Client, Method, Request, Response};
+use hyper::server::accept::Accept;
+use hyper::server::conn::{AddrIncoming, AddrStream, Http};
+use tokio_rustls::TlsAcceptor;
 
+mod access_log;
+mod audit_log;
+mod activation;
+mod accounting;
+mod acl;
+mod admin;
+mod balance;
+mod config;
+mod connections;
+mod connector;
+mod cors;
+mod dial;
+mod doh;
+mod error_page;
+mod latency;
+mod loops;
+mod metrics;
+mod mirror;
+#[cfg(feature = "kerberos")]
+mod negotiate;
+mod normalize;
+mod outgoing;
+mod privacy;
+mod prewarm;
+mod ratelimit;
+mod recent;
+mod redact;
+mod request_id;
+mod resolve;
+mod response_size;
+mod rewrite;
+mod route;
+mod self_signed;
+mod slow_client;
+mod split;
+mod stale;
+mod startup;
+mod statsd;
+mod tap;
+mod target;
+mod tls;
+mod tunnel_pool;
+mod transform;
+mod upstream_proxy;
+mod webhook;
+use access_log::{AccessLog, AccessLogEntry};
+use audit_log::{AuditEntry, AuditLog};
+use accounting::{ByteAccounting, Counted, TunnelMeter};
+use acl::{Acl, Denial};
+use balance::Balancer;
+use config::{Config, ConfigError, ServerHeader, Source};
+use connections::{ConnectionGuard, Connections};
+use connector::{ConnectionHandle, Connector, TlsError, UpstreamPool};
+use dial::{DialError, Dialer};
+use error_page::{ErrorKind, ErrorPages, ProxyError};
+use latency::Latency;
+use loops::LoopGuard;
+use metrics::Metrics;
+use mirror::Mirror;
+use normalize::UriNormalization;
+use outgoing::OutgoingLimiter;
+use privacy::{Peer, Privacy};
+use ratelimit::UserRateLimiter;
+use redact::QueryRedaction;
+use resolve::{Failure, Resolver, SystemResolver};
+use rewrite::PathRewriter;
+use route::Route;
+use slow_client::{ClientStream, ListenerStream};
+use split::Split;
+use stale::StaleResponses;
+use self_signed::SelfSigned;
+use recent::{RecentRequest, RecentRequests};
+use request_id::RequestIds;
+use startup::StartupError;
+use statsd::Statsd;
+use tap::Tap;
+use target::Target;
+use tls::{ReloadingCert, UpstreamTls};
+use transform::BodyTransforms;
+use tunnel_pool::TunnelPool;
+use upstream_proxy::UpstreamProxy;
+use webhook::{Outcome, Webhook};
 
-pub type HttpClient = Client<hyper::client::HttpConnector>;
+
+pub type HttpClient = Client<Connector>;
+
+/// Shared by all connections of the server
+pub struct State {
+    /// Config as of the last reload, read through `config()`
+    config: watch::Receiver<Arc<Config>>,
+    pub client: HttpClient,
+    /// HTTP/2 client of gRPC requests when `client` speaks HTTP/1
+    pub grpc_client: Option<HttpClient>,
+    /// Upstream connections of both clients
+    pub pool: Arc<UpstreamPool>,
+    pub mirror: Option<Mirror>,
+    pub webhook: Option<Webhook>,
+    pub tap: Option<Arc<Tap>>,
+    pub split: Option<Split>,
+    pub balancer: Option<Balancer>,
+    pub rewriter: Option<PathRewriter>,
+    pub transforms: Option<BodyTransforms>,
+    /// Copies of `serve_stale_on_error`
+    pub stale: Option<Arc<StaleResponses>>,
+    pub resolver: Arc<dyn Resolver>,
+    pub dialer: Dialer,
+    /// Idle connections of `connect_prewarm`
+    pub tunnel_pool: Option<Arc<TunnelPool>>,
+    /// Parent proxy CONNECT tunnels are opened through
+    pub upstream_proxy: Option<UpstreamProxy>,
+    pub upstream_tls: Arc<UpstreamTls>,
+    /// TLS of `grpc_client`, which offers `h2` by ALPN
+    pub grpc_upstream_tls: Option<Arc<UpstreamTls>>,
+    /// Certificates of the proxy and admin listeners by role, `listener` or `admin`
+    pub listener_certs: Vec<(&'static str, Arc<ReloadingCert>)>,
+    /// Generated certificate of the proxy listener with `tls_auto_self_signed`
+    pub self_signed: Option<SelfSigned>,
+    pub latency: Option<Latency>,
+    /// Masking of query strings in logged uris
+    pub log_query: QueryRedaction,
+    /// Anonymization of client addresses, applied once a connection is accepted
+    pub privacy: Privacy,
+    pub metrics: Arc<Metrics>,
+    pub loops: LoopGuard,
+    /// Replaced when a reload changes `acl`, read through `acl()`
+    acl: RwLock<Arc<Acl>>,
+    pub connections: Arc<Connections>,
+    pub outgoing: Arc<OutgoingLimiter>,
+    /// `limits.max_pending_upgrades`, `None` when it is unlimited
+    pub pending_upgrades: Option<Arc<Semaphore>>,
+    /// `limits.max_tunnels`, `None` when it is unlimited
+    pub tunnels: Option<Arc<Semaphore>>,
+    pub accounting: Option<ByteAccounting>,
+    /// `per_user_rate_limit`, `None` when it is not configured
+    pub user_limit: Option<UserRateLimiter>,
+    pub access_log: Option<Arc<AccessLog>>,
+    pub audit_log: Option<AuditLog>,
+    pub error_pages: Option<ErrorPages>,
+    pub request_ids: RequestIds,
+    /// Latest requests listed by `/admin/requests`
+    pub recent: Option<RecentRequests>,
+}
+
+impl State {
+    /// Current config, a request should keep the one it started with
+    pub fn config(&self) -> Arc<Config> {
+        self.config.borrow().clone()
+    }
+
+    pub fn acl(&self) -> Arc<Acl> {
+        self.acl.read().unwrap().clone()
+    }
+}
 
 
 #[tokio::main]
@@ -29,18 +187,26 @@ async fn main() {
                      record.args()
             )
         })
-        .filter(None, log::LevelFilter::Debug)
+        // log_level of the config sets the max level, which can change on reload
+        .filter(None, log::LevelFilter::Trace)
         .init();
 
+    if let Err(e) = run().await {
+        startup::exit_with(e);
+    }
+}
+
+async fn run() -> Result<(), StartupError> {
     // setup argument parser
-    const NAME: &'static str = env!("CARGO_PKG_NAME");
-    const VERSION: &'static str = env!("CARGO_PKG_VERSION");
+    const NAME: &str = env!("CARGO_PKG_NAME");
+    const VERSION: &str = env!("CARGO_PKG_VERSION");
     let env_app: String = NAME.to_uppercase().replace('-', "_");
     let env_ip = format!("{}{}", env_app, "_IP");
     let env_port = format!("{}{}", env_app, "_PORT");
 
     let arg_matches = App::new(NAME)
         .version(VERSION)
+        .after_help(startup::EXIT_CODES_HELP)
         .arg(Arg::with_name("config")
             .long("config")
             .short("c")
@@ -49,127 +215,1336 @@ async fn main() {
         )
         .arg(Arg::with_name("ip")
             .long("ip")
-            .env(&*env_ip)
+            .env(&env_ip)
             .help("Sets a ip address for server")
         )
         .arg(Arg::with_name("port")
             .long("port")
             .short("p")
-            .env(&*env_port)
+            .env(&env_port)
             .help("Sets a port for server")
         )
-        .get_matches();
+        .arg(Arg::with_name("print-config")
+            .long("print-config")
+            .help("Prints the effective config with the source of every value and exits")
+        )
+        .subcommand(SubCommand::with_name("verify-audit-log")
+            .about("Checks the hash chain of audit_log files, exits with 1 when it is broken")
+            .arg(Arg::with_name("files")
+                .required(true)
+                .multiple(true)
+                .help("Files of the audit log, oldest first when it was rotated")
+            )
+        )
+        .subcommand(SubCommand::with_name("resolve")
+            .about("Resolves a host the way the proxy does and prints its addresses, exits with 1 on failure")
+            .arg(Arg::with_name("target")
+                .required(true)
+                .help("Host to resolve as hostname[:port]")
+            )
+        )
+        .get_matches_safe();
+    let arg_matches = match arg_matches {
+        Ok(v) => v,
+        // --help and --version are not errors, clap prints them and exits with 0
+        Err(e) if !e.use_stderr() => e.exit(),
+        Err(e) => return Err(StartupError::Usage(e.message))
+    };
 
-    const DEFAULT_IP: &'static str = "127.0.0.1";
-    let mut ip = String::from(arg_matches.value_of("ip").unwrap_or(DEFAULT_IP));
-    const DEFAULT_PORT: u16 = 8080;
-    let mut port: u16 = match arg_matches.value_of("port") {
-        Some(v) => match v.parse::<u16>() {
-            Ok(v) => v,
+    // the files are all it needs, no config
+    if let Some(arg_matches) = arg_matches.subcommand_matches("verify-audit-log") {
+        let files: Vec<&str> = arg_matches.values_of("files").unwrap().collect();
+        exit(audit_log::command(&files));
+    }
+
+    // read config
+    let config_path = arg_matches.value_of("config").unwrap();
+    let mut config = Config::load(config_path).map_err(|e| match e {
+        ConfigError::Open(e) => StartupError::NoInput(String::from(config_path), e),
+        e => StartupError::Config(format!("can not load config file {:?}; {}", config_path, e))
+    })?;
+
+    // values from env and args take precedence over config file
+    if let Some(v) = arg_matches.value_of("ip") {
+        config.ip = String::from(v);
+        config.set_source("ip", arg_source(&arg_matches, "ip"));
+    }
+
+    if let Some(v) = arg_matches.value_of("port") {
+        match v.parse::<u16>() {
+            Ok(v) => {
+                config.port = v;
+                config.set_source("port", arg_source(&arg_matches, "port"));
+            },
             Err(_) => {
                 warn!("invalid set port in args (must be a number in the range 0..65535, got {:?}) \
-                       will be changed to default value ({})", v, DEFAULT_PORT);
-                DEFAULT_PORT
+                       will be changed to default value ({})", v, config::DEFAULT_PORT);
+                config.port = config::DEFAULT_PORT;
+                config.set_source("port", Source::Default);
+            }
+        }
+    }
+
+    log::set_max_level(config.log_level.filter());
+
+    if arg_matches.is_present("print-config") {
+        print!("{}", config.to_annotated_yaml());
+        return Ok(());
+    }
+
+    if let Some(arg_matches) = arg_matches.subcommand_matches("resolve") {
+        exit(resolve::command(&config, arg_matches.value_of("target").unwrap()).await);
+    }
+    info!("config file {:?} in effect: {}", config_path, config.summary());
+
+    // under systemd socket activation the first socket is the proxy listener, a second one the
+    // admin listener; ip, port and admin_listen are not bound then
+    let mut activated = activation::listeners().map_err(StartupError::Activation)?.into_iter();
+    let proxy_listener = activated.next();
+    let admin_listener = if config.admin_listen.is_some() { activated.next() } else { None };
+    if activated.len() > 0 {
+        warn!("ignoring {} more sockets of socket activation, the proxy and admin_listen take one each",
+              activated.len());
+    }
+    let local_addr = |l: &std::net::TcpListener| l.local_addr()
+        .map_err(|e| StartupError::Activation(format!("can not get address of activated socket; err = {}", e)));
+    let addr = match &proxy_listener {
+        Some(listener) => local_addr(listener)?,
+        None => {
+            let ip = config.ip.trim_start_matches('[').trim_end_matches(']');
+            match SystemResolver.resolve(ip, config.port).await {
+                Ok(v) => v[0],
+                Err(_) => {
+                    return Err(StartupError::Resolve(format!("can not resolve server address {}:{}", config.ip,
+                                                             config.port)));
+                }
             }
+        }
+    };
+    let admin_addr = match (&admin_listener, &config.admin_listen) {
+        (Some(listener), _) => Some(local_addr(listener)?),
+        (None, Some(v)) => match v.parse::<SocketAddr>() {
+            Ok(v) => Some(v),
+            Err(_) => return Err(StartupError::Config(format!("invalid admin_listen address {:?}", v)))
+        },
+        (None, None) => None
+    };
+    let mut listener_certs = Vec::new();
+    let admin_acceptor = match (&config.admin_cert_pem, &config.admin_key_pem) {
+        (Some(cert), Some(key)) => {
+            let client_ca = if config.admin_mtls { config.admin_ca_pem.as_deref() } else { None };
+            let (tls_config, cert) = tls::server_config(cert, key, client_ca).map_err(StartupError::Tls)?;
+            listener_certs.push(("admin", cert));
+            Some(TlsAcceptor::from(Arc::new(tls_config)))
         },
-        None => DEFAULT_PORT
+        _ => None
+    };
+    // the generated pair is served like configured files, the config itself keeps them unset
+    let mut listener_tls = config.tls.clone();
+    let self_signed = if config.tls_auto_self_signed {
+        if config.tls.cert_pem.is_some() || config.tls.key_pem.is_some() {
+            return Err(StartupError::Config(String::from(
+                "tls_auto_self_signed can not be combined with tls.cert_pem and tls.key_pem")));
+        }
+        let cert = SelfSigned::generate(&config.tls_self_signed_hostname, Some(addr.ip())).map_err(StartupError::Tls)?;
+        self_signed::warn_untrusted(&cert);
+        listener_tls.cert_pem = Some(cert.cert_pem.clone());
+        listener_tls.key_pem = Some(cert.key_pem.clone());
+        Some(cert)
+    } else {
+        None
+    };
+    let acceptor = match tls::listener_config(&listener_tls, config.serves_http2()).map_err(StartupError::Tls)? {
+        Some((tls_config, cert)) => {
+            listener_certs.push(("listener", cert));
+            Some(TlsAcceptor::from(Arc::new(tls_config)))
+        },
+        None => None
     };
+    if config.admin_mtls && (admin_addr.is_none() || admin_acceptor.is_none() || config.admin_ca_pem.is_none()) {
+        return Err(StartupError::Config(String::from(
+            "admin_mtls requires admin_listen, admin_cert_pem, admin_key_pem and admin_ca_pem")));
+    }
 
-    // read config
-    let config_path = arg_matches.value_of("config").unwrap();
-    let config_file = match std::fs::File::open(config_path) {
-        Ok(v) => v,
-        Err(e) => {
-            error!("can not open config file {:?}; err = {:?}", config_path, e);
-            exit(78);
+    if config.kerberos.enabled {
+        if cfg!(not(feature = "kerberos")) {
+            return Err(StartupError::Config(String::from(
+                "kerberos requires a build with the kerberos feature (cargo build --features kerberos)")));
+        }
+        if let Some(keytab) = &config.kerberos.keytab {
+            // read by the GSSAPI library when a ticket is validated
+            std::env::set_var("KRB5_KTNAME", keytab);
+        }
+    }
+    let latency = Latency::from_config(&config);
+    if latency.is_some() {
+        warn!("simulating latency of {:?}ms (jitter {}ms), do not use in production",
+              config.simulate_latency_ms, config.simulate_jitter_ms);
+    } else if config.simulate_latency_ms.is_some() || !config.simulate_latency_overrides.is_empty() {
+        warn!("simulate_latency_ms is ignored in production mode");
+    }
+    let metrics = Arc::new(Metrics::new());
+    if let Some(statsd) = Statsd::from_config(&config, metrics.clone()).map_err(StartupError::Config)? {
+        tokio::task::spawn(statsd.run());
+    }
+    let mirror = Mirror::from_config(&config, metrics.clone()).map_err(StartupError::Config)?;
+    let webhook = Webhook::from_config(&config, metrics.clone()).map_err(StartupError::Config)?;
+    let tap = Tap::from_config(&config, metrics.clone()).map_err(StartupError::Config)?;
+    let resolver = resolve::from_config(&config, metrics.clone()).map_err(StartupError::Config)?;
+    let split = match &config.split_traffic {
+        Some(v) => Some(Split::from_config(v).map_err(StartupError::Config)?),
+        None => None
+    };
+    let balancer = Balancer::from_config(&config.load_balance, metrics.clone()).map_err(StartupError::Config)?;
+    let rewriter = PathRewriter::from_config(&config.path_rewrites).map_err(StartupError::Config)?;
+    let transforms = BodyTransforms::from_config(&config.body_transforms, config.body_transform_max_bytes)
+        .map_err(StartupError::Config)?;
+    if let Some(balancer) = &balancer {
+        balancer.spawn_health_checks();
+    }
+    let client_config = &config.client;
+    info!("upstream pool: idle timeout {:?}, max idle per host {:?}, http1 only {}, retry canceled requests {}",
+          client_config.pool_idle_timeout.map(Duration::from_secs), client_config.pool_max_idle_per_host,
+          client_config.http1_only, client_config.retry_canceled_requests);
+    // hyper ignores a zero idle timeout, no idle connections are kept instead
+    let max_idle_per_host = match client_config.pool_idle_timeout {
+        Some(0) => 0,
+        _ => client_config.pool_max_idle_per_host.unwrap_or(usize::MAX)
+    };
+    prewarm::validate(&config.prewarm).map_err(StartupError::Config)?;
+    route::validate(&config.routes).map_err(StartupError::Config)?;
+    let dialer = Dialer::from_config(&config).map_err(StartupError::Config)?;
+    let upstream_proxy = UpstreamProxy::from_config(&config).map_err(StartupError::Config)?;
+    let upstream_tls = Arc::new(UpstreamTls::from_config(&config.upstream_tls, client_config.http1_only)
+        .map_err(StartupError::Tls)?);
+    let pool = Arc::new(UpstreamPool::default());
+    let client = Client::builder()
+        .pool_idle_timeout(client_config.pool_idle_timeout.map(Duration::from_secs))
+        .pool_max_idle_per_host(max_idle_per_host)
+        .http2_only(!client_config.http1_only)
+        .retry_canceled_requests(client_config.retry_canceled_requests)
+        .build(Connector::new(dialer.clone(), config.dns.address_order, metrics.clone(), resolver.clone(),
+                              upstream_tls.clone(), pool.clone(), !client_config.http1_only));
+    let (grpc_client, grpc_upstream_tls) = if config.grpc_proxy && client_config.http1_only {
+        let tls = Arc::new(UpstreamTls::from_config(&config.upstream_tls, false).map_err(StartupError::Tls)?);
+        let client = Client::builder()
+            .pool_idle_timeout(client_config.pool_idle_timeout.map(Duration::from_secs))
+            .pool_max_idle_per_host(max_idle_per_host)
+            .http2_only(true)
+            .build(Connector::new(dialer.clone(), config.dns.address_order, metrics.clone(), resolver.clone(),
+                                  tls.clone(), pool.clone(), true));
+        (Some(client), Some(tls))
+    } else {
+        (None, None)
+    };
+    let listen: Vec<SocketAddr> = std::iter::once(addr).chain(admin_addr).collect();
+    let loops = LoopGuard::new(config.loop_detection, listen, config.via_pseudonym.clone());
+    let acl = Acl::from_config(&config.acl, metrics.clone()).map_err(StartupError::Config)?;
+    if acl.denies_all() {
+        warn!("acl.allow and acl.allow_file are empty with default_action deny, every destination is denied");
+    }
+    let connections = Arc::new(Connections::new());
+    let outgoing = Arc::new(OutgoingLimiter::new(config.max_outgoing_per_host,
+                                                 config.max_outgoing_per_host_overrides.clone(),
+                                                 Duration::from_millis(config.outgoing_queue_timeout_ms),
+                                                 config.outgoing_fairness, metrics.clone()));
+    let accounting = match config.billing_interval_kb {
+        Some(interval_kb) => {
+            let (accounting, events) = ByteAccounting::new(interval_kb);
+            tokio::task::spawn(accounting::consume(events, metrics.clone()));
+            Some(accounting)
+        },
+        None => None
+    };
+    let log_query = QueryRedaction::from_config(&config);
+    let privacy = Privacy::from_config(&config.privacy).map_err(StartupError::Config)?;
+    let user_limit = UserRateLimiter::from_config(&config.per_user_rate_limit);
+    let access_log = AccessLog::from_config(&config).map_err(StartupError::Config)?.map(Arc::new);
+    let audit_log = AuditLog::from_config(&config.audit_log).map_err(StartupError::Config)?;
+    let error_pages = ErrorPages::from_config(&config.error_pages).map_err(StartupError::Config)?;
+    let recent = RecentRequests::new(config.recent_requests_buffer);
+    let request_ids = RequestIds::from_config(&config.request_id).map_err(StartupError::Config)?;
+    let stale = StaleResponses::from_config(&config.serve_stale_on_error);
+    let pending_upgrades = config.limits.max_pending_upgrades.map(|max| Arc::new(Semaphore::new(max)));
+    let tunnels = config.limits.max_tunnels.map(|max| Arc::new(Semaphore::new(max)));
+    let tunnel_pool = TunnelPool::from_config(&config.connect_prewarm, dialer.clone(), outgoing.clone(),
+                                              metrics.clone());
+    let (config_sender, config) = watch::channel(Arc::new(config));
+    let acl = RwLock::new(Arc::new(acl));
+    let state = Arc::new(State {
+        config, client, grpc_client, pool, mirror, webhook, tap, split, balancer, rewriter, transforms, stale, resolver,
+        dialer,
+        tunnel_pool, upstream_proxy,
+        upstream_tls, grpc_upstream_tls, listener_certs, self_signed, latency, log_query, privacy, metrics, loops, acl,
+        connections, outgoing, accounting, pending_upgrades, tunnels, user_limit, access_log, audit_log, error_pages,
+        request_ids, recent,
+    });
+
+    if !state.config().prewarm.is_empty() {
+        tokio::task::spawn(prewarm::prewarm(state.client.clone(), state.config().prewarm.clone()));
+    }
+    tokio::task::spawn(watch_certs(state.clone()));
+    // always watched, a reload may add rule files
+    tokio::task::spawn(watch_acl_files(state.clone()));
+    if state.user_limit.is_some() {
+        tokio::task::spawn(forget_idle_users(state.clone()));
+    }
+    if let Some(interval) = state.config().client.pool_reap_interval_secs {
+        tokio::task::spawn(reap_pool(state.clone(), Duration::from_secs(interval)));
+    }
+
+    if let Some(admin_addr) = admin_addr {
+        let listener = match admin_listener {
+            Some(v) => tokio::net::TcpListener::from_std(v).map_err(|e| StartupError::Activation(
+                format!("can not listen on activated socket {}; err = {}", admin_addr, e)))?,
+            None => tokio::net::TcpListener::bind(admin_addr).await
+                .map_err(|e| StartupError::from_io_bind(admin_addr, e))?
+        };
+        info!("admin listening at {}{}", admin_addr, if state.config().admin_mtls { " (mTLS)" } else { "" });
+        tokio::task::spawn(admin::serve(state.clone(), listener, admin_acceptor));
+    }
+
+    #[cfg(unix)]
+    {
+        // local interfaces may change while running, SIGHUP enumerates them again, reopens the access
+        // log, re-reads the listener certificates and reloads the config file
+        let state = state.clone();
+        let config_path = String::from(config_path);
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+            Ok(mut hangup) => {
+                tokio::task::spawn(async move {
+                    while hangup.recv().await.is_some() {
+                        info!("SIGHUP received, refreshing local interface addresses and certificates, reloading \
+                               config file {:?}", config_path);
+                        state.loops.refresh();
+                        for (_, cert) in &state.listener_certs {
+                            cert.refresh(true);
+                        }
+                        if let Some(log) = &state.access_log {
+                            if let Err(e) = log.reopen() {
+                                warn!("{}, writing to the previous file", e);
+                            }
+                        }
+                        if let Some(log) = &state.audit_log {
+                            if let Err(e) = log.reopen() {
+                                warn!("{}, writing to the previous file", e);
+                            }
+                        }
+                        reload_config(&state, &config_path, &config_sender);
+                    }
+                });
+            },
+            Err(e) => warn!("can not handle SIGHUP; err = {:?}", e)
         }
+    }
+
+    // bind before serving, so a busy or privileged address is reported as a startup error
+    let activated = proxy_listener.is_some();
+    let mut incoming = match proxy_listener {
+        Some(v) => {
+            let listen_error = |e: &dyn std::fmt::Display| StartupError::Activation(
+                format!("can not listen on activated socket {}; err = {}", addr, e));
+            let listener = tokio::net::TcpListener::from_std(v).map_err(|e| listen_error(&e))?;
+            AddrIncoming::from_listener(listener).map_err(|e| listen_error(&e))?
+        },
+        None => AddrIncoming::bind(&addr).map_err(|e| StartupError::from_bind(addr, e))?
     };
-    let config: serde_yaml::Value = match serde_yaml::from_reader(config_file) {
+    let mut http = Http::new();
+    if state.config().serves_http2() {
+        // h2 refuses streams beyond the limit by itself
+        http.http2_max_concurrent_streams(state.config().max_concurrent_streams);
+    } else {
+        http.http1_only(true);
+    }
+    if let Some(v) = state.config().limits.header_timeout() {
+        http.http1_header_read_timeout(v);
+    }
+    http.max_buf_size(state.config().http1_max_buf_size_kb * 1024);
+    http.http1_half_close(state.config().http1_half_close);
+
+    info!("server listening at {}{}{}", addr, if acceptor.is_some() { " (TLS)" } else { "" },
+          if activated { " (socket activation)" } else { "" });
+
+    loop {
+        let stream = match poll_fn(|cx| Pin::new(&mut incoming).poll_accept(cx)).await {
+            Some(Ok(v)) => v,
+            Some(Err(e)) => return Err(StartupError::Crash(e)),
+            None => return Ok(())
+        };
+        tokio::task::spawn(serve_client(state.clone(), http.clone(), acceptor.clone(), stream));
+    }
+}
+
+/// Reloads certificates whose files changed, e.g. after a renewal, without dropping connections,
+/// and keeps track of their expiry
+async fn watch_certs(state: Arc<State>) {
+    let mut interval = tokio::time::interval(tls::CERT_CHECK_INTERVAL);
+    let mut warned: Option<Instant> = None;
+    loop {
+        interval.tick().await;
+        if let Some(cert) = &state.self_signed {
+            cert.refresh();
+        }
+        for (_, cert) in &state.listener_certs {
+            cert.refresh(false);
+        }
+        for tls in std::iter::once(&state.upstream_tls).chain(&state.grpc_upstream_tls) {
+            tls.refresh_client_certs();
+        }
+        let warn_now = warned.is_none_or(|v| v.elapsed() >= tls::EXPIRY_WARNING_INTERVAL);
+        if warn_now {
+            warned = Some(Instant::now());
+        }
+        let threshold = state.config().tls.expiry_warning_days as i64 * 24 * 60 * 60;
+        for info in certificates(&state) {
+            if let Some(seconds) = info.expires_in_seconds {
+                state.metrics.set("tls_cert_expiry_seconds", &[("role", info.role), ("cert", &info.cert)], seconds);
+                let date = info.not_after.unwrap_or_default();
+                if warn_now && seconds < 0 {
+                    warn!("{} certificate {:?} expired on {}", info.role, info.cert, date);
+                } else if warn_now && seconds < threshold {
+                    warn!("{} certificate {:?} expires in {} days on {}", info.role, info.cert,
+                          seconds / (24 * 60 * 60), date);
+                }
+            }
+        }
+    }
+}
+
+/// Re-reads the config file and applies the keys of `config::RELOADABLE_KEYS`, request handlers see the
+/// new config from their next request on. A file which does not load or whose acl is invalid keeps the
+/// current config, changes of other keys are logged as needing a restart.
+fn reload_config(state: &State, config_path: &str, sender: &watch::Sender<Arc<Config>>) {
+    let loaded = match Config::load(config_path) {
         Ok(v) => v,
         Err(e) => {
-            error!("can not open config file {:?}; err = {:?}", config_path, e);
-            exit(78);
+            warn!("can not reload config file {:?}, keeping the current config; {}", config_path, e);
+            state.metrics.inc("config_reloads_total", &[("result", "failed")]);
+            return;
         }
     };
+    let running = state.config();
+    let config = loaded.reloaded(&running);
+    // ip and port given by env or args are kept, they are no change of the file
+    let (applied, restart): (Vec<String>, Vec<String>) = running.changed_keys(&loaded).into_iter()
+        .filter(|k| !matches!(running.source(k), Source::Env | Source::Cli))
+        .partition(|k| config::is_reloadable(k));
 
-    if !arg_matches.is_present("ip") {
-        ip = match config.get("ip") {
-            Some(v) => serde_yaml::from_value(v.clone()).unwrap(),
-            None => ip
+    if config.kerberos.enabled && cfg!(not(feature = "kerberos")) {
+        warn!("can not reload config file {:?}, keeping the current config; kerberos requires a build with the \
+               kerberos feature", config_path);
+        state.metrics.inc("config_reloads_total", &[("result", "failed")]);
+        return;
+    }
+    if let Err(e) = route::validate(&config.routes) {
+        warn!("can not reload config file {:?}, keeping the current config; {}", config_path, e);
+        state.metrics.inc("config_reloads_total", &[("result", "failed")]);
+        return;
+    }
+    if applied.iter().any(|k| k == "acl" || k.starts_with("acl.")) {
+        let acl = match Acl::from_config(&config.acl, state.metrics.clone()) {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("can not reload config file {:?}, keeping the current config; {}", config_path, e);
+                state.metrics.inc("config_reloads_total", &[("result", "failed")]);
+                return;
+            }
         };
+        if acl.denies_all() {
+            warn!("acl.allow and acl.allow_file are empty with default_action deny, every destination is denied");
+        }
+        *state.acl.write().unwrap() = Arc::new(acl);
+    }
+    if let Err(e) = state.resolver.reload(&config) {
+        warn!("can not reload hosts from config file {:?}, keeping the current ones; {}", config_path, e);
+    }
+    for tls in std::iter::once(&state.upstream_tls).chain(&state.grpc_upstream_tls) {
+        if let Err(e) = tls.reload(&config.upstream_tls) {
+            warn!("can not reload pins and client certificates from config file {:?}, keeping the current ones; {}",
+                  config_path, e);
+        }
     }
+    log::set_max_level(config.log_level.filter());
+    sender.send_replace(Arc::new(config));
+    state.metrics.inc("config_reloads_total", &[("result", "applied")]);
 
-    if !arg_matches.is_present("port") {
-        let p = match &config.get("port") {
-            Some(v) => {
-                let p = match v {
-                    &serde_yaml::Value::Number(v) => {
-                        match &v.as_u64() {
-                            Some(v) => match u16::try_from(v.clone()) {
-                                Ok(v) => Some(v),
-                                Err(_) => None
-                            },
-                            None => None
-                        }
-                    },
-                    _ => None
+    if !applied.is_empty() {
+        info!("config file {:?} reloaded, changed {}", config_path, applied.join(", "));
+    } else if restart.is_empty() {
+        info!("config file {:?} reloaded, nothing changed", config_path);
+    }
+    if !restart.is_empty() {
+        warn!("config file {:?} changed {}, which are read at startup only; restart to apply them",
+              config_path, restart.join(", "));
+    }
+}
+
+/// Applies edits of the acl rule files without a restart or SIGHUP
+async fn watch_acl_files(state: Arc<State>) {
+    let mut interval = tokio::time::interval(acl::FILE_CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        state.acl().refresh();
+    }
+}
+
+/// Drops the request times of users who stopped sending requests
+async fn forget_idle_users(state: Arc<State>) {
+    let limiter = match &state.user_limit {
+        Some(v) => v,
+        None => return
+    };
+    let mut interval = tokio::time::interval(ratelimit::WINDOW);
+    loop {
+        interval.tick().await;
+        let users = limiter.cleanup();
+        debug!("per_user_rate_limit: {} users with requests in the last minute", users);
+    }
+}
+
+/// Closes upstream connections idle for longer than `client.pool_idle_timeout` and reports what is left,
+/// hyper would close them only when it next looks at its pool
+async fn reap_pool(state: Arc<State>, every: Duration) {
+    let idle_after = state.config().client.pool_idle_timeout.map(Duration::from_secs);
+    let mut interval = tokio::time::interval(every);
+    loop {
+        interval.tick().await;
+        let (reaped, info) = state.pool.reap(idle_after);
+        state.metrics.add("upstream_connections_reaped_total", &[], reaped as i64);
+        state.metrics.set("upstream_pool_connections", &[("state", "idle")], info.idle as i64);
+        state.metrics.set("upstream_pool_connections", &[("state", "busy")], (info.open - info.idle) as i64);
+        if reaped > 0 {
+            info!("upstream pool: reaped {} idle connections; {} idle, {} busy", reaped, info.idle,
+                  info.open - info.idle);
+        } else {
+            debug!("upstream pool: {} idle, {} busy connections", info.idle, info.open - info.idle);
+        }
+    }
+}
+
+/// Expiry of every loaded certificate, the upstream client certificates of gRPC are the same files
+pub fn certificates(state: &State) -> Vec<tls::CertInfo> {
+    let listeners = state.listener_certs.iter().map(|(role, cert)| cert.info(role));
+    let upstream = state.upstream_tls.client_certs().into_iter().map(|cert| cert.info("upstream_client"));
+    listeners.chain(upstream).collect()
+}
+
+/// Serves requests of a client connection until it is closed
+async fn serve_client(state: Arc<State>, http: Http, acceptor: Option<TlsAcceptor>, stream: AddrStream) {
+    let peer = state.privacy.peer(stream.remote_addr());
+    let stream = ClientStream::new(stream, peer, state.config().limits.write_timeout(), state.metrics.clone());
+    let (stream, identity) = match acceptor {
+        Some(acceptor) => match accept_tls(&state, &acceptor, stream, peer).await {
+            Some(v) => v,
+            None => return
+        },
+        None => (ListenerStream::Plain(stream), None)
+    };
+    let conn = state.connections.open(peer, identity);
+    let service = {
+        let state = state.clone();
+        service_fn(move |mut req| {
+            let state = state.clone();
+            let conn = conn.clone();
+            async move {
+                let is_connect = req.method() == Method::CONNECT;
+                let version = req.version();
+                let started = Instant::now();
+                // counted before the request id adds a header line of the proxy
+                let too_many_headers = check_header_count(&state, &req, peer);
+                let (request_id, refused) = state.request_ids.assign(&mut req);
+                if refused {
+                    debug!("client {:?}: invalid request id, replaced by {}", peer, request_id);
+                }
+                let malformed = too_many_headers.or_else(|| normalize_target(&state, &mut req, peer));
+                let (method, uri) = (req.method().clone(), req.uri().clone());
+                let route = Arc::new(Route::resolve(&state.config(), &method, &uri));
+                let accept = req.headers().get(http::header::ACCEPT).and_then(|v| v.to_str().ok()).map(String::from);
+                let origin = req.headers().get(http::header::ORIGIN).and_then(|v| v.to_str().ok()).map(String::from);
+                let host = uri.host().or_else(|| req.headers().get(http::header::HOST).and_then(|v| v.to_str().ok()))
+                    .map(String::from).unwrap_or_default();
+                let _stream = if req.version() == hyper::Version::HTTP_2 {
+                    Some(StreamGuard::new(state.metrics.clone()))
+                } else {
+                    None
+                };
+                let proxied = match malformed {
+                    Some(resp) => Ok(reject(&state, peer, resp, req).await),
+                    None => proxy(state.clone(), req, peer, conn.clone(), route.clone()).await
+                };
+                let mut resp = match proxied {
+                    Ok(v) => v,
+                    Err(e) => {
+                        log_aborted(&state, &method, &uri, &conn, started, &route);
+                        record_recent(&state, &method, &uri, peer, None, started);
+                        return Err(e);
+                    }
                 };
-                if p.is_none() {
-                    warn!("invalid set port in config (must be a number in the range 0..65535, got {:?}) \
-                           will be changed to default value ({})", v, port);
+                state.metrics.inc("requests_total", &[("method", method.as_str()), ("status", resp.status().as_str()),
+                                                      ("route", &route.name)]);
+                if !is_connect {
+                    state.metrics.observe("request_duration_ms", &[("route", &route.name)],
+                                          started.elapsed().as_millis() as u64);
+                    log_slow(&state, &route, peer, &method, &uri, resp.status(), started.elapsed());
+                    limit_connection(&state, &conn, peer, &mut resp);
+                    if version == hyper::Version::HTTP_10 {
+                        fit_http10(&mut resp);
+                    }
+                }
+                // answers of servers come with their connection, the others are made by the proxy
+                let own = resp.extensions().get::<ConnectionHandle>().is_none();
+                let error = resp.extensions().get::<ProxyError>().map(|e| e.kind);
+                if let Some(kind) = error {
+                    state.metrics.inc("proxy_errors_total", &[("code", kind.code())]);
+                }
+                audit(&state, &resp, &method, &uri, &conn, &request_id);
+                // automation gets the code of errors as JSON, error_pages render the others as well
+                let json = accept.as_deref().map(error_page::prefers_json).unwrap_or(false);
+                if own && resp.status().as_u16() >= 400 && ((json && error.is_some()) || state.error_pages.is_some()) {
+                    info!("client {:?}: {} {} answered {} with request id {}", peer, method, state.log_query.uri(&uri),
+                          resp.status().as_u16(), request_id);
+                    resp = match &state.error_pages {
+                        Some(pages) if !json => pages.render(resp, &request_id, &host),
+                        _ => error_page::render_json(resp, &request_id)
+                    };
+                }
+                if route.log_headers {
+                    debug!("client {:?}: response {}{}", peer, resp.status(),
+                           format_headers(resp.headers(), &route.log_headers_redact));
                 }
-                p
+                record_recent(&state, &method, &uri, peer, Some(resp.status().as_u16()), started);
+                state.request_ids.echo(&mut resp, &request_id);
+                hide_software(&state.config().response, &mut resp);
+                if let (Some(cors), false) = (&state.config().cors, is_connect) {
+                    cors::inject(cors, origin.as_deref(), &mut resp);
+                }
+                // an established tunnel is logged once it is closed
+                if !(is_connect && resp.status().is_success()) {
+                    resp = log_access(&state, resp, &method, &uri, &conn, started, &route);
+                }
+                Ok::<_, hyper::Error>(resp)
             }
-            None => None
-        };
-        if !p.is_none() {
-            port = p.unwrap();
+        })
+    };
+    if let Err(e) = http.serve_connection(stream, service).with_upgrades().await {
+        if slow_client::is_header_timeout(&e) {
+            warn!("client {:?}: request headers not received in {:?}, closing connection; reason=slow_client",
+                  peer, state.config().limits.header_timeout().unwrap_or_default());
+            state.metrics.inc("slow_clients_total", &[("reason", "header_timeout")]);
+        } else if is_head_too_large(&e) {
+            // hyper answered 431 itself, before `check_header_count` could see the request
+            debug!("client {:?}: refused request head over the limits of hyper; err = {}", peer, e);
+            state.metrics.inc("malformed_requests_total", &[("reason", "too_many_headers")]);
+        } else if e.is_parse() {
+            // hyper answered 400 Bad Request itself, HTTP/0.9 and garbage request lines end up here
+            debug!("client {:?}: refused unparsable request; err = {}", peer, e);
+            state.metrics.inc("malformed_requests_total", &[("reason", "unparsable")]);
+        } else {
+            debug!("client {:?}: connection error; err = {:?}", peer, e);
+        }
+    }
+}
+
+/// Runs the TLS handshake of a client within the header timeout, returns the stream with the
+/// identity of the client certificate or `None` when the handshake failed
+async fn accept_tls(state: &State, acceptor: &TlsAcceptor, stream: ClientStream, peer: Peer)
+    -> Option<(ListenerStream, Option<String>)> {
+    let accepting = acceptor.accept(stream);
+    let accepted = match state.config().limits.header_timeout() {
+        Some(timeout) => match tokio::time::timeout(timeout, accepting).await {
+            Ok(v) => v,
+            Err(_) => {
+                warn!("client {:?}: TLS handshake not finished in {:?}, closing connection", peer, timeout);
+                state.metrics.inc("tls_handshake_failures_total", &[]);
+                return None;
+            }
+        },
+        None => accepting.await
+    };
+    match accepted {
+        Ok(stream) => {
+            let identity = tls::peer_identity(stream.get_ref().1);
+            if let Some(identity) = &identity {
+                debug!("client {:?}: authenticated by certificate as {:?}", peer, identity);
+            }
+            Some((ListenerStream::Tls(Box::new(stream)), identity))
+        },
+        Err(e) => {
+            warn!("client {:?}: TLS handshake failed; err = {}", peer, e);
+            state.metrics.inc("tls_handshake_failures_total", &[]);
+            None
         }
     }
+}
+
+/// Counts an HTTP/2 stream of a client as active until dropped
+struct StreamGuard {
+    metrics: Arc<Metrics>,
+}
+
+impl StreamGuard {
+    fn new(metrics: Arc<Metrics>) -> StreamGuard {
+        metrics.add("http2_streams_active", &[], 1);
+        StreamGuard { metrics }
+    }
+}
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        self.metrics.add("http2_streams_active", &[], -1);
+    }
+}
+
+/// Tells whether an argument value was given on the command line or taken from the environment
+fn arg_source(arg_matches: &ArgMatches, name: &str) -> Source {
+    if arg_matches.occurrences_of(name) > 0 {
+        Source::Cli
+    } else {
+        Source::Env
+    }
+}
+
+/// Logs a request which took longer than `slow_request_threshold_ms` of its route until its response
+/// headers, or a tunnel which was open longer
+fn log_slow(state: &State, route: &Route, peer: Peer, method: &Method, uri: &hyper::Uri,
+            status: http::StatusCode, elapsed: Duration) {
+    if route.slow_request_threshold.map(|v| elapsed > v).unwrap_or(false) {
+        warn!("client {:?}: slow request {} {} {} took {}ms", peer, method, state.log_query.uri(uri), status.as_u16(),
+              elapsed.as_millis());
+    }
+}
+
+/// Writes the refusal a response answers to `audit_log`
+fn audit(state: &State, resp: &Response<Body>, method: &Method, uri: &hyper::Uri, conn: &ConnectionGuard,
+         request_id: &str) {
+    let (log, error) = match (&state.audit_log, resp.extensions().get::<ProxyError>()) {
+        (Some(log), Some(error)) if audit_log::audited(error.kind) => (log, error),
+        _ => return
+    };
+    log.write(AuditEntry {
+        time: audit_log::now(),
+        request_id: String::from(request_id),
+        client: conn.peer.to_string(),
+        user: conn.user(),
+        method: String::from(method.as_str()),
+        destination: state.log_query.uri(uri),
+        status: resp.status().as_u16(),
+        category: String::from(error.kind.code()),
+        reason: error.message.clone(),
+        prev: None,
+    });
+}
 
-    let addr = match to_addr(format!("{}:{}", ip, port)) {
+/// Writes the access log entry of a response once its body was sent
+fn log_access(state: &State, resp: Response<Body>, method: &Method, uri: &hyper::Uri, conn: &ConnectionGuard,
+              started: Instant, route: &Route) -> Response<Body> {
+    let log = match &state.access_log {
         Some(v) => v,
-        None => {
-            error!("can not resolve server address {}:{}", ip, port);
-            exit(78);
+        None => return resp
+    };
+    let server = resp.extensions().get::<ConnectionHandle>().map(|h| h.info().remote.map(|a| a.ip()));
+    let action = match (&server, resp.status().as_u16()) {
+        (Some(_), _) => access_log::Action::Miss,
+        (None, 403 | 407 | 429) => access_log::Action::Denied,
+        (None, _) => access_log::Action::None
+    };
+    let entry = AccessLogEntry {
+        time: SystemTime::now(),
+        elapsed: started.elapsed(),
+        client: conn.peer.shown_ip(),
+        action,
+        status: resp.status().as_u16(),
+        bytes: access_log::head_size(&resp),
+        method: String::from(method.as_str()),
+        url: state.log_query.uri(uri),
+        user: conn.user(),
+        hierarchy: if server.is_some() { access_log::Hierarchy::Direct } else { access_log::Hierarchy::None },
+        server: server.flatten(),
+        content_type: resp.headers().get(http::header::CONTENT_TYPE)
+            .map(|v| String::from_utf8_lossy(v.as_bytes()).into_owned()),
+        route: route.logged.then(|| route.name.clone()),
+    };
+    // the body of gRPC is followed by trailers, which a wrapped body would drop
+    if is_grpc(resp.headers()) || resp.status() == http::StatusCode::SWITCHING_PROTOCOLS {
+        log.write(&entry);
+        return resp;
+    }
+    log.log_response(resp, entry, started)
+}
+
+/// Writes the access log entry of a request whose upstream failed, the client connection is closed
+/// without a response
+fn log_aborted(state: &State, method: &Method, uri: &hyper::Uri, conn: &ConnectionGuard, started: Instant,
+               route: &Route) {
+    if let Some(log) = &state.access_log {
+        log.write(&AccessLogEntry {
+            time: SystemTime::now(),
+            elapsed: started.elapsed(),
+            client: conn.peer.shown_ip(),
+            action: access_log::Action::MissAborted,
+            status: 0,
+            bytes: 0,
+            method: String::from(method.as_str()),
+            url: state.log_query.uri(uri),
+            user: conn.user(),
+            hierarchy: access_log::Hierarchy::None,
+            server: None,
+            content_type: None,
+            route: route.logged.then(|| route.name.clone()),
+        });
+    }
+}
+
+/// Adds a request to `/admin/requests` once its response headers are ready, `status` is `None` when
+/// the client connection is closed without a response
+fn record_recent(state: &State, method: &Method, uri: &hyper::Uri, peer: Peer, status: Option<u16>,
+                 started: Instant) {
+    if let Some(recent) = &state.recent {
+        recent.record(RecentRequest {
+            id: 0,
+            method: String::from(method.as_str()),
+            uri: state.log_query.uri(uri),
+            peer,
+            status,
+            timestamp: chrono::Local::now(),
+            duration_ms: started.elapsed().as_millis() as u64,
+        });
+    }
+}
+
+/// Asks the client to reconnect once its connection reached one of `limits`
+fn limit_connection(state: &State, conn: &ConnectionGuard, peer: Peer, resp: &mut Response<Body>) {
+    let limits = &state.config().limits;
+    let requests = conn.count_request();
+    let too_many = limits.max_requests_per_connection.map(|max| requests >= max).unwrap_or(false);
+    let too_old = limits.max_connection_age().map(|max| conn.age() >= max).unwrap_or(false);
+    if too_many || too_old {
+        debug!("client {:?}: connection reached its limits after {} requests in {:?}, closing it",
+               peer, requests, conn.age());
+        resp.headers_mut().insert(http::header::CONNECTION, http::HeaderValue::from_static("close"));
+    }
+}
+
+/// Fits a response to an HTTP/1.0 client. HTTP/1.0 has no chunked encoding, so a body of unknown
+/// length is delimited by closing the connection, which hyper does without telling clients that
+/// asked for keep-alive. It even replaces `Connection: close` by keep-alive for those clients unless
+/// the response is HTTP/1.0 as well.
+fn fit_http10(resp: &mut Response<Body>) {
+    let bodiless = resp.status().is_informational() || resp.status() == http::StatusCode::NO_CONTENT
+        || resp.status() == http::StatusCode::NOT_MODIFIED;
+    let sized = resp.headers().contains_key(http::header::CONTENT_LENGTH)
+        || hyper::body::HttpBody::size_hint(resp.body()).exact().is_some();
+    if !bodiless && !sized {
+        resp.headers_mut().remove(http::header::TRANSFER_ENCODING);
+        resp.headers_mut().insert(http::header::CONNECTION, http::HeaderValue::from_static("close"));
+    }
+    let close = resp.headers().get_all(http::header::CONNECTION).iter()
+        .filter_map(|v| v.to_str().ok())
+        .any(|v| v.split(',').any(|t| t.trim().eq_ignore_ascii_case("close")));
+    if close {
+        *resp.version_mut() = hyper::Version::HTTP_10;
+    }
+}
+
+/// Applies `response.server_header` and `response.strip_fingerprint_headers`, to responses of servers
+/// and the proxy alike so both look the same
+fn hide_software(config: &config::ResponseConfig, resp: &mut Response<Body>) {
+    match config.server_header() {
+        ServerHeader::Passthrough => (),
+        ServerHeader::Remove => {
+            resp.headers_mut().remove(http::header::SERVER);
+        },
+        ServerHeader::Override(value) => {
+            if let Ok(value) = http::HeaderValue::from_str(value) {
+                resp.headers_mut().insert(http::header::SERVER, value);
+            }
+        }
+    }
+    if config.strip_fingerprint_headers {
+        for name in ["x-powered-by", "x-aspnet-version", "x-aspnetmvc-version"] {
+            resp.headers_mut().remove(name);
+        }
+    }
+}
+
+/// Renders headers as indented `key: value` lines, values of `redact` headers are masked
+fn format_headers(headers: &http::HeaderMap, redact: &[String]) -> String {
+    let mut out = String::new();
+    for (name, value) in headers.iter() {
+        let value = if redact.iter().any(|r| r.eq_ignore_ascii_case(name.as_str())) {
+            "[redacted]"
+        } else {
+            value.to_str().unwrap_or("[non-ascii]")
+        };
+        out.push_str(&format!("\n    {}: {}", name, value));
+    }
+    out
+}
+
+/// Checks the Kerberos ticket of a request when `kerberos` is enabled, returns the authenticated
+/// principal with the `Proxy-Authenticate` header of its answer, or the answer of a request without
+/// a valid ticket; the credentials are not forwarded
+#[cfg(feature = "kerberos")]
+async fn authenticate(state: &State, req: &mut Request<Body>, peer: Peer)
+    -> Result<Option<(String, Option<http::HeaderValue>)>, Response<Body>> {
+    if !state.config().kerberos.enabled {
+        return Ok(None);
+    }
+    match negotiate::authenticate(req.headers()).await {
+        Ok(accepted) => {
+            info!("client {:?}: authenticated as {}", peer, accepted.principal);
+            req.headers_mut().remove(http::header::PROXY_AUTHORIZATION);
+            let mutual = accepted.token.and_then(|t| http::HeaderValue::from_str(&format!("Negotiate {}", t)).ok());
+            Ok(Some((accepted.principal, mutual)))
+        },
+        Err(e) => {
+            warn!("client {:?}: Negotiate authentication failed; {}", peer, e);
+            state.metrics.inc("auth_failures_total", &[]);
+            let mut resp = ProxyError::new(ErrorKind::AuthRequired, "proxy authentication required")
+                .into_response(http::StatusCode::PROXY_AUTHENTICATION_REQUIRED);
+            resp.headers_mut().insert(http::header::PROXY_AUTHENTICATE, http::HeaderValue::from_static("Negotiate"));
+            Err(resp)
         }
+    }
+}
+
+#[cfg(not(feature = "kerberos"))]
+async fn authenticate(_state: &State, _req: &mut Request<Body>, _peer: Peer)
+    -> Result<Option<(String, Option<http::HeaderValue>)>, Response<Body>> {
+    Ok(None)
+}
+
+/// Applies `per_user_rate_limit` to a request of `user`, `Some` is the answer of a request over the limit
+fn rate_limit(state: &State, user: &str, peer: Peer) -> Option<Response<Body>> {
+    let limiter = state.user_limit.as_ref()?;
+    let wait = limiter.check(user).err()?;
+    // Retry-After has whole seconds, rounding down would send the client back too early
+    let retry_after = wait.as_secs() + (wait.subsec_nanos() > 0) as u64;
+    warn!("client {:?}: user {:?} exceeded {} requests per minute, retry after {}s", peer, user,
+          limiter.max_requests(), retry_after);
+    state.metrics.inc("user_rate_limited_total", &[]);
+    let mut resp = ProxyError::new(ErrorKind::RateLimited,
+                                   format!("rate limit of {} requests per minute exceeded", limiter.max_requests()))
+        .retry_after(Some(wait))
+        .into_response(http::StatusCode::TOO_MANY_REQUESTS);
+    resp.headers_mut().insert(http::header::RETRY_AFTER, http::HeaderValue::from(retry_after));
+    Some(resp)
+}
+
+/// Applies `split_traffic` to the destination of a request
+fn split_target(state: &State, target: Target, peer: Peer) -> Target {
+    match state.split.as_ref().and_then(|s| s.route(&target)) {
+        Some((backend, routed)) => {
+            info!("client {:?}: {} is routed to backend {:?} ({})", peer, target, backend, routed);
+            routed.clone()
+        },
+        None => target
+    }
+}
+
+/// Applies `load_balance` to the destination of a request, `None` when its pool has no healthy backend
+fn balance_target(state: &State, target: &Target, headers: &http::HeaderMap, peer: Peer) -> Option<Target> {
+    match state.balancer.as_ref().and_then(|b| b.route(target, headers)) {
+        Some(Some(backend)) => {
+            debug!("client {:?}: {} is balanced to backend {}", peer, target, backend);
+            Some(backend.clone())
+        },
+        Some(None) => None,
+        None => Some(target.clone())
+    }
+}
+
+/// Races the healthy backends of the pool of `target` on a `race_backends` route, returns the
+/// connection of the winner or the response telling that none connected; `None` when the route does
+/// not race or `target` is not balanced
+async fn race_backends(state: &State, route: &Route, target: &Target, peer: Peer)
+    -> Option<Result<(TcpStream, SocketAddr, Target), Response<Body>>> {
+    if !route.race_backends || state.upstream_proxy.is_some() {
+        return None;
+    }
+    let balancer = state.balancer.as_ref()?;
+    let backends = balancer.candidates(target)?;
+    if backends.is_empty() {
+        return Some(Err(no_healthy_backend(target, peer)));
+    }
+    let started = Instant::now();
+    match state.dialer.race(state.resolver.clone(), &backends, state.config().dns.address_order).await {
+        Ok((stream, addr, backend)) => {
+            debug!("client {:?}: backend {} of {} connected first of {} in {:?}", peer, backend, target,
+                   backends.len(), started.elapsed());
+            balancer.count_won(target, &backend);
+            Some(Ok((stream, addr, backend)))
+        },
+        Err(e) => {
+            error!("client {:?}: can not connect to any backend of {}; tried {}", peer, target, e);
+            let message = format!("can not connect to any backend of {}; tried {}", target, e);
+            Some(Err(ProxyError::new(ErrorKind::UpstreamConnectFailed, message)
+                .into_response(http::StatusCode::BAD_GATEWAY)))
+        }
+    }
+}
+
+fn no_healthy_backend(target: &Target, peer: Peer) -> Response<Body> {
+    warn!("client {:?}: no healthy backend of {}", peer, target);
+    ProxyError::new(ErrorKind::UpstreamConnectFailed, format!("no healthy backend of {}", target))
+        .into_response(http::StatusCode::SERVICE_UNAVAILABLE)
+}
+
+fn ports_exhausted(state: &State, target: &str, peer: Peer) -> Response<Body> {
+    let range = state.config().outbound.port_range.unwrap_or_default();
+    error!("client {:?}: can not connect to {}, no free source port in {}-{}", peer, target, range[0], range[1]);
+    state.metrics.inc("source_ports_exhausted_total", &[]);
+    // ports are freed as connections close
+    ProxyError::new(ErrorKind::InternalError, format!("no free source port to connect to {}", target))
+        .retry_after(Some(error_page::RETRY_AFTER))
+        .into_response(http::StatusCode::SERVICE_UNAVAILABLE)
+}
+
+/// Answers a failed upstream request whose connection ran out of source ports or failed
+/// the TLS handshake, other errors close the client connection as before
+fn upstream_error(state: &State, uri: &hyper::Uri, peer: Peer, e: hyper::Error)
+    -> Result<Response<Body>, hyper::Error> {
+    let authority = uri.authority().map(|a| a.as_str()).unwrap_or("");
+    let source = std::error::Error::source(&e);
+    if source.and_then(|e| e.downcast_ref::<DialError>()).map(|e| e.ports_exhausted()).unwrap_or(false) {
+        return Ok(ports_exhausted(state, authority, peer));
+    }
+    if let Some(tls) = source.and_then(|e| e.downcast_ref::<TlsError>()) {
+        error!("client {:?}: TLS handshake with {} failed; {}", peer, authority, tls);
+        let message = format!("TLS handshake with {} failed: {}", authority, tls);
+        let error = ProxyError::new(ErrorKind::UpstreamConnectFailed, message);
+        return Ok(error.into_response(http::StatusCode::BAD_GATEWAY));
+    }
+    let rejected = source.and_then(|e| e.downcast_ref::<std::io::Error>())
+        .and_then(|e| e.get_ref())
+        .and_then(|e| e.downcast_ref::<rustls::Error>())
+        .filter(|e| tls::is_client_cert_rejected(e));
+    if let Some(e) = rejected {
+        error!("client {:?}: {} rejected the client certificate; {}", peer, authority, e);
+        let message = format!("TLS handshake with {} failed: client certificate rejected; {}", authority, e);
+        let error = ProxyError::new(ErrorKind::UpstreamConnectFailed, message);
+        return Ok(error.into_response(http::StatusCode::BAD_GATEWAY));
+    }
+    Err(e)
+}
+
+/// Opens the tunnel to `target` through the parent proxy, passing on the credentials of the client.
+///
+/// Returns the answer of a refused tunnel, the challenge of the parent when it wants credentials.
+async fn parent_tunnel(state: &State, route: &Route, parent: &UpstreamProxy, server: &mut TcpStream,
+                       target: &Target, headers: &http::HeaderMap, peer: Peer) -> Option<Response<Body>> {
+    let credentials = headers.get(http::header::PROXY_AUTHORIZATION);
+    let handshake = parent.connect(server, target, credentials);
+    let answer = match route.request_timeout(&state.config(), &target.host) {
+        Some(timeout) => tokio::time::timeout(timeout, handshake).await.unwrap_or_else(|_| {
+            Err(std::io::Error::new(std::io::ErrorKind::TimedOut, format!("no answer in {:?}", timeout)))
+        }),
+        None => handshake.await
+    };
+    match answer {
+        Ok(None) => {
+            state.metrics.inc("upstream_proxy_tunnels_total", &[("result", "established")]);
+            None
+        },
+        Ok(Some((status, resp))) => {
+            let result = match status {
+                http::StatusCode::PROXY_AUTHENTICATION_REQUIRED => "auth_required",
+                _ => "refused"
+            };
+            warn!("client {:?}: upstream proxy {} answered {} to tunnel to {} (credentials {})", peer, parent.target,
+                  status, target, if credentials.is_some() { "given" } else { "missing" });
+            state.metrics.inc("upstream_proxy_tunnels_total", &[("result", result)]);
+            Some(resp)
+        },
+        Err(e) => {
+            error!("client {:?}: upstream proxy {} failed to open tunnel to {}; err = {}", peer, parent.target,
+                   target, e);
+            state.metrics.inc("upstream_proxy_tunnels_total", &[("result", "failed")]);
+            let kind = match e.kind() {
+                std::io::ErrorKind::TimedOut => ErrorKind::UpstreamTimeout,
+                _ => ErrorKind::UpstreamConnectFailed
+            };
+            let message = format!("upstream proxy {} failed to open tunnel to {}: {}", parent.target, target, e);
+            Some(ProxyError::new(kind, message).into_response(http::StatusCode::BAD_GATEWAY))
+        }
+    }
+}
+
+/// Passes on what interim responses of the upstream said.
+///
+/// hyper neither hands interim responses to the client nor lets the server send them, so they can
+/// not be relayed as such: `100 Continue` is answered to the client by hyper itself once the request
+/// body is read, the hints of `103 Early Hints` are added to the final response as `Link` headers,
+/// which browsers preload as well, and `102 Processing` is only logged.
+fn relay_interim(state: &State, resp: &mut Response<Body>, peer: Peer) {
+    let (statuses, links) = match resp.extensions().get::<ConnectionHandle>() {
+        Some(handle) => handle.take_interim(),
+        None => return
     };
-    let client = HttpClient::new();
+    if statuses.is_empty() {
+        return;
+    }
+    debug!("client {:?}: upstream sent interim responses {:?} before {}", peer, statuses, resp.status().as_u16());
+    for status in &statuses {
+        state.metrics.inc("upstream_interim_responses_total", &[("status", &status.to_string())]);
+    }
+    for link in links {
+        if !resp.headers().get_all(http::header::LINK).iter().any(|v| v == link) {
+            resp.headers_mut().append(http::header::LINK, link);
+        }
+    }
+}
 
-    let make_service = make_service_fn(move |conn: &AddrStream| {
-        let client = client.clone();
-        let peer = conn.remote_addr();
-        async move { Ok::<_, Infallible>(service_fn(move |req| proxy(client.clone(), req, peer))) }
-    });
+/// Removes the headers of one hop, the client connection and the upstream connection are kept alive
+/// or closed independently: those named by `Connection`, `Connection` itself, `Keep-Alive`,
+/// `Proxy-Connection` and `Upgrade`, which is not passed on for plain-HTTP requests. `TE: trailers`
+/// is kept for gRPC, other values of `TE` are removed. The credentials of the client for this proxy,
+/// `Proxy-Authorization`, and challenges of an upstream, `Proxy-Authenticate`, are removed as well;
+/// only `parent_tunnel` relays credentials, to the parent proxy.
+fn strip_hop_by_hop(headers: &mut http::HeaderMap) {
+    let named: Vec<String> = headers.get_all(http::header::CONNECTION).iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|t| t.trim().to_ascii_lowercase())
+        // framing is hyper's business, whatever the client names
+        .filter(|t| !t.is_empty() && t != "content-length" && t != "transfer-encoding" && t != "host")
+        .collect();
+    let fixed = ["connection", "keep-alive", "proxy-connection", "upgrade", "proxy-authorization", "proxy-authenticate"];
+    for name in named.iter().map(String::as_str).chain(fixed) {
+        headers.remove(name);
+    }
+    if headers.get(http::header::TE).map(|v| v != "trailers").unwrap_or(false) {
+        headers.remove(http::header::TE);
+    }
+}
+
+/// Tells whether a request is gRPC, including `application/grpc+proto` and the like
+fn is_grpc(headers: &http::HeaderMap) -> bool {
+    headers.get(http::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v == "application/grpc" || v.starts_with("application/grpc+") || v.starts_with("application/grpc;"))
+        .unwrap_or(false)
+}
 
-    let server = Server::bind(&addr).serve(make_service);
+/// Answers a request to a destination the acl denies
+fn deny(state: &State, target: &Target, peer: Peer, reason: &Denial) -> Response<Body> {
+    warn!("client {:?}: destination {} {}", peer, target, reason);
+    state.metrics.inc("acl_denied_total", &[("reason", reason.as_str())]);
+    ProxyError::new(ErrorKind::PolicyBlocked, format!("destination {} {}", target, reason))
+        .into_response(http::StatusCode::FORBIDDEN)
+}
+
+/// Answers a request to localhost while `allow_localhost` is off
+fn deny_localhost(state: &State, target: &Target, peer: Peer) -> Response<Body> {
+    warn!("client {:?}: destination {} is localhost, denied by allow_localhost false", peer, target);
+    state.metrics.inc("acl_denied_total", &[("reason", "localhost")]);
+    let message = "connections to localhost are blocked; set allow_localhost: true to enable";
+    ProxyError::new(ErrorKind::PolicyBlocked, message).into_response(http::StatusCode::FORBIDDEN)
+}
 
-    info!("server listening at {}", addr);
+/// Refuses HTTP/0.9, which hyper does not parse anyway, and HTTP/1.0 unless `allow_http10`
+fn check_version(state: &State, req: &Request<Body>, peer: Peer) -> Option<Response<Body>> {
+    match req.version() {
+        hyper::Version::HTTP_09 => {
+            Some(refuse_malformed(state, peer, "http_version", String::from("HTTP/0.9 is not supported"),
+                                  http::StatusCode::BAD_REQUEST))
+        },
+        hyper::Version::HTTP_10 if !state.config().allow_http10 => {
+            Some(refuse_malformed(state, peer, "http_version", String::from("HTTP/1.0 is not allowed"),
+                                  http::StatusCode::HTTP_VERSION_NOT_SUPPORTED))
+        },
+        _ => None
+    }
+}
 
-    if let Err(e) = server.await {
-        error!("server crashed; err = {:?}", e);
+/// Refuses HTTP/1 requests with more than `http1_max_headers` header lines, hyper refuses those
+/// with more than it parses itself. This is a policy check of parsed requests: hyper 0.14 has
+/// allocated the headers by then, only `http1_max_buf_size_kb` bounds that memory.
+fn check_header_count(state: &State, req: &Request<Body>, peer: Peer) -> Option<Response<Body>> {
+    let max = state.config().http1_max_headers;
+    if req.version() >= hyper::Version::HTTP_2 || req.headers().len() <= max {
+        return None;
     }
+    Some(refuse_malformed(state, peer, "too_many_headers",
+                          format!("request has {} header lines, at most {} are allowed", req.headers().len(), max),
+                          http::StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE))
 }
 
-fn to_addr(host: String) -> Option<SocketAddr> {
+/// Tells whether hyper refused a request head with 431, for more header lines than it parses or a
+/// head over `http1_max_buf_size_kb`; hyper exposes this only through the error message
+fn is_head_too_large(err: &hyper::Error) -> bool {
+    err.is_parse() && err.to_string().contains("message head is too large")
+}
 
-    let mut addrs_iter = match host.to_socket_addrs() {
-        Ok(v) => v,
-        Err(_) => {
-            return None;
+/// Normalizes the target of a request before routes, policies and logs see it, unless
+/// `uri_normalization` is `off`; returns the answer of a refused target, which loses its userinfo
+/// so it is logged without
+fn normalize_target(state: &State, req: &mut Request<Body>, peer: Peer) -> Option<Response<Body>> {
+    if state.config().uri_normalization == UriNormalization::Off {
+        return None;
+    }
+    match normalize::normalize(req.uri()) {
+        Ok(uri) => {
+            if &uri != req.uri() {
+                debug!("client {:?}: request target {} normalized to {}", peer, state.log_query.uri(req.uri()),
+                       state.log_query.uri(&uri));
+            }
+            *req.uri_mut() = uri;
+            None
+        },
+        Err(refused) => {
+            *req.uri_mut() = normalize::without_userinfo(req.uri());
+            Some(refuse_malformed(state, peer, refused.reason, refused.message, http::StatusCode::BAD_REQUEST))
+        }
+    }
+}
+
+fn refuse_malformed(state: &State, peer: Peer, reason: &str, message: String, status: http::StatusCode)
+    -> Response<Body> {
+    debug!("client {:?}: refusing malformed request; {}", peer, message);
+    state.metrics.inc("malformed_requests_total", &[("reason", reason)]);
+    ProxyError::new(ErrorKind::BadRequest, message).into_response(status)
+}
+
+/// `reason` is `via` for a request which already passed the proxy, `address` for a destination
+/// resolving to a listening address
+fn refuse_loop(state: &State, peer: Peer, reason: &str) -> Response<Body> {
+    warn!("client {:?}: request points back at the proxy, refusing to proxy to myself; reason={}", peer, reason);
+    state.metrics.inc("loops_refused_total", &[("reason", reason)]);
+    ProxyError::new(ErrorKind::LoopDetected, "refusing to proxy to myself").into_response(http::StatusCode::FORBIDDEN)
+}
+
+/// Answers `req` with `resp` without forwarding it. Its body is read up to `limits.reject_drain_max`
+/// bytes first, so the client gets to its end before the answer and may send the next request; a
+/// longer body, or one not sent within `request_timeout_ms`, is left unread and HTTP/1 clients
+/// are told the connection closes, rather than unread bytes resetting it under the answer.
+async fn reject(state: &State, peer: Peer, mut resp: Response<Body>, req: Request<Body>) -> Response<Body> {
+    let version = req.version();
+    let mut body = req.into_body();
+    if hyper::body::HttpBody::is_end_stream(&body) {
+        return resp;
+    }
+    let max = state.config().limits.reject_drain_max;
+    let drain = async {
+        let mut read = 0;
+        // a body announced longer is not read at all
+        while read <= max && hyper::body::HttpBody::size_hint(&body).lower() <= max - read {
+            match hyper::body::HttpBody::data(&mut body).await {
+                Some(Ok(chunk)) => read += chunk.len() as u64,
+                Some(Err(_)) => return false,
+                None => return true
+            }
         }
+        false
+    };
+    let drained = match state.config().request_timeout(None) {
+        Some(timeout) => tokio::time::timeout(timeout, drain).await.unwrap_or(false),
+        None => drain.await
     };
+    if drained {
+        state.metrics.inc("rejected_bodies_total", &[("outcome", "drained")]);
+        return resp;
+    }
+    debug!("client {:?}: body of the rejected request is over {} bytes, closing the connection", peer, max);
+    state.metrics.inc("rejected_bodies_total", &[("outcome", "closed")]);
+    if version < hyper::Version::HTTP_2 {
+        resp.headers_mut().insert(http::header::CONNECTION, http::HeaderValue::from_static("close"));
+    }
+    resp
+}
 
-    match addrs_iter.next() {
-        Some(v) => Some(v),
-        None => None
+async fn proxy(state: Arc<State>, req: Request<Body>, peer: Peer, conn: Arc<ConnectionGuard>, route: Arc<Route>)
+    -> Result<Response<Body>, hyper::Error> {
+    match &conn.identity {
+        Some(identity) => info!("client {:?}: connected as {:?}", peer, identity),
+        None => info!("client {:?}: connected", peer)
+    }
+    if route.name != route::DEFAULT_ROUTE {
+        debug!("client {:?}: route {:?}", peer, route.name);
+    }
+    if route.log_headers {
+        debug!("client {:?}: request {} {} {:?}{}", peer, req.method(), state.log_query.uri(req.uri()), req.version(),
+               format_headers(req.headers(), &route.log_headers_redact));
+    } else if state.log_query.is_active() {
+        // the debug dump of the request would show its query
+        debug!("client {:?}: request {} {} {:?}; headers = {:?}", peer, req.method(), state.log_query.uri(req.uri()),
+               req.version(), req.headers());
+    } else {
+        debug!("client {:?}: request = {:?}", peer, req);
+    }
+
+    if let Some(resp) = check_version(&state, &req, peer) {
+        return Ok(reject(&state, peer, resp, req).await);
+    }
+
+    if !route.allows(req.method()) {
+        // Method is not listed in `allowed_methods` or listed in `denied_methods`, answer with the
+        // list of methods we accept
+        warn!("client {:?}: method {} is not allowed", peer, req.method());
+        let mut resp = ProxyError::new(ErrorKind::PolicyBlocked, format!("method {} is not allowed", req.method()))
+            .into_response(http::StatusCode::METHOD_NOT_ALLOWED);
+        if let Ok(v) = http::HeaderValue::from_str(&route.allow_header()) {
+            resp.headers_mut().insert(http::header::ALLOW, v);
+        }
+        return Ok(reject(&state, peer, resp, req).await);
+    }
+
+    if state.loops.seen(req.headers()) {
+        return Ok(reject(&state, peer, refuse_loop(&state, peer, "via"), req).await);
     }
 
+    if req.uri().authority().is_none() && state.config().admin_listen.is_none() && admin::is_admin_path(req.uri().path()) {
+        // Request in origin-form is addressed to the proxy itself
+        return Ok(admin::handle(&state, &req, peer, false));
+    }
+    if req.method() == Method::OPTIONS && req.uri() == "*" {
+        // asterisk-form asks the proxy itself which methods it supports
+        debug!("client {:?}: answering OPTIONS *", peer);
+        let mut resp = Response::new(Body::empty());
+        if let Ok(v) = http::HeaderValue::from_str(&route.allow_header()) {
+            resp.headers_mut().insert(http::header::ALLOW, v);
+        }
+        return Ok(reject(&state, peer, resp, req).await);
+    }
+    if req.method() != Method::CONNECT && (req.uri().scheme().is_none() || req.uri().authority().is_none()) {
+        // origin-form, asterisk-form and the like are for servers, a proxy has nowhere to send them
+        let resp = refuse_malformed(&state, peer, "not_absolute",
+                                    format!("request target {:?} is not an absolute uri", req.uri()),
+                                    http::StatusCode::BAD_REQUEST);
+        return Ok(reject(&state, peer, resp, req).await);
+    }
+
+    if let Some(resp) = state.config().cors.as_ref().and_then(|cors| cors::preflight(cors, &req)) {
+        // preflights carry no credentials, browsers send them only with the actual request
+        debug!("client {:?}: answering CORS preflight of {}", peer, state.log_query.uri(req.uri()));
+        return Ok(resp);
+    }
+
+    let mut req = req;
+    let mutual = match authenticate(&state, &mut req, peer).await {
+        Ok(Some((principal, mutual))) => {
+            conn.set_principal(principal);
+            mutual
+        },
+        Ok(None) => None,
+        Err(resp) => return Ok(reject(&state, peer, resp, req).await)
+    };
+    if let Some(user) = conn.user() {
+        if let Some(resp) = rate_limit(&state, &user, peer) {
+            return Ok(reject(&state, peer, resp, req).await);
+        }
+    }
+    let mut resp = proxy_allowed(state, req, peer, conn, route).await?;
+    // the final token of the Negotiate exchange goes with the answer, the client may verify the proxy with it
+    if let Some(v) = mutual {
+        resp.headers_mut().insert(http::header::PROXY_AUTHENTICATE, v);
+    }
+    Ok(resp)
 }
 
-async fn proxy(client: HttpClient, req: Request<Body>, peer: SocketAddr) -> Result<Response<Body>, hyper::Error> {
-    info!("client {:?}: connected", peer);
-    debug!("client {:?}: request = {:?}", peer, req);
+/// Tunnels or forwards a request which passed the checks of `proxy`
+async fn proxy_allowed(state: Arc<State>, mut req: Request<Body>, peer: Peer, conn: Arc<ConnectionGuard>,
+                       route: Arc<Route>) -> Result<Response<Body>, hyper::Error> {
 
     if Method::CONNECT == req.method() {
         // Creates a tunnel between the client and the remote server
@@ -195,69 +1570,617 @@ async fn proxy(client: HttpClient, req: Request<Body>, peer: SocketAddr) -> Resu
         //
         // Note: only after client received an empty body with STATUS_OK can the
         // connection be upgraded, so we can't return a response inside
-        // `on_upgrade` future.
+        // `on_upgrade` future. That is why the remote server is connected first,
+        // if it fails the client gets BAD_GATEWAY instead.
         //
         let uri = req.uri();
-        let addr = match uri.authority() {
-            Some(v) => {
-                let host = v.to_string();
-                to_addr(host)
+        let target = match Target::from_uri(uri, state.config().connect_default_port) {
+            Ok(v) => v,
+            Err(e) => {
+                error!("client {:?}: malformed remote uri {:?}; {}", peer, uri, e);
+                return Ok(ProxyError::new(ErrorKind::BadRequest, format!("malformed remote uri {:?}: {}", uri, e))
+                    .into_response(http::StatusCode::BAD_REQUEST));
+            }
+        };
+        if let Err(reason) = state.acl().check(&target) {
+            return Ok(deny(&state, &target, peer, &reason));
+        }
+        let allow_localhost = state.config().allow_localhost;
+        if !allow_localhost && target.is_localhost() {
+            return Ok(deny_localhost(&state, &target, peer));
+        }
+        let requested = target.clone();
+        let target = split_target(&state, target, peer);
+        let (target, raced) = match race_backends(&state, &route, &target, peer).await {
+            Some(Ok((stream, addr, backend))) => (backend, Some((stream, addr))),
+            Some(Err(resp)) => return Ok(resp),
+            None => match balance_target(&state, &target, req.headers(), peer) {
+                Some(v) => (v, None),
+                None => return Ok(no_healthy_backend(&target, peer))
+            }
+        };
+        // a parent proxy resolves the target itself
+        let dialed = state.upstream_proxy.as_ref().map(|p| &p.target).unwrap_or(&target);
+        let resolved = match &raced {
+            Some((_, addr)) => Ok(vec![*addr]),
+            None => state.resolver.resolve(&dialed.host, dialed.port).await
+        };
+        let addrs = match resolved {
+            Ok(v) => dial::order_addrs(v, state.config().dns.address_order),
+            Err(e) => {
+                let failure = Failure::of(&e);
+                error!("client {:?}: cannot resolve remote host {} ({}); err = {:?}", peer, dialed, failure.as_str(), e);
+                let (status, message) = match failure {
+                    Failure::NotFound => (
+                        http::StatusCode::from_u16(state.config().dns.not_found_status).unwrap_or(http::StatusCode::BAD_GATEWAY),
+                        format!("host not found: {}", dialed.host)
+                    ),
+                    Failure::Temporary => (
+                        http::StatusCode::BAD_GATEWAY,
+                        format!("temporary failure resolving remote host {}", dialed.host)
+                    ),
+                    Failure::Other => (http::StatusCode::BAD_GATEWAY, format!("cannot resolve remote host {}", dialed))
+                };
+                let mut error = ProxyError::new(ErrorKind::DnsFailure, message);
+                if failure == Failure::NotFound {
+                    // the name does not exist, asking again gives the same answer
+                    error = error.retry_after(None);
+                }
+                return Ok(error.into_response(status));
+            }
+        };
+        if addrs.iter().any(|a| state.loops.is_local(a)) {
+            return Ok(refuse_loop(&state, peer, "address"));
+        }
+        // a name may resolve to a loopback address, backends and the parent proxy are configured ones
+        let direct = state.upstream_proxy.is_none() && target == requested;
+        if !allow_localhost && direct && addrs.iter().any(|a| target::is_loopback(&a.ip())) {
+            return Ok(deny_localhost(&state, &target, peer));
+        }
+        error!("client {:?}: upstream remote uri {:?}", peer, uri);
+        // held by the tunnel task until the tunnel is closed, checked before queueing for a slot of the host
+        let tunnel_permit = match state.tunnels.as_ref().map(|s| s.clone().try_acquire_owned()) {
+            Some(Err(_)) => {
+                warn!("client {:?}: {} tunnels are open, refusing", peer,
+                      state.config().limits.max_tunnels.unwrap_or_default());
+                state.metrics.inc("upgrades_aborted_total", &[("reason", "tunnel_limit")]);
+                let error = ProxyError::new(ErrorKind::RateLimited, "too many open tunnels");
+                return Ok(error.into_response(http::StatusCode::SERVICE_UNAVAILABLE));
             },
+            Some(Ok(v)) => Some(v),
             None => None
         };
-        if addr.is_some() {
-            let addr = addr.unwrap();
-            error!("client {:?}: upstream remote uri {:?}", peer, uri);
-            tokio::task::spawn(async move {
-                match hyper::upgrade::on(req).await {
-                    Ok(upgraded) => {
-                        if let Err(e) = tunnel(upgraded, addr, peer).await {
-                            error!("client {:?}: server io error; err = {:?}", peer, e);
-                        };
-                        info!("client {:?}: connection closed", peer);
+        // a parent proxy and a race pick connections of their own
+        let pool = state.tunnel_pool.as_ref().filter(|_| state.upstream_proxy.is_none() && raced.is_none());
+        let pooled = pool.and_then(|p| p.take(&target, &addrs));
+        // The slot is held by the tunnel task and freed when the tunnel is closed, an idle connection of
+        // the pool comes with the slot it held
+        let (pooled, permit) = match pooled {
+            Some(taken) => (Some((taken.stream, taken.addr)), Ok(taken.permit)),
+            None => (None, state.outgoing.acquire(&target.host, peer).await)
+        };
+        let permit = match permit {
+            Ok(v) => v,
+            Err(limit) => {
+                warn!("client {:?}: {} already has {} tunnels open, refusing", peer, target.host, limit);
+                state.metrics.inc("outgoing_limited_total", &[("host", &target.host)]);
+                let message = format!("too many connections to remote host {}", target.host);
+                let error = ProxyError::new(ErrorKind::RateLimited, message);
+                return Ok(error.into_response(http::StatusCode::SERVICE_UNAVAILABLE));
+            }
+        };
+        // held until the client connection is upgraded, so stalled upgrades can not pile up upstream connections
+        let pending = match state.pending_upgrades.as_ref().map(|s| s.clone().try_acquire_owned()) {
+            Some(Err(_)) => {
+                warn!("client {:?}: too many CONNECT requests waiting for their upgrade, refusing", peer);
+                state.metrics.inc("upgrades_aborted_total", &[("reason", "pending_limit")]);
+                let error = ProxyError::new(ErrorKind::RateLimited, "too many pending CONNECT requests");
+                return Ok(error.into_response(http::StatusCode::SERVICE_UNAVAILABLE));
+            },
+            Some(Ok(v)) => Some(v),
+            None => None
+        };
+        if let Some(latency) = &state.latency {
+            latency.sleep(&target.host).await;
+        }
+        // Connect to remote server before answering, so the client never gets STATUS_OK
+        // for a tunnel which can not be established
+        let dialing = match raced.or(pooled) {
+            Some(v) => Ok(v),
+            None => state.dialer.connect(&addrs).await
+        };
+        let (mut server, addr) = match dialing {
+            Ok(v) => v,
+            Err(e) if e.ports_exhausted() => return Ok(ports_exhausted(&state, &target.to_string(), peer)),
+            Err(e) => {
+                error!("client {:?}: can not connect to {}; tried {}", peer, dialed, e);
+                return Ok(ProxyError::new(ErrorKind::UpstreamConnectFailed,
+                                          format!("can not connect to remote host {}; tried {}", dialed, e))
+                    .into_response(http::StatusCode::BAD_GATEWAY));
+            }
+        };
+        if let Some(parent) = &state.upstream_proxy {
+            if let Some(resp) = parent_tunnel(&state, &route, parent, &mut server, &target, req.headers(), peer).await {
+                return Ok(resp);
+            }
+        }
+        if let Some(pool) = pool {
+            pool.refill(&target, addrs);
+        }
+        info!("client {:?}: tunnel to {} connected to {}", peer, target, addr);
+        if let Some(latency) = &state.latency {
+            latency.sleep(&target.host).await;
+        }
+        conn.set_tunnel(target.to_string(), addr);
+        let meter = state.accounting.as_ref().map(|a| a.meter(peer, target.to_string()));
+        let max_age = state.config().limits.max_connection_age();
+        let upgrade_timeout = state.config().limits.upgrade_timeout();
+        let state = state.clone();
+        tokio::task::spawn(async move {
+            let _permit = (permit, tunnel_permit);
+            let started = Instant::now();
+            let uri = req.uri().clone();
+            let upgrading = hyper::upgrade::on(req);
+            let upgraded = match upgrade_timeout {
+                Some(timeout) => tokio::time::timeout(timeout, upgrading).await,
+                None => Ok(upgrading.await)
+            };
+            drop(pending);
+            match upgraded {
+                Ok(Ok(upgraded)) => {
+                    state.metrics.inc("tunnels_total", &[]);
+                    state.metrics.add("tunnels_active", &[], 1);
+                    let to_client = Arc::new(AtomicU64::new(0));
+                    let tunneling = tunnel(upgraded, server, peer, meter, to_client.clone(), &state, &route);
+                    let result = match max_age {
+                        // watchdog, the tunnel is closed once the connection reaches its max age
+                        Some(max_age) => match tokio::time::timeout(max_age.saturating_sub(conn.age()), tunneling).await {
+                            Ok(v) => v,
+                            Err(_) => {
+                                info!("client {:?}: tunnel to {} reached max connection age {:?}, closing it",
+                                      peer, addr, max_age);
+                                Ok(())
+                            }
+                        },
+                        None => tunneling.await
+                    };
+                    state.metrics.add("tunnels_active", &[], -1);
+                    if let Err(e) = result {
+                        error!("client {:?}: server io error; err = {:?}", peer, e);
+                    };
+                    state.metrics.observe("tunnel_duration_ms", &[("route", &route.name)],
+                                          started.elapsed().as_millis() as u64);
+                    log_slow(&state, &route, peer, &Method::CONNECT, &uri, http::StatusCode::OK, started.elapsed());
+                    if let Some(log) = &state.access_log {
+                        log.write(&AccessLogEntry {
+                            time: SystemTime::now(),
+                            elapsed: started.elapsed(),
+                            client: peer.shown_ip(),
+                            action: access_log::Action::Tunnel,
+                            status: 200,
+                            bytes: to_client.load(Ordering::Relaxed),
+                            method: String::from("CONNECT"),
+                            url: uri.to_string(),
+                            user: conn.user(),
+                            hierarchy: match state.upstream_proxy {
+                                Some(_) => access_log::Hierarchy::Parent,
+                                None => access_log::Hierarchy::Direct
+                            },
+                            server: Some(addr.ip()),
+                            content_type: None,
+                            route: route.logged.then(|| route.name.clone()),
+                        });
                     }
-                    Err(e) => error!("client {:?}: upgrade error; err = {:?}", peer, e),
+                    info!("client {:?}: connection closed", peer);
                 }
-            });
-            Ok(Response::new(Body::empty()))
-        } else {
-            error!("client {:?}: cannot resolve remote uri {:?}", peer, uri);
-            let mut resp = Response::new(Body::from(format!("cannot resolve remote uri {:?}", uri)));
-            *resp.status_mut() = http::StatusCode::BAD_REQUEST;
-            Ok(resp)
-        }
+                Ok(Err(e)) => error!("client {:?}: upgrade error; err = {:?}", peer, e),
+                Err(_) => {
+                    // dropping the server stream closes the upstream connection
+                    warn!("client {:?}: tunnel to {} not upgraded in {:?}, closing it; reason=upgrade_timeout",
+                          peer, addr, upgrade_timeout.unwrap_or_default());
+                    state.metrics.inc("upgrades_aborted_total", &[("reason", "upgrade_timeout")]);
+                }
+            }
+        });
+        Ok(Response::new(Body::empty()))
     } else {
-        client.request(req).await.and_then(|resp| {
-            info!("client {:?}: connection closed", peer);
-            Ok(resp)
-        })
+        // hyper already decided from them whether to keep the client connection alive
+        strip_hop_by_hop(req.headers_mut());
+        if let Some(target) = Target::from_request_uri(req.uri()) {
+            let target = match target {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("client {:?}: malformed remote uri {:?}; {}", peer, state.log_query.uri(req.uri()), e);
+                    let message = format!("malformed remote uri {:?}: {}", req.uri(), e);
+                    let error = ProxyError::new(ErrorKind::BadRequest, message);
+                    return Ok(reject(&state, peer, error.into_response(http::StatusCode::BAD_REQUEST), req).await);
+                }
+            };
+            if let Err(reason) = state.acl().check(&target) {
+                return Ok(reject(&state, peer, deny(&state, &target, peer, &reason), req).await);
+            }
+            let allow_localhost = state.config().allow_localhost;
+            if !allow_localhost && target.is_localhost() {
+                return Ok(reject(&state, peer, deny_localhost(&state, &target, peer), req).await);
+            }
+            let routed = split_target(&state, target.clone(), peer);
+            let routed = match race_backends(&state, &route, &routed, peer).await {
+                // the request goes through the forwarding client and its pool, the race only picks the backend
+                Some(Ok((_, _, backend))) => backend,
+                Some(Err(resp)) => return Ok(reject(&state, peer, resp, req).await),
+                None => match balance_target(&state, &routed, req.headers(), peer) {
+                    Some(v) => v,
+                    None => return Ok(reject(&state, peer, no_healthy_backend(&routed, peer), req).await)
+                }
+            };
+            if routed != target {
+                // the Host header is kept, backends serve the same site
+                let mut parts = req.uri().clone().into_parts();
+                parts.authority = routed.to_string().parse().ok();
+                if let Ok(uri) = hyper::Uri::from_parts(parts) {
+                    *req.uri_mut() = uri;
+                }
+            }
+            if let Some(uri) = state.rewriter.as_ref().and_then(|r| r.rewrite(&target, req.uri())) {
+                info!("client {:?}: path of {} rewritten to {}", peer, state.log_query.uri(req.uri()),
+                      uri.path());
+                *req.uri_mut() = uri;
+            }
+            match state.resolver.resolve(&routed.host, routed.port).await {
+                // the connector falls back to any of the addresses
+                Ok(v) if v.iter().any(|a| state.loops.is_local(a)) => {
+                    return Ok(reject(&state, peer, refuse_loop(&state, peer, "address"), req).await);
+                },
+                // backends are configured ones, only destinations of clients are checked
+                Ok(v) if !allow_localhost && routed == target && v.iter().any(|a| target::is_loopback(&a.ip())) => {
+                    return Ok(reject(&state, peer, deny_localhost(&state, &target, peer), req).await);
+                },
+                // the connector does not resolve IP literals, so `dns.family` is enforced here for them
+                Err(e) if routed.ip().is_some() => {
+                    error!("client {:?}: refusing address {}; {}", peer, routed, e);
+                    let message = format!("address family of {} is not allowed", routed);
+                    let error = ProxyError::new(ErrorKind::PolicyBlocked, message);
+                    return Ok(reject(&state, peer, error.into_response(http::StatusCode::BAD_GATEWAY), req).await);
+                },
+                _ => {}
+            }
+        }
+        let version = req.version();
+        state.loops.add_via(req.headers_mut(), version);
+        // gRPC streams bodies both ways and ends with trailers, so it is neither mirrored
+        // nor downgraded to HTTP/1, which has no trailers
+        let grpc = state.config().grpc_proxy && is_grpc(req.headers());
+        let client = match &state.grpc_client {
+            Some(v) if grpc => v,
+            _ => &state.client
+        };
+        if version == hyper::Version::HTTP_2 && !grpc {
+            // upstream connections are HTTP/1, hyper adds the Host header from the uri
+            *req.version_mut() = hyper::Version::HTTP_11;
+        }
+        // the webhook needs the whole body, which gRPC streams
+        if let Some(webhook) = state.webhook.as_ref().filter(|_| !grpc) {
+            req = match webhook.inspect(&state.client, req, peer, conn.identity.as_deref()).await? {
+                Outcome::Forward(v) => v,
+                Outcome::Respond(resp) => return Ok(resp)
+            };
+        }
+
+        let target = state.mirror.as_ref().filter(|_| !grpc).and_then(|m| m.pick());
+        let (mut req, primary_tx) = match (&state.mirror, target) {
+            (Some(mirror), Some(target)) => {
+                let (parts, body) = req.into_parts();
+                let (body, bytes) = mirror::buffer_body(body, route.mirror_max_body_bytes).await?;
+                let primary_tx = match bytes {
+                    Some(bytes) => mirror.send(target, &state.client, &parts, bytes, peer),
+                    None => {
+                        debug!("client {:?}: request body exceeds {} bytes, it will not be mirrored",
+                               peer, route.mirror_max_body_bytes);
+                        None
+                    }
+                };
+                (Request::from_parts(parts, body), primary_tx)
+            },
+            _ => (req, None)
+        };
+        if let Some(transforms) = state.transforms.as_ref().filter(|_| !grpc) {
+            transforms.prepare(&mut req);
+        }
+        let stale_key = state.stale.as_ref().filter(|_| !grpc).and_then(|_| StaleResponses::key(&req));
+        // gRPC streams may never end, the tap sends completed exchanges
+        let tapped = state.tap.as_ref().filter(|_| !grpc)
+            .map(|tap| tap.request(&mut req, peer, conn.identity.as_deref()));
+        let host = req.uri().host().map(target::strip_brackets).unwrap_or("").to_string();
+        let timeout = route.request_timeout(&state.config(), &host);
+        let (method, uri) = (req.method().clone(), req.uri().clone());
+        if let Some(latency) = &state.latency {
+            latency.sleep(&host).await;
+        }
+        let sent = match timeout {
+            Some(timeout) => tokio::time::timeout(timeout, client.request(req)).await.map_err(|_| timeout),
+            None => Ok(client.request(req).await)
+        };
+        let mut resp = match sent {
+            Ok(Ok(v)) => v,
+            Ok(Err(e)) => return or_stale(&state, stale_key.as_deref(), peer, upstream_error(&state, &uri, peer, e)),
+            Err(timeout) => {
+                error!("client {:?}: no response from remote server in {:?}", peer, timeout);
+                let message = format!("no response from remote server in {:?}", timeout);
+                let error = ProxyError::new(ErrorKind::UpstreamTimeout, message);
+                return or_stale(&state, stale_key.as_deref(), peer,
+                                Ok(error.into_response(http::StatusCode::GATEWAY_TIMEOUT)));
+            }
+        };
+        // the body is passed through chunk by chunk as it arrives, so long-lived streams
+        // like `multipart/x-mixed-replace` reach the client part by part
+        if let Some(handle) = resp.extensions().get::<ConnectionHandle>() {
+            let reused = handle.count_response();
+            if reused {
+                state.metrics.inc("upstream_connections_reused_total", &[]);
+            }
+            let info = handle.info();
+            let addr = |a: Option<SocketAddr>| a.map(|a| a.to_string()).unwrap_or_else(|| String::from("-"));
+            info!("client {:?}: {} {} {}; conn={} connect_ms={} local={} upstream={}", peer, method,
+                  state.log_query.uri(&uri), resp.status().as_u16(), if reused { "reused" } else { "new" },
+                  info.connect_time.as_millis(), addr(info.local), addr(info.remote));
+        }
+        if resp.status().is_server_error() {
+            if let Some(stale) = serve_stale(&state, stale_key.as_deref(), peer, "status") {
+                return Ok(stale);
+            }
+        }
+        relay_interim(&state, &mut resp, peer);
+        // a closing upstream connection is no reason to close the client one, and the other way round;
+        // the version is one of the hop as well, HTTP/1.0 would tell clients to close
+        strip_hop_by_hop(resp.headers_mut());
+        if resp.version() == hyper::Version::HTTP_10 {
+            *resp.version_mut() = hyper::Version::HTTP_11;
+        }
+        // an HTTP/2 connection carries other streams, and closing it would drop the trailers
+        if !grpc {
+            let close = state.config().close_connection_on_status.contains(&resp.status().as_u16());
+            if close {
+                // the upstream may be in a bad state, neither connection is reused
+                debug!("client {:?}: upstream answered {}, closing connections", peer, resp.status());
+                resp.headers_mut().insert(http::header::CONNECTION, http::HeaderValue::from_static("close"));
+            }
+            resp = connector::track_body(resp, close);
+        }
+        // a wrapped body would drop the trailers of gRPC
+        let resp = match grpc {
+            true => resp,
+            false => response_size::measure(resp, state.metrics.clone(), &route.name,
+                                            state.config().response_size_warn_bytes, peer,
+                                            format!("{} {}", method, state.log_query.uri(&uri)))
+        };
+        if let Some(latency) = &state.latency {
+            latency.sleep(&host).await;
+        }
+        info!("client {:?}: connection closed", peer);
+        // the mirror compares the response of the server, before it is transformed
+        let resp = match primary_tx {
+            Some(tx) => {
+                let capture_body = state.mirror.as_ref().map(|m| m.captures_body(resp.status())).unwrap_or(false);
+                mirror::tee_response(resp, route.mirror_max_body_bytes, capture_body, tx)
+            },
+            None => resp
+        };
+        let resp = match state.transforms.as_ref().filter(|_| !grpc && method != Method::HEAD) {
+            Some(transforms) => match transforms.apply(resp).await? {
+                (resp, Some(transformed)) => {
+                    let result = if transformed { "transformed" } else { "too_large" };
+                    state.metrics.inc("body_transforms_total", &[("result", result)]);
+                    if !transformed {
+                        debug!("client {:?}: response body exceeds {} bytes, it is not transformed",
+                               peer, state.config().body_transform_max_bytes);
+                    }
+                    resp
+                },
+                (resp, None) => resp
+            },
+            None => resp
+        };
+        let resp = match (&state.tap, tapped) {
+            (Some(tap), Some(tapped)) => tap.response(tapped, &state.client, resp),
+            _ => resp
+        };
+        // clients get the copy as it was sent to them
+        match (&state.stale, stale_key) {
+            (Some(stale), Some(key)) => Ok(stale.record(key, resp)),
+            _ => Ok(resp)
+        }
     }
 }
 
+/// Answers a request whose upstream failed with the copy of `serve_stale_on_error`, `failed` when
+/// there is none
+fn or_stale(state: &State, key: Option<&str>, peer: Peer, failed: Result<Response<Body>, hyper::Error>)
+    -> Result<Response<Body>, hyper::Error> {
+    match serve_stale(state, key, peer, "error") {
+        Some(resp) => Ok(resp),
+        None => failed
+    }
+}
 
-async fn tunnel(upgraded: Upgraded, addr: SocketAddr, peer: SocketAddr) -> std::io::Result<()> {
-    // Connect to remote server
-    let mut server = TcpStream::connect(addr).await?;
-
-    // Proxying data
-    let amounts = {
-        let (mut server_rd, mut server_wr) = server.split();
-        let (mut client_rd, mut client_wr) = tokio::io::split(upgraded);
+/// `trigger` is `status` for a 5xx answer of the upstream, `error` when there is no answer
+fn serve_stale(state: &State, key: Option<&str>, peer: Peer, trigger: &str) -> Option<Response<Body>> {
+    let resp = state.stale.as_ref()?.serve(key?)?;
+    info!("client {:?}: upstream failed, serving the copy of {} seconds ago", peer,
+          resp.headers().get(http::header::AGE).and_then(|v| v.to_str().ok()).unwrap_or("0"));
+    state.metrics.inc("stale_responses_total", &[("trigger", trigger)]);
+    Some(resp)
+}
 
-        let client_to_server = tokio::io::copy(&mut client_rd, &mut server_wr);
-        let server_to_client = tokio::io::copy(&mut server_rd, &mut client_wr);
 
-        try_join(client_to_server, server_to_client).await
-    };
+/// `to_client` counts the bytes sent to the client as they are read from the server
+async fn tunnel(upgraded: Upgraded, server: TcpStream, peer: Peer, meter: Option<Arc<TunnelMeter>>,
+                to_client: Arc<AtomicU64>, state: &State, route: &Route) -> std::io::Result<()> {
+    let addr = server.peer_addr()?;
+    // Proxying data, each direction runs until its own end of stream so a half-closed
+    // tunnel keeps carrying the other one
+    let (server_rd, server_wr) = server.into_split();
+    let (client_rd, client_wr) = tokio::io::split(upgraded);
+    let timeouts = (route.tunnel_read_timeout, route.tunnel_write_timeout);
+    let activity = Arc::new(Activity::new());
+    // bytes are counted as they are read from either side; the pipes end with this future, also
+    // when the max age watchdog drops it
+    let mut client_to_server = AbortOnDrop(tokio::task::spawn(
+        pipe(Counted::new(client_rd, meter.clone()), server_wr, ("client", "server"), timeouts, activity.clone())));
+    let mut server_to_client = AbortOnDrop(tokio::task::spawn(
+        pipe(Counted::new(server_rd, meter).with_total(to_client), client_wr, ("server", "client"), timeouts,
+             activity)));
+    let amounts: std::io::Result<(u64, u64)> = async {
+        // a failed direction ends the tunnel without waiting for the other one
+        tokio::select! {
+            v = &mut client_to_server => {
+                let from_client = v??;
+                Ok((from_client, (&mut server_to_client).await??))
+            },
+            v = &mut server_to_client => {
+                let from_server = v??;
+                Ok(((&mut client_to_server).await??, from_server))
+            }
+        }
+    }.await;
+    drop((client_to_server, server_to_client));
 
     // Print message when done
     match amounts {
         Ok((from_client, from_server)) => {
             debug!("client {:?}: {} - wrote {} bytes and received {} bytes", peer, addr, from_client, from_server);
+            state.metrics.add("tunnel_transferred_bytes_total", &[("direction", "client_to_server")], from_client as i64);
+            state.metrics.add("tunnel_transferred_bytes_total", &[("direction", "server_to_client")], from_server as i64);
         }
-        Err(e) => {
-            error!("client {:?}: tunnel error err = {:?}", peer, e);
+        Err(e) => match e.get_ref().and_then(|e| e.downcast_ref::<TunnelTimeout>()) {
+            Some(timeout) => {
+                warn!("client {:?}: {} - {}, closing tunnel", peer, addr, timeout);
+                state.metrics.inc("tunnel_timeouts_total", &[("side", timeout.side), ("operation", timeout.operation)]);
+            },
+            None => error!("client {:?}: tunnel error err = {:?}", peer, e)
         }
     };
     Ok(())
 }
+
+/// Task which is aborted when its handle is dropped
+struct AbortOnDrop<T>(tokio::task::JoinHandle<T>);
+
+impl<T> Future for AbortOnDrop<T> {
+    type Output = Result<T, tokio::task::JoinError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> std::task::Poll<Self::Output> {
+        Pin::new(&mut self.0).poll(cx)
+    }
+}
+
+impl<T> Drop for AbortOnDrop<T> {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Side of a tunnel which did not send or accept bytes in time
+#[derive(Debug)]
+struct TunnelTimeout {
+    /// `client` or `server`
+    side: &'static str,
+    /// `read` or `write`
+    operation: &'static str,
+    after: Duration,
+}
+
+impl std::fmt::Display for TunnelTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.operation {
+            "read" => write!(f, "{} sent no bytes in {:?}", self.side, self.after),
+            _ => write!(f, "{} accepted no bytes in {:?}", self.side, self.after),
+        }
+    }
+}
+
+impl std::error::Error for TunnelTimeout {}
+
+/// Time bytes were last read from either side of a tunnel
+struct Activity {
+    started: Instant,
+    /// Milliseconds since `started`
+    last_ms: AtomicU64,
+}
+
+impl Activity {
+    fn new() -> Activity {
+        Activity { started: Instant::now(), last_ms: AtomicU64::new(0) }
+    }
+
+    fn touch(&self) {
+        self.last_ms.store(self.started.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    fn last(&self) -> Instant {
+        self.started + Duration::from_millis(self.last_ms.load(Ordering::Relaxed))
+    }
+}
+
+/// Copies one direction of a tunnel, then half-closes the writing side so its peer sees the
+/// end of stream while the other direction goes on. `sides` names the reading and the writing
+/// side. A read fails with a `TunnelTimeout` once neither direction read bytes within the read
+/// timeout of `timeouts`, so a one-way stream is not cut while its other direction is silent;
+/// a write fails once it alone exceeds the write timeout.
+async fn pipe<R, W>(mut rd: R, mut wr: W, sides: (&'static str, &'static str),
+                    timeouts: (Option<Duration>, Option<Duration>), activity: Arc<Activity>) -> std::io::Result<u64>
+    where R: AsyncRead + Unpin, W: AsyncWrite + Unpin {
+    let (from, to) = sides;
+    let (read_timeout, write_timeout) = timeouts;
+    // same buffer size as tokio::io::copy
+    let mut buf = vec![0u8; 8 * 1024];
+    let mut copied = 0;
+    loop {
+        let n = read_idle(read_timeout, rd.read(&mut buf), &activity, from).await?;
+        if n == 0 {
+            break;
+        }
+        activity.touch();
+        timed(write_timeout, wr.write_all(&buf[..n]), to, "write").await?;
+        copied += n as u64;
+    }
+    timed(write_timeout, wr.shutdown(), to, "write").await?;
+    Ok(copied)
+}
+
+/// Runs a read of a tunnel, failing with a `TunnelTimeout` once the tunnel carried no bytes in either
+/// direction for longer than `timeout`
+async fn read_idle(timeout: Option<Duration>, read: impl Future<Output = std::io::Result<usize>>, activity: &Activity,
+                   side: &'static str) -> std::io::Result<usize> {
+    let timeout = match timeout {
+        Some(v) => v,
+        None => return read.await
+    };
+    tokio::pin!(read);
+    loop {
+        let idle_until = tokio::time::Instant::from_std(activity.last() + timeout);
+        tokio::select! {
+            v = &mut read => return v,
+            // the other direction may have read bytes meanwhile
+            _ = tokio::time::sleep_until(idle_until) => if activity.last() + timeout <= Instant::now() {
+                let e = TunnelTimeout { side, operation: "read", after: timeout };
+                return Err(std::io::Error::new(std::io::ErrorKind::TimedOut, e));
+            }
+        }
+    }
+}
+
+/// Runs an io operation of a tunnel, failing with a `TunnelTimeout` once it takes longer than `timeout`
+async fn timed<T>(timeout: Option<Duration>, operation: impl Future<Output = std::io::Result<T>>,
+                  side: &'static str, name: &'static str) -> std::io::Result<T> {
+    let timeout = match timeout {
+        Some(v) => v,
+        None => return operation.await
+    };
+    match tokio::time::timeout(timeout, operation).await {
+        Ok(v) => v,
+        Err(_) => {
+            let e = TunnelTimeout { side, operation: name, after: timeout };
+            Err(std::io::Error::new(std::io::ErrorKind::TimedOut, e))
+        }
+    }
+}
@@ -0,0 +1,219 @@
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::collections::BTreeMap;
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Counter,
+    Gauge,
+    /// Durations in milliseconds, a summary of their count and sum in Prometheus
+    Timer,
+    /// Sizes in bytes, a histogram of `SIZE_BUCKETS` in Prometheus
+    Histogram,
+}
+
+/// Upper bounds of the buckets of histograms, 1 KiB to 1 GiB
+const SIZE_BUCKETS: [u64; 7] = [1 << 10, 10 << 10, 100 << 10, 1 << 20, 10 << 20, 100 << 20, 1 << 30];
+
+/// Label names and values of a sample, in the order they were given
+pub type Labels = Vec<(String, String)>;
+
+/// Every metric exported by the server: name, kind and help text
+const METRICS: &[(&str, Kind, &str)] = &[
+    ("mirror_requests_total", Kind::Counter, "Requests sent to mirror targets by result"),
+    ("mirror_mismatch_total", Kind::Counter, "Mirror responses which differ from the primary one by kind of difference"),
+    ("http2_streams_active", Kind::Gauge, "HTTP/2 streams of clients waiting for a response"),
+    ("upstream_connections_opened_total", Kind::Counter, "Connections opened to upstream servers by host"),
+    ("upstream_connect_ms_total", Kind::Counter, "Milliseconds spent opening connections to upstream servers by host"),
+    ("upstream_connections_reused_total", Kind::Counter, "Requests sent over an already used upstream connection"),
+    ("upstream_connections_reaped_total", Kind::Counter, "Idle upstream connections closed by client.pool_reap_interval_secs"),
+    ("upstream_pool_connections", Kind::Gauge, "Open upstream connections by state (idle, busy) as of the last pool reaping"),
+    ("outgoing_limited_total", Kind::Counter, "CONNECT tunnels refused because their target host had too many open ones by host"),
+    ("outgoing_queue_wait_ms", Kind::Timer, "Milliseconds CONNECT tunnels waited for a slot of max_outgoing_per_host by client"),
+    ("source_ports_exhausted_total", Kind::Counter, "Outgoing connections refused because outbound.port_range had no free port"),
+    ("auth_failures_total", Kind::Counter, "Requests answered 407 because of missing or invalid Negotiate credentials"),
+    ("dns_resolutions_total", Kind::Counter, "Resolutions by the system or DoH resolver by result"),
+    ("dns_retries_total", Kind::Counter, "Resolutions retried after a temporary failure"),
+    ("tunnel_bytes_total", Kind::Counter, "Bytes transferred through CONNECT tunnels as published by byte accounting"),
+    ("balanced_requests_total", Kind::Counter, "Requests routed to load_balance backends by pool and backend"),
+    ("health_checks_total", Kind::Counter, "Health check probes of load_balance backends by pool, backend and result"),
+    ("backend_healthy", Kind::Gauge, "Whether a load_balance backend gets requests (1) or was taken out by health checks (0)"),
+    ("user_rate_limited_total", Kind::Counter, "Requests answered 429 because their user exceeded per_user_rate_limit"),
+    ("tap_exchanges_total", Kind::Counter, "Exchanges sent to tap_endpoint by result (sent, failed)"),
+    ("webhook_requests_total", Kind::Counter, "Requests inspected by the webhook by result (allowed, modified, denied, failed)"),
+    ("upstream_proxy_tunnels_total", Kind::Counter, "CONNECT tunnels requested from upstream_proxy by result (established, auth_required, refused, failed)"),
+    ("acl_file_rules", Kind::Gauge, "Rules of acl.allow_file and acl.deny_file by list (allow, deny)"),
+    ("acl_file_loaded_timestamp_seconds", Kind::Gauge, "Time of the last successful read of acl.allow_file and acl.deny_file by list"),
+    ("config_reloads_total", Kind::Counter, "Reloads of the config file on SIGHUP by result (applied, failed)"),
+    ("acl_denied_total", Kind::Counter, "Requests denied by the acl or allow_localhost by reason (rule, default, localhost)"),
+    ("loops_refused_total", Kind::Counter, "Requests refused for pointing back at the proxy by reason (via, address)"),
+    ("upstream_interim_responses_total", Kind::Counter, "Interim responses of upstreams by status (100, 102, 103), early hints are passed on as Link headers of the final response, the others are only logged"),
+    ("body_transforms_total", Kind::Counter, "Response bodies of body_transforms content types by result (transformed, too_large)"),
+    ("malformed_requests_total", Kind::Counter, "Requests refused before they were forwarded by reason (unparsable, http_version, too_many_headers, not_absolute, userinfo, bad_encoding, bad_authority); too_many_headers includes heads over http1_max_buf_size_kb, which hyper refuses alike"),
+    ("proxy_errors_total", Kind::Counter, "Error responses made by the proxy itself by code (policy_blocked, auth_required, rate_limited, bad_request, dns_failure, upstream_connect_failed, upstream_timeout, loop_detected, internal_error)"),
+    ("tls_cert_expiry_seconds", Kind::Gauge, "Seconds until a loaded certificate expires by role (listener, admin, upstream_client) and file, negative once expired"),
+    ("tls_handshake_failures_total", Kind::Counter, "Client connections closed for a failed or timed out TLS handshake"),
+    ("slow_clients_total", Kind::Counter, "Client connections aborted for sending headers or accepting bytes too slowly by reason"),
+    ("requests_total", Kind::Counter, "Requests answered to clients by method, status and route, CONNECT counts once its tunnel is set up"),
+    ("upstream_response_bytes", Kind::Histogram, "Body sizes of responses of upstream servers by route, also of bodies cut off by the client; gRPC is not counted"),
+    ("request_duration_ms", Kind::Timer, "Milliseconds until the response headers of requests other than CONNECT by route"),
+    ("tunnels_total", Kind::Counter, "CONNECT tunnels opened"),
+    ("tunnels_active", Kind::Gauge, "CONNECT tunnels open"),
+    ("stale_responses_total", Kind::Counter, "Stale copies of serve_stale_on_error answering failed requests by trigger (status, error)"),
+    ("rejected_bodies_total", Kind::Counter, "Bodies of requests answered without forwarding them by outcome (drained, closed)"),
+    ("upgrades_aborted_total", Kind::Counter, "CONNECT requests whose upgrade was given up by reason (upgrade_timeout, pending_limit, tunnel_limit)"),
+    ("connect_prewarm_total", Kind::Counter, "CONNECT tunnels of connect_prewarm targets by whether they took an idle connection (hit, miss)"),
+    ("tunnel_duration_ms", Kind::Timer, "Milliseconds CONNECT tunnels were open by route"),
+    ("tunnel_timeouts_total", Kind::Counter, "CONNECT tunnels closed by tunnel_read_timeout_secs or tunnel_write_timeout_secs by side (client, server) and operation (read, write)"),
+    ("tunnel_transferred_bytes_total", Kind::Counter, "Bytes copied through closed CONNECT tunnels by direction"),
+];
+
+
+/// Registry of counters, gauges and timers, rendered in Prometheus text format or pushed to StatsD
+#[derive(Default)]
+pub struct Metrics {
+    /// Samples by metric name, then by labels (e.g. `[("target", "http://a"), ("result", "success")]`)
+    samples: Mutex<BTreeMap<&'static str, BTreeMap<Labels, i64>>>,
+    timers: Mutex<BTreeMap<&'static str, BTreeMap<Labels, Summary>>>,
+    /// Observations since the last `take_timings`, kept only once an exporter takes them
+    timings: Mutex<Vec<(&'static str, Labels, u64)>>,
+    keep_timings: AtomicBool,
+}
+
+/// Count and sum of the observations of a timer or histogram
+#[derive(Default)]
+struct Summary {
+    count: u64,
+    sum: u64,
+    /// Observations of a histogram by bucket of `SIZE_BUCKETS`, the last one is for larger ones
+    buckets: Vec<u64>,
+}
+
+/// Value of a sample as pushed to StatsD
+pub enum Value {
+    /// Total since startup, StatsD gets the increase since the previous push
+    Counter(i64),
+    Gauge(i64),
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    pub fn inc(&self, name: &'static str, labels: &[(&str, &str)]) {
+        self.add(name, labels, 1);
+    }
+
+    /// Replaces the value of a gauge
+    pub fn set(&self, name: &'static str, labels: &[(&str, &str)], value: i64) {
+        let mut samples = self.samples.lock().unwrap();
+        samples.entry(name).or_default().insert(owned(labels), value);
+    }
+
+    pub fn add(&self, name: &'static str, labels: &[(&str, &str)], value: i64) {
+        let mut samples = self.samples.lock().unwrap();
+        *samples.entry(name).or_default().entry(owned(labels)).or_insert(0) += value;
+    }
+
+    /// Records a duration of a timer or a size of a histogram
+    pub fn observe(&self, name: &'static str, labels: &[(&str, &str)], value: u64) {
+        {
+            let mut timers = self.timers.lock().unwrap();
+            let summary = timers.entry(name).or_default().entry(owned(labels)).or_default();
+            summary.count += 1;
+            summary.sum += value;
+            if kind(name) == Some(Kind::Histogram) {
+                summary.buckets.resize(SIZE_BUCKETS.len() + 1, 0);
+                summary.buckets[SIZE_BUCKETS.iter().take_while(|b| value > **b).count()] += 1;
+            }
+        }
+        if self.keep_timings.load(Ordering::Relaxed) {
+            self.timings.lock().unwrap().push((name, owned(labels), value));
+        }
+    }
+
+    /// Counters and gauges with their current values
+    pub fn snapshot(&self) -> Vec<(&'static str, Labels, Value)> {
+        let samples = self.samples.lock().unwrap();
+        let mut out = Vec::new();
+        for (name, kind, _) in METRICS {
+            for (labels, value) in samples.get(name).into_iter().flatten() {
+                let value = match kind {
+                    Kind::Gauge => Value::Gauge(*value),
+                    _ => Value::Counter(*value),
+                };
+                out.push((*name, labels.clone(), value));
+            }
+        }
+        out
+    }
+
+    /// Observations of timers and histograms since the previous call, the first call starts keeping them
+    pub fn take_timings(&self) -> Vec<(&'static str, Labels, u64)> {
+        self.keep_timings.store(true, Ordering::Relaxed);
+        std::mem::take(&mut *self.timings.lock().unwrap())
+    }
+
+    /// Renders all metrics in Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let samples = self.samples.lock().unwrap();
+        let timers = self.timers.lock().unwrap();
+        let mut out = String::new();
+        for (name, kind, help) in METRICS {
+            let type_name = match kind {
+                Kind::Counter => "counter",
+                Kind::Gauge => "gauge",
+                Kind::Timer => "summary",
+                Kind::Histogram => "histogram",
+            };
+            writeln!(out, "# HELP {} {}", name, help).unwrap();
+            writeln!(out, "# TYPE {} {}", name, type_name).unwrap();
+            if *kind == Kind::Timer || *kind == Kind::Histogram {
+                for (labels, summary) in timers.get(name).into_iter().flatten() {
+                    let mut count = 0;
+                    for (i, observed) in summary.buckets.iter().enumerate() {
+                        count += observed;
+                        let le = SIZE_BUCKETS.get(i).map(|b| b.to_string()).unwrap_or_else(|| String::from("+Inf"));
+                        let mut labels = labels.clone();
+                        labels.push((String::from("le"), le));
+                        write_sample(&mut out, &format!("{}_bucket", name), &labels, count as i64);
+                    }
+                    write_sample(&mut out, &format!("{}_sum", name), labels, summary.sum as i64);
+                    write_sample(&mut out, &format!("{}_count", name), labels, summary.count as i64);
+                }
+            } else {
+                for (labels, value) in samples.get(name).into_iter().flatten() {
+                    write_sample(&mut out, name, labels, *value);
+                }
+            }
+        }
+        out
+    }
+}
+
+/// Kind of a metric of `METRICS`
+pub fn kind(name: &str) -> Option<Kind> {
+    METRICS.iter().find(|(n, _, _)| *n == name).map(|(_, kind, _)| *kind)
+}
+
+fn owned(labels: &[(&str, &str)]) -> Labels {
+    labels.iter().map(|(k, v)| (String::from(*k), String::from(*v))).collect()
+}
+
+fn write_sample(out: &mut String, name: &str, labels: &Labels, value: i64) {
+    if labels.is_empty() {
+        writeln!(out, "{} {}", name, value).unwrap();
+    } else {
+        writeln!(out, "{}{{{}}} {}", name, render_labels(labels), value).unwrap();
+    }
+}
+
+fn render_labels(labels: &Labels) -> String {
+    labels.iter()
+        .map(|(k, v)| format!("{}=\"{}\"", k, v.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")))
+        .collect::<Vec<String>>()
+        .join(",")
+}
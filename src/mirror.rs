@@ -0,0 +1,365 @@
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use log::{debug, warn};
+use rand::Rng;
+use futures_util::stream::{self, Stream, StreamExt};
+use tokio::sync::oneshot;
+use hyper::body::{Bytes, HttpBody};
+use hyper::{Body, Request, Response, Uri};
+use hyper::http::{HeaderMap, StatusCode};
+use hyper::http::request::Parts;
+
+use crate::HttpClient;
+use crate::config::{Config, MirrorTargetConfig};
+use crate::metrics::Metrics;
+use crate::privacy::Peer;
+use crate::redact::QueryRedaction;
+
+/// Headers which describe a single connection and never take part in comparison
+const HOP_BY_HOP_HEADERS: [&str; 4] = ["connection", "keep-alive", "transfer-encoding", "proxy-connection"];
+
+
+/// Server receiving copies of requests, picked with a probability proportional to its weight
+struct Target {
+    uri: Uri,
+    weight: u32,
+}
+
+/// Sends a copy of a sampled share of proxied plain-HTTP requests to one of the mirror targets,
+/// responses of mirrors are dropped
+pub struct Mirror {
+    targets: Vec<Target>,
+    total_weight: u32,
+    sample_rate: f64,
+    /// Compare responses of mirrors against the primary one
+    compare: bool,
+    compare_ignore_headers: Vec<String>,
+    /// Bodies of other statuses are not captured, empty captures all
+    compare_body_statuses: Vec<String>,
+    max_body_bytes: u64,
+    log_query: QueryRedaction,
+    metrics: Arc<Metrics>,
+}
+
+/// Response captured for comparison, body is `None` when it exceeds the size limit
+pub struct Captured {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Option<Bytes>,
+}
+
+impl Mirror {
+    pub fn from_config(config: &Config, metrics: Arc<Metrics>) -> Result<Option<Mirror>, String> {
+        let max_body_bytes = config.mirror_max_body_bytes;
+        let log_query = QueryRedaction::from_config(config);
+        let config = &config.mirror;
+        let mut targets = Vec::new();
+        let single = config.target.iter().map(|uri| MirrorTargetConfig { uri: uri.clone(), weight: 1 });
+        for target in single.chain(config.targets.iter().cloned()) {
+            let uri = match target.uri.parse::<Uri>() {
+                Ok(v) if v.scheme().is_some() && v.authority().is_some() => v,
+                _ => return Err(format!("invalid mirror target {:?} (must be an absolute uri like http://host:port)",
+                                        target.uri))
+            };
+            if target.weight > 0 {
+                targets.push(Target { uri, weight: target.weight });
+            }
+        }
+        for pattern in &config.compare_body_statuses {
+            let valid = pattern.len() == 3 && pattern.chars().enumerate()
+                .all(|(i, c)| c.is_ascii_digit() || (i > 0 && (c == 'x' || c == 'X')));
+            if !valid {
+                return Err(format!("invalid mirror.compare_body_statuses entry {:?} (must be like 5xx or 404)", pattern));
+            }
+        }
+        if targets.is_empty() || config.sample_rate <= 0.0 {
+            return Ok(None);
+        }
+        let total_weight = targets.iter().map(|t| t.weight).sum();
+        Ok(Some(Mirror {
+            targets,
+            total_weight,
+            sample_rate: config.sample_rate,
+            compare: config.compare,
+            compare_ignore_headers: config.compare_ignore_headers.iter().map(|h| h.to_lowercase()).collect(),
+            compare_body_statuses: config.compare_body_statuses.clone(),
+            max_body_bytes,
+            log_query,
+            metrics,
+        }))
+    }
+
+    /// Tells whether the body of a response with `status` is captured for comparison
+    pub fn captures_body(&self, status: StatusCode) -> bool {
+        captures_body(&self.compare_body_statuses, status)
+    }
+
+    /// Decides whether the next request is mirrored and picks its target
+    pub fn pick(&self) -> Option<Uri> {
+        let mut rng = rand::thread_rng();
+        if self.sample_rate < 1.0 && !rng.gen_bool(self.sample_rate) {
+            return None;
+        }
+        let mut n = rng.gen_range(0..self.total_weight);
+        for target in &self.targets {
+            if n < target.weight {
+                return Some(target.uri.clone());
+            }
+            n -= target.weight;
+        }
+        None
+    }
+
+    /// Spawns a task sending the request to a mirror, failures are logged and never affect the client.
+    ///
+    /// In compare mode returns a sender the primary response has to be captured into
+    /// (see `tee_response`), the task compares it against the mirror response.
+    pub fn send(&self, target: Uri, client: &HttpClient, parts: &Parts, body: Bytes, peer: Peer)
+        -> Option<oneshot::Sender<Captured>> {
+        let path = match parts.uri.path_and_query() {
+            Some(v) => v.as_str(),
+            None => "/"
+        };
+        let uri = format!("{}://{}{}", target.scheme_str().unwrap(), target.authority().unwrap(), path);
+        let logged_uri = self.log_query.uri(&uri);
+        let mut req = Request::new(Body::from(body));
+        *req.method_mut() = parts.method.clone();
+        *req.headers_mut() = parts.headers.clone();
+        *req.uri_mut() = match uri.parse() {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("client {:?}: can not build mirror uri {:?}; err = {:?}", peer, logged_uri, e);
+                return None;
+            }
+        };
+        if let Ok(v) = hyper::http::HeaderValue::from_str(target.authority().unwrap().as_str()) {
+            req.headers_mut().insert(hyper::http::header::HOST, v);
+        }
+
+        let (primary_tx, primary_rx) = if self.compare {
+            let (tx, rx) = oneshot::channel();
+            (Some(tx), Some(rx))
+        } else {
+            (None, None)
+        };
+        let client = client.clone();
+        let metrics = self.metrics.clone();
+        let label = target.to_string();
+        let ignore_headers = self.compare_ignore_headers.clone();
+        let body_statuses = self.compare_body_statuses.clone();
+        let limit = self.max_body_bytes;
+        let original_uri = self.log_query.uri(&parts.uri);
+        tokio::task::spawn(async move {
+            let resp = match client.request(req).await {
+                Ok(resp) => {
+                    debug!("client {:?}: mirror {} responded {}", peer, logged_uri, resp.status());
+                    metrics.inc("mirror_requests_total", &[("target", &label), ("result", "success")]);
+                    resp
+                },
+                Err(e) => {
+                    warn!("client {:?}: mirror {} error; err = {:?}", peer, logged_uri, e);
+                    metrics.inc("mirror_requests_total", &[("target", &label), ("result", "failure")]);
+                    return;
+                }
+            };
+            let primary_rx = match primary_rx {
+                Some(v) => v,
+                None => return
+            };
+            let limit = if captures_body(&body_statuses, resp.status()) { limit } else { 0 };
+            let mirrored = match capture(resp, limit).await {
+                Ok(v) => v,
+                Err(e) => {
+                    warn!("client {:?}: mirror {} body error; err = {:?}", peer, logged_uri, e);
+                    return;
+                }
+            };
+            let primary = match primary_rx.await {
+                Ok(v) => v,
+                Err(_) => {
+                    debug!("client {:?}: primary response for {} is not complete, it will not be compared",
+                           peer, original_uri);
+                    return;
+                }
+            };
+            let diffs = compare(&primary, &mirrored, &ignore_headers);
+            if diffs.is_empty() {
+                debug!("client {:?}: mirror {} matches primary response", peer, logged_uri);
+            }
+            for (kind, diff) in diffs {
+                warn!("client {:?}: mirror_mismatch {} for {}; {}", peer, label, original_uri, diff);
+                metrics.inc("mirror_mismatch_total", &[("target", &label), ("kind", kind)]);
+            }
+        });
+        primary_tx
+    }
+}
+
+/// Matches a status against patterns like `5xx`, `40x` or `404`
+fn captures_body(patterns: &[String], status: StatusCode) -> bool {
+    let status = status.as_str().as_bytes();
+    patterns.is_empty() || patterns.iter().any(|p| {
+        p.bytes().zip(status.iter()).all(|(p, s)| p == *s || p == b'x' || p == b'X')
+    })
+}
+
+/// Reads a response for comparison, at most `limit` bytes of the body are kept, 0 keeps none
+async fn capture(resp: Response<Body>, limit: u64) -> Result<Captured, hyper::Error> {
+    let (parts, mut body) = resp.into_parts();
+    let mut buf: Vec<u8> = Vec::new();
+    let mut overflow = limit == 0;
+    while !overflow {
+        let chunk = match body.data().await {
+            Some(v) => v?,
+            None => break
+        };
+        if buf.len() as u64 + chunk.len() as u64 > limit {
+            overflow = true;
+        } else {
+            buf.extend_from_slice(&chunk);
+        }
+    }
+    Ok(Captured {
+        status: parts.status,
+        headers: parts.headers,
+        body: if overflow { None } else { Some(Bytes::from(buf)) },
+    })
+}
+
+/// Lists differences between primary and mirror responses as `(kind, description)`
+fn compare(primary: &Captured, mirrored: &Captured, ignore_headers: &[String]) -> Vec<(&'static str, String)> {
+    let mut diffs = Vec::new();
+    if primary.status != mirrored.status {
+        diffs.push(("status", format!("status {} != {}", primary.status.as_u16(), mirrored.status.as_u16())));
+    }
+
+    let ignored = |name: &str| HOP_BY_HOP_HEADERS.contains(&name) || ignore_headers.iter().any(|h| h == name);
+    let mut names: Vec<&str> = primary.headers.keys()
+        .chain(mirrored.headers.keys())
+        .map(|k| k.as_str())
+        .filter(|k| !ignored(k))
+        .collect();
+    names.sort_unstable();
+    names.dedup();
+    for name in names {
+        let a: Vec<_> = primary.headers.get_all(name).iter().collect();
+        let b: Vec<_> = mirrored.headers.get_all(name).iter().collect();
+        if a != b {
+            diffs.push(("headers", format!("header {} {:?} != {:?}", name, a, b)));
+        }
+    }
+
+    match (&primary.body, &mirrored.body) {
+        (Some(a), Some(b)) if a != b => {
+            let offset = a.iter().zip(b.iter()).position(|(x, y)| x != y).unwrap_or_else(|| a.len().min(b.len()));
+            diffs.push(("body", format!("body of {} bytes != body of {} bytes, first difference at byte {}",
+                                        a.len(), b.len(), offset)));
+        },
+        (Some(_), Some(_)) => {},
+        _ => debug!("response body exceeds size limit or its status is not captured, it will not be compared"),
+    }
+    diffs
+}
+
+/// Passes the response body through to the client unchanged while capturing it for comparison,
+/// a body which is not captured is passed through without buffering
+pub fn tee_response(resp: Response<Body>, limit: u64, capture_body: bool, tx: oneshot::Sender<Captured>)
+    -> Response<Body> {
+    let (parts, body) = resp.into_parts();
+    let mut tee = Tee {
+        body,
+        buf: Vec::new(),
+        limit,
+        overflow: !capture_body,
+        status: parts.status,
+        headers: parts.headers.clone(),
+        tx: Some(tx),
+    };
+    if !capture_body {
+        // status and headers are all there is to compare
+        tee.finish();
+    }
+    Response::from_parts(parts, Body::wrap_stream(tee))
+}
+
+struct Tee {
+    body: Body,
+    buf: Vec<u8>,
+    limit: u64,
+    overflow: bool,
+    status: StatusCode,
+    headers: HeaderMap,
+    tx: Option<oneshot::Sender<Captured>>,
+}
+
+impl Stream for Tee {
+    type Item = Result<Bytes, hyper::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        let next = Pin::new(&mut this.body).poll_next(cx);
+        match &next {
+            Poll::Ready(Some(Ok(chunk))) => {
+                if !this.overflow {
+                    if this.buf.len() as u64 + chunk.len() as u64 > this.limit {
+                        // status and headers are compared right away, a streamed body such as
+                        // `multipart/x-mixed-replace` may never end
+                        this.overflow = true;
+                        this.buf = Vec::new();
+                        this.finish();
+                    } else {
+                        this.buf.extend_from_slice(chunk);
+                    }
+                }
+                // a body with known length is not polled again after its last chunk
+                if HttpBody::is_end_stream(&this.body) {
+                    this.finish();
+                }
+            },
+            Poll::Ready(None) => this.finish(),
+            // an error drops the sender so the comparison is skipped
+            Poll::Ready(Some(Err(_))) => { this.tx.take(); },
+            Poll::Pending => {}
+        }
+        next
+    }
+}
+
+impl Tee {
+    fn finish(&mut self) {
+        if let Some(tx) = self.tx.take() {
+            let body = if self.overflow { None } else { Some(Bytes::from(std::mem::take(&mut self.buf))) };
+            let _ = tx.send(Captured {
+                status: self.status,
+                headers: std::mem::take(&mut self.headers),
+                body,
+            });
+        }
+    }
+}
+
+/// Buffers a body of at most `limit` bytes.
+///
+/// Returns the body to forward and its content when it was buffered completely. A body which
+/// exceeds the limit is forwarded as a stream made of the already read chunks and the rest of it.
+pub async fn buffer_body(mut body: Body, limit: u64) -> Result<(Body, Option<Bytes>), hyper::Error> {
+    if HttpBody::size_hint(&body).lower() > limit {
+        return Ok((body, None));
+    }
+
+    let mut chunks: Vec<Bytes> = Vec::new();
+    let mut size: u64 = 0;
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        size += chunk.len() as u64;
+        chunks.push(chunk);
+        if size > limit {
+            let head = stream::iter(chunks.into_iter().map(Ok::<_, hyper::Error>));
+            return Ok((Body::wrap_stream(head.chain(body)), None));
+        }
+    }
+
+    let bytes = Bytes::from(chunks.concat());
+    Ok((Body::from(bytes.clone()), Some(bytes)))
+}
@@ -0,0 +1,147 @@
+use std::ptr;
+use std::os::raw::{c_int, c_void};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use hyper::HeaderMap;
+
+
+/// Major status codes of GSSAPI (RFC 2744)
+const GSS_S_COMPLETE: u32 = 0;
+const GSS_S_CONTINUE_NEEDED: u32 = 1;
+/// Routine and calling errors of a major status
+const GSS_ERROR_MASK: u32 = 0xffff_0000;
+/// Status types of `gss_display_status`
+const GSS_C_GSS_CODE: c_int = 1;
+const GSS_C_MECH_CODE: c_int = 2;
+
+
+#[repr(C)]
+struct GssBuffer {
+    length: usize,
+    value: *mut c_void,
+}
+
+impl GssBuffer {
+    fn empty() -> GssBuffer {
+        GssBuffer { length: 0, value: ptr::null_mut() }
+    }
+
+    fn bytes(&self) -> Vec<u8> {
+        if self.value.is_null() {
+            return Vec::new();
+        }
+        unsafe { std::slice::from_raw_parts(self.value as *const u8, self.length) }.to_vec()
+    }
+
+    fn text(&self) -> String {
+        String::from_utf8_lossy(&self.bytes()).into_owned()
+    }
+}
+
+#[link(name = "gssapi_krb5")]
+extern "C" {
+    fn gss_accept_sec_context(minor: *mut u32, context: *mut *mut c_void, acceptor_cred: *mut c_void,
+                              input_token: *mut GssBuffer, bindings: *mut c_void, src_name: *mut *mut c_void,
+                              mech_type: *mut *mut c_void, output_token: *mut GssBuffer, ret_flags: *mut u32,
+                              time_rec: *mut u32, delegated_cred: *mut *mut c_void) -> u32;
+    fn gss_display_name(minor: *mut u32, name: *mut c_void, output: *mut GssBuffer, name_type: *mut *mut c_void)
+        -> u32;
+    fn gss_display_status(minor: *mut u32, status: u32, status_type: c_int, mech_type: *mut c_void,
+                          message_context: *mut u32, output: *mut GssBuffer) -> u32;
+    fn gss_release_buffer(minor: *mut u32, buffer: *mut GssBuffer) -> u32;
+    fn gss_release_name(minor: *mut u32, name: *mut *mut c_void) -> u32;
+    fn gss_delete_sec_context(minor: *mut u32, context: *mut *mut c_void, output_token: *mut GssBuffer) -> u32;
+}
+
+
+/// Client authenticated by its Negotiate token
+pub struct Accepted {
+    /// Name of the principal like `alice@EXAMPLE.COM`
+    pub principal: String,
+    /// Base64 token of the proxy for `Proxy-Authenticate: Negotiate <token>`, with which a client asking
+    /// for mutual authentication verifies the proxy; `None` when the mechanism returned none
+    pub token: Option<String>,
+}
+
+/// Validates the `Proxy-Authorization: Negotiate <token>` credentials of a request
+pub async fn authenticate(headers: &HeaderMap) -> Result<Accepted, String> {
+    let token = headers.get(hyper::header::PROXY_AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Negotiate "))
+        .ok_or_else(|| String::from("no Negotiate credentials"))?;
+    let token = STANDARD.decode(token.trim()).map_err(|e| format!("invalid Negotiate token; err = {}", e))?;
+    // the keytab is read and the ticket decrypted by the library, which blocks
+    tokio::task::spawn_blocking(move || accept(token)).await
+        .map_err(|e| format!("can not validate Negotiate token; err = {}", e))?
+}
+
+/// Accepts a single-leg SPNEGO/Kerberos token with the default acceptor credentials,
+/// i.e. any service principal of the keytab named by `KRB5_KTNAME`
+fn accept(mut token: Vec<u8>) -> Result<Accepted, String> {
+    let mut minor = 0;
+    let mut context = ptr::null_mut();
+    let mut name = ptr::null_mut();
+    let mut input = GssBuffer { length: token.len(), value: token.as_mut_ptr() as *mut c_void };
+    let mut output = GssBuffer::empty();
+    let major = unsafe {
+        gss_accept_sec_context(&mut minor, &mut context, ptr::null_mut(), &mut input, ptr::null_mut(),
+                               &mut name, ptr::null_mut(), &mut output, ptr::null_mut(), ptr::null_mut(),
+                               ptr::null_mut())
+    };
+    let result = if major & GSS_ERROR_MASK != 0 {
+        Err(status_message(major, minor))
+    } else if major == GSS_S_CONTINUE_NEEDED {
+        Err(String::from("Negotiate needs more than one round trip, which is not supported"))
+    } else if major == GSS_S_COMPLETE {
+        let token = Some(output.bytes()).filter(|v| !v.is_empty()).map(|v| STANDARD.encode(v));
+        display_name(name).map(|principal| Accepted { principal, token })
+    } else {
+        Err(format!("unexpected GSSAPI status {:#x}", major))
+    };
+    unsafe {
+        let mut ignored = 0;
+        gss_release_buffer(&mut ignored, &mut output);
+        if !name.is_null() {
+            gss_release_name(&mut ignored, &mut name);
+        }
+        if !context.is_null() {
+            gss_delete_sec_context(&mut ignored, &mut context, ptr::null_mut());
+        }
+    }
+    result
+}
+
+fn display_name(name: *mut c_void) -> Result<String, String> {
+    let mut minor = 0;
+    let mut output = GssBuffer::empty();
+    let major = unsafe { gss_display_name(&mut minor, name, &mut output, ptr::null_mut()) };
+    let result = if major & GSS_ERROR_MASK != 0 {
+        Err(status_message(major, minor))
+    } else {
+        Ok(output.text())
+    };
+    unsafe { gss_release_buffer(&mut minor, &mut output) };
+    result
+}
+
+/// Text of a GSSAPI error, the mechanism (Kerberos) part tells e.g. that the ticket expired
+fn status_message(major: u32, minor: u32) -> String {
+    let mut messages = Vec::new();
+    for (status, status_type) in [(major, GSS_C_GSS_CODE), (minor, GSS_C_MECH_CODE)].iter() {
+        let mut context = 0;
+        loop {
+            let mut ignored = 0;
+            let mut output = GssBuffer::empty();
+            unsafe { gss_display_status(&mut ignored, *status, *status_type, ptr::null_mut(), &mut context, &mut output) };
+            let message = output.text();
+            unsafe { gss_release_buffer(&mut ignored, &mut output) };
+            if !message.is_empty() {
+                messages.push(message);
+            }
+            if context == 0 {
+                break;
+            }
+        }
+    }
+    messages.join("; ")
+}
@@ -0,0 +1,162 @@
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use tokio::sync::oneshot;
+
+use crate::metrics::Metrics;
+use crate::privacy::Peer;
+use crate::target::host_matches;
+
+
+/// Limits tunnels open at once to every target host, one set of slots per host
+pub struct OutgoingLimiter {
+    /// Tunnels per host, `None` means unlimited
+    default: Option<usize>,
+    /// Limits of host patterns, they take precedence over `default`
+    overrides: BTreeMap<String, usize>,
+    /// Time to wait for a free slot, 0 refuses at once
+    queue_timeout: Duration,
+    /// Hand freed slots to the waiting clients in turn instead of in order of arrival
+    fairness: bool,
+    slots: Mutex<HashMap<String, Arc<Slots>>>,
+    metrics: Arc<Metrics>,
+}
+
+/// Slots of one host and the tunnels waiting for one, by client when slots are handed out fairly
+struct Slots {
+    state: Mutex<SlotsState>,
+}
+
+#[derive(Default)]
+struct SlotsState {
+    free: usize,
+    /// Waiting tunnels by client, all under `None` without fairness
+    waiting: HashMap<Option<IpAddr>, VecDeque<oneshot::Sender<()>>>,
+    /// Clients with waiting tunnels in the order they get the next slots
+    turns: VecDeque<Option<IpAddr>>,
+}
+
+/// Slot of a tunnel, freed or handed to the next waiting tunnel when dropped
+pub struct OutgoingPermit {
+    slots: Arc<Slots>,
+}
+
+impl OutgoingLimiter {
+    pub fn new(default: Option<usize>, overrides: BTreeMap<String, usize>, queue_timeout: Duration, fairness: bool,
+               metrics: Arc<Metrics>) -> OutgoingLimiter {
+        OutgoingLimiter { default, overrides, queue_timeout, fairness, slots: Mutex::new(HashMap::new()), metrics }
+    }
+
+    fn limit(&self, host: &str) -> Option<usize> {
+        match self.overrides.iter().find(|(pattern, _)| host_matches(pattern, host)) {
+            Some((_, limit)) => Some(*limit),
+            None => self.default
+        }
+    }
+
+    fn slots(&self, host: &str, limit: usize) -> Arc<Slots> {
+        let mut slots = self.slots.lock().unwrap();
+        // slots nobody holds a permit of or waits for are not needed anymore
+        slots.retain(|_, s| Arc::strong_count(s) > 1);
+        let state = || SlotsState { free: limit, ..Default::default() };
+        slots.entry(host.to_lowercase()).or_insert_with(|| Arc::new(Slots { state: Mutex::new(state()) })).clone()
+    }
+
+    /// Takes a slot of `host` for a tunnel of `client`, the tunnel holds it until the permit is dropped.
+    ///
+    /// Returns `Ok(None)` for unlimited hosts and `Err(limit)` when no slot was freed in time.
+    pub async fn acquire(&self, host: &str, client: Peer) -> Result<Option<OutgoingPermit>, usize> {
+        let limit = match self.limit(host) {
+            Some(v) => v,
+            None => return Ok(None)
+        };
+        let slots = self.slots(host, limit);
+        let mut waiting = {
+            let mut guard = slots.state.lock().unwrap();
+            let state = &mut *guard;
+            if state.free > 0 && state.turns.is_empty() {
+                state.free -= 1;
+                return Ok(Some(OutgoingPermit { slots: slots.clone() }));
+            }
+            if self.queue_timeout.is_zero() {
+                return Err(limit);
+            }
+            let (tx, rx) = oneshot::channel();
+            let key = if self.fairness { Some(client.addr().ip()) } else { None };
+            let queue = state.waiting.entry(key).or_default();
+            if queue.is_empty() {
+                state.turns.push_back(key);
+            }
+            queue.push_back(tx);
+            rx
+        };
+        let started = Instant::now();
+        let granted = match tokio::time::timeout(self.queue_timeout, &mut waiting).await {
+            Ok(v) => v.is_ok(),
+            Err(_) => {
+                // a slot handed over right as the wait timed out is taken rather than lost
+                waiting.close();
+                slots.state.lock().unwrap().forget_gone();
+                waiting.try_recv().is_ok()
+            }
+        };
+        if !granted {
+            return Err(limit);
+        }
+        let client = client.shown_ip();
+        self.metrics.observe("outgoing_queue_wait_ms", &[("client", &client)], started.elapsed().as_millis() as u64);
+        Ok(Some(OutgoingPermit { slots }))
+    }
+
+    /// Takes a free slot of `host` without waiting, tunnels waiting for one go first
+    pub fn try_acquire(&self, host: &str) -> Result<Option<OutgoingPermit>, usize> {
+        let limit = match self.limit(host) {
+            Some(v) => v,
+            None => return Ok(None)
+        };
+        let slots = self.slots(host, limit);
+        {
+            let mut state = slots.state.lock().unwrap();
+            if state.free == 0 || !state.turns.is_empty() {
+                return Err(limit);
+            }
+            state.free -= 1;
+        }
+        Ok(Some(OutgoingPermit { slots }))
+    }
+}
+
+impl SlotsState {
+    /// Removes the tunnels which gave up waiting
+    fn forget_gone(&mut self) {
+        self.waiting.retain(|_, queue| {
+            queue.retain(|tx| !tx.is_closed());
+            !queue.is_empty()
+        });
+        let waiting = &self.waiting;
+        self.turns.retain(|key| waiting.contains_key(key));
+    }
+}
+
+impl Drop for OutgoingPermit {
+    fn drop(&mut self) {
+        let mut guard = self.slots.state.lock().unwrap();
+        let state = &mut *guard;
+        // the client whose turn it is gets the slot, and queues again behind the others if it waits for more
+        while let Some(key) = state.turns.pop_front() {
+            let queue = state.waiting.entry(key).or_default();
+            let tx = queue.pop_front();
+            if queue.is_empty() {
+                state.waiting.remove(&key);
+            } else {
+                state.turns.push_back(key);
+            }
+            // a tunnel which gave up waiting has dropped its receiver
+            if tx.map(|tx| tx.send(()).is_ok()).unwrap_or(false) {
+                return;
+            }
+        }
+        state.free += 1;
+    }
+}
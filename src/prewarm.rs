@@ -0,0 +1,63 @@
+use std::time::Instant;
+use log::{info, warn};
+use hyper::{Body, Method, Request, Uri};
+use hyper::body::HttpBody;
+
+use crate::HttpClient;
+use crate::config::PrewarmConfig;
+use crate::connector::ConnectionHandle;
+
+
+/// Checks `prewarm` entries before the server starts
+pub fn validate(prewarm: &[PrewarmConfig]) -> Result<(), String> {
+    for entry in prewarm {
+        match entry.uri.parse::<Uri>() {
+            Ok(v) if v.scheme_str() == Some("http") && v.authority().is_some() => {},
+            _ => return Err(format!("invalid prewarm uri {:?} (must be an absolute uri like http://host:port/)",
+                                    entry.uri))
+        }
+    }
+    Ok(())
+}
+
+/// Fills the pool of the client with idle connections to every `prewarm` upstream.
+///
+/// hyper pools only connections which served a request, so `connections` concurrent `HEAD`
+/// requests are sent to the uri of an entry; each of them opens its own connection which
+/// is kept idle afterwards, until the pool idle timeout expires.
+pub async fn prewarm(client: HttpClient, prewarm: Vec<PrewarmConfig>) {
+    for entry in prewarm {
+        let started = Instant::now();
+        let requests: Vec<_> = (0..entry.connections).map(|_| {
+            let client = client.clone();
+            let uri = entry.uri.clone();
+            tokio::task::spawn(async move {
+                let req = Request::builder().method(Method::HEAD).uri(uri).body(Body::empty()).unwrap();
+                let mut resp = client.request(req).await?;
+                // client requests over the connection count as reuses
+                if let Some(handle) = resp.extensions().get::<ConnectionHandle>() {
+                    handle.count_response();
+                }
+                // the connection returns to the pool once the body is read
+                while let Some(chunk) = resp.body_mut().data().await {
+                    chunk?;
+                }
+                Ok::<_, hyper::Error>(())
+            })
+        }).collect();
+        let mut failed = Vec::new();
+        for request in requests {
+            match request.await {
+                Ok(Ok(())) => {},
+                Ok(Err(e)) => failed.push(e.to_string()),
+                Err(e) => failed.push(e.to_string())
+            }
+        }
+        if failed.is_empty() {
+            info!("prewarmed {} connections to {} in {:?}", entry.connections, entry.uri, started.elapsed());
+        } else {
+            warn!("prewarmed {} of {} connections to {}; errors = {:?}",
+                  entry.connections - failed.len(), entry.connections, entry.uri, failed);
+        }
+    }
+}
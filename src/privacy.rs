@@ -0,0 +1,197 @@
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use ring::hmac;
+use serde::{Deserialize, Serialize, Serializer};
+
+use crate::config::PrivacyConfig;
+
+
+/// How addresses of clients appear in logs, metrics and what is sent to other servers
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AnonymizeClientIp {
+    /// Addresses appear as they are
+    #[default]
+    None,
+    /// The last octet of IPv4 and the last 80 bits of IPv6 addresses are zeroed
+    Truncate,
+    /// Addresses are replaced with a keyed hash, a client keeps its hash as long as the key
+    Hash,
+}
+
+
+/// Address of a client: the full one for what the proxy decides by it, e.g. slots handed out by
+/// client, and the anonymized one everything shows. Debug, Display and Serialize render the latter
+/// with the port, e.g. `10.0.0.0:51234` or `5f3a0c9e12b47d68:51234`.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Peer {
+    addr: SocketAddr,
+    shown: Shown,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum Shown {
+    Ip(IpAddr),
+    /// First 8 bytes of the HMAC of the address
+    Hash(u64),
+}
+
+impl Peer {
+    /// Full address of the client, never to be logged
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Anonymized address without the port, e.g. for the client field of the access log
+    pub fn shown_ip(&self) -> String {
+        match self.shown {
+            Shown::Ip(ip) => ip.to_string(),
+            Shown::Hash(hash) => format!("{:016x}", hash),
+        }
+    }
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.shown {
+            Shown::Ip(ip) => write!(f, "{}", SocketAddr::new(ip, self.addr.port())),
+            Shown::Hash(hash) => write!(f, "{:016x}:{}", hash, self.addr.port()),
+        }
+    }
+}
+
+impl fmt::Debug for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl Serialize for Peer {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+
+/// Anonymizes client addresses as `privacy.anonymize_client_ip` says, once per connection
+pub struct Privacy {
+    mode: AnonymizeClientIp,
+    /// Set in `hash` mode
+    key: Option<hmac::Key>,
+}
+
+impl Privacy {
+    pub fn from_config(config: &PrivacyConfig) -> Result<Privacy, String> {
+        let key = match (config.anonymize_client_ip, &config.hash_key) {
+            (AnonymizeClientIp::Hash, Some(key)) if !key.is_empty() => {
+                Some(hmac::Key::new(hmac::HMAC_SHA256, key.as_bytes()))
+            },
+            (AnonymizeClientIp::Hash, _) => {
+                return Err(String::from("privacy.hash_key is required with privacy.anonymize_client_ip: hash"));
+            },
+            _ => None
+        };
+        Ok(Privacy { mode: config.anonymize_client_ip, key })
+    }
+
+    pub fn peer(&self, addr: SocketAddr) -> Peer {
+        let shown = match (self.mode, &self.key) {
+            (AnonymizeClientIp::Truncate, _) => Shown::Ip(truncate(addr.ip())),
+            (AnonymizeClientIp::Hash, Some(key)) => {
+                // a client connecting over IPv4 and IPv4-mapped IPv6 gets the same hash
+                let ip = match addr.ip() {
+                    IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(IpAddr::V6(v6)),
+                    v4 => v4
+                };
+                let octets = match ip {
+                    IpAddr::V4(v4) => v4.octets().to_vec(),
+                    IpAddr::V6(v6) => v6.octets().to_vec(),
+                };
+                let mut hash = [0u8; 8];
+                hash.copy_from_slice(&hmac::sign(key, &octets).as_ref()[..8]);
+                Shown::Hash(u64::from_be_bytes(hash))
+            },
+            _ => Shown::Ip(addr.ip())
+        };
+        Peer { addr, shown }
+    }
+}
+
+/// Zeroes the last octet of an IPv4 address, also of one mapped to IPv6, or the last 80 bits of
+/// an IPv6 address, which leaves its /48 prefix
+fn truncate(ip: IpAddr) -> IpAddr {
+    let v4 = |v4: Ipv4Addr| {
+        let [a, b, c, _] = v4.octets();
+        Ipv4Addr::new(a, b, c, 0)
+    };
+    match ip {
+        IpAddr::V4(ip) => IpAddr::V4(v4(ip)),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(mapped) => IpAddr::V6(v4(mapped).to_ipv6_mapped()),
+            None => {
+                let s = ip.segments();
+                IpAddr::V6(Ipv6Addr::new(s[0], s[1], s[2], 0, 0, 0, 0, 0))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn privacy(mode: AnonymizeClientIp, key: Option<&str>) -> Privacy {
+        Privacy::from_config(&PrivacyConfig { anonymize_client_ip: mode, hash_key: key.map(String::from) }).unwrap()
+    }
+
+    fn addr(addr: &str) -> SocketAddr {
+        addr.parse().unwrap()
+    }
+
+    #[test]
+    fn truncates_addresses() {
+        let cases = [
+            ("192.0.2.77:51234", "192.0.2.0:51234"),
+            ("[2001:db8:1234:5678:9abc::1]:51234", "[2001:db8:1234::]:51234"),
+            ("[::ffff:192.0.2.77]:51234", "[::ffff:192.0.2.0]:51234"),
+        ];
+        let privacy = privacy(AnonymizeClientIp::Truncate, None);
+        for (full, shown) in cases {
+            let peer = privacy.peer(addr(full));
+            assert_eq!(peer.to_string(), shown, "{}", full);
+            assert_eq!(peer.addr(), addr(full));
+        }
+    }
+
+    #[test]
+    fn hashes_addresses_with_key() {
+        let hashing = privacy(AnonymizeClientIp::Hash, Some("s3cret"));
+        let peer = hashing.peer(addr("192.0.2.77:51234"));
+        let shown = peer.shown_ip();
+
+        assert_eq!(shown.len(), 16);
+        assert!(!peer.to_string().contains("192.0.2"), "{}", peer);
+        assert_eq!(peer.to_string(), format!("{}:51234", shown));
+        // stable for the key, across connections and the IPv4-mapped form
+        assert_eq!(hashing.peer(addr("192.0.2.77:40000")).shown_ip(), shown);
+        assert_eq!(hashing.peer(addr("[::ffff:192.0.2.77]:51234")).shown_ip(), shown);
+        assert_eq!(privacy(AnonymizeClientIp::Hash, Some("s3cret")).peer(addr("192.0.2.77:1")).shown_ip(), shown);
+        assert_ne!(hashing.peer(addr("192.0.2.78:51234")).shown_ip(), shown);
+        assert_ne!(privacy(AnonymizeClientIp::Hash, Some("other")).peer(addr("192.0.2.77:1")).shown_ip(), shown);
+    }
+
+    #[test]
+    fn hash_requires_key() {
+        for key in [None, Some(String::new())] {
+            let config = PrivacyConfig { anonymize_client_ip: AnonymizeClientIp::Hash, hash_key: key };
+            assert!(Privacy::from_config(&config).is_err());
+        }
+    }
+
+    #[test]
+    fn none_shows_addresses() {
+        let peer = privacy(AnonymizeClientIp::None, None).peer(addr("192.0.2.77:51234"));
+        assert_eq!(peer.to_string(), "192.0.2.77:51234");
+        assert_eq!(serde_json::to_string(&peer).unwrap(), "\"192.0.2.77:51234\"");
+    }
+}
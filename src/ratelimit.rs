@@ -0,0 +1,55 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use std::collections::{HashMap, VecDeque};
+
+use crate::config::UserRateLimitConfig;
+
+
+/// Window requests are counted in
+pub const WINDOW: Duration = Duration::from_secs(60);
+
+
+/// Limits the requests of every authenticated user within a sliding window
+pub struct UserRateLimiter {
+    max_requests: usize,
+    /// Times of the requests of every user within the window, oldest first
+    requests: Mutex<HashMap<String, VecDeque<Instant>>>,
+}
+
+impl UserRateLimiter {
+    /// Returns `None` when `per_user_rate_limit` is not configured
+    pub fn from_config(config: &Option<UserRateLimitConfig>) -> Option<UserRateLimiter> {
+        config.as_ref().map(|c| UserRateLimiter {
+            max_requests: c.max_requests_per_minute as usize,
+            requests: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn max_requests(&self) -> usize {
+        self.max_requests
+    }
+
+    /// Counts a request of `user`, the error is the time until the next one is accepted
+    pub fn check(&self, user: &str) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut requests = self.requests.lock().unwrap();
+        let times = requests.entry(String::from(user)).or_default();
+        while times.front().map(|t| now.duration_since(*t) >= WINDOW).unwrap_or(false) {
+            times.pop_front();
+        }
+        if times.len() >= self.max_requests {
+            // refused requests are not counted, they would extend the wait of a client retrying too early
+            return Err(times.front().map(|t| WINDOW - now.duration_since(*t)).unwrap_or(WINDOW));
+        }
+        times.push_back(now);
+        Ok(())
+    }
+
+    /// Forgets users without a request within the window, returns how many are left
+    pub fn cleanup(&self) -> usize {
+        let now = Instant::now();
+        let mut requests = self.requests.lock().unwrap();
+        requests.retain(|_, times| times.back().map(|t| now.duration_since(*t) < WINDOW).unwrap_or(false));
+        requests.len()
+    }
+}
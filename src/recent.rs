@@ -0,0 +1,57 @@
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use chrono::{DateTime, Local};
+use serde::Serialize;
+
+use crate::privacy::Peer;
+
+
+/// Completed request as listed by `/admin/requests`
+#[derive(Debug, Clone, Serialize)]
+pub struct RecentRequest {
+    /// Sequence number, increasing with every request recorded
+    pub id: u64,
+    pub method: String,
+    /// Uri as logged, `host:port` for CONNECT
+    pub uri: String,
+    pub peer: Peer,
+    /// `None` when the upstream failed and the client connection was closed without a response
+    pub status: Option<u16>,
+    /// Time the response headers were sent
+    pub timestamp: DateTime<Local>,
+    /// Time until the response headers
+    pub duration_ms: u64,
+}
+
+/// Ring buffer of the latest requests, a summary of recent traffic without an access log.
+///
+/// Every request takes the next slot and locks just that one, so concurrent requests only
+/// contend when the buffer wrapped around completely while one of them was writing.
+pub struct RecentRequests {
+    next_id: AtomicU64,
+    slots: Vec<Mutex<Option<RecentRequest>>>,
+}
+
+impl RecentRequests {
+    /// Returns `None` when `recent_requests_buffer` is 0
+    pub fn new(size: usize) -> Option<RecentRequests> {
+        if size == 0 {
+            return None;
+        }
+        Some(RecentRequests { next_id: AtomicU64::new(0), slots: (0..size).map(|_| Mutex::new(None)).collect() })
+    }
+
+    /// Replaces the oldest request, the id of `request` is assigned here
+    pub fn record(&self, mut request: RecentRequest) {
+        request.id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let slot = (request.id % self.slots.len() as u64) as usize;
+        *self.slots[slot].lock().unwrap() = Some(request);
+    }
+
+    /// Requests in the buffer, newest first
+    pub fn list(&self) -> Vec<RecentRequest> {
+        let mut requests: Vec<_> = self.slots.iter().filter_map(|s| s.lock().unwrap().clone()).collect();
+        requests.sort_unstable_by_key(|r| std::cmp::Reverse(r.id));
+        requests
+    }
+}
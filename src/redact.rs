@@ -0,0 +1,45 @@
+use std::fmt;
+
+use crate::config::Config;
+
+
+/// Masking of query strings in logged uris, forwarded requests keep theirs
+#[derive(Debug, Clone, Default)]
+pub struct QueryRedaction {
+    /// Mask the whole query
+    strip: bool,
+    /// Parameters whose values are masked, matched case-insensitively
+    params: Vec<String>,
+}
+
+impl QueryRedaction {
+    pub fn from_config(config: &Config) -> QueryRedaction {
+        QueryRedaction { strip: config.log_strip_query, params: config.log_strip_query_params.clone() }
+    }
+
+    /// Tells whether logged uris differ from the requested ones
+    pub fn is_active(&self) -> bool {
+        self.strip || !self.params.is_empty()
+    }
+
+    /// Renders a uri for the log, `?token=abc&page=2` becomes `?[redacted]` or `?token=[redacted]&page=2`
+    pub fn uri(&self, uri: impl fmt::Display) -> String {
+        let uri = uri.to_string();
+        let (base, query) = match uri.split_once('?') {
+            Some(v) if self.is_active() => v,
+            _ => return uri
+        };
+        if self.strip {
+            return format!("{}?[redacted]", base);
+        }
+        let pairs: Vec<String> = query.split('&')
+            .map(|pair| match pair.split_once('=') {
+                Some((name, _)) if self.params.iter().any(|p| p.eq_ignore_ascii_case(name)) => {
+                    format!("{}=[redacted]", name)
+                },
+                _ => String::from(pair)
+            })
+            .collect();
+        format!("{}?{}", base, pairs.join("&"))
+    }
+}
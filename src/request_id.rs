@@ -0,0 +1,125 @@
+use hyper::{Body, Request, Response};
+use hyper::header::{HeaderName, HeaderValue};
+use rand::Rng;
+
+use crate::config::RequestIdConfig;
+
+
+/// Longest id of a client which is adopted
+const MAX_LENGTH: usize = 128;
+
+
+/// Gives every request an id which is forwarded upstream and echoed to the client, so the logs
+/// of every hop correlate; error pages show it as well
+pub struct RequestIds {
+    header: HeaderName,
+    trust_client: bool,
+}
+
+impl RequestIds {
+    pub fn from_config(config: &RequestIdConfig) -> Result<RequestIds, String> {
+        let header = HeaderName::from_bytes(config.header.as_bytes())
+            .map_err(|_| format!("invalid request_id.header {:?} (must be a header name)", config.header))?;
+        Ok(RequestIds { header, trust_client: config.trust_client })
+    }
+
+    /// Returns the id of the request, the one sent by the client when it is trusted and valid or a new
+    /// one replacing it, and whether an id of a trusted client was refused as invalid
+    pub fn assign(&self, req: &mut Request<Body>) -> (String, bool) {
+        let given = req.headers().get(&self.header).map(|v| v.as_bytes());
+        if let Some(id) = given.filter(|_| self.trust_client).and_then(valid) {
+            return (id, false);
+        }
+        let refused = given.is_some() && self.trust_client;
+        let id = generate();
+        req.headers_mut().insert(&self.header, HeaderValue::from_str(&id).unwrap());
+        (id, refused)
+    }
+
+    /// Sends the id of the request with its response
+    pub fn echo(&self, resp: &mut Response<Body>, id: &str) {
+        if let Ok(v) = HeaderValue::from_str(id) {
+            resp.headers_mut().insert(&self.header, v);
+        }
+    }
+}
+
+/// 16 hex digits
+fn generate() -> String {
+    format!("{:016x}", rand::thread_rng().gen::<u64>())
+}
+
+/// Ids of clients have at most `MAX_LENGTH` characters of UUIDs, base64 and the like, anything else
+/// could garble logs
+fn valid(id: &[u8]) -> Option<String> {
+    let allowed = |c: &u8| c.is_ascii_alphanumeric() || b"-_.:@+/=".contains(c);
+    if id.is_empty() || id.len() > MAX_LENGTH || !id.iter().all(allowed) {
+        return None;
+    }
+    String::from_utf8(id.to_vec()).ok()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ids(yaml: &str) -> RequestIds {
+        RequestIds::from_config(&serde_yaml::from_str(yaml).unwrap()).unwrap()
+    }
+
+    fn request(id: Option<&str>) -> Request<Body> {
+        let mut req = Request::get("http://example.com/");
+        if let Some(id) = id {
+            req = req.header("x-request-id", id);
+        }
+        req.body(Body::empty()).unwrap()
+    }
+
+    #[test]
+    fn valid_ids_of_trusted_clients_are_adopted() {
+        let ids = ids("{}");
+        for id in ["f81d4fae-7dec-11d0-a765-00a0c91e6bf6", "YWxpY2U=", "edge-1:42"] {
+            let mut req = request(Some(id));
+            assert_eq!(ids.assign(&mut req), (String::from(id), false));
+            assert_eq!(req.headers()["x-request-id"], id);
+        }
+    }
+
+    #[test]
+    fn invalid_ids_are_replaced() {
+        let ids = ids("{}");
+        let long = "a".repeat(MAX_LENGTH + 1);
+        for id in ["", "two words", "tab\there", &long] {
+            let mut req = request(Some(id));
+            let (assigned, refused) = ids.assign(&mut req);
+            assert!(refused, "{:?}", id);
+            assert_eq!(assigned.len(), 16);
+            assert_eq!(req.headers()["x-request-id"], assigned.as_str());
+        }
+        assert_eq!(ids.assign(&mut request(Some(&long[..MAX_LENGTH]))).0, long[..MAX_LENGTH]);
+    }
+
+    #[test]
+    fn ids_of_untrusted_clients_are_replaced() {
+        let ids = ids("header: x-correlation-id\ntrust_client: false\n");
+        let mut req = Request::get("http://example.com/").header("x-correlation-id", "forged")
+            .body(Body::empty()).unwrap();
+
+        let (assigned, refused) = ids.assign(&mut req);
+
+        assert_ne!(assigned, "forged");
+        assert!(!refused);
+        assert_eq!(req.headers()["x-correlation-id"], assigned.as_str());
+        let mut resp = Response::new(Body::empty());
+        ids.echo(&mut resp, &assigned);
+        assert_eq!(resp.headers()["x-correlation-id"], assigned.as_str());
+    }
+
+    #[test]
+    fn refuses_invalid_header_names() {
+        let config = serde_yaml::from_str("header: \"x request id\"\n").unwrap();
+        assert_eq!(RequestIds::from_config(&config).err(),
+                   Some(String::from("invalid request_id.header \"x request id\" (must be a header name)")));
+    }
+}
@@ -0,0 +1,412 @@
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+use std::collections::{BTreeMap, HashMap};
+use log::{info, warn};
+use hyper::Uri;
+use serde::{Deserialize, Serialize};
+
+use crate::config::{Config, DnsConfig, HostAddrs};
+use crate::doh::DohResolver;
+use crate::metrics::Metrics;
+use crate::target::Target;
+
+
+pub type Resolving<'a> = Pin<Box<dyn Future<Output = io::Result<Vec<SocketAddr>>> + Send + 'a>>;
+
+/// Resolves destinations of the proxy, implementations must not block the runtime.
+///
+/// The returned list is never empty, a host without addresses is an error.
+pub trait Resolver: Send + Sync {
+    fn resolve<'a>(&'a self, host: &'a str, port: u16) -> Resolving<'a>;
+
+    /// Applies resolution settings of a re-read config, resolvers without settings ignore it
+    fn reload(&self, _config: &Config) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+
+/// Resolves with the resolver of the operating system
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn resolve<'a>(&'a self, host: &'a str, port: u16) -> Resolving<'a> {
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port)).await?.collect();
+            if addrs.is_empty() {
+                return Err(io::Error::new(io::ErrorKind::NotFound, format!("no addresses found for {}", host)));
+            }
+            Ok(addrs)
+        })
+    }
+}
+
+
+/// Kind of a failed resolution, only temporary failures are worth retrying
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    /// The name does not exist (NXDOMAIN) or has no addresses
+    NotFound,
+    /// The resolver timed out or could not answer (e.g. SERVFAIL while it restarts)
+    Temporary,
+    Other,
+}
+
+impl Failure {
+    /// Classifies an error of a resolver; the system resolver reports `getaddrinfo` errors
+    /// by their message only
+    pub fn of(err: &io::Error) -> Failure {
+        let message = err.to_string();
+        match err.kind() {
+            io::ErrorKind::NotFound => Failure::NotFound,
+            io::ErrorKind::TimedOut | io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted => Failure::Temporary,
+            _ if message.contains("not known") || message.contains("No address associated") => Failure::NotFound,
+            _ if message.contains("Temporary failure") || message.contains("try again") => Failure::Temporary,
+            _ => Failure::Other
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Failure::NotFound => "not_found",
+            Failure::Temporary => "temporary",
+            Failure::Other => "error",
+        }
+    }
+}
+
+
+/// Retries temporary failures of the inner resolver, waiting `backoff` before the first retry
+/// and twice as long before every next one
+pub struct RetryingResolver {
+    inner: Box<dyn Resolver>,
+    retries: u32,
+    backoff: Duration,
+    metrics: Arc<Metrics>,
+}
+
+impl RetryingResolver {
+    pub fn new(config: &DnsConfig, inner: Box<dyn Resolver>, metrics: Arc<Metrics>) -> RetryingResolver {
+        RetryingResolver { inner, retries: config.retries, backoff: config.retry_backoff(), metrics }
+    }
+}
+
+impl Resolver for RetryingResolver {
+    fn resolve<'a>(&'a self, host: &'a str, port: u16) -> Resolving<'a> {
+        Box::pin(async move {
+            let mut attempt = 0;
+            loop {
+                let err = match self.inner.resolve(host, port).await {
+                    Ok(v) => {
+                        self.metrics.inc("dns_resolutions_total", &[("result", "success")]);
+                        return Ok(v);
+                    },
+                    Err(e) => e
+                };
+                let failure = Failure::of(&err);
+                if failure != Failure::Temporary || attempt >= self.retries {
+                    self.metrics.inc("dns_resolutions_total", &[("result", failure.as_str())]);
+                    return Err(err);
+                }
+                let backoff = self.backoff * 2u32.saturating_pow(attempt);
+                warn!("can not resolve {}, retrying in {:?}; err = {}", host, backoff, err);
+                self.metrics.inc("dns_retries_total", &[]);
+                tokio::time::sleep(backoff).await;
+                attempt += 1;
+            }
+        })
+    }
+
+    fn reload(&self, config: &Config) -> Result<(), String> {
+        self.inner.reload(config)
+    }
+}
+
+
+/// Resolver names missing from `hosts` are passed to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResolverKind {
+    System,
+    Doh,
+}
+
+/// Address families destinations may be connected over
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AddressFamily {
+    Any,
+    Ipv4Only,
+    Ipv6Only,
+}
+
+/// Drops addresses of the other family from answers of the inner resolver, e.g. where IPv6
+/// is not routed; a destination left without addresses is not found
+pub struct FamilyResolver {
+    inner: Box<dyn Resolver>,
+    family: AddressFamily,
+}
+
+impl FamilyResolver {
+    pub fn new(family: AddressFamily, inner: Box<dyn Resolver>) -> FamilyResolver {
+        FamilyResolver { inner, family }
+    }
+}
+
+impl Resolver for FamilyResolver {
+    fn resolve<'a>(&'a self, host: &'a str, port: u16) -> Resolving<'a> {
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = self.inner.resolve(host, port).await?.into_iter()
+                .filter(|a| match self.family {
+                    AddressFamily::Any => true,
+                    AddressFamily::Ipv4Only => a.is_ipv4(),
+                    AddressFamily::Ipv6Only => a.is_ipv6(),
+                })
+                .collect();
+            if addrs.is_empty() {
+                return Err(io::Error::new(io::ErrorKind::NotFound,
+                                          format!("no {:?} addresses found for {}", self.family, host)));
+            }
+            Ok(addrs)
+        })
+    }
+
+    fn reload(&self, config: &Config) -> Result<(), String> {
+        self.inner.reload(config)
+    }
+}
+
+
+/// Answers names of the `hosts` config map, other names are passed to the fallback resolver
+pub struct StaticResolver {
+    hosts: RwLock<HashMap<String, Vec<IpAddr>>>,
+    fallback: Box<dyn Resolver>,
+}
+
+impl StaticResolver {
+    pub fn new(hosts: &BTreeMap<String, HostAddrs>, fallback: Box<dyn Resolver>) -> Result<StaticResolver, String> {
+        Ok(StaticResolver { hosts: RwLock::new(parse_hosts(hosts)?), fallback })
+    }
+}
+
+impl Resolver for StaticResolver {
+    fn resolve<'a>(&'a self, host: &'a str, port: u16) -> Resolving<'a> {
+        let ips = self.hosts.read().unwrap().get(&host.to_lowercase()).cloned();
+        match ips {
+            Some(ips) => {
+                let addrs = ips.into_iter().map(|ip| SocketAddr::new(ip, port)).collect();
+                Box::pin(async move { Ok(addrs) })
+            },
+            None => self.fallback.resolve(host, port)
+        }
+    }
+
+    fn reload(&self, config: &Config) -> Result<(), String> {
+        let hosts = parse_hosts(&config.hosts)?;
+        info!("hosts reloaded, {} names pinned", hosts.len());
+        *self.hosts.write().unwrap() = hosts;
+        self.fallback.reload(config)
+    }
+}
+
+fn parse_hosts(hosts: &BTreeMap<String, HostAddrs>) -> Result<HashMap<String, Vec<IpAddr>>, String> {
+    let mut parsed = HashMap::new();
+    for (name, addrs) in hosts {
+        let mut ips = Vec::new();
+        for ip in addrs.to_vec() {
+            ips.push(ip.parse::<IpAddr>()
+                .map_err(|_| format!("invalid address {:?} of host {:?} in hosts (must be an IP address)", ip, name))?);
+        }
+        if ips.is_empty() {
+            return Err(format!("host {:?} in hosts has no addresses", name));
+        }
+        parsed.insert(name.to_lowercase(), ips);
+    }
+    Ok(parsed)
+}
+
+/// Builds the resolver configured by `config`
+pub fn from_config(config: &Config, metrics: Arc<Metrics>) -> Result<Arc<dyn Resolver>, String> {
+    let resolver: Box<dyn Resolver> = match config.dns.resolver {
+        ResolverKind::System => Box::new(SystemResolver),
+        ResolverKind::Doh => Box::new(DohResolver::new(&config.dns.doh_url, &config.dns.doh_ca_pem)?),
+    };
+    let retrying = RetryingResolver::new(&config.dns, resolver, metrics);
+    // built even without hosts, so names added to the config later are picked up on reload
+    let hosts = StaticResolver::new(&config.hosts, Box::new(retrying))?;
+    match config.dns.family {
+        AddressFamily::Any => Ok(Arc::new(hosts)),
+        family => Ok(Arc::new(FamilyResolver::new(family, Box::new(hosts))))
+    }
+}
+
+/// Runs the `resolve` subcommand, prints every address of `target` (`host[:port]`) and returns the exit code
+pub async fn command(config: &Config, target: &str) -> i32 {
+    let target = match target.parse::<Uri>().map_err(|e| e.to_string())
+        .and_then(|uri| Target::from_uri(&uri, config.connect_default_port).map_err(|e| e.to_string())) {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("invalid target {:?}; {}", target, e);
+            return 1;
+        }
+    };
+    let resolver = match from_config(config, Arc::new(Metrics::new())) {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 1;
+        }
+    };
+    let started = Instant::now();
+    match resolver.resolve(&target.host, target.port).await {
+        Ok(addrs) => {
+            println!("{} resolved in {:?}", target, started.elapsed());
+            for addr in addrs {
+                // neither the system resolver nor the hosts map report TTLs
+                println!("  {}  ttl -", addr);
+            }
+            0
+        },
+        Err(e) => {
+            eprintln!("can not resolve {} ({}); err = {}", target, Failure::of(&e).as_str(), e);
+            1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+    use std::sync::Mutex;
+
+    /// Result of a scripted resolution
+    type Outcome = fn() -> io::Result<Vec<SocketAddr>>;
+
+    /// Answers with the scripted results in turn, then with the last one
+    struct Scripted {
+        results: Mutex<VecDeque<Outcome>>,
+        calls: Arc<Mutex<Vec<Instant>>>,
+    }
+
+    impl Resolver for Scripted {
+        fn resolve<'a>(&'a self, _host: &'a str, _port: u16) -> Resolving<'a> {
+            self.calls.lock().unwrap().push(Instant::now());
+            let mut results = self.results.lock().unwrap();
+            let result = if results.len() > 1 { results.pop_front().unwrap() } else { results[0] };
+            Box::pin(async move { result() })
+        }
+    }
+
+    fn found() -> io::Result<Vec<SocketAddr>> {
+        Ok(vec![SocketAddr::from(([192, 0, 2, 1], 443))])
+    }
+
+    fn nxdomain() -> io::Result<Vec<SocketAddr>> {
+        Err(io::Error::new(io::ErrorKind::NotFound, "example.com does not exist"))
+    }
+
+    fn servfail() -> io::Result<Vec<SocketAddr>> {
+        Err(io::Error::other("Temporary failure in name resolution (SERVFAIL)"))
+    }
+
+    fn timeout() -> io::Result<Vec<SocketAddr>> {
+        Err(io::Error::new(io::ErrorKind::TimedOut, "no answer"))
+    }
+
+    fn refused() -> io::Result<Vec<SocketAddr>> {
+        Err(io::Error::other("DoH server answered rcode 5"))
+    }
+
+    /// Resolves `example.com` with 2 retries 20ms apart at first, returns the result, the times
+    /// the scripted resolver was asked and the metrics
+    async fn resolve(script: &[Outcome]) -> (io::Result<Vec<SocketAddr>>, Vec<Instant>, String) {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let scripted = Scripted { results: Mutex::new(script.iter().copied().collect()), calls: calls.clone() };
+        let config = DnsConfig { retries: 2, retry_backoff_ms: 20, ..DnsConfig::default() };
+        let metrics = Arc::new(Metrics::new());
+        let resolver = RetryingResolver::new(&config, Box::new(scripted), metrics.clone());
+        let result = resolver.resolve("example.com", 443).await;
+        let calls = calls.lock().unwrap().clone();
+        (result, calls, metrics.render())
+    }
+
+    #[test]
+    fn classifies_failures() {
+        let cases = [
+            (io::Error::new(io::ErrorKind::NotFound, "no addresses found for example.com"), Failure::NotFound),
+            (io::Error::other("failed to lookup address information: Name or service not known"), Failure::NotFound),
+            (io::Error::other("failed to lookup address information: No address associated with hostname"),
+             Failure::NotFound),
+            (io::Error::other("failed to lookup address information: Temporary failure in name resolution"),
+             Failure::Temporary),
+            (io::Error::other("nodename nor servname provided, try again"), Failure::Temporary),
+            (io::Error::new(io::ErrorKind::TimedOut, "no answer"), Failure::Temporary),
+            (io::Error::new(io::ErrorKind::ConnectionAborted, "can not reach DoH server"), Failure::Temporary),
+            (io::Error::other("DoH server answered rcode 5"), Failure::Other),
+        ];
+        for (err, failure) in cases {
+            assert_eq!(Failure::of(&err), failure, "{}", err);
+        }
+    }
+
+    #[tokio::test]
+    async fn temporary_failures_are_retried_with_backoff() {
+        let (result, calls, metrics) = resolve(&[servfail, timeout, found]).await;
+
+        assert_eq!(result.unwrap(), found().unwrap());
+        assert_eq!(calls.len(), 3);
+        // the backoff doubles
+        assert!(calls[1] - calls[0] >= Duration::from_millis(20), "{:?}", calls);
+        assert!(calls[2] - calls[1] >= Duration::from_millis(40), "{:?}", calls);
+        assert!(metrics.contains("dns_retries_total 2\n"), "{}", metrics);
+        assert!(metrics.contains("dns_resolutions_total{result=\"success\"} 1\n"), "{}", metrics);
+    }
+
+    #[tokio::test]
+    async fn retries_end_at_the_limit() {
+        let (result, calls, metrics) = resolve(&[servfail]).await;
+
+        assert_eq!(Failure::of(&result.unwrap_err()), Failure::Temporary);
+        assert_eq!(calls.len(), 3);
+        assert!(metrics.contains("dns_resolutions_total{result=\"temporary\"} 1\n"), "{}", metrics);
+    }
+
+    #[tokio::test]
+    async fn names_which_do_not_exist_are_not_retried() {
+        for (script, result) in [(nxdomain as Outcome, "not_found"), (refused, "error")] {
+            let (resolved, calls, metrics) = resolve(&[script, found]).await;
+
+            assert!(resolved.is_err());
+            assert_eq!(calls.len(), 1, "{}", result);
+            assert!(!metrics.contains("dns_retries_total 1"), "{}", metrics);
+            assert!(metrics.contains(&format!("dns_resolutions_total{{result=\"{}\"}} 1\n", result)), "{}", metrics);
+        }
+    }
+
+    fn dual_stack() -> io::Result<Vec<SocketAddr>> {
+        Ok(vec![SocketAddr::from(([192, 0, 2, 1], 443)), SocketAddr::from(([0x2001, 0xdb8, 0, 0, 0, 0, 0, 1], 443))])
+    }
+
+    async fn resolve_family(family: AddressFamily, outcome: Outcome) -> io::Result<Vec<SocketAddr>> {
+        let scripted = Scripted { results: Mutex::new(VecDeque::from(vec![outcome])), calls: Arc::default() };
+        FamilyResolver::new(family, Box::new(scripted)).resolve("example.com", 443).await
+    }
+
+    #[tokio::test]
+    async fn drops_addresses_of_the_other_family() {
+        let all = dual_stack().unwrap();
+
+        assert_eq!(resolve_family(AddressFamily::Any, dual_stack).await.unwrap(), all);
+        assert_eq!(resolve_family(AddressFamily::Ipv4Only, dual_stack).await.unwrap(), &all[..1]);
+        assert_eq!(resolve_family(AddressFamily::Ipv6Only, dual_stack).await.unwrap(), &all[1..]);
+        let error = resolve_family(AddressFamily::Ipv6Only, found).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        assert_eq!(error.to_string(), "no Ipv6Only addresses found for example.com");
+    }
+}
@@ -0,0 +1,70 @@
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use futures_util::stream::Stream;
+use hyper::body::Bytes;
+use hyper::{Body, Response};
+use log::warn;
+
+use crate::metrics::Metrics;
+use crate::privacy::Peer;
+
+
+/// Counts the body of a response of an upstream server as it is passed on, without buffering it,
+/// into `upstream_response_bytes`; a body larger than `warn_bytes` is logged once it gets there
+pub fn measure(resp: Response<Body>, metrics: Arc<Metrics>, route: &str, warn_bytes: Option<u64>, peer: Peer,
+               uri: String) -> Response<Body> {
+    let (parts, body) = resp.into_parts();
+    let mut body = MeasuredBody { body, metrics, route: String::from(route), warn_bytes, peer, uri, bytes: 0,
+                                  warned: false };
+    let declared = parts.headers.get(hyper::header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    if let Some(declared) = declared {
+        body.check(declared, "declares");
+    }
+    Response::from_parts(parts, Body::wrap_stream(body))
+}
+
+struct MeasuredBody {
+    body: Body,
+    metrics: Arc<Metrics>,
+    route: String,
+    warn_bytes: Option<u64>,
+    peer: Peer,
+    /// Uri as logged
+    uri: String,
+    bytes: u64,
+    warned: bool,
+}
+
+impl MeasuredBody {
+    fn check(&mut self, bytes: u64, verb: &str) {
+        if self.warned || !self.warn_bytes.map(|max| bytes > max).unwrap_or(false) {
+            return;
+        }
+        self.warned = true;
+        warn!("client {:?}: response of {} {} {} bytes, more than response_size_warn_bytes {}",
+              self.peer, self.uri, verb, bytes, self.warn_bytes.unwrap_or_default());
+    }
+}
+
+impl Stream for MeasuredBody {
+    type Item = Result<Bytes, hyper::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let poll = Pin::new(&mut self.body).poll_next(cx);
+        if let Poll::Ready(Some(Ok(chunk))) = &poll {
+            self.bytes += chunk.len() as u64;
+            let bytes = self.bytes;
+            self.check(bytes, "exceeds");
+        }
+        poll
+    }
+}
+
+impl Drop for MeasuredBody {
+    fn drop(&mut self) {
+        self.metrics.observe("upstream_response_bytes", &[("route", &self.route)], self.bytes);
+    }
+}
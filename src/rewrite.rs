@@ -0,0 +1,178 @@
+use hyper::Uri;
+use regex::Regex;
+
+use crate::config::PathRewriteConfig;
+use crate::target::{host_matches, Target};
+
+
+struct Rule {
+    host: String,
+    strip_prefix: Option<String>,
+    regex: Option<(Regex, String)>,
+    add_prefix: Option<String>,
+}
+
+impl Rule {
+    fn parse(config: &PathRewriteConfig) -> Result<Rule, String> {
+        let regex = match (&config.regex, &config.replacement) {
+            (Some(regex), Some(replacement)) => {
+                let regex = Regex::new(regex)
+                    .map_err(|e| format!("invalid path_rewrites regex {:?} of host {:?}; {}", regex, config.host, e))?;
+                Some((regex, replacement.clone()))
+            },
+            (None, None) => None,
+            _ => return Err(format!("path_rewrites of host {:?} needs both regex and replacement", config.host))
+        };
+        if config.strip_prefix.is_none() && regex.is_none() && config.add_prefix.is_none() {
+            return Err(format!("path_rewrites of host {:?} does not rewrite anything (needs strip_prefix, regex \
+                                or add_prefix)", config.host));
+        }
+        let trim = |v: &Option<String>| v.as_ref().map(|v| String::from(v.trim_end_matches('/')));
+        Ok(Rule {
+            host: config.host.to_lowercase(),
+            strip_prefix: trim(&config.strip_prefix),
+            regex,
+            add_prefix: trim(&config.add_prefix),
+        })
+    }
+
+    /// Returns the rewritten path, `None` when the rule does not apply to it
+    fn apply(&self, path: &str) -> Option<String> {
+        let mut path = String::from(path);
+        if let Some(prefix) = &self.strip_prefix {
+            // `/api` strips `/api` and `/api/users` but not `/apis`
+            path = match path.strip_prefix(prefix.as_str()) {
+                Some(rest) if rest.is_empty() || rest.starts_with('/') => String::from(rest),
+                _ => return None
+            };
+        }
+        if let Some((regex, replacement)) = &self.regex {
+            if !regex.is_match(&path) {
+                return None;
+            }
+            path = regex.replace(&path, replacement.as_str()).into_owned();
+        }
+        if let Some(prefix) = &self.add_prefix {
+            path = format!("{}{}", prefix, path);
+        }
+        if !path.starts_with('/') {
+            path.insert(0, '/');
+        }
+        Some(path)
+    }
+}
+
+
+/// Rewrites the paths of forwarded requests, makes the proxy usable as a simple reverse proxy
+/// for backends rooted elsewhere than the paths clients use
+pub struct PathRewriter {
+    rules: Vec<Rule>,
+}
+
+impl PathRewriter {
+    /// Returns `None` when `path_rewrites` is empty
+    pub fn from_config(config: &[PathRewriteConfig]) -> Result<Option<PathRewriter>, String> {
+        if config.is_empty() {
+            return Ok(None);
+        }
+        let rules = config.iter().map(Rule::parse).collect::<Result<_, _>>()?;
+        Ok(Some(PathRewriter { rules }))
+    }
+
+    /// Rewrites the path of a request to `target` by the first rule matching it, the query is kept;
+    /// `None` when no rule matches
+    pub fn rewrite(&self, target: &Target, uri: &Uri) -> Option<Uri> {
+        let path = self.rules.iter()
+            .filter(|r| r.host == "*" || host_matches(&r.host, &target.host))
+            .find_map(|r| r.apply(uri.path()))?;
+        let path_and_query = match uri.query() {
+            Some(query) => format!("{}?{}", path, query),
+            None => path
+        };
+        let mut parts = uri.clone().into_parts();
+        parts.path_and_query = Some(path_and_query.parse().ok()?);
+        Uri::from_parts(parts).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rewriter(yaml: &str) -> Result<Option<PathRewriter>, String> {
+        PathRewriter::from_config(&serde_yaml::from_str::<Vec<PathRewriteConfig>>(yaml).unwrap())
+    }
+
+    fn refusal(yaml: &str) -> String {
+        match rewriter(yaml) {
+            Err(e) => e,
+            Ok(_) => panic!("{} is accepted", yaml)
+        }
+    }
+
+    fn rewritten(rewriter: &PathRewriter, uri: &str) -> Option<String> {
+        let uri: Uri = uri.parse().unwrap();
+        let target = Target::from_request_uri(&uri).unwrap().unwrap();
+        rewriter.rewrite(&target, &uri).map(|v| v.to_string())
+    }
+
+    #[test]
+    fn strips_prefix_and_keeps_query() {
+        let rewriter = rewriter("[{host: api.example.com, strip_prefix: /api/}]").unwrap().unwrap();
+
+        assert_eq!(rewritten(&rewriter, "http://api.example.com/api/users?id=1").as_deref(),
+                   Some("http://api.example.com/users?id=1"));
+        assert_eq!(rewritten(&rewriter, "http://api.example.com/api").as_deref(), Some("http://api.example.com/"));
+        assert_eq!(rewritten(&rewriter, "http://api.example.com/apis/users"), None);
+        assert_eq!(rewritten(&rewriter, "http://other.example.com/api/users"), None);
+    }
+
+    #[test]
+    fn replaces_by_regex() {
+        let rewriter = rewriter(r#"
+- host: "*.example.com"
+  regex: "^/v(?P<version>[0-9]+)/(.*)$"
+  replacement: "/api/${version}/$2"
+"#).unwrap().unwrap();
+
+        assert_eq!(rewritten(&rewriter, "http://api.example.com/v2/users?id=1").as_deref(),
+                   Some("http://api.example.com/api/2/users?id=1"));
+        assert_eq!(rewritten(&rewriter, "http://example.com/v2/users"), None);
+        assert_eq!(rewritten(&rewriter, "http://api.example.com/users"), None);
+    }
+
+    #[test]
+    fn strips_replaces_and_adds_in_order() {
+        let rewriter = rewriter(r#"
+- host: "*"
+  strip_prefix: /public
+  regex: "\\.html$"
+  replacement: ""
+  add_prefix: /static/
+"#).unwrap().unwrap();
+
+        assert_eq!(rewritten(&rewriter, "http://example.com/public/index.html?x=1").as_deref(),
+                   Some("http://example.com/static/index?x=1"));
+        assert_eq!(rewritten(&rewriter, "http://example.com/public/index.css"), None);
+    }
+
+    #[test]
+    fn first_matching_rule_applies() {
+        let rewriter = rewriter("\
+- {host: example.com, strip_prefix: /a}
+- {host: example.com, add_prefix: /b}
+- {host: example.com, add_prefix: /c}
+").unwrap().unwrap();
+
+        assert_eq!(rewritten(&rewriter, "http://example.com/a/x").as_deref(), Some("http://example.com/x"));
+        assert_eq!(rewritten(&rewriter, "http://example.com/x").as_deref(), Some("http://example.com/b/x"));
+    }
+
+    #[test]
+    fn refuses_rules_rewriting_nothing() {
+        assert!(rewriter("[]").unwrap().is_none());
+        assert!(refusal("[{host: example.com}]").contains("does not rewrite anything"));
+        assert!(refusal("[{host: example.com, regex: x}]").contains("needs both"));
+        assert!(refusal("[{host: example.com, regex: '(', replacement: x}]").contains("invalid path_rewrites regex"));
+    }
+}
@@ -0,0 +1,225 @@
+use std::time::Duration;
+use hyper::{Method, Uri};
+
+use crate::config::{Config, RouteConfig};
+use crate::target::{host_matches, strip_brackets};
+
+
+/// Name of the route of requests matching no configured one
+pub const DEFAULT_ROUTE: &str = "default";
+
+
+/// Checks the names and path prefixes of `routes`
+pub fn validate(routes: &[RouteConfig]) -> Result<(), String> {
+    for (i, route) in routes.iter().enumerate() {
+        if route.name == DEFAULT_ROUTE {
+            return Err(format!("route name {:?} is taken by requests matching no route", DEFAULT_ROUTE));
+        }
+        if routes[..i].iter().any(|r| r.name == route.name) {
+            return Err(format!("route name {:?} is used more than once", route.name));
+        }
+        if route.path_prefix.as_ref().is_some_and(|p| !p.starts_with('/')) {
+            return Err(format!("path_prefix of route {:?} must start with /", route.name));
+        }
+    }
+    Ok(())
+}
+
+
+/// Settings of one request or tunnel: those of the first route matching it, the global ones
+/// where the route has none
+#[derive(Debug, Clone)]
+pub struct Route {
+    /// Name of the route, `default` when none matched
+    pub name: String,
+    /// Whether the access log names the route, only when routes are configured
+    pub logged: bool,
+    /// Override of the request timeout, 0 disables it
+    request_timeout_ms: Option<u64>,
+    pub tunnel_read_timeout: Option<Duration>,
+    pub tunnel_write_timeout: Option<Duration>,
+    pub mirror_max_body_bytes: u64,
+    pub slow_request_threshold: Option<Duration>,
+    pub log_headers: bool,
+    pub log_headers_redact: Vec<String>,
+    /// Connect to all healthy backends of a pool and take the fastest
+    pub race_backends: bool,
+    pub allowed_methods: Vec<Method>,
+    pub denied_methods: Vec<Method>,
+}
+
+impl Route {
+    /// Resolves the route of a request by its uri, CONNECT requests by their authority
+    pub fn resolve(config: &Config, method: &Method, uri: &Uri) -> Route {
+        let host = uri.host().map(strip_brackets).unwrap_or("");
+        // the path of a CONNECT request is empty, it matches no path_prefix
+        let path = if method == Method::CONNECT { None } else { Some(uri.path()) };
+        let route = config.routes.iter().find(|r| matches(r, host, path));
+        let non_zero = |v: u64| if v == 0 { None } else { Some(v) };
+        Route {
+            name: String::from(route.map(|r| r.name.as_str()).unwrap_or(DEFAULT_ROUTE)),
+            logged: !config.routes.is_empty(),
+            request_timeout_ms: route.and_then(|r| r.request_timeout_ms),
+            tunnel_read_timeout: match route.and_then(|r| r.tunnel_read_timeout_secs) {
+                Some(v) => non_zero(v).map(Duration::from_secs),
+                None => config.tunnel_read_timeout()
+            },
+            tunnel_write_timeout: match route.and_then(|r| r.tunnel_write_timeout_secs) {
+                Some(v) => non_zero(v).map(Duration::from_secs),
+                None => config.tunnel_write_timeout()
+            },
+            mirror_max_body_bytes: route.and_then(|r| r.mirror_max_body_bytes).unwrap_or(config.mirror_max_body_bytes),
+            slow_request_threshold: match route.and_then(|r| r.slow_request_threshold_ms) {
+                Some(v) => non_zero(v).map(Duration::from_millis),
+                None => config.slow_request_threshold()
+            },
+            log_headers: route.and_then(|r| r.log_headers).unwrap_or(config.log_headers),
+            log_headers_redact: route.and_then(|r| r.log_headers_redact.clone())
+                .unwrap_or_else(|| config.log_headers_redact.clone()),
+            race_backends: route.map(|r| r.race_backends).unwrap_or(false),
+            allowed_methods: route.and_then(|r| r.allowed_methods.clone())
+                .unwrap_or_else(|| config.allowed_methods.clone()),
+            denied_methods: route.and_then(|r| r.denied_methods.clone())
+                .unwrap_or_else(|| config.denied_methods.clone()),
+        }
+    }
+
+    /// Whether `method` is in `allowed_methods` and not in `denied_methods`
+    pub fn allows(&self, method: &Method) -> bool {
+        self.allowed_methods.contains(method) && !self.denied_methods.contains(method)
+    }
+
+    /// Value of the `Allow` header of a 405 answer and of `OPTIONS *`
+    pub fn allow_header(&self) -> String {
+        self.allowed_methods.iter()
+            .filter(|m| !self.denied_methods.contains(m))
+            .map(|m| m.as_str())
+            .collect::<Vec<&str>>()
+            .join(", ")
+    }
+
+    /// Time to wait for the response headers of a request to `host`, the global timeout applies
+    /// when the route has none
+    pub fn request_timeout(&self, config: &Config, host: &str) -> Option<Duration> {
+        match self.request_timeout_ms {
+            Some(0) => None,
+            Some(ms) => Some(Duration::from_millis(ms)),
+            None => config.request_timeout(Some(host))
+        }
+    }
+}
+
+fn matches(route: &RouteConfig, host: &str, path: Option<&str>) -> bool {
+    let host_matches = route.host.as_ref().is_none_or(|p| p == "*" || host_matches(p, host));
+    let path_matches = match (&route.path_prefix, path) {
+        (None, _) => true,
+        (Some(_), None) => false,
+        // `/api` matches `/api` and `/api/users` but not `/apis`
+        (Some(prefix), Some(path)) => {
+            let prefix = prefix.trim_end_matches('/');
+            path.strip_prefix(prefix).is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+        }
+    };
+    host_matches && path_matches
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(yaml: &str) -> Config {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    const ROUTES: &str = "\
+request_timeout_ms: 30000
+tunnel_read_timeout_secs: 60
+routes:
+  - name: artifacts
+    host: artifacts.example.com
+    request_timeout_ms: 600000
+    tunnel_read_timeout_secs: 0
+  - name: api
+    host: \"*.example.com\"
+    path_prefix: /api/
+    log_headers: true
+  - name: everything
+    host: \"*\"
+    path_prefix: /api
+";
+
+    fn route(config: &Config, method: Method, uri: &str) -> Route {
+        Route::resolve(config, &method, &uri.parse().unwrap())
+    }
+
+    #[test]
+    fn first_matching_route_applies() {
+        let config = config(ROUTES);
+        let name = |method, uri| route(&config, method, uri).name;
+
+        assert_eq!(name(Method::GET, "http://artifacts.example.com/api/v1"), "artifacts");
+        assert_eq!(name(Method::GET, "http://www.example.com/api"), "api");
+        assert_eq!(name(Method::GET, "http://www.example.com/api/users"), "api");
+        assert_eq!(name(Method::GET, "http://www.example.com/apis"), DEFAULT_ROUTE);
+        assert_eq!(name(Method::GET, "http://example.org/api"), "everything");
+        // tunnels have no path
+        assert_eq!(name(Method::CONNECT, "www.example.com:443"), DEFAULT_ROUTE);
+        assert_eq!(name(Method::CONNECT, "artifacts.example.com:443"), "artifacts");
+    }
+
+    #[test]
+    fn route_settings_override_global_ones() {
+        let config = config(ROUTES);
+
+        let artifacts = route(&config, Method::GET, "http://artifacts.example.com/");
+        assert_eq!(artifacts.request_timeout(&config, "artifacts.example.com"), Some(Duration::from_secs(600)));
+        assert_eq!(artifacts.tunnel_read_timeout, None);
+        assert!(!artifacts.log_headers);
+        let api = route(&config, Method::GET, "http://www.example.com/api");
+        assert_eq!(api.request_timeout(&config, "www.example.com"), Some(Duration::from_secs(30)));
+        assert_eq!(api.tunnel_read_timeout, Some(Duration::from_secs(60)));
+        assert!(api.log_headers);
+        assert!(api.logged);
+        assert!(!route(&Config::default(), Method::GET, "http://www.example.com/").logged);
+    }
+
+    #[test]
+    fn methods_of_the_route_replace_global_ones() {
+        let config = config("\
+allowed_methods: [GET, POST, CONNECT, TRACE]
+routes:
+  - name: locked
+    host: locked.example.com
+    allowed_methods: [GET, CONNECT]
+  - name: debug
+    host: debug.example.com
+    denied_methods: []
+");
+
+        let default = route(&config, Method::GET, "http://www.example.com/");
+        assert!(default.allows(&Method::POST));
+        assert!(!default.allows(&Method::TRACE));
+        assert!(!default.allows(&Method::PUT));
+        assert_eq!(default.allow_header(), "GET, POST, CONNECT");
+        let locked = route(&config, Method::GET, "http://locked.example.com/");
+        assert!(!locked.allows(&Method::POST));
+        assert_eq!(locked.allow_header(), "GET, CONNECT");
+        let debug = route(&config, Method::GET, "http://debug.example.com/");
+        assert!(debug.allows(&Method::TRACE));
+        assert_eq!(debug.allow_header(), "GET, POST, CONNECT, TRACE");
+    }
+
+    #[test]
+    fn refuses_ambiguous_routes() {
+        let validated = |yaml: &str| validate(&config(yaml).routes).err();
+
+        assert_eq!(validated(ROUTES), None);
+        assert_eq!(validated("routes:\n  - name: default\n"),
+                   Some(String::from("route name \"default\" is taken by requests matching no route")));
+        assert_eq!(validated("routes:\n  - name: a\n  - name: a\n"),
+                   Some(String::from("route name \"a\" is used more than once")));
+        assert_eq!(validated("routes:\n  - name: a\n    path_prefix: api\n"),
+                   Some(String::from("path_prefix of route \"a\" must start with /")));
+    }
+}
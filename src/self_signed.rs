@@ -0,0 +1,286 @@
+use std::fs::{DirBuilder, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::net::IpAddr;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use chrono::Datelike;
+use log::{error, info, warn};
+use rand::Rng;
+use ring::digest::{digest, SHA256};
+use ring::rand::SystemRandom;
+use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_ASN1_SIGNING};
+
+
+/// Days a generated certificate is valid
+const VALIDITY_DAYS: i64 = 365;
+/// A certificate with fewer days left is generated again
+pub const RENEW_DAYS: i64 = 30;
+
+/// ecdsa-with-SHA256, 1.2.840.10045.4.3.2
+const OID_ECDSA_SHA256: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02];
+/// id-ecPublicKey, 1.2.840.10045.2.1
+const OID_EC_PUBLIC_KEY: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+/// prime256v1, 1.2.840.10045.3.1.7
+const OID_P256: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+/// commonName, 2.5.4.3
+const OID_COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+/// subjectAltName, 2.5.29.17
+const OID_SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1d, 0x11];
+/// extKeyUsage, 2.5.29.37
+const OID_EXT_KEY_USAGE: &[u8] = &[0x55, 0x1d, 0x25];
+/// id-kp-serverAuth, 1.3.6.1.5.5.7.3.1
+const OID_SERVER_AUTH: &[u8] = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x03, 0x01];
+
+
+/// EC P-256 certificate of the proxy listener signed by its own key, for `tls_auto_self_signed`.
+///
+/// Certificate and key are written to a directory only the proxy user can read, the listener loads
+/// them like configured files and picks up a renewed pair the same way. The directory is named after
+/// the user, hostname and address, so a restart reuses the pair instead of leaving another key behind.
+pub struct SelfSigned {
+    pub cert_pem: String,
+    pub key_pem: String,
+    hostname: String,
+    ip: Option<IpAddr>,
+    /// `notAfter` of the current certificate in seconds since the epoch
+    not_after: Mutex<i64>,
+}
+
+impl SelfSigned {
+    /// Generates a certificate for `hostname`, with `ip` as an additional name when the listener is
+    /// bound to a specific address
+    pub fn generate(hostname: &str, ip: Option<IpAddr>) -> Result<SelfSigned, String> {
+        if hostname.is_empty() {
+            return Err(String::from("tls_self_signed_hostname must not be empty"));
+        }
+        let ip = ip.filter(|ip| !ip.is_unspecified());
+        let dir = std::env::temp_dir().join(dir_name(hostname, ip));
+        match DirBuilder::new().mode(0o700).create(&dir) {
+            Ok(()) => {},
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => check_private(&dir)?,
+            Err(e) => return Err(format!("can not create directory {:?} for the self-signed certificate; err = {}",
+                                         dir, e))
+        }
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        let cert = SelfSigned {
+            cert_pem: path("cert.pem"),
+            key_pem: path("key.pem"),
+            hostname: String::from(hostname),
+            ip,
+            not_after: Mutex::new(0),
+        };
+        match crate::tls::valid_until(&cert.cert_pem, &cert.key_pem) {
+            Ok(Some(not_after)) if not_after - now() >= RENEW_DAYS * 24 * 60 * 60 => {
+                info!("reusing the self-signed certificate {:?}, it expires in {} days", cert.cert_pem,
+                      (not_after - now()) / (24 * 60 * 60));
+                *cert.not_after.lock().unwrap() = not_after;
+            },
+            _ => cert.write()?
+        }
+        Ok(cert)
+    }
+
+    /// Generates a new certificate when the current one has fewer than `RENEW_DAYS` left
+    pub fn refresh(&self) {
+        let left = *self.not_after.lock().unwrap() - now();
+        if left >= RENEW_DAYS * 24 * 60 * 60 {
+            return;
+        }
+        match self.write() {
+            Ok(()) => info!("generated a new self-signed certificate {:?}, the previous one expires in {} days",
+                            self.cert_pem, left / (24 * 60 * 60)),
+            Err(e) => error!("can not renew the self-signed certificate {:?}; {}", self.cert_pem, e)
+        }
+    }
+
+    /// Writes a new key and certificate, the files are replaced by renaming so the listener never
+    /// reads a pair half written
+    fn write(&self) -> Result<(), String> {
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng)
+            .map_err(|_| String::from("can not generate a key"))?;
+        let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8.as_ref(), &rng)
+            .map_err(|_| String::from("can not load the generated key"))?;
+        let not_before = now() - 60 * 60;
+        let not_after = now() + VALIDITY_DAYS * 24 * 60 * 60;
+        let tbs = self.tbs_certificate(key.public_key().as_ref(), not_before, not_after)?;
+        let signature = key.sign(&rng, &tbs).map_err(|_| String::from("can not sign the certificate"))?;
+        let cert = sequence(&[tbs, sequence(&[oid(OID_ECDSA_SHA256)]), bit_string(signature.as_ref())]);
+        replace(&self.key_pem, &pem("PRIVATE KEY", pkcs8.as_ref()))?;
+        replace(&self.cert_pem, &pem("CERTIFICATE", &cert))?;
+        *self.not_after.lock().unwrap() = not_after;
+        Ok(())
+    }
+
+    fn tbs_certificate(&self, public_key: &[u8], not_before: i64, not_after: i64) -> Result<Vec<u8>, String> {
+        // a random positive serial, browsers refuse a certificate whose issuer and serial they saw before
+        let mut serial = rand::thread_rng().gen::<[u8; 16]>();
+        serial[0] = (serial[0] & 0x7f) | 0x40;
+        let name = sequence(&[der(0x31, &sequence(&[oid(OID_COMMON_NAME), der(0x0c, self.hostname.as_bytes())]))]);
+        let mut names = Vec::new();
+        match self.hostname.parse::<IpAddr>() {
+            Ok(ip) => names.push(ip_name(&ip)),
+            Err(_) => names.push(der(0x82, self.hostname.as_bytes()))
+        }
+        if let Some(ip) = self.ip.filter(|ip| self.hostname.parse() != Ok(*ip)) {
+            names.push(ip_name(&ip));
+        }
+        let extensions = [
+            sequence(&[oid(OID_SUBJECT_ALT_NAME), der(0x04, &sequence(&names))]),
+            sequence(&[oid(OID_EXT_KEY_USAGE), der(0x04, &sequence(&[oid(OID_SERVER_AUTH)]))]),
+        ];
+        Ok(sequence(&[
+            // version 3
+            der(0xa0, &der(0x02, &[2])),
+            der(0x02, &serial),
+            sequence(&[oid(OID_ECDSA_SHA256)]),
+            name.clone(),
+            sequence(&[time(not_before)?, time(not_after)?]),
+            name,
+            sequence(&[sequence(&[oid(OID_EC_PUBLIC_KEY), oid(OID_P256)]), bit_string(public_key)]),
+            der(0xa3, &sequence(&extensions)),
+        ]))
+    }
+}
+
+/// Logs that clients will not trust the certificate, at startup and so on every restart
+pub fn warn_untrusted(cert: &SelfSigned) {
+    warn!("*****************************************************************************");
+    warn!("tls_auto_self_signed: the proxy listener uses a self-signed certificate for {:?}", cert.hostname);
+    warn!("browsers and other clients do not trust it, configure tls.cert_pem and tls.key_pem");
+    warn!("for production; certificate {:?}", cert.cert_pem);
+    warn!("*****************************************************************************");
+}
+
+/// Directory of the pair of the user for `hostname` and `ip`, the same across restarts
+fn dir_name(hostname: &str, ip: Option<IpAddr>) -> String {
+    let names = format!("{}/{}", hostname, ip.map(|v| v.to_string()).unwrap_or_default());
+    let hash: String = digest(&SHA256, names.as_bytes()).as_ref()[..8].iter().map(|b| format!("{:02x}", b)).collect();
+    // SAFETY: geteuid has no preconditions and can not fail
+    format!("mirror-proxy-{}-{}", unsafe { libc::geteuid() }, hash)
+}
+
+/// Refuses an existing directory another user could have placed or can read, e.g. in a shared /tmp
+fn check_private(dir: &Path) -> Result<(), String> {
+    let meta = std::fs::symlink_metadata(dir)
+        .map_err(|e| format!("can not read directory {:?} of the self-signed certificate; err = {}", dir, e))?;
+    // SAFETY: geteuid has no preconditions and can not fail
+    if !meta.is_dir() || meta.uid() != unsafe { libc::geteuid() } || meta.mode() & 0o077 != 0 {
+        return Err(format!("{:?} for the self-signed certificate is not a directory only the proxy user can access",
+                           dir));
+    }
+    Ok(())
+}
+
+fn now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0)
+}
+
+/// Writes a file readable by the proxy user only and renames it over `path`
+fn replace(path: &str, content: &str) -> Result<(), String> {
+    let tmp = PathBuf::from(format!("{}.tmp", path));
+    let mut file = OpenOptions::new().create(true).write(true).truncate(true).mode(0o600).open(&tmp)
+        .map_err(|e| format!("can not write {:?}; err = {}", tmp, e))?;
+    file.write_all(content.as_bytes()).map_err(|e| format!("can not write {:?}; err = {}", tmp, e))?;
+    std::fs::rename(&tmp, path).map_err(|e| format!("can not write {:?}; err = {}", path, e))
+}
+
+fn pem(label: &str, der: &[u8]) -> String {
+    let encoded = STANDARD.encode(der);
+    let mut pem = format!("-----BEGIN {}-----\n", label);
+    for line in encoded.as_bytes().chunks(64) {
+        pem.push_str(std::str::from_utf8(line).unwrap_or_default());
+        pem.push('\n');
+    }
+    pem.push_str(&format!("-----END {}-----\n", label));
+    pem
+}
+
+/// DER item of `tag` with its length
+fn der(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut item = vec![tag];
+    let len = content.len();
+    if len < 0x80 {
+        item.push(len as u8);
+    } else {
+        let bytes: Vec<u8> = len.to_be_bytes().iter().copied().skip_while(|b| *b == 0).collect();
+        item.push(0x80 | bytes.len() as u8);
+        item.extend(bytes);
+    }
+    item.extend_from_slice(content);
+    item
+}
+
+fn sequence(items: &[Vec<u8>]) -> Vec<u8> {
+    der(0x30, &items.concat())
+}
+
+fn oid(encoded: &[u8]) -> Vec<u8> {
+    der(0x06, encoded)
+}
+
+/// Bit string without unused bits
+fn bit_string(content: &[u8]) -> Vec<u8> {
+    der(0x03, &[&[0][..], content].concat())
+}
+
+fn ip_name(ip: &IpAddr) -> Vec<u8> {
+    match ip {
+        IpAddr::V4(ip) => der(0x87, &ip.octets()),
+        IpAddr::V6(ip) => der(0x87, &ip.octets()),
+    }
+}
+
+/// UTCTime until 2049, GeneralizedTime after
+fn time(seconds: i64) -> Result<Vec<u8>, String> {
+    let time = chrono::NaiveDateTime::from_timestamp_opt(seconds, 0).ok_or_else(|| String::from("invalid validity"))?;
+    Ok(match time.year() {
+        year if year < 2050 => der(0x17, time.format("%y%m%d%H%M%SZ").to_string().as_bytes()),
+        _ => der(0x18, time.format("%Y%m%d%H%M%SZ").to_string().as_bytes()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+    use std::time::Duration;
+    use rustls::pki_types::{ServerName, UnixTime};
+
+    #[test]
+    fn generated_certificate_is_valid_and_reused() {
+        let hostname = format!("self-signed-{:x}.test", rand::thread_rng().gen::<u32>());
+        let ip: IpAddr = "192.0.2.7".parse().unwrap();
+        let cert = SelfSigned::generate(&hostname, Some(ip)).unwrap();
+        let dir = Path::new(&cert.cert_pem).parent().unwrap().to_path_buf();
+
+        let der = crate::tls::load_certs(&cert.cert_pem).unwrap().remove(0);
+        let parsed = webpki::EndEntityCert::try_from(&der).unwrap();
+        parsed.verify_is_valid_for_subject_name(&ServerName::try_from(hostname.as_str()).unwrap()).unwrap();
+        parsed.verify_is_valid_for_subject_name(&ServerName::from(ip)).unwrap();
+        assert!(parsed.verify_is_valid_for_subject_name(&ServerName::try_from("other.test").unwrap()).is_err());
+
+        // the certificate is its own issuer, which checks its signature, validity and extended key usage
+        let anchors = [webpki::anchor_from_trusted_cert(&der).unwrap()];
+        let algorithms = crate::tls::provider().signature_verification_algorithms.all;
+        let verify = |at: SystemTime| {
+            let at = UnixTime::since_unix_epoch(at.duration_since(UNIX_EPOCH).unwrap());
+            let usage = webpki::KeyUsage::required(OID_SERVER_AUTH);
+            parsed.verify_for_usage(algorithms, &anchors, &[], at, usage, None, None).map(|_| ())
+        };
+        let day = Duration::from_secs(24 * 60 * 60);
+        assert_eq!(verify(SystemTime::now()), Ok(()));
+        assert!(verify(SystemTime::now() + 366 * day).is_err());
+        assert!(verify(SystemTime::now() - 2 * Duration::from_secs(60 * 60)).is_err());
+
+        let again = SelfSigned::generate(&hostname, Some(ip)).unwrap();
+        assert_eq!(again.cert_pem, cert.cert_pem);
+        assert_eq!(crate::tls::load_certs(&again.cert_pem).unwrap()[0], der);
+        assert_eq!(std::fs::metadata(&dir).unwrap().mode() & 0o777, 0o700);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,144 @@
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::future::Future;
+use std::time::Duration;
+use std::task::{Context, Poll};
+use log::warn;
+use tokio::time::Sleep;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use hyper::server::conn::AddrStream;
+use tokio_rustls::server::TlsStream;
+
+use crate::metrics::Metrics;
+use crate::privacy::Peer;
+
+
+/// Connection of a client which is aborted once it accepts no bytes for `write_timeout`.
+///
+/// Only the write side is timed, so a tunnel with a fast uploading client is still aborted
+/// when the client stops reading the download side.
+pub struct ClientStream {
+    inner: AddrStream,
+    peer: Peer,
+    write_timeout: Option<Duration>,
+    /// Running since the first write which made no progress
+    stalled: Option<Pin<Box<Sleep>>>,
+    metrics: Arc<Metrics>,
+}
+
+impl ClientStream {
+    pub fn new(inner: AddrStream, peer: Peer, write_timeout: Option<Duration>, metrics: Arc<Metrics>) -> ClientStream {
+        ClientStream { inner, peer, write_timeout, stalled: None, metrics }
+    }
+
+    /// Tracks progress of a write, turning a stall longer than `write_timeout` into an error
+    fn progress<T>(&mut self, cx: &mut Context<'_>, poll: Poll<io::Result<T>>) -> Poll<io::Result<T>> {
+        let timeout = match (&poll, self.write_timeout) {
+            (Poll::Pending, Some(v)) => v,
+            _ => {
+                self.stalled = None;
+                return poll;
+            }
+        };
+        let stalled = self.stalled.get_or_insert_with(|| Box::pin(tokio::time::sleep(timeout)));
+        if stalled.as_mut().poll(cx).is_pending() {
+            return Poll::Pending;
+        }
+        warn!("client {:?}: no bytes accepted in {:?}, aborting connection; reason=slow_client", self.peer, timeout);
+        self.metrics.inc("slow_clients_total", &[("reason", "write_timeout")]);
+        Poll::Ready(Err(io::Error::new(io::ErrorKind::TimedOut, "client accepts no bytes")))
+    }
+}
+
+impl AsyncRead for ClientStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for ClientStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        self.progress(cx, poll)
+    }
+
+    fn poll_write_vectored(mut self: Pin<&mut Self>, cx: &mut Context<'_>, bufs: &[io::IoSlice<'_>])
+        -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write_vectored(cx, bufs);
+        self.progress(cx, poll)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let poll = Pin::new(&mut self.inner).poll_flush(cx);
+        self.progress(cx, poll)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Client connection as served by hyper, TLS runs on top of `ClientStream` so a client which
+/// stops reading is still detected
+pub enum ListenerStream {
+    Plain(ClientStream),
+    Tls(Box<TlsStream<ClientStream>>),
+}
+
+impl AsyncRead for ListenerStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ListenerStream::Plain(v) => Pin::new(v).poll_read(cx, buf),
+            ListenerStream::Tls(v) => Pin::new(v).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for ListenerStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            ListenerStream::Plain(v) => Pin::new(v).poll_write(cx, buf),
+            ListenerStream::Tls(v) => Pin::new(v).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_write_vectored(self: Pin<&mut Self>, cx: &mut Context<'_>, bufs: &[io::IoSlice<'_>])
+        -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            ListenerStream::Plain(v) => Pin::new(v).poll_write_vectored(cx, bufs),
+            ListenerStream::Tls(v) => Pin::new(v).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match self {
+            ListenerStream::Plain(v) => v.is_write_vectored(),
+            ListenerStream::Tls(v) => v.is_write_vectored(),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ListenerStream::Plain(v) => Pin::new(v).poll_flush(cx),
+            ListenerStream::Tls(v) => Pin::new(v).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ListenerStream::Plain(v) => Pin::new(v).poll_shutdown(cx),
+            ListenerStream::Tls(v) => Pin::new(v).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Tells whether a connection failed because the client did not send request headers in time,
+/// hyper exposes this only through the error message
+pub fn is_header_timeout(err: &hyper::Error) -> bool {
+    err.to_string().contains("read header from client timeout")
+}
@@ -0,0 +1,70 @@
+use std::sync::atomic::{AtomicU8, Ordering};
+use rand::Rng;
+use serde::Serialize;
+use hyper::Uri;
+
+use crate::config::SplitConfig;
+use crate::target::Target;
+
+
+/// Backend a request was routed to by `Split`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    A,
+    B,
+}
+
+/// A/B traffic splitting, a share of the requests to `backend_a` is routed to `backend_b` instead
+pub struct Split {
+    backend_a: Target,
+    backend_b: Target,
+    /// Percentage of requests routed to `backend_b`, adjustable with the admin API
+    percent_b: AtomicU8,
+}
+
+/// Current splitting as served by `/admin/split`
+#[derive(Debug, Serialize)]
+pub struct SplitInfo {
+    pub backend_a: String,
+    pub backend_b: String,
+    pub percent_b: u8,
+}
+
+impl Split {
+    pub fn from_config(config: &SplitConfig) -> Result<Split, String> {
+        let backend = |v: &str| {
+            v.parse::<Uri>().ok()
+                .and_then(|uri| Target::from_uri(&uri, 443).ok())
+                .ok_or_else(|| format!("invalid split_traffic backend {:?} (must be host:port)", v))
+        };
+        Ok(Split {
+            backend_a: backend(&config.backend_a)?,
+            backend_b: backend(&config.backend_b)?,
+            percent_b: AtomicU8::new(config.percent_b.min(100)),
+        })
+    }
+
+    /// Picks the backend for a request to `target`, `None` when the target is not `backend_a`
+    pub fn route(&self, target: &Target) -> Option<(Backend, &Target)> {
+        if *target != self.backend_a {
+            return None;
+        }
+        if rand::thread_rng().gen_range(0..100) < self.percent_b.load(Ordering::Relaxed) {
+            Some((Backend::B, &self.backend_b))
+        } else {
+            Some((Backend::A, &self.backend_a))
+        }
+    }
+
+    pub fn set_percent_b(&self, percent_b: u8) {
+        self.percent_b.store(percent_b.min(100), Ordering::Relaxed);
+    }
+
+    pub fn info(&self) -> SplitInfo {
+        SplitInfo {
+            backend_a: self.backend_a.to_string(),
+            backend_b: self.backend_b.to_string(),
+            percent_b: self.percent_b.load(Ordering::Relaxed),
+        }
+    }
+}
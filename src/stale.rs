@@ -0,0 +1,166 @@
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use futures_util::stream::Stream;
+use hyper::body::Bytes;
+use hyper::header::{HeaderMap, HeaderValue, ACCEPT_ENCODING, AGE, AUTHORIZATION, CACHE_CONTROL, CONTENT_LENGTH,
+                    SET_COOKIE, TRANSFER_ENCODING, VARY, WARNING};
+use hyper::{Body, Method, Request, Response, StatusCode};
+
+use crate::config::ServeStaleConfig;
+
+
+/// Copies of the last complete `200 OK` responses to `GET` requests, served instead of the error
+/// when the upstream fails afterwards (stale-if-error).
+///
+/// Only responses every client may see are kept: none to requests with `Authorization`, none
+/// setting cookies, marked `private` or `no-store` or varying by other headers than `Accept-Encoding`.
+pub struct StaleResponses {
+    max_stale: Duration,
+    max_entries: usize,
+    max_body_bytes: usize,
+    entries: Mutex<HashMap<String, Arc<Entry>>>,
+}
+
+struct Entry {
+    headers: HeaderMap,
+    body: Bytes,
+    stored: Instant,
+}
+
+impl StaleResponses {
+    /// Returns `None` when `serve_stale_on_error` is not configured
+    pub fn from_config(config: &Option<ServeStaleConfig>) -> Option<Arc<StaleResponses>> {
+        let config = config.as_ref()?;
+        Some(Arc::new(StaleResponses {
+            max_stale: Duration::from_secs(config.max_stale_secs),
+            max_entries: config.max_entries,
+            max_body_bytes: config.max_body_bytes,
+            entries: Mutex::new(HashMap::new()),
+        }))
+    }
+
+    /// Key of the responses to `req`, `None` when they are not kept; the encoding asked for is
+    /// part of it since the body depends on it
+    pub fn key(req: &Request<Body>) -> Option<String> {
+        if req.method() != Method::GET || req.headers().contains_key(AUTHORIZATION) {
+            return None;
+        }
+        let encoding = req.headers().get(ACCEPT_ENCODING).and_then(|v| v.to_str().ok()).unwrap_or("");
+        Some(format!("{} {}", req.uri(), encoding))
+    }
+
+    /// Keeps a copy of `resp` once its body is passed on completely, when it may be served later
+    pub fn record(self: &Arc<Self>, key: String, resp: Response<Body>) -> Response<Body> {
+        if !self.keeps(&resp) {
+            return resp;
+        }
+        let (parts, body) = resp.into_parts();
+        let length = parts.headers.get(CONTENT_LENGTH).and_then(|v| v.to_str().ok()).and_then(|v| v.parse().ok());
+        let recording = Recording {
+            body,
+            chunks: Some(Vec::new()),
+            bytes: 0,
+            length,
+            key,
+            headers: parts.headers.clone(),
+            responses: self.clone(),
+        };
+        Response::from_parts(parts, Body::wrap_stream(recording))
+    }
+
+    fn keeps(&self, resp: &Response<Body>) -> bool {
+        let headers = resp.headers();
+        let cache_control = headers.get_all(CACHE_CONTROL).iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .any(|d| matches!(d.trim().to_ascii_lowercase().as_str(), "no-store" | "private"));
+        let varies = headers.get_all(VARY).iter()
+            .map(|v| v.to_str().unwrap_or("*"))
+            .flat_map(|v| v.split(','))
+            .any(|h| !h.trim().eq_ignore_ascii_case("accept-encoding"));
+        let length = headers.get(CONTENT_LENGTH).and_then(|v| v.to_str().ok()).and_then(|v| v.parse::<usize>().ok());
+        resp.status() == StatusCode::OK && !cache_control && !varies && !headers.contains_key(SET_COOKIE)
+            && length.map(|l| l <= self.max_body_bytes).unwrap_or(true)
+    }
+
+    fn store(&self, key: String, headers: HeaderMap, body: Bytes) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, e| e.stored.elapsed() <= self.max_stale);
+        if entries.len() >= self.max_entries && !entries.contains_key(&key) {
+            // the copy stored first is the stalest one
+            let oldest = entries.iter().min_by_key(|(_, e)| e.stored).map(|(k, _)| k.clone());
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+        entries.insert(key, Arc::new(Entry { headers, body, stored: Instant::now() }));
+    }
+
+    /// The copy kept for `key` with `Age` and `Warning: 110`, unless it is older than `max_stale_secs`
+    pub fn serve(&self, key: &str) -> Option<Response<Body>> {
+        let entry = self.entries.lock().unwrap().get(key).cloned()?;
+        let age = entry.stored.elapsed();
+        if age > self.max_stale {
+            return None;
+        }
+        let mut resp = Response::new(Body::from(entry.body.clone()));
+        *resp.headers_mut() = entry.headers.clone();
+        let headers = resp.headers_mut();
+        headers.remove(TRANSFER_ENCODING);
+        headers.insert(CONTENT_LENGTH, HeaderValue::from(entry.body.len()));
+        headers.insert(AGE, HeaderValue::from(age.as_secs()));
+        headers.append(WARNING, HeaderValue::from_static("110 - \"Response is Stale\""));
+        Some(resp)
+    }
+}
+
+/// Body passed on to the client while it is copied, the copy is stored once the body ended
+struct Recording {
+    body: Body,
+    /// `None` once the body exceeded `max_body_bytes` or failed
+    chunks: Option<Vec<Bytes>>,
+    bytes: usize,
+    /// `Content-Length`, hyper stops polling a body once it is sent
+    length: Option<usize>,
+    key: String,
+    headers: HeaderMap,
+    responses: Arc<StaleResponses>,
+}
+
+impl Stream for Recording {
+    type Item = Result<Bytes, hyper::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let poll = Pin::new(&mut self.body).poll_next(cx);
+        match &poll {
+            Poll::Ready(Some(Ok(chunk))) => {
+                self.bytes += chunk.len();
+                if self.bytes > self.responses.max_body_bytes {
+                    self.chunks = None;
+                } else if let Some(chunks) = self.chunks.as_mut() {
+                    chunks.push(chunk.clone());
+                }
+                if Some(self.bytes) == self.length {
+                    self.finish();
+                }
+            },
+            Poll::Ready(Some(Err(_))) => self.chunks = None,
+            Poll::Ready(None) if self.length.is_none() => self.finish(),
+            _ => {}
+        }
+        poll
+    }
+}
+
+impl Recording {
+    fn finish(&mut self) {
+        if let Some(chunks) = self.chunks.take() {
+            let body = Bytes::from(chunks.concat());
+            let (key, headers) = (std::mem::take(&mut self.key), std::mem::take(&mut self.headers));
+            self.responses.store(key, headers, body);
+        }
+    }
+}
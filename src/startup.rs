@@ -0,0 +1,189 @@
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::process::exit;
+use log::error;
+
+
+/// Exit codes of the server, values follow `sysexits.h`
+pub const EXIT_USAGE: i32 = 64;
+pub const EXIT_NOINPUT: i32 = 66;
+pub const EXIT_NOHOST: i32 = 68;
+pub const EXIT_SOFTWARE: i32 = 70;
+pub const EXIT_OSERR: i32 = 71;
+pub const EXIT_TEMPFAIL: i32 = 75;
+pub const EXIT_PROTOCOL: i32 = 76;
+pub const EXIT_NOPERM: i32 = 77;
+pub const EXIT_CONFIG: i32 = 78;
+
+/// Help text listing exit codes, shown by `--help`
+pub const EXIT_CODES_HELP: &str = "EXIT CODES:
+    0     success
+    64    invalid command line arguments
+    66    config file can not be opened
+    68    server address can not be resolved
+    70    runtime crash of the server
+    71    socket activation passed an invalid listening socket
+    75    can not bind server address (address is already in use)
+    76    invalid TLS certificate or key
+    77    not enough privileges to bind server address
+    78    invalid config";
+
+
+/// Failure which stops the server, every variant is mapped to its own exit code
+#[derive(Debug)]
+pub enum StartupError {
+    /// Command line can not be parsed, holds the message of the argument parser
+    Usage(String),
+    NoInput(String, io::Error),
+    Config(String),
+    Resolve(String),
+    Bind(SocketAddr, io::Error),
+    Privilege(SocketAddr, io::Error),
+    Tls(String),
+    /// A socket passed by systemd can not be listened on
+    Activation(String),
+    Crash(io::Error),
+}
+
+impl StartupError {
+    /// Classifies an error returned by binding the server address
+    pub fn from_bind(addr: SocketAddr, err: hyper::Error) -> StartupError {
+        let err = match std::error::Error::source(&err).and_then(|e| e.downcast_ref::<io::Error>()) {
+            Some(e) => io::Error::new(e.kind(), e.to_string()),
+            None => io::Error::other(err.to_string())
+        };
+        StartupError::from_io_bind(addr, err)
+    }
+
+    pub fn from_io_bind(addr: SocketAddr, err: io::Error) -> StartupError {
+        match err.kind() {
+            io::ErrorKind::PermissionDenied => StartupError::Privilege(addr, err),
+            _ => StartupError::Bind(addr, err)
+        }
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            StartupError::Usage(_) => EXIT_USAGE,
+            StartupError::NoInput(_, _) => EXIT_NOINPUT,
+            StartupError::Config(_) => EXIT_CONFIG,
+            StartupError::Resolve(_) => EXIT_NOHOST,
+            StartupError::Bind(_, _) => EXIT_TEMPFAIL,
+            StartupError::Privilege(_, _) => EXIT_NOPERM,
+            StartupError::Tls(_) => EXIT_PROTOCOL,
+            StartupError::Activation(_) => EXIT_OSERR,
+            StartupError::Crash(_) => EXIT_SOFTWARE,
+        }
+    }
+}
+
+impl fmt::Display for StartupError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StartupError::Usage(e) => write!(f, "{}", e),
+            StartupError::NoInput(path, e) => write!(f, "can not open config file {:?}; err = {}", path, e),
+            StartupError::Config(e) => write!(f, "{}", e),
+            StartupError::Resolve(e) => write!(f, "{}", e),
+            StartupError::Bind(addr, e) => write!(f, "can not bind server address {}; err = {}", addr, e),
+            StartupError::Privilege(addr, e) => {
+                write!(f, "not enough privileges to bind server address {}; err = {}", addr, e)
+            },
+            StartupError::Tls(e) => write!(f, "TLS error; {}", e),
+            StartupError::Activation(e) => write!(f, "{}", e),
+            StartupError::Crash(e) => write!(f, "server crashed; err = {:?}", e),
+        }
+    }
+}
+
+/// Tells the likely cause of a failure to bind `addr` and how to fix it
+pub fn diagnose_bind_error(addr: &SocketAddr, err: &io::Error) -> String {
+    let port = addr.port();
+    match err.kind() {
+        io::ErrorKind::AddrInUse => format!(
+            "another process listens on port {port}, find it with `ss -tlnp 'sport = :{port}'` or \
+             `netstat -tlnp | grep :{port}` and stop it, or configure another port", port = port),
+        io::ErrorKind::PermissionDenied if port < 1024 => format!(
+            "ports below 1024 need privileges, run as root, allow the binary to bind them with \
+             `sudo setcap cap_net_bind_service=+ep {}` or configure a port from 1024",
+            std::env::current_exe().map(|p| p.display().to_string()).unwrap_or_else(|_| String::from("<binary>"))),
+        io::ErrorKind::PermissionDenied => String::from(
+            "binding is not permitted, check security policies like SELinux or AppArmor of this host"),
+        io::ErrorKind::AddrNotAvailable => format!(
+            "{} is not an address of this host, list them with `ip addr` or listen on 0.0.0.0 (all interfaces)",
+            addr.ip()),
+        _ => format!("check that {} is an address of this host and the port is free", addr)
+    }
+}
+
+/// Reports the reason the server stops with and exits with the code of the error
+pub fn exit_with(err: StartupError) -> ! {
+    let code = err.exit_code();
+    match &err {
+        StartupError::Bind(addr, e) | StartupError::Privilege(addr, e) => {
+            error!("{}", diagnose_bind_error(addr, e));
+        },
+        StartupError::Resolve(_) => {
+            error!("the server address must be an IP address or a host name DNS resolves, check it with \
+                    `getent hosts <name>` and the DNS servers of /etc/resolv.conf");
+        },
+        _ => {}
+    }
+    match &err {
+        // the argument parser renders its own usage message
+        StartupError::Usage(e) => eprintln!("{}", e),
+        e => error!("{}; exit code {}", e, code),
+    }
+    exit(code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn every_failure_has_its_exit_code() {
+        let err = || io::Error::other("err");
+        let cases = [
+            (StartupError::Usage(String::new()), EXIT_USAGE),
+            (StartupError::NoInput(String::from("config.yaml"), err()), EXIT_NOINPUT),
+            (StartupError::Config(String::new()), EXIT_CONFIG),
+            (StartupError::Resolve(String::new()), EXIT_NOHOST),
+            (StartupError::Bind(addr(8080), err()), EXIT_TEMPFAIL),
+            (StartupError::Privilege(addr(80), err()), EXIT_NOPERM),
+            (StartupError::Tls(String::new()), EXIT_PROTOCOL),
+            (StartupError::Activation(String::new()), EXIT_OSERR),
+            (StartupError::Crash(err()), EXIT_SOFTWARE),
+        ];
+        for (err, code) in cases {
+            assert_eq!(err.exit_code(), code, "{:?}", err);
+            assert!(EXIT_CODES_HELP.contains(&format!("    {}    ", code)), "{} is not documented", code);
+        }
+    }
+
+    #[test]
+    fn bind_errors_are_told_apart() {
+        let denied = StartupError::from_io_bind(addr(80), io::Error::from(io::ErrorKind::PermissionDenied));
+        let in_use = StartupError::from_io_bind(addr(8080), io::Error::from(io::ErrorKind::AddrInUse));
+        let other = StartupError::from_io_bind(addr(8080), io::Error::from(io::ErrorKind::AddrNotAvailable));
+
+        assert!(matches!(denied, StartupError::Privilege(_, _)), "{:?}", denied);
+        assert!(matches!(in_use, StartupError::Bind(_, _)), "{:?}", in_use);
+        assert!(matches!(other, StartupError::Bind(_, _)), "{:?}", other);
+        assert!(denied.to_string().starts_with("not enough privileges to bind server address 127.0.0.1:80"));
+    }
+
+    #[test]
+    fn bind_errors_are_diagnosed() {
+        let diagnose = |port, kind| diagnose_bind_error(&addr(port), &io::Error::from(kind));
+
+        assert!(diagnose(8080, io::ErrorKind::AddrInUse).contains("ss -tlnp 'sport = :8080'"));
+        assert!(diagnose(80, io::ErrorKind::PermissionDenied).contains("cap_net_bind_service"));
+        assert!(diagnose(8080, io::ErrorKind::PermissionDenied).contains("SELinux"));
+        assert!(diagnose(8080, io::ErrorKind::AddrNotAvailable).contains("127.0.0.1 is not an address of this host"));
+    }
+}
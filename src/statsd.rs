@@ -0,0 +1,128 @@
+use std::sync::Arc;
+use std::time::Duration;
+use std::collections::BTreeMap;
+use std::net::{SocketAddr, ToSocketAddrs};
+use log::{debug, info, warn};
+use tokio::net::UdpSocket;
+
+use crate::config::{Config, StatsdFormat};
+use crate::metrics::{self, Kind, Labels, Metrics, Value};
+
+
+/// Datagrams stay below the MTU of common networks, so they are not fragmented
+const MAX_DATAGRAM_BYTES: usize = 1432;
+
+
+/// Pushes the metrics of the registry to a StatsD server, the same ones `/metrics` serves
+pub struct Statsd {
+    addr: SocketAddr,
+    format: StatsdFormat,
+    prefix: String,
+    interval: Duration,
+    metrics: Arc<Metrics>,
+}
+
+impl Statsd {
+    /// Returns `None` when `statsd` is not configured
+    pub fn from_config(config: &Config, metrics: Arc<Metrics>) -> Result<Option<Statsd>, String> {
+        let address = match &config.statsd {
+            Some(v) => v,
+            None => return Ok(None)
+        };
+        let addr = address.to_socket_addrs().ok()
+            .and_then(|mut v| v.next())
+            .ok_or_else(|| format!("invalid statsd address {:?} (must be host:port)", address))?;
+        Ok(Some(Statsd {
+            addr,
+            format: config.statsd_format,
+            prefix: config.statsd_prefix.clone(),
+            interval: Duration::from_millis(config.statsd_flush_interval_ms),
+            metrics,
+        }))
+    }
+
+    /// Every flush interval sends the increase of counters, the value of gauges and every
+    /// observation of timers since the previous flush
+    pub async fn run(self) {
+        let bind = if self.addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+        let socket = match UdpSocket::bind(bind).await {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("can not open socket to statsd {}, metrics are not pushed; err = {:?}", self.addr, e);
+                return;
+            }
+        };
+        if let Err(e) = socket.connect(self.addr).await {
+            warn!("can not open socket to statsd {}, metrics are not pushed; err = {:?}", self.addr, e);
+            return;
+        }
+        info!("pushing metrics to statsd {} every {:?}", self.addr, self.interval);
+        self.metrics.take_timings();
+        let mut pushed: BTreeMap<(&'static str, Labels), i64> = BTreeMap::new();
+        let mut interval = tokio::time::interval(self.interval);
+        loop {
+            interval.tick().await;
+            let mut lines = Vec::new();
+            for (name, labels, value) in self.metrics.snapshot() {
+                match value {
+                    Value::Counter(total) => {
+                        let previous = pushed.insert((name, labels.clone()), total).unwrap_or(0);
+                        if total != previous {
+                            lines.push(self.line(name, &labels, total - previous, "c"));
+                        }
+                    },
+                    Value::Gauge(v) => lines.push(self.line(name, &labels, v, "g"))
+                }
+            }
+            for (name, labels, value) in self.metrics.take_timings() {
+                let kind = if metrics::kind(name) == Some(Kind::Histogram) { "h" } else { "ms" };
+                lines.push(self.line(name, &labels, value as i64, kind));
+            }
+            for datagram in batch(&lines) {
+                // nothing listening is only noticed by a later send, on a connected socket
+                if let Err(e) = socket.send(datagram.as_bytes()).await {
+                    debug!("can not push metrics to statsd {}; err = {:?}", self.addr, e);
+                }
+            }
+        }
+    }
+
+    fn line(&self, name: &str, labels: &Labels, value: i64, kind: &str) -> String {
+        match self.format {
+            StatsdFormat::Dogstatsd if !labels.is_empty() => {
+                let tags: Vec<String> = labels.iter()
+                    .map(|(k, v)| format!("{}:{}", k, v.replace([',', '|', '#'], "_")))
+                    .collect();
+                format!("{}{}:{}|{}|#{}", self.prefix, name, value, kind, tags.join(","))
+            },
+            StatsdFormat::Dogstatsd => format!("{}{}:{}|{}", self.prefix, name, value, kind),
+            StatsdFormat::Statsd => {
+                let mut path = format!("{}{}", self.prefix, name);
+                for (_, v) in labels {
+                    path.push('.');
+                    path.extend(v.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' }));
+                }
+                format!("{}:{}|{}", path, value, kind)
+            }
+        }
+    }
+}
+
+/// Joins lines into datagrams of at most `MAX_DATAGRAM_BYTES`, a longer line is sent alone
+fn batch(lines: &[String]) -> Vec<String> {
+    let mut datagrams = Vec::new();
+    let mut current = String::new();
+    for line in lines {
+        if !current.is_empty() && current.len() + 1 + line.len() > MAX_DATAGRAM_BYTES {
+            datagrams.push(std::mem::take(&mut current));
+        }
+        if !current.is_empty() {
+            current.push('\n');
+        }
+        current.push_str(line);
+    }
+    if !current.is_empty() {
+        datagrams.push(current);
+    }
+    datagrams
+}
@@ -0,0 +1,248 @@
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use std::task::{Context, Poll};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use futures_util::stream::Stream;
+use log::{debug, warn};
+use serde::Serialize;
+use tokio::sync::oneshot;
+use hyper::body::{Bytes, HttpBody};
+use hyper::{Body, Request, Response, Uri};
+use hyper::http::{header, HeaderMap};
+
+use crate::HttpClient;
+use crate::config::Config;
+use crate::metrics::Metrics;
+use crate::privacy::Peer;
+
+/// Time the collector has to take an exchange, tasks of a stalled one do not pile up
+const SEND_TIMEOUT: Duration = Duration::from_secs(5);
+
+
+/// Copy of a plain-HTTP exchange, POSTed to `tap_endpoint` as JSON:
+///
+/// ```json
+/// {
+///   "client": "10.0.0.7:51234",
+///   "identity": null,
+///   "method": "POST",
+///   "uri": "http://example.com/upload",
+///   "status": 201,
+///   "duration_ms": 42,
+///   "request": {"headers": [["content-length", "5"]], "body": "aGVsbG8=", "body_truncated": false},
+///   "response": {"headers": [["content-length", "2"]], "body": "b2s=", "body_truncated": false}
+/// }
+/// ```
+#[derive(Debug, Serialize)]
+struct Exchange {
+    /// Address of the client
+    client: String,
+    /// Name of the client certificate, `null` when the client presented none
+    identity: Option<String>,
+    method: String,
+    /// Absolute uri of the request as it was forwarded
+    uri: String,
+    status: u16,
+    /// Time from forwarding the request to the end of the response body
+    duration_ms: u64,
+    request: Message,
+    response: Message,
+}
+
+#[derive(Debug, Serialize)]
+struct Message {
+    /// Every header as a `[name, value]` pair in order, names are lowercase
+    headers: Vec<(String, String)>,
+    /// Base64 of the first `tap_max_body_bytes` of the body
+    body: String,
+    /// Whether the body is longer than `body`
+    body_truncated: bool,
+}
+
+/// Request of an exchange being tapped, its body is copied while it is sent upstream
+pub struct Tapped {
+    client: String,
+    identity: Option<String>,
+    method: String,
+    uri: String,
+    headers: HeaderMap,
+    body: oneshot::Receiver<(Bytes, bool)>,
+    started: Instant,
+}
+
+
+/// Sends a copy of every completed plain-HTTP exchange to a collector, e.g. a traffic recorder or
+/// a security tool; the collector never affects the client, failures are logged and counted
+pub struct Tap {
+    uri: Uri,
+    max_body_bytes: usize,
+    metrics: Arc<Metrics>,
+}
+
+impl Tap {
+    /// Returns `None` when `tap_endpoint` is not configured
+    pub fn from_config(config: &Config, metrics: Arc<Metrics>) -> Result<Option<Arc<Tap>>, String> {
+        let endpoint = match &config.tap_endpoint {
+            Some(v) => v,
+            None => return Ok(None)
+        };
+        let uri = match endpoint.parse::<Uri>() {
+            Ok(v) if matches!(v.scheme_str(), Some("http") | Some("https")) && v.authority().is_some() => v,
+            _ => return Err(format!("invalid tap_endpoint {:?} (must be an absolute uri like http://host:port/path)",
+                                    endpoint))
+        };
+        Ok(Some(Arc::new(Tap { uri, max_body_bytes: config.tap_max_body_bytes as usize, metrics })))
+    }
+
+    /// Copies the request as it is forwarded, its body while it is sent
+    pub fn request(&self, req: &mut Request<Body>, peer: Peer, identity: Option<&str>) -> Tapped {
+        let (tx, rx) = oneshot::channel();
+        let body = std::mem::replace(req.body_mut(), Body::empty());
+        let done = Box::new(move |body, truncated| {
+            let _ = tx.send((body, truncated));
+        });
+        *req.body_mut() = copy(body, content_length(req.headers()), self.max_body_bytes, done);
+        Tapped {
+            client: peer.to_string(),
+            identity: identity.map(String::from),
+            method: req.method().to_string(),
+            uri: req.uri().to_string(),
+            headers: req.headers().clone(),
+            body: rx,
+            started: Instant::now(),
+        }
+    }
+
+    /// Copies the response as it is sent to the client, the exchange is sent to the collector
+    /// once its body ended; a response the client does not read to the end is not sent
+    pub fn response(self: &Arc<Self>, tapped: Tapped, client: &HttpClient, resp: Response<Body>) -> Response<Body> {
+        let (parts, body) = resp.into_parts();
+        let (tap, client) = (self.clone(), client.clone());
+        let (status, headers) = (parts.status.as_u16(), parts.headers.clone());
+        let done = Box::new(move |body: Bytes, truncated| {
+            let duration = tapped.started.elapsed();
+            tokio::task::spawn(async move {
+                // a request body the upstream did not read to the end is missing
+                let (request_body, request_truncated) = tapped.body.await.unwrap_or((Bytes::new(), true));
+                let exchange = Exchange {
+                    client: tapped.client,
+                    identity: tapped.identity,
+                    method: tapped.method,
+                    uri: tapped.uri,
+                    status,
+                    duration_ms: duration.as_millis() as u64,
+                    request: message(&tapped.headers, &request_body, request_truncated),
+                    response: message(&headers, &body, truncated),
+                };
+                tap.send(&client, exchange).await;
+            });
+        });
+        let body = copy(body, content_length(&parts.headers), self.max_body_bytes, done);
+        Response::from_parts(parts, body)
+    }
+
+    async fn send(&self, client: &HttpClient, exchange: Exchange) {
+        let described = format!("{} {}", exchange.method, exchange.uri);
+        let result = async {
+            let payload = serde_json::to_vec(&exchange).map_err(|e| format!("can not encode exchange; err = {}", e))?;
+            let req = Request::post(self.uri.clone())
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(payload))
+                .map_err(|e| format!("can not build request; err = {}", e))?;
+            let resp = client.request(req).await.map_err(|e| format!("err = {}", e))?;
+            let status = resp.status();
+            // the connection is reused once the answer is read
+            let _ = hyper::body::to_bytes(resp.into_body()).await;
+            if !status.is_success() {
+                return Err(format!("status {}", status));
+            }
+            Ok(())
+        };
+        match tokio::time::timeout(SEND_TIMEOUT, result).await {
+            Ok(Ok(())) => {
+                debug!("exchange {} sent to tap {}", described, self.uri);
+                self.metrics.inc("tap_exchanges_total", &[("result", "sent")]);
+            },
+            Ok(Err(e)) => {
+                warn!("can not send exchange {} to tap {}; {}", described, self.uri, e);
+                self.metrics.inc("tap_exchanges_total", &[("result", "failed")]);
+            },
+            Err(_) => {
+                warn!("can not send exchange {} to tap {}; no answer in {:?}", described, self.uri, SEND_TIMEOUT);
+                self.metrics.inc("tap_exchanges_total", &[("result", "failed")]);
+            }
+        }
+    }
+}
+
+fn message(headers: &HeaderMap, body: &Bytes, truncated: bool) -> Message {
+    Message {
+        headers: headers.iter()
+            .map(|(k, v)| (k.as_str().to_string(), String::from_utf8_lossy(v.as_bytes()).into_owned()))
+            .collect(),
+        body: STANDARD.encode(body),
+        body_truncated: truncated,
+    }
+}
+
+fn content_length(headers: &HeaderMap) -> Option<u64> {
+    headers.get(header::CONTENT_LENGTH).and_then(|v| v.to_str().ok()).and_then(|v| v.parse().ok())
+}
+
+type Done = Box<dyn FnOnce(Bytes, bool) + Send>;
+
+/// Passes a body of `length` bytes, when it is known, on unchanged while keeping its first `limit`
+/// bytes; `done` gets them and whether the body was longer once it ended
+fn copy(body: Body, length: Option<u64>, limit: usize, done: Done) -> Body {
+    if HttpBody::is_end_stream(&body) || length == Some(0) {
+        done(Bytes::new(), false);
+        return body;
+    }
+    Body::wrap_stream(Copy { body, buf: Vec::new(), read: 0, length, limit, truncated: false, done: Some(done) })
+}
+
+struct Copy {
+    body: Body,
+    buf: Vec<u8>,
+    read: u64,
+    /// hyper stops polling a body once `Content-Length` bytes are sent, its end is never seen
+    length: Option<u64>,
+    limit: usize,
+    truncated: bool,
+    /// `None` once the body ended or failed, a failed body is not copied
+    done: Option<Done>,
+}
+
+impl Stream for Copy {
+    type Item = Result<Bytes, hyper::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        let next = Pin::new(&mut this.body).poll_next(cx);
+        match &next {
+            Poll::Ready(Some(Ok(chunk))) => {
+                let room = this.limit - this.buf.len();
+                this.truncated |= chunk.len() > room;
+                this.buf.extend_from_slice(&chunk[..chunk.len().min(room)]);
+                this.read += chunk.len() as u64;
+                if Some(this.read) == this.length || HttpBody::is_end_stream(&this.body) {
+                    this.finish();
+                }
+            },
+            Poll::Ready(None) => this.finish(),
+            Poll::Ready(Some(Err(_))) => { this.done.take(); },
+            Poll::Pending => {}
+        }
+        next
+    }
+}
+
+impl Copy {
+    fn finish(&mut self) {
+        if let Some(done) = self.done.take() {
+            done(Bytes::from(std::mem::take(&mut self.buf)), self.truncated);
+        }
+    }
+}
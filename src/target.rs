@@ -0,0 +1,194 @@
+use std::fmt;
+use std::net::{IpAddr, Ipv6Addr};
+use hyper::Uri;
+
+
+/// Destination of a CONNECT request parsed from its authority-form target
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Target {
+    /// Host name or IP literal, IPv6 literals are kept without brackets
+    pub host: String,
+    pub port: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TargetError {
+    MissingAuthority,
+    UserInfo,
+    Path,
+    EmptyHost,
+    InvalidHost,
+    InvalidPort,
+}
+
+impl fmt::Display for TargetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            TargetError::MissingAuthority => "authority is missing",
+            TargetError::UserInfo => "authority must not contain userinfo",
+            TargetError::Path => "target must not contain a path",
+            TargetError::EmptyHost => "host is empty",
+            TargetError::InvalidHost => "host is invalid",
+            TargetError::InvalidPort => "port is invalid",
+        };
+        f.write_str(s)
+    }
+}
+
+impl Target {
+    /// Parses a CONNECT target like `example.com:443` or `[2001:db8::1]:443`,
+    /// `default_port` is used when the port is missing
+    pub fn from_uri(uri: &Uri, default_port: u16) -> Result<Target, TargetError> {
+        let authority = match uri.authority() {
+            Some(v) => v,
+            None => return Err(TargetError::MissingAuthority)
+        };
+        if uri.scheme().is_some() || !uri.path().is_empty() || uri.query().is_some() {
+            return Err(TargetError::Path);
+        }
+        if authority.as_str().contains('@') {
+            return Err(TargetError::UserInfo);
+        }
+
+        let host = parse_host(authority.host())?;
+
+        let port = match authority.port_u16() {
+            Some(0) => return Err(TargetError::InvalidPort),
+            Some(v) => v,
+            // `host:` has an empty port which is as malformed as a wrong one
+            None if authority.as_str().ends_with(':') => return Err(TargetError::InvalidPort),
+            None => default_port
+        };
+
+        Ok(Target { host, port })
+    }
+
+    /// Destination of a plain-HTTP request in absolute-form, the port defaults by scheme;
+    /// `None` for requests in origin-form
+    pub fn from_request_uri(uri: &Uri) -> Option<Result<Target, TargetError>> {
+        let authority = uri.authority()?;
+        let port = match authority.port_u16() {
+            Some(v) => v,
+            None if uri.scheme() == Some(&http::uri::Scheme::HTTPS) => 443,
+            None => 80
+        };
+        Some(parse_host(authority.host()).map(|host| Target { host, port }))
+    }
+
+    pub fn ip(&self) -> Option<IpAddr> {
+        self.host.parse().ok()
+    }
+
+    /// Tells whether the host names this machine, `localhost`, its subdomains or a loopback address
+    pub fn is_localhost(&self) -> bool {
+        match self.ip() {
+            Some(ip) => is_loopback(&ip),
+            None => self.host == "localhost" || self.host.ends_with(".localhost")
+        }
+    }
+}
+
+/// Tells whether a connection to an address reaches this machine: loopback addresses, also mapped
+/// to IPv6, and the unspecified address, which Linux connects to the loopback interface
+pub fn is_loopback(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => v4.is_loopback() || v4.is_unspecified(),
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => v4.is_loopback() || v4.is_unspecified(),
+            None => v6.is_loopback() || v6.is_unspecified()
+        }
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.ip() {
+            Some(IpAddr::V6(ip)) => write!(f, "[{}]:{}", ip, self.port),
+            _ => write!(f, "{}:{}", self.host, self.port)
+        }
+    }
+}
+
+/// Validates the host of an authority, IPv6 literals lose their brackets and IP literals
+/// are brought to their canonical form, so they compare equal however they were written
+fn parse_host(host: &str) -> Result<String, TargetError> {
+    let host = match host.strip_prefix('[').and_then(|v| v.strip_suffix(']')) {
+        // brackets are allowed around IPv6 literals only
+        Some(v) => match v.parse::<Ipv6Addr>() {
+            Ok(ip) => return Ok(ip.to_string()),
+            Err(_) => return Err(TargetError::InvalidHost)
+        },
+        None => host
+    };
+    if host.is_empty() {
+        return Err(TargetError::EmptyHost);
+    }
+    match host.parse::<IpAddr>() {
+        Ok(ip) => Ok(ip.to_string()),
+        Err(_) => Ok(host.to_lowercase())
+    }
+}
+
+/// Host of a uri without the brackets of IPv6 literals
+pub fn strip_brackets(host: &str) -> &str {
+    host.strip_prefix('[').and_then(|v| v.strip_suffix(']')).unwrap_or(host)
+}
+
+/// Tells whether a host matches a pattern, `*.example.com` and `.example.com` match subdomains
+pub fn host_matches(pattern: &str, host: &str) -> bool {
+    let pattern = pattern.trim_start_matches('*');
+    if pattern.starts_with('.') {
+        host.len() > pattern.len() && host[host.len() - pattern.len()..].eq_ignore_ascii_case(pattern)
+    } else {
+        host.eq_ignore_ascii_case(pattern)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_connect_authorities() {
+        let target = |host: &str, port| Ok(Target { host: String::from(host), port });
+        let cases = [
+            ("example.com:443", target("example.com", 443)),
+            ("Example.COM:8443", target("example.com", 8443)),
+            ("example.com", target("example.com", 4443)),
+            ("192.0.2.1:443", target("192.0.2.1", 443)),
+            ("[2001:db8::1]:443", target("2001:db8::1", 443)),
+            ("[2001:DB8:0::1]", target("2001:db8::1", 4443)),
+            ("[::ffff:192.0.2.1]:443", target("::ffff:192.0.2.1", 443)),
+            ("alice:s3cret@example.com:443", Err(TargetError::UserInfo)),
+            ("alice@example.com", Err(TargetError::UserInfo)),
+            ("http://example.com:443", Err(TargetError::Path)),
+            ("/path", Err(TargetError::MissingAuthority)),
+            (":443", Err(TargetError::EmptyHost)),
+            ("[example.com]:443", Err(TargetError::InvalidHost)),
+            ("[192.0.2.1]:443", Err(TargetError::InvalidHost)),
+            ("example.com:", Err(TargetError::InvalidPort)),
+            ("example.com:0", Err(TargetError::InvalidPort)),
+        ];
+        for (authority, expected) in cases {
+            let uri = authority.parse::<Uri>().unwrap_or_else(|e| panic!("{}: {}", authority, e));
+            assert_eq!(Target::from_uri(&uri, 4443), expected, "{}", authority);
+        }
+    }
+
+    #[test]
+    fn shows_ipv6_literals_in_brackets() {
+        let uri = "[2001:db8::1]:443".parse::<Uri>().unwrap();
+        assert_eq!(Target::from_uri(&uri, 443).unwrap().to_string(), "[2001:db8::1]:443");
+        let uri = "example.com".parse::<Uri>().unwrap();
+        assert_eq!(Target::from_uri(&uri, 443).unwrap().to_string(), "example.com:443");
+    }
+
+    #[test]
+    fn defaults_port_of_request_uris_by_scheme() {
+        let target = |uri: &str| Target::from_request_uri(&uri.parse::<Uri>().unwrap()).map(|t| t.unwrap().to_string());
+        assert_eq!(target("http://example.com/"), Some(String::from("example.com:80")));
+        assert_eq!(target("https://example.com/"), Some(String::from("example.com:443")));
+        assert_eq!(target("http://[::1]:8080/"), Some(String::from("[::1]:8080")));
+        assert_eq!(target("/"), None);
+    }
+}
@@ -0,0 +1,925 @@
+use std::fmt;
+use std::fs::File;
+use std::sync::{Arc, RwLock};
+use std::io::BufReader;
+use std::convert::TryFrom;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use log::{error, info, warn};
+use serde::Serialize;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use ring::digest;
+use rustls::{CertificateError, ClientConfig, DigitallySignedStruct, OtherError, RootCertStore, ServerConfig,
+             SignatureScheme};
+use rustls::client::{ResolvesClientCert, WebPkiServerVerifier};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::{DistinguishedName, ServerConnection};
+use rustls::pki_types::{CertificateDer, CertificateRevocationListDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier};
+use rustls::server::danger::{ClientCertVerified, ClientCertVerifier};
+use rustls::sign::CertifiedKey;
+
+use crate::config::{ClientAuth, ListenerTlsConfig, TlsVersion, UpstreamTlsConfig, SYSTEM_CA_PEM};
+use crate::target::host_matches;
+
+
+/// How often certificate and key files are checked for changes
+pub const CERT_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+/// How often certificates close to their expiry are warned about
+pub const EXPIRY_WARNING_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+
+pub fn provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+/// Reads all certificates of a PEM file
+pub fn load_certs(path: &str) -> Result<Vec<CertificateDer<'static>>, String> {
+    let file = File::open(path).map_err(|e| format!("can not open certificate file {:?}; err = {:?}", path, e))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("can not parse certificate file {:?}; err = {:?}", path, e))?;
+    if certs.is_empty() {
+        return Err(format!("no certificates found in {:?}", path));
+    }
+    Ok(certs)
+}
+
+/// Reads the first private key of a PEM file, the key itself is never logged
+pub fn load_key(path: &str) -> Result<PrivateKeyDer<'static>, String> {
+    let file = File::open(path).map_err(|e| format!("can not open key file {:?}; err = {:?}", path, e))?;
+    match rustls_pemfile::private_key(&mut BufReader::new(file)) {
+        Ok(Some(v)) => Ok(v),
+        Ok(None) => Err(format!("no private key found in {:?}", path)),
+        Err(e) => Err(format!("can not parse key file {:?}; err = {:?}", path, e))
+    }
+}
+
+/// Reads trust anchors from a PEM bundle
+pub fn load_roots(path: &str) -> Result<RootCertStore, String> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(cert).map_err(|e| format!("invalid CA certificate in {:?}; err = {:?}", path, e))?;
+    }
+    Ok(roots)
+}
+
+/// Builds a server config, when `client_ca` is given clients must present a certificate signed by it.
+///
+/// The certificate is returned as well, it is replaced when its files change.
+pub fn server_config(cert: &str, key: &str, client_ca: Option<&str>)
+    -> Result<(ServerConfig, Arc<ReloadingCert>), String> {
+    let provider = provider();
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| format!("can not setup TLS; err = {:?}", e))?;
+    let builder = match client_ca {
+        Some(ca) => {
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(load_roots(ca)?), provider)
+                .build()
+                .map_err(|e| format!("can not setup client certificate verification; err = {:?}", e))?;
+            builder.with_client_cert_verifier(verifier)
+        },
+        None => builder.with_no_client_auth()
+    };
+    let cert = Arc::new(ReloadingCert::load(cert, key)?);
+    Ok((builder.with_cert_resolver(cert.clone()), cert))
+}
+
+
+/// Builds the server config of the proxy listener, `None` when it serves plain HTTP.
+///
+/// ALPN offers `h2` only when the listener speaks HTTP/2. The certificate is returned as well,
+/// it is replaced when its files change.
+pub fn listener_config(config: &ListenerTlsConfig, http2: bool)
+    -> Result<Option<(ServerConfig, Arc<ReloadingCert>)>, String> {
+    let (cert, key) = match (&config.cert_pem, &config.key_pem) {
+        (Some(cert), Some(key)) => (cert, key),
+        (None, None) if config.client_auth == ClientAuth::None => return Ok(None),
+        (None, None) => return Err(String::from("tls.client_auth requires tls.cert_pem and tls.key_pem")),
+        _ => return Err(String::from("tls.cert_pem and tls.key_pem must be set together"))
+    };
+    let provider = provider();
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| format!("can not setup TLS; err = {:?}", e))?;
+    let builder = match (config.client_auth, &config.client_ca) {
+        (ClientAuth::None, _) => builder.with_no_client_auth(),
+        (_, None) => return Err(String::from("tls.client_auth requires tls.client_ca")),
+        (client_auth, Some(ca)) => {
+            let mut crls = Vec::new();
+            for path in &config.crl_pem {
+                crls.extend(load_crls(path)?);
+            }
+            let mut verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(load_roots(ca)?), provider)
+                .with_crls(crls)
+                .only_check_end_entity_revocation();
+            if client_auth == ClientAuth::Optional {
+                verifier = verifier.allow_unauthenticated();
+            }
+            let verifier = verifier.build()
+                .map_err(|e| format!("can not setup client certificate verification; err = {:?}", e))?;
+            let mut revoked = Vec::new();
+            for serial in &config.revoked_serials {
+                revoked.push(parse_serial(serial)
+                    .ok_or_else(|| format!("invalid tls.revoked_serials entry {:?} (must be hex)", serial))?);
+            }
+            builder.with_client_cert_verifier(Arc::new(RevokingVerifier { webpki: verifier, revoked }))
+        }
+    };
+    let cert = Arc::new(ReloadingCert::load(cert, key)?);
+    let mut server = builder.with_cert_resolver(cert.clone());
+    server.alpn_protocols = if http2 { vec![b"h2".to_vec(), b"http/1.1".to_vec()] } else { vec![b"http/1.1".to_vec()] };
+    Ok(Some((server, cert)))
+}
+
+
+/// Certificate as served by `/admin/certificates`
+#[derive(Debug, Serialize)]
+pub struct CertInfo {
+    /// `listener`, `admin` or `upstream_client`
+    pub role: &'static str,
+    /// Path of the PEM file
+    pub cert: String,
+    pub not_after: Option<String>,
+    /// Negative once the certificate expired, `None` when its validity can not be read
+    pub expires_in_seconds: Option<i64>,
+}
+
+
+/// Certificate and key loaded from PEM files, replaced when the files change so new handshakes
+/// use the new pair while established connections are untouched
+#[derive(Debug)]
+pub struct ReloadingCert {
+    cert_pem: String,
+    key_pem: String,
+    current: RwLock<LoadedCert>,
+}
+
+#[derive(Debug)]
+struct LoadedCert {
+    key: Arc<CertifiedKey>,
+    /// `notAfter` of the certificate in seconds since the epoch
+    not_after: Option<i64>,
+    /// Latest modification time of the files when they were last read
+    modified: Option<SystemTime>,
+}
+
+impl ReloadingCert {
+    /// An expired certificate is loaded with a warning, only a reload refuses it
+    pub fn load(cert_pem: &str, key_pem: &str) -> Result<ReloadingCert, String> {
+        let modified = modified(&[cert_pem, key_pem]);
+        let key = load_certified_key(cert_pem, key_pem)?;
+        let not_after = check_expiry(&key, cert_pem).unwrap_or_else(|e| {
+            warn!("{}, clients will not accept it", e);
+            key.end_entity_cert().ok().and_then(|c| not_after(c.as_ref()))
+        });
+        Ok(ReloadingCert {
+            cert_pem: String::from(cert_pem),
+            key_pem: String::from(key_pem),
+            current: RwLock::new(LoadedCert { key: Arc::new(key), not_after, modified }),
+        })
+    }
+
+    pub fn key(&self) -> Arc<CertifiedKey> {
+        self.current.read().unwrap().key.clone()
+    }
+
+    pub fn cert_pem(&self) -> &str {
+        &self.cert_pem
+    }
+
+    /// Expiry of the certificate in use, `role` tells what it is used for
+    pub fn info(&self, role: &'static str) -> CertInfo {
+        let not_after = self.current.read().unwrap().not_after;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0);
+        CertInfo {
+            role,
+            cert: self.cert_pem.clone(),
+            not_after: not_after
+                .and_then(|v| chrono::NaiveDateTime::from_timestamp_opt(v, 0))
+                .map(|v| v.format("%Y-%m-%dT%H:%M:%SZ").to_string()),
+            expires_in_seconds: not_after.map(|v| v - now),
+        }
+    }
+
+    /// Loads the files again when they changed since they were last read, or anyway with `force`.
+    ///
+    /// A pair whose key does not match or whose certificate expired keeps the current one.
+    pub fn refresh(&self, force: bool) {
+        let modified = modified(&[&self.cert_pem, &self.key_pem]);
+        {
+            let mut current = self.current.write().unwrap();
+            if !force && current.modified == modified {
+                return;
+            }
+            // a bad pair is reported once, not at every check
+            current.modified = modified;
+        }
+        let loaded = load_certified_key(&self.cert_pem, &self.key_pem)
+            .and_then(|key| check_expiry(&key, &self.cert_pem).map(|not_after| (key, not_after)));
+        match loaded {
+            Ok((key, not_after)) => {
+                info!("loaded new certificate {:?} and key {:?}", self.cert_pem, self.key_pem);
+                let mut current = self.current.write().unwrap();
+                current.key = Arc::new(key);
+                current.not_after = not_after;
+            },
+            Err(e) => error!("can not reload certificate {:?}, keeping the current one; {}", self.cert_pem, e)
+        }
+    }
+}
+
+impl ResolvesServerCert for ReloadingCert {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.key())
+    }
+}
+
+/// Latest modification time of the files, `None` when one of them can not be read
+fn modified(paths: &[&str]) -> Option<SystemTime> {
+    let mut latest = None;
+    for path in paths {
+        let modified = std::fs::metadata(path).and_then(|m| m.modified()).ok()?;
+        latest = latest.max(Some(modified));
+    }
+    latest
+}
+
+/// Loads a certificate chain and its key, errors name the files but never contain key material
+fn load_certified_key(cert_pem: &str, key_pem: &str) -> Result<CertifiedKey, String> {
+    let key = provider().key_provider.load_private_key(load_key(key_pem)?)
+        .map_err(|_| format!("unsupported private key in {:?}", key_pem))?;
+    let certified = CertifiedKey::new(load_certs(cert_pem)?, key);
+    certified.keys_match().map_err(|_| format!("key {:?} does not match certificate {:?}", key_pem, cert_pem))?;
+    Ok(certified)
+}
+
+/// `notAfter` of the certificate of a pair which loads, its key matching, and did not expire yet
+pub fn valid_until(cert_pem: &str, key_pem: &str) -> Result<Option<i64>, String> {
+    let key = load_certified_key(cert_pem, key_pem)?;
+    check_expiry(&key, cert_pem)
+}
+
+/// Returns the `notAfter` of a certificate which did not expire yet
+fn check_expiry(key: &CertifiedKey, cert_pem: &str) -> Result<Option<i64>, String> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0);
+    match key.end_entity_cert().ok().and_then(|c| not_after(c.as_ref())) {
+        Some(v) if v < now => Err(format!("certificate {:?} expired", cert_pem)),
+        Some(v) => Ok(Some(v)),
+        None => Err(format!("can not read the validity of certificate {:?}", cert_pem))
+    }
+}
+
+/// `notAfter` of a DER certificate in seconds since the epoch
+fn not_after(cert: &[u8]) -> Option<i64> {
+    let (_, cert, _) = der_item(cert)?;
+    let (_, mut tbs, _) = der_item(cert)?;
+    // the version is an optional explicit [0]
+    if tbs.first() == Some(&0xa0) {
+        tbs = der_item(tbs)?.2;
+    }
+    // serial number, signature algorithm and issuer precede the validity
+    for _ in 0..3 {
+        tbs = der_item(tbs)?.2;
+    }
+    let (_, validity, _) = der_item(tbs)?;
+    let (_, _, validity) = der_item(validity)?;
+    let (tag, time, _) = der_item(validity)?;
+    let time = std::str::from_utf8(time).ok()?;
+    // UTCTime has two digits of the year, 50 and later are in the 1900s
+    let (year, time) = match tag {
+        0x17 => {
+            let year: i32 = time.get(..2)?.parse().ok()?;
+            (if year >= 50 { 1900 + year } else { 2000 + year }, &time[2..])
+        },
+        0x18 => (time.get(..4)?.parse().ok()?, &time[4..]),
+        _ => return None
+    };
+    let field = |i: usize| time.get(i..i + 2).and_then(|v| v.parse::<u32>().ok());
+    let date = chrono::NaiveDate::from_ymd_opt(year, field(0)?, field(2)?)?;
+    Some(date.and_hms_opt(field(4)?, field(6)?, field(8)?)?.timestamp())
+}
+
+/// Reads all revocation lists of a PEM file
+fn load_crls(path: &str) -> Result<Vec<CertificateRevocationListDer<'static>>, String> {
+    let file = File::open(path).map_err(|e| format!("can not open CRL file {:?}; err = {:?}", path, e))?;
+    let crls = rustls_pemfile::crls(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("can not parse CRL file {:?}; err = {:?}", path, e))?;
+    if crls.is_empty() {
+        return Err(format!("no revocation lists found in {:?}", path));
+    }
+    Ok(crls)
+}
+
+/// Parses a hex serial number like `0a:1b:2c`, without the leading zero bytes DER may add
+fn parse_serial(serial: &str) -> Option<Vec<u8>> {
+    let hex: String = serial.chars().filter(|c| *c != ':').collect();
+    if hex.is_empty() || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    let hex = if hex.len() % 2 == 1 { format!("0{}", hex) } else { hex };
+    let bytes: Vec<u8> = (0..hex.len()).step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+        .collect();
+    Some(strip_zeros(&bytes).to_vec())
+}
+
+fn strip_zeros(serial: &[u8]) -> &[u8] {
+    let start = serial.iter().position(|b| *b != 0).unwrap_or(serial.len());
+    &serial[start..]
+}
+
+/// Verifies client certificates against the roots and CRLs, then their serial against `revoked_serials`
+#[derive(Debug)]
+struct RevokingVerifier {
+    webpki: Arc<dyn ClientCertVerifier>,
+    revoked: Vec<Vec<u8>>,
+}
+
+impl ClientCertVerifier for RevokingVerifier {
+    fn offer_client_auth(&self) -> bool {
+        self.webpki.offer_client_auth()
+    }
+
+    fn client_auth_mandatory(&self) -> bool {
+        self.webpki.client_auth_mandatory()
+    }
+
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        self.webpki.root_hint_subjects()
+    }
+
+    fn verify_client_cert(&self, end_entity: &CertificateDer<'_>, intermediates: &[CertificateDer<'_>],
+                          now: UnixTime) -> Result<ClientCertVerified, rustls::Error> {
+        let verified = self.webpki.verify_client_cert(end_entity, intermediates, now)?;
+        if !self.revoked.is_empty() {
+            let cert = webpki::EndEntityCert::try_from(end_entity)
+                .map_err(|e| rustls::Error::InvalidCertificate(CertificateError::Other(OtherError(Arc::new(e)))))?;
+            if self.revoked.iter().any(|v| v.as_slice() == strip_zeros(cert.serial())) {
+                return Err(rustls::Error::InvalidCertificate(CertificateError::Revoked));
+            }
+        }
+        Ok(verified)
+    }
+
+    fn verify_tls12_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct)
+        -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.webpki.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct)
+        -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.webpki.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.webpki.supported_verify_schemes()
+    }
+}
+
+/// Identity of a client by its verified certificate: the subject CN, or the first DNS name
+/// when the subject has no CN. `None` for anonymous clients.
+pub fn peer_identity(conn: &ServerConnection) -> Option<String> {
+    let der = conn.peer_certificates()?.first()?;
+    let cert = webpki::EndEntityCert::try_from(der).ok()?;
+    common_name(cert.subject()).or_else(|| cert.valid_dns_names().next().map(String::from))
+}
+
+/// Finds the CN attribute (2.5.4.3) in the contents of a DER encoded `Name`
+fn common_name(mut name: &[u8]) -> Option<String> {
+    const CN: &[u8] = &[0x55, 0x04, 0x03];
+    while !name.is_empty() {
+        let (_, mut set, rest) = der_item(name)?;
+        name = rest;
+        while !set.is_empty() {
+            let (_, attribute, rest) = der_item(set)?;
+            set = rest;
+            let (_, oid, value) = der_item(attribute)?;
+            if oid == CN {
+                let (_, value, _) = der_item(value)?;
+                return Some(String::from_utf8_lossy(value).into_owned());
+            }
+        }
+    }
+    None
+}
+
+/// Splits a DER item into its tag, contents and the bytes after it
+fn der_item(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = input.split_first()?;
+    let (&len, mut rest) = rest.split_first()?;
+    let len = if len < 0x80 {
+        len as usize
+    } else {
+        let n = (len & 0x7f) as usize;
+        if n == 0 || n > 4 || rest.len() < n {
+            return None;
+        }
+        let len = rest[..n].iter().fold(0, |acc, b| acc << 8 | *b as usize);
+        rest = &rest[n..];
+        len
+    };
+    if rest.len() < len {
+        return None;
+    }
+    Some((tag, &rest[..len], &rest[len..]))
+}
+
+
+/// Client configs of connections to `https` upstreams, one per host pattern of `upstream_tls`
+pub struct UpstreamTls {
+    default: Arc<ClientConfig>,
+    /// Replaces `default` for hosts matching `insecure_hosts`
+    insecure: Arc<ClientConfig>,
+    hosts: Vec<HostTls>,
+    /// Certificates are verified unless a host says otherwise
+    verify: bool,
+    insecure_hosts: Vec<String>,
+    /// Shared by the verifiers of every verified config
+    pins: Arc<RwLock<Pins>>,
+    /// Client certificates by host pattern
+    client_certs: RwLock<Vec<(String, Arc<ReloadingCert>)>>,
+}
+
+impl UpstreamTls {
+    /// ALPN offers `h2` or `http/1.1`, the protocol the forwarding client speaks
+    pub fn from_config(config: &UpstreamTlsConfig, http1_only: bool) -> Result<UpstreamTls, String> {
+        let mut roots = RootCertStore::empty();
+        if config.use_system_roots {
+            // a missing system bundle must not prevent startup, only https upstreams fail
+            match load_certs(SYSTEM_CA_PEM) {
+                Ok(certs) => {
+                    roots.add_parsable_certificates(certs);
+                },
+                Err(e) => warn!("can not load system CA roots, https upstreams are not trusted; {}", e)
+            }
+        }
+        if let Some(path) = &config.ca_bundle {
+            for cert in load_certs(path)? {
+                roots.add(cert).map_err(|e| format!("invalid CA certificate in {:?}; err = {:?}", path, e))?;
+            }
+        }
+        if !config.use_system_roots && config.ca_bundle.is_none() {
+            return Err(String::from("upstream_tls.ca_bundle is required when use_system_roots is false"));
+        }
+        let provider = provider();
+        let pins = Arc::new(RwLock::new(Pins::from_config(config)?));
+        // without any root every certificate is rejected as unknown
+        let webpki = WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider.clone()).build().ok();
+        let verified: Arc<dyn ServerCertVerifier> = Arc::new(PinningVerifier {
+            webpki,
+            pins: pins.clone(),
+            provider: provider.clone(),
+        });
+        let unverified: Arc<dyn ServerCertVerifier> = Arc::new(NoVerification(provider));
+
+        let alpn = vec![String::from(if http1_only { "http/1.1" } else { "h2" })];
+        let build = |version: Option<TlsVersion>, alpn: &[String], insecure: bool| {
+            let verifier = if insecure { unverified.clone() } else { verified.clone() };
+            client_config(verifier, version, alpn).map(Arc::new)
+        };
+        let mut hosts = Vec::new();
+        for (pattern, host) in &config.hosts {
+            let alpn = host.alpn.as_ref().unwrap_or(&alpn);
+            let sni = host.sni.as_ref().map(|name| match ServerName::try_from(name.clone()) {
+                Ok(ServerName::DnsName(name)) => Ok(ServerName::DnsName(name)),
+                _ => Err(format!("invalid upstream_tls.hosts.{:?}.sni {:?} (must be a DNS name)", pattern, name))
+            }).transpose()?;
+            hosts.push(HostTls {
+                pattern: pattern.clone(),
+                verify: host.verify,
+                sni,
+                verified: build(host.min_version, alpn, false)?,
+                unverified: build(host.min_version, alpn, true)?,
+            });
+        }
+        if !config.verify {
+            let verified: Vec<&str> = config.hosts.iter()
+                .filter(|(_, h)| h.verify == Some(true))
+                .map(|(p, _)| p.as_str())
+                .collect();
+            warn!("TLS certificates of upstream hosts are NOT verified except for {:?}, connections to them \
+                   can be intercepted", verified);
+        }
+        for (pattern, _) in config.hosts.iter().filter(|(_, h)| h.verify == Some(false)) {
+            warn!("TLS certificates of upstream hosts matching {:?} are NOT verified, connections to them \
+                   can be intercepted", pattern);
+        }
+        for pattern in &config.insecure_hosts {
+            warn!("TLS certificates of upstream hosts matching {:?} are NOT verified, connections to them \
+                   can be intercepted", pattern);
+        }
+        Ok(UpstreamTls {
+            default: build(None, &alpn, false)?,
+            insecure: build(None, &alpn, true)?,
+            hosts,
+            verify: config.verify,
+            insecure_hosts: config.insecure_hosts.clone(),
+            pins,
+            client_certs: RwLock::new(load_client_certs(config)?),
+        })
+    }
+
+    /// Replaces the pins and client certificates, other settings need a restart
+    pub fn reload(&self, config: &UpstreamTlsConfig) -> Result<(), String> {
+        let pins = Pins::from_config(config)?;
+        let client_certs = load_client_certs(config)?;
+        *self.pins.write().unwrap() = pins;
+        *self.client_certs.write().unwrap() = client_certs;
+        Ok(())
+    }
+
+    pub fn client_certs(&self) -> Vec<Arc<ReloadingCert>> {
+        self.client_certs.read().unwrap().iter().map(|(_, cert)| cert.clone()).collect()
+    }
+
+    /// Loads client certificates whose files changed again
+    pub fn refresh_client_certs(&self) {
+        for (_, cert) in self.client_certs.read().unwrap().iter() {
+            cert.refresh(false);
+        }
+    }
+
+    /// Name a connection to `host` sends in SNI and its certificate is verified for, the `sni` of its
+    /// host settings or the host itself
+    pub fn server_name(&self, host: &str) -> Result<ServerName<'static>, rustls::pki_types::InvalidDnsNameError> {
+        match self.hosts.iter().find(|h| host_matches(&h.pattern, host)).and_then(|h| h.sni.clone()) {
+            Some(name) => Ok(name),
+            None => ServerName::try_from(String::from(host))
+        }
+    }
+
+    /// Picks the client config of a connection to `host`, the `verify` of its host settings
+    /// takes precedence over `insecure_hosts` and the global `verify`
+    pub fn config(&self, host: &str) -> Arc<ClientConfig> {
+        let settings = self.hosts.iter().find(|h| host_matches(&h.pattern, host));
+        let verify = match settings.and_then(|h| h.verify) {
+            Some(v) => v,
+            None => self.verify && !self.insecure_hosts.iter().any(|p| host_matches(p, host))
+        };
+        let (verified, unverified) = match settings {
+            Some(h) => (&h.verified, &h.unverified),
+            None => (&self.default, &self.insecure)
+        };
+        let config = if verify { verified } else { unverified };
+        match self.client_certs.read().unwrap().iter().find(|(pattern, _)| host_matches(pattern, host)) {
+            Some((_, key)) => {
+                let mut config = ClientConfig::clone(config);
+                config.client_auth_cert_resolver = Arc::new(ClientCert(key.key()));
+                Arc::new(config)
+            },
+            None => config.clone()
+        }
+    }
+}
+
+fn load_client_certs(config: &UpstreamTlsConfig) -> Result<Vec<(String, Arc<ReloadingCert>)>, String> {
+    let mut client_certs = Vec::new();
+    for (pattern, files) in &config.client_certs {
+        client_certs.push((pattern.clone(), Arc::new(ReloadingCert::load(&files.cert_pem, &files.key_pem)?)));
+    }
+    Ok(client_certs)
+}
+
+/// Presents the same certificate to every upstream which asks for one
+#[derive(Debug)]
+struct ClientCert(Arc<CertifiedKey>);
+
+impl ResolvesClientCert for ClientCert {
+    fn resolve(&self, _root_hint_subjects: &[&[u8]], _sigschemes: &[SignatureScheme]) -> Option<Arc<CertifiedKey>> {
+        Some(self.0.clone())
+    }
+
+    fn has_certs(&self) -> bool {
+        true
+    }
+}
+
+/// Tells whether an upstream refused the handshake over the client certificate, TLS 1.3 upstreams
+/// tell only after the handshake, when the response is read
+pub fn is_client_cert_rejected(err: &rustls::Error) -> bool {
+    use rustls::AlertDescription::*;
+    matches!(err, rustls::Error::AlertReceived(BadCertificate | UnsupportedCertificate | CertificateRevoked
+        | CertificateExpired | CertificateUnknown | UnknownCA | CertificateRequired))
+}
+
+/// Configs of an `upstream_tls.hosts` entry
+struct HostTls {
+    pattern: String,
+    verify: Option<bool>,
+    sni: Option<ServerName<'static>>,
+    verified: Arc<ClientConfig>,
+    unverified: Arc<ClientConfig>,
+}
+
+fn client_config(verifier: Arc<dyn ServerCertVerifier>, version: Option<TlsVersion>, alpn: &[String])
+    -> Result<ClientConfig, String> {
+    let versions: &[&rustls::SupportedProtocolVersion] = match version {
+        Some(TlsVersion::Tls13) => &[&rustls::version::TLS13],
+        _ => &[&rustls::version::TLS12, &rustls::version::TLS13],
+    };
+    let mut config = ClientConfig::builder_with_provider(provider())
+        .with_protocol_versions(versions)
+        .map_err(|e| format!("can not setup TLS; err = {:?}", e))?
+        .dangerous()
+        .with_custom_certificate_verifier(verifier)
+        .with_no_client_auth();
+    config.alpn_protocols = alpn.iter().map(|p| p.as_bytes().to_vec()).collect();
+    Ok(config)
+}
+
+
+/// SHA-256 hashes of the SubjectPublicKeyInfo of leaf certificates, by host pattern
+#[derive(Debug)]
+struct Pins {
+    hosts: Vec<(String, Vec<Vec<u8>>)>,
+    report_only: bool,
+}
+
+impl Pins {
+    fn from_config(config: &UpstreamTlsConfig) -> Result<Pins, String> {
+        let mut hosts = Vec::new();
+        for (pattern, pins) in &config.pins {
+            let mut hashes = Vec::new();
+            for pin in pins {
+                let hash = pin.strip_prefix("sha256/")
+                    .and_then(|v| STANDARD.decode(v).ok())
+                    .filter(|v| v.len() == digest::SHA256_OUTPUT_LEN)
+                    .ok_or_else(|| format!("invalid upstream_tls.pins entry {:?} of {:?} (must be sha256/<base64>)",
+                                           pin, pattern))?;
+                hashes.push(hash);
+            }
+            hosts.push((pattern.clone(), hashes));
+        }
+        Ok(Pins { hosts, report_only: config.pins_report_only })
+    }
+}
+
+/// Error of a certificate whose key matches none of the pins of its host
+#[derive(Debug)]
+pub struct PinMismatch;
+
+impl fmt::Display for PinMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "certificate pin mismatch")
+    }
+}
+
+impl std::error::Error for PinMismatch {}
+
+/// Tells whether a handshake failed because of `PinMismatch`
+pub fn is_pin_mismatch(err: &rustls::Error) -> bool {
+    match err {
+        rustls::Error::InvalidCertificate(CertificateError::Other(OtherError(e))) => e.is::<PinMismatch>(),
+        _ => false
+    }
+}
+
+/// Verifies the chain against the roots, then the key of the leaf against the pins of the host
+#[derive(Debug)]
+struct PinningVerifier {
+    webpki: Option<Arc<WebPkiServerVerifier>>,
+    pins: Arc<RwLock<Pins>>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinningVerifier {
+    fn verify_server_cert(&self, end_entity: &CertificateDer<'_>, intermediates: &[CertificateDer<'_>],
+                          server_name: &ServerName<'_>, ocsp: &[u8], now: UnixTime)
+        -> Result<ServerCertVerified, rustls::Error> {
+        let verified = match &self.webpki {
+            Some(v) => v.verify_server_cert(end_entity, intermediates, server_name, ocsp, now)?,
+            None => return Err(rustls::Error::InvalidCertificate(CertificateError::UnknownIssuer))
+        };
+        let pins = self.pins.read().unwrap();
+        let host = server_name.to_str();
+        let expected = match pins.hosts.iter().find(|(pattern, _)| host_matches(pattern, &host)) {
+            Some((_, v)) => v,
+            None => return Ok(verified)
+        };
+        let cert = webpki::EndEntityCert::try_from(end_entity)
+            .map_err(|e| rustls::Error::InvalidCertificate(CertificateError::Other(OtherError(Arc::new(e)))))?;
+        let hash = digest::digest(&digest::SHA256, cert.subject_public_key_info().as_ref());
+        if expected.iter().any(|pin| pin.as_slice() == hash.as_ref()) {
+            return Ok(verified);
+        }
+        if pins.report_only {
+            warn!("certificate of {} matches none of its pins, its key is sha256/{}; allowed in report-only mode",
+                  host, STANDARD.encode(hash));
+            return Ok(verified);
+        }
+        warn!("certificate of {} matches none of its pins, its key is sha256/{}", host, STANDARD.encode(hash));
+        Err(rustls::Error::InvalidCertificate(CertificateError::Other(OtherError(Arc::new(PinMismatch)))))
+    }
+
+    fn verify_tls12_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct)
+        -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct)
+        -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// Accepts any certificate, signatures of the handshake are still checked
+#[derive(Debug)]
+struct NoVerification(Arc<CryptoProvider>);
+impl ServerCertVerifier for NoVerification {
+    fn verify_server_cert(&self, _end_entity: &CertificateDer<'_>, _intermediates: &[CertificateDer<'_>],
+                          _server_name: &ServerName<'_>, _ocsp: &[u8], _now: UnixTime)
+        -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct)
+        -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct)
+        -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture(name: &str) -> String {
+        format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    /// SPKI pin of `server.pem`
+    fn server_pin() -> String {
+        std::fs::read_to_string(fixture("server.pin")).unwrap().trim().to_string()
+    }
+
+    /// Verifier trusting the CA of the fixtures with the pins of `yaml`
+    fn verifier(yaml: &str) -> PinningVerifier {
+        let config: UpstreamTlsConfig = serde_yaml::from_str(yaml).unwrap();
+        let mut roots = RootCertStore::empty();
+        roots.add_parsable_certificates(load_certs(&fixture("ca.pem")).unwrap());
+        PinningVerifier {
+            webpki: WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider()).build().ok(),
+            pins: Arc::new(RwLock::new(Pins::from_config(&config).unwrap())),
+            provider: provider(),
+        }
+    }
+
+    /// Verifies `server.pem`, or `server2.pem` with another key, as certificate of `host`
+    fn verify(verifier: &PinningVerifier, cert: &str, host: &str) -> Result<ServerCertVerified, rustls::Error> {
+        let cert = load_certs(&fixture(cert)).unwrap().remove(0);
+        let name = ServerName::try_from(host.to_string()).unwrap();
+        verifier.verify_server_cert(&cert, &[], &name, &[], UnixTime::now())
+    }
+
+    #[test]
+    fn accepts_keys_of_any_pin_of_the_host() {
+        let other = format!("sha256/{}", STANDARD.encode([0; 32]));
+        let verifier = verifier(&format!("pins:\n  localhost: [{}, {}]\n", other, server_pin()));
+
+        assert!(verify(&verifier, "server.pem", "localhost").is_ok());
+        assert!(is_pin_mismatch(&verify(&verifier, "server2.pem", "localhost").unwrap_err()));
+    }
+
+    #[test]
+    fn report_only_accepts_mismatches() {
+        let pins = format!("pins:\n  localhost: [sha256/{}]\n", STANDARD.encode([0; 32]));
+
+        assert!(is_pin_mismatch(&verify(&verifier(&pins), "server.pem", "localhost").unwrap_err()));
+        assert!(verify(&verifier(&format!("{}pins_report_only: true\n", pins)), "server.pem", "localhost").is_ok());
+    }
+
+    #[test]
+    fn pins_come_after_chain_validation() {
+        let verifier = verifier(&format!("pins:\n  localhost: [{}]\n", server_pin()));
+
+        // the names of the certificate are localhost and 127.0.0.1 only
+        let error = verify(&verifier, "server.pem", "example.com").unwrap_err();
+        assert!(!is_pin_mismatch(&error), "{:?}", error);
+        let error = verify(&verifier, "other-client.pem", "localhost").unwrap_err();
+        assert!(!is_pin_mismatch(&error), "{:?}", error);
+    }
+
+    #[test]
+    fn parses_serials() {
+        assert_eq!(parse_serial("0a:1b:2c"), Some(vec![0x0a, 0x1b, 0x2c]));
+        assert_eq!(parse_serial("0A1B2C"), Some(vec![0x0a, 0x1b, 0x2c]));
+        // leading zeros are no part of the number
+        assert_eq!(parse_serial("00:a1b"), Some(vec![0x0a, 0x1b]));
+        assert_eq!(parse_serial("0x1b"), None);
+        assert_eq!(parse_serial(":"), None);
+    }
+
+    #[test]
+    fn client_auth_needs_certificate_and_roots() {
+        let config = |yaml: &str| listener_config(&serde_yaml::from_str(yaml).unwrap(), false).map(|v| v.is_some());
+        let cert = format!("cert_pem: {}\nkey_pem: {}\n", fixture("server.pem"), fixture("server-key.pem"));
+
+        assert_eq!(config("{}"), Ok(false));
+        assert_eq!(config(&cert), Ok(true));
+        assert_eq!(config("client_auth: required\n"),
+                   Err(String::from("tls.client_auth requires tls.cert_pem and tls.key_pem")));
+        assert_eq!(config(&format!("{}client_auth: optional\n", cert)),
+                   Err(String::from("tls.client_auth requires tls.client_ca")));
+        let revoked = format!("{}client_auth: required\nclient_ca: {}\nrevoked_serials: [xyz]\n",
+                              cert, fixture("ca.pem"));
+        assert_eq!(config(&revoked), Err(String::from("invalid tls.revoked_serials entry \"xyz\" (must be hex)")));
+    }
+
+    fn upstream(yaml: &str) -> Result<UpstreamTls, String> {
+        let yaml = format!("use_system_roots: false\nca_bundle: {}\n{}", fixture("ca.pem"), yaml);
+        UpstreamTls::from_config(&serde_yaml::from_str(&yaml).unwrap(), true)
+    }
+
+    #[test]
+    fn picks_client_config_by_host() {
+        let tls = upstream("\
+insecure_hosts: [\"*.dev.example.com\"]
+hosts:
+  strict.dev.example.com: {verify: true}
+  legacy.example.com: {min_version: \"1.2\", sni: legacy.internal}
+").unwrap();
+
+        assert!(Arc::ptr_eq(&tls.config("example.com"), &tls.default));
+        assert!(Arc::ptr_eq(&tls.config("api.dev.example.com"), &tls.insecure));
+        // settings of the host take precedence over insecure_hosts
+        assert!(Arc::ptr_eq(&tls.config("strict.dev.example.com"), &tls.hosts[1].verified));
+        assert!(Arc::ptr_eq(&tls.config("legacy.example.com"), &tls.hosts[0].verified));
+        assert_eq!(tls.server_name("legacy.example.com").unwrap(), ServerName::try_from("legacy.internal").unwrap());
+        assert_eq!(tls.server_name("example.com").unwrap(), ServerName::try_from("example.com").unwrap());
+    }
+
+    #[test]
+    fn verify_of_host_overrides_global_one() {
+        let tls = upstream("verify: false\nhosts:\n  bank.example.com: {verify: true}\n").unwrap();
+
+        assert!(Arc::ptr_eq(&tls.config("example.com"), &tls.insecure));
+        assert!(Arc::ptr_eq(&tls.config("bank.example.com"), &tls.hosts[0].verified));
+    }
+
+    #[test]
+    fn roots_are_required() {
+        let config = serde_yaml::from_str("use_system_roots: false\n").unwrap();
+        assert_eq!(UpstreamTls::from_config(&config, true).err(),
+                   Some(String::from("upstream_tls.ca_bundle is required when use_system_roots is false")));
+        let error = upstream("hosts:\n  example.com: {sni: \"1.2.3.4\"}\n").err().unwrap();
+        assert!(error.contains("must be a DNS name"), "{}", error);
+    }
+
+    #[test]
+    fn tells_rejected_client_certificates_apart() {
+        use rustls::AlertDescription::*;
+        for alert in [BadCertificate, CertificateRequired, UnknownCA, CertificateRevoked] {
+            assert!(is_client_cert_rejected(&rustls::Error::AlertReceived(alert)), "{:?}", alert);
+        }
+        assert!(!is_client_cert_rejected(&rustls::Error::AlertReceived(HandshakeFailure)));
+        assert!(!is_client_cert_rejected(&rustls::Error::InvalidCertificate(CertificateError::UnknownIssuer)));
+    }
+
+    #[test]
+    fn refuses_malformed_pins() {
+        for pin in ["sha1/Kk0txGJkyhw8e+YCZoDvhR3m2ed8qIJed101LOF6bkg=", "sha256/not base64", "sha256/AAAA"] {
+            let config: UpstreamTlsConfig = serde_yaml::from_str(&format!("pins:\n  localhost: [\"{}\"]\n", pin))
+                .unwrap();
+            let error = Pins::from_config(&config).unwrap_err();
+            assert!(error.starts_with("invalid upstream_tls.pins entry"), "{}", error);
+        }
+    }
+
+    #[test]
+    fn reads_not_after_of_both_time_formats() {
+        let der = |name: &str| load_certs(&fixture(name)).unwrap().remove(0);
+        // UTCTime before 2050
+        assert_eq!(not_after(&der("expired.pem")), Some(978_307_200));
+        // GeneralizedTime from 2050 on, as `openssl x509 -enddate` prints it
+        let expected = chrono::NaiveDate::from_ymd_opt(2126, 9, 21).unwrap().and_hms_opt(5, 2, 11).unwrap();
+        assert_eq!(not_after(&der("server.pem")), Some(expected.timestamp()));
+        assert_eq!(not_after(b"\x30\x03\x02\x01\x01"), None);
+    }
+
+    #[test]
+    fn expired_certificate_is_loaded_but_never_reloaded() {
+        let cert = ReloadingCert::load(&fixture("expired.pem"), &fixture("expired-key.pem")).unwrap();
+
+        let info = cert.info("listener");
+        assert_eq!(info.not_after.as_deref(), Some("2001-01-01T00:00:00Z"));
+        assert!(info.expires_in_seconds.unwrap() < 0);
+        let error = check_expiry(&cert.key(), "expired.pem").unwrap_err();
+        assert_eq!(error, "certificate \"expired.pem\" expired");
+    }
+}
@@ -0,0 +1,77 @@
+use hyper::{Body, Request, Response};
+use hyper::header::{HeaderValue, ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, ETAG,
+                    TRANSFER_ENCODING};
+use regex::bytes::Regex;
+
+use crate::config::BodyTransformConfig;
+use crate::mirror;
+
+
+struct Transform {
+    /// Lowercase media type, e.g. `text/html`
+    content_type: String,
+    find: Regex,
+    replace: String,
+}
+
+/// Regex find-and-replace on the bodies of forwarded responses, e.g. to point the API endpoints of
+/// HTML pages to a development server without changing the origin.
+///
+/// The proxy can not decompress bodies, so requests ask upstreams for uncompressed responses and
+/// transformed bodies are sent to clients uncompressed; responses compressed anyway pass unchanged.
+pub struct BodyTransforms {
+    transforms: Vec<Transform>,
+    max_bytes: u64,
+}
+
+impl BodyTransforms {
+    /// Returns `None` when `body_transforms` is empty
+    pub fn from_config(config: &[BodyTransformConfig], max_bytes: u64) -> Result<Option<BodyTransforms>, String> {
+        if config.is_empty() {
+            return Ok(None);
+        }
+        let transforms = config.iter().map(|t| {
+            let find = Regex::new(&t.find)
+                .map_err(|e| format!("invalid body_transforms regex {:?} of {:?}; {}", t.find, t.content_type_match, e))?;
+            Ok(Transform { content_type: t.content_type_match.to_lowercase(), find, replace: t.replace.clone() })
+        }).collect::<Result<_, String>>()?;
+        Ok(Some(BodyTransforms { transforms, max_bytes }))
+    }
+
+    /// Asks the upstream for an uncompressed response, bodies are only transformed without encoding
+    pub fn prepare(&self, req: &mut Request<Body>) {
+        if req.headers().contains_key(ACCEPT_ENCODING) {
+            req.headers_mut().insert(ACCEPT_ENCODING, HeaderValue::from_static("identity"));
+        }
+    }
+
+    /// Applies the transforms matching the content type of the response in order, returns whether
+    /// it was transformed: `None` when no transform matches or the body is encoded, `Some(false)` when
+    /// it exceeds `body_transform_max_bytes`
+    pub async fn apply(&self, resp: Response<Body>) -> Result<(Response<Body>, Option<bool>), hyper::Error> {
+        let media_type = resp.headers().get(CONTENT_TYPE).and_then(|v| v.to_str().ok())
+            .and_then(|v| v.split(';').next()).map(|v| v.trim().to_lowercase()).unwrap_or_default();
+        let encoded = resp.headers().get(CONTENT_ENCODING).map(|v| v != "identity").unwrap_or(false);
+        let matching: Vec<_> = self.transforms.iter().filter(|t| t.content_type == media_type).collect();
+        if matching.is_empty() || encoded {
+            return Ok((resp, None));
+        }
+        let (mut parts, body) = resp.into_parts();
+        let (body, bytes) = mirror::buffer_body(body, self.max_bytes).await?;
+        let bytes = match bytes {
+            Some(v) => v,
+            None => return Ok((Response::from_parts(parts, body), Some(false)))
+        };
+        let mut transformed = bytes.to_vec();
+        for transform in matching {
+            transformed = transform.find.replace_all(&transformed, transform.replace.as_bytes()).into_owned();
+        }
+        if transformed != bytes {
+            // a validator of the original body would let caches mix both
+            parts.headers.remove(ETAG);
+            parts.headers.remove(TRANSFER_ENCODING);
+            parts.headers.insert(CONTENT_LENGTH, HeaderValue::from(transformed.len()));
+        }
+        Ok((Response::from_parts(parts, Body::from(transformed)), Some(true)))
+    }
+}
@@ -0,0 +1,168 @@
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use log::debug;
+use tokio::net::TcpStream;
+
+use crate::config::ConnectPrewarmConfig;
+use crate::dial::Dialer;
+use crate::metrics::Metrics;
+use crate::outgoing::{OutgoingLimiter, OutgoingPermit};
+use crate::target::{host_matches, Target};
+
+
+/// Connections dialed ahead to the targets of CONNECT tunnels, so a burst of tunnels to one
+/// target, e.g. of browser tabs loading from a CDN, does not wait for connecting one by one.
+///
+/// Every tunnel opened to a target fills its idle connections up to `connections` in the
+/// background, the next tunnels take one of them instead of dialing. Idle connections are
+/// closed after `idle_timeout`.
+///
+/// An idle connection holds a slot of `max_outgoing_per_host` like a tunnel and hands it to the
+/// tunnel taking it, so connections to a host never exceed its limit. Filling stops at a host
+/// without free slots, tunnels waiting for one go first.
+pub struct TunnelPool {
+    connections: usize,
+    idle_timeout: Duration,
+    /// Host patterns of the targets, empty means all
+    hosts: Vec<String>,
+    dialer: Dialer,
+    outgoing: Arc<OutgoingLimiter>,
+    metrics: Arc<Metrics>,
+    idle: Mutex<Idle>,
+}
+
+#[derive(Default)]
+struct Idle {
+    /// Idle connections by `host:port` of the target
+    by_target: HashMap<String, Vec<IdleConnection>>,
+    /// Targets connections are being dialed to
+    filling: HashSet<String>,
+}
+
+struct IdleConnection {
+    stream: TcpStream,
+    addr: SocketAddr,
+    since: Instant,
+    /// Slot of the target host, `None` for unlimited hosts
+    permit: Option<OutgoingPermit>,
+}
+
+/// Idle connection taken by a tunnel, with the slot of its host the tunnel holds from now on
+pub struct Taken {
+    pub stream: TcpStream,
+    pub addr: SocketAddr,
+    pub permit: Option<OutgoingPermit>,
+}
+
+impl TunnelPool {
+    /// Returns `None` when `connect_prewarm` is not configured
+    pub fn from_config(config: &Option<ConnectPrewarmConfig>, dialer: Dialer, outgoing: Arc<OutgoingLimiter>,
+                       metrics: Arc<Metrics>) -> Option<Arc<TunnelPool>> {
+        let config = config.as_ref()?;
+        Some(Arc::new(TunnelPool {
+            connections: config.connections,
+            idle_timeout: Duration::from_millis(config.idle_timeout_ms),
+            hosts: config.hosts.clone(),
+            dialer,
+            outgoing,
+            metrics,
+            idle: Mutex::new(Idle::default()),
+        }))
+    }
+
+    fn applies(&self, target: &Target) -> bool {
+        self.hosts.is_empty() || self.hosts.iter().any(|pattern| host_matches(pattern, &target.host))
+    }
+
+    /// Takes an idle connection to `target` which is still open, connections to addresses no longer
+    /// among `addrs` the target resolves to are closed
+    pub fn take(&self, target: &Target, addrs: &[SocketAddr]) -> Option<Taken> {
+        if !self.applies(target) {
+            return None;
+        }
+        let taken = {
+            let key = target.to_string();
+            let mut idle = self.idle.lock().unwrap();
+            let mut taken = None;
+            while let Some(c) = idle.by_target.get_mut(&key).and_then(|c| c.pop()) {
+                if c.since.elapsed() >= self.idle_timeout || !addrs.contains(&c.addr) {
+                    continue;
+                }
+                if let Some(stream) = open(c.stream) {
+                    taken = Some(Taken { stream, addr: c.addr, permit: c.permit });
+                    break;
+                }
+            }
+            if idle.by_target.get(&key).map(|c| c.is_empty()).unwrap_or(false) {
+                idle.by_target.remove(&key);
+            }
+            taken
+        };
+        let result = if taken.is_some() { "hit" } else { "miss" };
+        self.metrics.inc("connect_prewarm_total", &[("result", result)]);
+        taken
+    }
+
+    /// Dials connections to `target` in the background until `connections` are idle, unless that
+    /// is already done
+    pub fn refill(self: &Arc<Self>, target: &Target, addrs: Vec<SocketAddr>) {
+        let key = target.to_string();
+        if !self.applies(target) || !self.idle.lock().unwrap().filling.insert(key.clone()) {
+            return;
+        }
+        let pool = self.clone();
+        let host = target.host.clone();
+        tokio::task::spawn(async move {
+            let idle = pool.idle.lock().unwrap().by_target.get(&key).map(|c| c.len()).unwrap_or(0);
+            for _ in idle..pool.connections {
+                let permit = match pool.outgoing.try_acquire(&host) {
+                    Ok(v) => v,
+                    Err(limit) => {
+                        debug!("stopped prewarming connections to {}, all {} slots of the host are taken", key, limit);
+                        break;
+                    }
+                };
+                match pool.dialer.connect(&addrs).await {
+                    Ok((stream, addr)) => {
+                        let connection = IdleConnection { stream, addr, since: Instant::now(), permit };
+                        pool.idle.lock().unwrap().by_target.entry(key.clone()).or_default().push(connection);
+                    },
+                    Err(e) => {
+                        debug!("can not prewarm connections to {}; tried {}", key, e);
+                        break;
+                    }
+                }
+            }
+            pool.idle.lock().unwrap().filling.remove(&key);
+            // the connections dialed last expire then, younger ones have a sweep of their own
+            tokio::time::sleep(pool.idle_timeout).await;
+            pool.expire(&key);
+        });
+    }
+
+    fn expire(&self, key: &str) {
+        let mut idle = self.idle.lock().unwrap();
+        if let Some(connections) = idle.by_target.get_mut(key) {
+            connections.retain(|c| c.since.elapsed() < self.idle_timeout);
+            if connections.is_empty() {
+                idle.by_target.remove(key);
+            }
+        }
+    }
+}
+
+/// Returns the stream unless the server closed it while idle; data a server sends first, e.g. the
+/// banner of SSH, is left for the client
+fn open(stream: TcpStream) -> Option<TcpStream> {
+    let stream = stream.into_std().ok()?;
+    match stream.peek(&mut [0u8; 1]) {
+        Ok(0) => return None,
+        Ok(_) => {},
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => {},
+        Err(_) => return None
+    }
+    TcpStream::from_std(stream).ok()
+}
@@ -0,0 +1,105 @@
+use std::io;
+use hyper::{Body, Response, Uri};
+use hyper::http::{header, HeaderValue, StatusCode};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::config::Config;
+use crate::error_page::{ErrorKind, ProxyError};
+use crate::target::Target;
+
+
+/// Port of the parent proxy when `upstream_proxy` has none, the usual one of HTTP proxies
+const DEFAULT_PORT: u16 = 3128;
+/// Longest response head of the parent proxy which is read
+const MAX_HEAD_BYTES: usize = 16 * 1024;
+/// Longest body of a challenge which is relayed to the client, longer ones are dropped
+const MAX_BODY_BYTES: usize = 64 * 1024;
+
+
+/// Parent HTTP proxy CONNECT tunnels are opened through
+pub struct UpstreamProxy {
+    pub target: Target,
+}
+
+impl UpstreamProxy {
+    /// Returns `None` when `upstream_proxy` is not configured
+    pub fn from_config(config: &Config) -> Result<Option<UpstreamProxy>, String> {
+        let address = match &config.upstream_proxy {
+            Some(v) => v,
+            None => return Ok(None)
+        };
+        let target = address.parse::<Uri>().ok()
+            .and_then(|uri| Target::from_uri(&uri, DEFAULT_PORT).ok())
+            .ok_or_else(|| format!("invalid upstream_proxy {:?} (must be host:port)", address))?;
+        Ok(Some(UpstreamProxy { target }))
+    }
+
+    /// Asks the parent to open a tunnel to `target` over `stream`, with the credentials of the client.
+    ///
+    /// Returns `None` once the tunnel is established. Otherwise returns the status of the parent and
+    /// the answer for the client: a `407` challenge is relayed as is so the client can authenticate
+    /// with the parent, other refusals become a `502`.
+    pub async fn connect(&self, stream: &mut TcpStream, target: &Target, credentials: Option<&HeaderValue>)
+        -> io::Result<Option<(StatusCode, Response<Body>)>> {
+        let mut request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n", target, target).into_bytes();
+        if let Some(credentials) = credentials {
+            request.extend_from_slice(b"Proxy-Authorization: ");
+            request.extend_from_slice(credentials.as_bytes());
+            request.extend_from_slice(b"\r\n");
+        }
+        request.extend_from_slice(b"\r\n");
+        stream.write_all(&request).await?;
+
+        let head = read_head(stream).await?;
+        let head = String::from_utf8_lossy(&head);
+        let mut lines = head.split("\r\n");
+        let status = lines.next()
+            .and_then(|line| line.split(' ').nth(1))
+            .and_then(|code| code.parse::<u16>().ok())
+            .and_then(|code| StatusCode::from_u16(code).ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid status line"))?;
+        if status.is_success() {
+            return Ok(None);
+        }
+        if status != StatusCode::PROXY_AUTHENTICATION_REQUIRED {
+            let message = format!("upstream proxy {} answered {} to CONNECT {}", self.target, status, target);
+            let error = ProxyError::new(ErrorKind::UpstreamConnectFailed, message);
+            return Ok(Some((status, error.into_response(StatusCode::BAD_GATEWAY))));
+        }
+
+        let mut resp = ProxyError::new(ErrorKind::AuthRequired, format!("upstream proxy {} requires authentication",
+                                                                        self.target))
+            .into_response(status);
+        let mut length = 0;
+        for (name, value) in lines.filter_map(|line| line.split_once(':')) {
+            let value = value.trim();
+            if name.eq_ignore_ascii_case("content-length") {
+                length = value.parse::<usize>().unwrap_or(0);
+            } else if name.eq_ignore_ascii_case("proxy-authenticate") || name.eq_ignore_ascii_case("content-type") {
+                if let (Ok(name), Ok(value)) = (name.parse::<header::HeaderName>(), HeaderValue::from_str(value)) {
+                    resp.headers_mut().append(name, value);
+                }
+            }
+        }
+        if length > 0 && length <= MAX_BODY_BYTES {
+            let mut body = vec![0; length];
+            stream.read_exact(&mut body).await?;
+            *resp.body_mut() = Body::from(body);
+        }
+        Ok(Some((status, resp)))
+    }
+}
+
+/// Reads a response head up to the empty line, byte by byte so nothing of the tunnel
+/// which follows it is consumed
+async fn read_head(stream: &mut TcpStream) -> io::Result<Vec<u8>> {
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_HEAD_BYTES {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "response head is too long"));
+        }
+        head.push(stream.read_u8().await?);
+    }
+    Ok(head)
+}
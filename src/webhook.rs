@@ -0,0 +1,248 @@
+use std::sync::Arc;
+use std::time::Duration;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use hyper::body::Bytes;
+use hyper::{Body, Request, Response, Uri};
+use hyper::http::{header, HeaderMap, HeaderValue, StatusCode};
+use hyper::http::header::HeaderName;
+use hyper::http::request::Parts;
+
+use crate::HttpClient;
+use crate::config::Config;
+use crate::error_page::{ErrorKind, ProxyError};
+use crate::metrics::Metrics;
+use crate::mirror;
+use crate::privacy::Peer;
+use crate::redact::QueryRedaction;
+
+
+/// Metadata and body of a forwarded request, POSTed to the webhook as JSON:
+///
+/// ```json
+/// {
+///   "client": "10.0.0.7:51234",
+///   "identity": "alice",
+///   "method": "POST",
+///   "uri": "http://example.com/upload",
+///   "headers": [["content-type", "text/plain"], ["content-length", "5"]],
+///   "body": "aGVsbG8="
+/// }
+/// ```
+#[derive(Debug, Serialize)]
+struct WebhookRequest<'a> {
+    /// Address of the client
+    client: String,
+    /// Name of the client certificate, `null` when the client presented none
+    identity: Option<&'a str>,
+    method: &'a str,
+    /// Absolute uri of the request
+    uri: String,
+    /// Every header as a `[name, value]` pair in request order, names are lowercase
+    headers: Vec<(&'a str, String)>,
+    /// Base64 of the body
+    body: String,
+}
+
+/// Decision of the webhook, the answer to a `WebhookRequest`:
+///
+/// ```json
+/// {
+///   "action": "allow",
+///   "headers": [["content-type", "text/plain"]],
+///   "body": "aGk="
+/// }
+/// ```
+///
+/// `headers` and `body` are optional and replace those of the request when present,
+/// `content-length` is set from a replaced body. A denial may give a `reason` which
+/// is sent to the client: `{"action": "deny", "reason": "contains card numbers"}`.
+#[derive(Debug, Deserialize)]
+struct WebhookResponse {
+    action: Action,
+    #[serde(default)]
+    reason: Option<String>,
+    #[serde(default)]
+    headers: Option<Vec<(String, String)>>,
+    #[serde(default)]
+    body: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Action {
+    Allow,
+    Deny,
+}
+
+/// Validated `WebhookResponse`
+enum Decision {
+    Allow { headers: Option<HeaderMap>, body: Option<Bytes> },
+    Deny(Option<String>),
+}
+
+/// What happens to a request after the webhook saw it
+pub enum Outcome {
+    /// The request to forward, possibly modified
+    Forward(Request<Body>),
+    /// The answer to the client instead of forwarding
+    Respond(Response<Body>),
+}
+
+
+/// External service which sees every forwarded plain-HTTP request and allows, rewrites or denies it
+pub struct Webhook {
+    uri: Uri,
+    timeout: Duration,
+    max_body_bytes: u64,
+    fail_open: bool,
+    log_query: QueryRedaction,
+    metrics: Arc<Metrics>,
+}
+
+impl Webhook {
+    /// Returns `None` when `webhook.url` is not configured
+    pub fn from_config(config: &Config, metrics: Arc<Metrics>) -> Result<Option<Webhook>, String> {
+        let log_query = QueryRedaction::from_config(config);
+        let config = &config.webhook;
+        let url = match &config.url {
+            Some(v) => v,
+            None => return Ok(None)
+        };
+        let uri = match url.parse::<Uri>() {
+            Ok(v) if matches!(v.scheme_str(), Some("http") | Some("https")) && v.authority().is_some() => v,
+            _ => return Err(format!("invalid webhook url {:?} (must be an absolute uri like http://host:port/path)",
+                                    url))
+        };
+        Ok(Some(Webhook {
+            uri,
+            timeout: Duration::from_millis(config.timeout_ms),
+            max_body_bytes: config.max_body_bytes,
+            fail_open: config.fail_open,
+            log_query,
+            metrics,
+        }))
+    }
+
+    /// Sends the request to the webhook and applies its decision.
+    ///
+    /// A body larger than `max_body_bytes`, a timeout or an invalid answer is a failure, the request
+    /// is then forwarded unchanged with `fail_open` and answered 502 otherwise.
+    pub async fn inspect(&self, client: &HttpClient, req: Request<Body>, peer: Peer, identity: Option<&str>)
+        -> Result<Outcome, hyper::Error> {
+        let (mut parts, body) = req.into_parts();
+        let (body, bytes) = mirror::buffer_body(body, self.max_body_bytes).await?;
+        let bytes = match bytes {
+            Some(v) => v,
+            None => {
+                let reason = format!("request body exceeds {} bytes", self.max_body_bytes);
+                return Ok(self.fail(Request::from_parts(parts, body), peer, reason));
+            }
+        };
+        let decision = match tokio::time::timeout(self.timeout, self.call(client, &parts, &bytes, peer, identity)).await {
+            Ok(Ok(v)) => v,
+            Ok(Err(e)) => return Ok(self.fail(Request::from_parts(parts, Body::from(bytes)), peer, e)),
+            Err(_) => {
+                let reason = format!("no answer in {:?}", self.timeout);
+                return Ok(self.fail(Request::from_parts(parts, Body::from(bytes)), peer, reason));
+            }
+        };
+        match decision {
+            Decision::Allow { headers: None, body: None } => {
+                self.metrics.inc("webhook_requests_total", &[("result", "allowed")]);
+                Ok(Outcome::Forward(Request::from_parts(parts, Body::from(bytes))))
+            },
+            Decision::Allow { headers, body } => {
+                debug!("client {:?}: webhook modified {} {} (headers {}, body {})", peer, parts.method,
+                       self.log_query.uri(&parts.uri), headers.is_some(), body.is_some());
+                self.metrics.inc("webhook_requests_total", &[("result", "modified")]);
+                if let Some(headers) = headers {
+                    parts.headers = headers;
+                }
+                let bytes = match body {
+                    Some(body) => {
+                        parts.headers.remove(header::TRANSFER_ENCODING);
+                        parts.headers.insert(header::CONTENT_LENGTH, HeaderValue::from(body.len()));
+                        body
+                    },
+                    None => bytes
+                };
+                Ok(Outcome::Forward(Request::from_parts(parts, Body::from(bytes))))
+            },
+            Decision::Deny(reason) => {
+                let message = match reason {
+                    Some(reason) => format!("denied by webhook: {}", reason),
+                    None => String::from("denied by webhook")
+                };
+                info!("client {:?}: {} {} {}", peer, parts.method, self.log_query.uri(&parts.uri), message);
+                self.metrics.inc("webhook_requests_total", &[("result", "denied")]);
+                let error = ProxyError::new(ErrorKind::PolicyBlocked, message);
+                Ok(Outcome::Respond(error.into_response(StatusCode::FORBIDDEN)))
+            }
+        }
+    }
+
+    async fn call(&self, client: &HttpClient, parts: &Parts, body: &Bytes, peer: Peer, identity: Option<&str>)
+        -> Result<Decision, String> {
+        let payload = WebhookRequest {
+            client: peer.to_string(),
+            identity,
+            method: parts.method.as_str(),
+            uri: parts.uri.to_string(),
+            headers: parts.headers.iter()
+                .map(|(k, v)| (k.as_str(), String::from_utf8_lossy(v.as_bytes()).into_owned()))
+                .collect(),
+            body: STANDARD.encode(body),
+        };
+        let payload = serde_json::to_vec(&payload).map_err(|e| format!("can not encode request; err = {}", e))?;
+        let req = Request::post(self.uri.clone())
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(payload))
+            .map_err(|e| format!("can not build request; err = {}", e))?;
+        let resp = client.request(req).await.map_err(|e| format!("err = {}", e))?;
+        let status = resp.status();
+        let answer = hyper::body::to_bytes(resp.into_body()).await.map_err(|e| format!("err = {}", e))?;
+        if !status.is_success() {
+            return Err(format!("status {}", status));
+        }
+        let answer: WebhookResponse = serde_json::from_slice(&answer)
+            .map_err(|e| format!("invalid answer; err = {}", e))?;
+        if answer.action == Action::Deny {
+            return Ok(Decision::Deny(answer.reason));
+        }
+        let headers = match answer.headers {
+            Some(pairs) => {
+                let mut headers = HeaderMap::new();
+                for (k, v) in pairs {
+                    let name = HeaderName::from_bytes(k.as_bytes())
+                        .map_err(|_| format!("invalid header name {:?} in answer", k))?;
+                    let value = HeaderValue::from_str(&v)
+                        .map_err(|_| format!("invalid value of header {:?} in answer", k))?;
+                    headers.append(name, value);
+                }
+                Some(headers)
+            },
+            None => None
+        };
+        let body = match answer.body {
+            Some(v) => Some(Bytes::from(STANDARD.decode(v).map_err(|e| format!("invalid body in answer; err = {}", e))?)),
+            None => None
+        };
+        Ok(Decision::Allow { headers, body })
+    }
+
+    fn fail(&self, req: Request<Body>, peer: Peer, reason: String) -> Outcome {
+        self.metrics.inc("webhook_requests_total", &[("result", "failed")]);
+        if self.fail_open {
+            warn!("client {:?}: webhook failed, forwarding {} {} unchanged; {}", peer, req.method(),
+                  self.log_query.uri(req.uri()), reason);
+            return Outcome::Forward(req);
+        }
+        warn!("client {:?}: webhook failed, refusing {} {}; {}", peer, req.method(), self.log_query.uri(req.uri()),
+              reason);
+        let message = format!("request webhook failed: {}", reason);
+        Outcome::Respond(ProxyError::new(ErrorKind::InternalError, message).into_response(StatusCode::BAD_GATEWAY))
+    }
+}
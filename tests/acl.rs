@@ -0,0 +1,61 @@
+//! Destination acl with rules read from `allow_file` and `deny_file`, which are reloaded without a signal
+mod helpers;
+
+use helpers::proxy::TempDir;
+use helpers::{client, Proxy, RawServer};
+
+
+/// Starts the proxy with `deny_file` holding `rules`
+fn denying(rules: &str) -> Proxy {
+    let dir = TempDir::new();
+    dir.write("blocked.txt", rules);
+    Proxy::start_in(dir, "acl:\n  deny_file: \"{dir}/blocked.txt\"\n", &[], &[])
+}
+
+#[tokio::test]
+async fn edited_deny_file_applies_without_signal() {
+    let server = RawServer::echo();
+    let proxy = denying("# blocked destinations\nads.example\n");
+    let authority = server.addr.to_string();
+    assert_eq!(client::connect(proxy.addr, &authority, &[]).await.0, 200);
+
+    proxy.dir.write("blocked.txt", format!("# blocked destinations\nads.example\n{}  # the test server\n", authority));
+    // the files are checked every 2 seconds
+    assert!(proxy.wait_log("loaded 2 acl deny rules").await, "{}", proxy.log());
+
+    let (status, _) = client::connect(proxy.addr, &authority, &[]).await;
+    assert_eq!(status, 403);
+    assert_eq!(proxy.metric(r#"acl_file_rules{list="deny"}"#).await, Some(2.0));
+}
+
+#[tokio::test]
+async fn half_written_deny_file_keeps_the_previous_rules() {
+    let server = RawServer::echo();
+    let authority = server.addr.to_string();
+    let proxy = denying(&format!("{}\n", authority));
+    let loaded = proxy.metric(r#"acl_file_loaded_timestamp_seconds{list="deny"}"#).await;
+
+    proxy.dir.write("blocked.txt", format!("{}\nads.example:\n", authority));
+    assert!(proxy.wait_log("can not reload acl deny_file").await, "{}", proxy.log());
+
+    assert!(proxy.log().contains("blocked.txt:2: invalid acl rule \"ads.example:\""), "{}", proxy.log());
+    assert_eq!(client::connect(proxy.addr, &authority, &[]).await.0, 403);
+    assert_eq!(proxy.metric(r#"acl_file_rules{list="deny"}"#).await, Some(1.0));
+    assert_eq!(proxy.metric(r#"acl_file_loaded_timestamp_seconds{list="deny"}"#).await, loaded);
+}
+
+#[tokio::test]
+async fn ipv6_literal_rules_match_every_spelling() {
+    let v6 = RawServer::listen("[::1]:0", |_| async {});
+    let upstream = helpers::MockUpstream::new().on_get("/", 200, "ok").listen("[::1]:0").build();
+    let proxy = Proxy::start("acl:\n  deny: [\"[::1]\"]\n");
+
+    let (status, _) = client::connect(proxy.addr, &format!("[0:0::1]:{}", v6.addr.port()), &[]).await;
+    let forwarded = client::get(proxy.addr, &format!("http://[0::1]:{}/", upstream.addr.port())).await;
+
+    assert_eq!(status, 403);
+    assert_eq!(forwarded.status, 403);
+    assert!(proxy.log().contains("denied by acl rule \"[::1]\""), "{}", proxy.log());
+    assert_eq!(v6.connections(), 0);
+    assert_eq!(upstream.connections(), 0);
+}
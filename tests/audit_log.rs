@@ -0,0 +1,103 @@
+//! Refusals written to `audit_log`, one category per kind of refusal
+mod helpers;
+
+use std::time::Duration;
+use hyper::{Body, Request};
+use helpers::{client, Connect, MockUpstream, Proxy};
+
+
+const AUDIT_LOG: &str = "audit_log:\n  path: \"{dir}/audit.log\"\n  hash_chain: true\n";
+
+/// Entries of the audit log of `proxy`, waiting a moment for them to be written
+async fn entries(proxy: &Proxy) -> Vec<serde_json::Value> {
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    proxy.dir.read("audit.log").lines().map(|l| serde_json::from_str(l).unwrap()).collect()
+}
+
+#[tokio::test]
+async fn acl_denial_is_audited() {
+    let proxy = Proxy::start(&format!("{}acl:\n  deny: [blocked.example]\n", AUDIT_LOG));
+
+    let (status, _) = client::connect(proxy.addr, "blocked.example:443", &[]).await;
+
+    assert_eq!(status, 403);
+    let entries = entries(&proxy).await;
+    assert_eq!(entries.len(), 1, "{:?}", entries);
+    assert_eq!(entries[0]["category"], "policy_blocked");
+    assert_eq!(entries[0]["method"], "CONNECT");
+    assert_eq!(entries[0]["destination"], "blocked.example:443");
+    assert_eq!(entries[0]["status"], 403);
+    assert_eq!(entries[0]["prev"], serde_json::Value::Null);
+}
+
+#[tokio::test]
+async fn auth_challenge_of_parent_is_audited() {
+    let parent = MockUpstream::new().on_connect("example.com:443", Connect::Refuse(407)).build();
+    let proxy = Proxy::start(&format!("{}upstream_proxy: {}\n", AUDIT_LOG, parent.authority()));
+
+    let (status, _) = client::connect(proxy.addr, "example.com:443", &[]).await;
+
+    assert_eq!(status, 407);
+    let entries = entries(&proxy).await;
+    assert_eq!(entries.len(), 1, "{:?}", entries);
+    assert_eq!(entries[0]["category"], "auth_required");
+    assert_eq!(entries[0]["status"], 407);
+}
+
+#[tokio::test]
+async fn tunnel_limit_is_audited() {
+    let server = helpers::RawServer::echo();
+    let proxy = Proxy::start(&format!("{}limits:\n  max_tunnels: 1\n", AUDIT_LOG));
+
+    let (status, _open) = client::connect(proxy.addr, &server.addr.to_string(), &[]).await;
+    assert_eq!(status, 200);
+    let (status, _) = client::connect(proxy.addr, &server.addr.to_string(), &[]).await;
+
+    assert_eq!(status, 503);
+    let entries = entries(&proxy).await;
+    assert_eq!(entries.len(), 1, "{:?}", entries);
+    assert_eq!(entries[0]["category"], "rate_limited");
+    assert_eq!(entries[0]["reason"], "too many open tunnels");
+}
+
+#[tokio::test]
+async fn loop_is_audited() {
+    let upstream = MockUpstream::new().on_get("/", 200, "ok").build();
+    let proxy = Proxy::start(&format!("{}via_pseudonym: edge-1\n", AUDIT_LOG));
+
+    let req = Request::get(upstream.url("/")).header("via", "1.1 edge-1").body(Body::empty()).unwrap();
+    let answer = client::request(proxy.addr, req).await;
+
+    assert_eq!(answer.status, 403);
+    let entries = entries(&proxy).await;
+    assert_eq!(entries.len(), 1, "{:?}", entries);
+    assert_eq!(entries[0]["category"], "loop_detected");
+    assert_eq!(entries[0]["destination"], upstream.url("/"));
+}
+
+#[tokio::test]
+async fn other_errors_are_not_audited() {
+    let upstream = MockUpstream::new().on_get("/", 200, "ok").build();
+    let proxy = Proxy::start(AUDIT_LOG);
+
+    let malformed = client::raw(proxy.addr, b"GET http://example.com/%zz HTTP/1.1\r\nHost: example.com\r\n\r\n").await;
+    let answer = client::get(proxy.addr, &upstream.url("/")).await;
+
+    assert_eq!(client::status_of(&malformed), 400);
+    assert_eq!(answer.status, 200);
+    assert_eq!(entries(&proxy).await.len(), 0);
+}
+
+#[tokio::test]
+async fn entries_are_chained() {
+    let proxy = Proxy::start(&format!("{}acl:\n  deny: [blocked.example]\n", AUDIT_LOG));
+
+    for _ in 0..3 {
+        client::connect(proxy.addr, "blocked.example:443", &[]).await;
+    }
+    assert_eq!(entries(&proxy).await.len(), 3);
+
+    let output = helpers::proxy::run(&["verify-audit-log", &proxy.dir.file("audit.log")], &[], &proxy.dir.path);
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "3 lines, hash chain intact\n");
+}
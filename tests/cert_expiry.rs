@@ -0,0 +1,59 @@
+//! Expiry of loaded certificates: the `tls_cert_expiry_seconds` gauge, `/admin/certificates` and warnings
+mod helpers;
+
+use helpers::{client, Proxy};
+
+
+/// Config presenting the certificate `name` of the fixtures to `localhost` upstreams
+fn client_cert(name: &str, more: &str) -> String {
+    format!("upstream_tls:
+  client_certs:
+    localhost:
+      cert_pem: \"{{fixtures}}/{name}.pem\"
+      key_pem: \"{{fixtures}}/{name}-key.pem\"
+{more}", name = name, more = more)
+}
+
+/// Value of the expiry gauge of the upstream client certificate `name`
+async fn expiry_gauge(proxy: &Proxy, name: &str) -> Option<f64> {
+    let cert = helpers::fixture(&format!("{}.pem", name));
+    proxy.metric(&format!("tls_cert_expiry_seconds{{role=\"upstream_client\",cert=\"{}\"}}", cert)).await
+}
+
+#[tokio::test]
+async fn expired_certificate_is_reported() {
+    let proxy = Proxy::start(&client_cert("expired", ""));
+    let cert = helpers::fixture("expired.pem");
+
+    let warning = format!("upstream_client certificate {:?} expired on 2001-01-01T00:00:00Z", cert);
+    assert!(proxy.wait_log(&warning).await, "{}", proxy.log());
+    let seconds = expiry_gauge(&proxy, "expired").await.unwrap();
+    let now = chrono::Utc::now().timestamp() as f64;
+    assert!((seconds - (978_307_200.0 - now)).abs() < 60.0, "{}", seconds);
+
+    let answer = client::get_origin(proxy.addr, "/admin/certificates").await;
+    let listed: serde_json::Value = serde_json::from_str(&answer.text()).unwrap();
+    assert_eq!(listed[0]["role"], "upstream_client");
+    assert_eq!(listed[0]["cert"], cert.as_str());
+    assert_eq!(listed[0]["not_after"], "2001-01-01T00:00:00Z");
+}
+
+#[tokio::test]
+async fn certificate_expiring_within_the_warning_days_is_reported() {
+    // the certificates of the fixtures are valid for 100 years
+    let proxy = Proxy::start(&client_cert("client", "tls:\n  expiry_warning_days: 40000\n"));
+
+    assert!(proxy.wait_log("upstream_client certificate").await, "{}", proxy.log());
+    let days = (expiry_gauge(&proxy, "client").await.unwrap() / (24.0 * 60.0 * 60.0)) as i64;
+    assert!(days > 36000, "{}", days);
+    let warning = format!("certificate {:?} expires in {} days on ", helpers::fixture("client.pem"), days);
+    assert!(proxy.log().contains(&warning), "{}", proxy.log());
+}
+
+#[tokio::test]
+async fn certificate_far_from_expiry_is_not_reported() {
+    let proxy = Proxy::start(&client_cert("client", ""));
+
+    assert!(expiry_gauge(&proxy, "client").await.unwrap() > 0.0);
+    assert!(!proxy.log().contains("upstream_client certificate"), "{}", proxy.log());
+}
@@ -0,0 +1,96 @@
+//! Idle connections of `connect_prewarm` taken by the next CONNECT tunnels to a target, and the slots of
+//! `max_outgoing_per_host` they hold
+mod helpers;
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use helpers::{client, Proxy, RawServer};
+
+
+/// Sends `ping` through the tunnel and expects it back
+async fn assert_echoes(tunnel: &mut TcpStream) {
+    tunnel.write_all(b"ping").await.unwrap();
+    let mut echoed = [0u8; 4];
+    tunnel.read_exact(&mut echoed).await.unwrap();
+    assert_eq!(&echoed, b"ping");
+}
+
+#[tokio::test]
+async fn next_tunnel_takes_an_idle_connection() {
+    let server = RawServer::echo();
+    let proxy = Proxy::start("connect_prewarm:\n  connections: 2\n");
+
+    let (first, mut first_tunnel) = client::connect(proxy.addr, &server.addr.to_string(), &[]).await;
+    assert_eq!(first, 200);
+    // the tunnel and the idle connections dialed after it
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert_eq!(server.connections(), 3);
+    let (second, mut second_tunnel) = client::connect(proxy.addr, &server.addr.to_string(), &[]).await;
+
+    assert_eq!(second, 200);
+    assert_echoes(&mut first_tunnel).await;
+    assert_echoes(&mut second_tunnel).await;
+    assert_eq!(proxy.metric(r#"connect_prewarm_total{result="miss"}"#).await, Some(1.0));
+    assert_eq!(proxy.metric(r#"connect_prewarm_total{result="hit"}"#).await, Some(1.0));
+    // the one taken is dialed again
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert_eq!(server.connections(), 4);
+}
+
+#[tokio::test]
+async fn connections_the_server_closed_while_idle_are_skipped() {
+    // echoes on the connection of the first tunnel and the ones dialed after the prewarmed ones,
+    // closes the prewarmed ones at once
+    let accepted = Arc::new(AtomicUsize::new(0));
+    let counted = accepted.clone();
+    let server = RawServer::start(move |stream| {
+        let n = counted.fetch_add(1, Ordering::SeqCst);
+        async move {
+            if n == 1 || n == 2 {
+                return;
+            }
+            let (mut rd, mut wr) = stream.into_split();
+            let _ = tokio::io::copy(&mut rd, &mut wr).await;
+        }
+    });
+    let proxy = Proxy::start("connect_prewarm:\n  connections: 2\n");
+
+    let (first, _first_tunnel) = client::connect(proxy.addr, &server.addr.to_string(), &[]).await;
+    assert_eq!(first, 200);
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    let (second, mut second_tunnel) = client::connect(proxy.addr, &server.addr.to_string(), &[]).await;
+
+    assert_eq!(second, 200);
+    assert_echoes(&mut second_tunnel).await;
+    assert_eq!(proxy.metric(r#"connect_prewarm_total{result="hit"}"#).await, None);
+    assert_eq!(proxy.metric(r#"connect_prewarm_total{result="miss"}"#).await, Some(2.0));
+}
+
+#[tokio::test]
+async fn idle_connections_hold_slots_of_the_host() {
+    let server = RawServer::echo();
+    let proxy = Proxy::start("\
+max_outgoing_per_host: 2
+outgoing_queue_timeout_ms: 0
+connect_prewarm:
+  connections: 4
+");
+
+    let (first, mut first_tunnel) = client::connect(proxy.addr, &server.addr.to_string(), &[]).await;
+    assert_eq!(first, 200);
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    // one idle connection in the second slot, not the four configured
+    assert_eq!(server.connections(), 2);
+    let (second, mut second_tunnel) = client::connect(proxy.addr, &server.addr.to_string(), &[]).await;
+    let (third, _) = client::connect(proxy.addr, &server.addr.to_string(), &[]).await;
+
+    assert_eq!(second, 200);
+    assert_eq!(third, 503);
+    assert_echoes(&mut first_tunnel).await;
+    assert_echoes(&mut second_tunnel).await;
+    assert_eq!(proxy.metric(r#"connect_prewarm_total{result="hit"}"#).await, Some(1.0));
+    assert_eq!(server.connections(), 2);
+}
@@ -0,0 +1,176 @@
+//! Hop-by-hop headers and the keep-alive of client connections, which are independent of the
+//! upstream ones
+mod helpers;
+
+use std::time::Duration;
+use hyper::{Body, Request};
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use helpers::{client, MockUpstream, Proxy, Reply};
+use helpers::mock_upstream::Echo;
+
+
+#[tokio::test]
+async fn proxy_credentials_are_not_forwarded_to_origins() {
+    let upstream = MockUpstream::new()
+        .on(hyper::Method::GET, "/", Reply::text(200, "ok").header("proxy-authenticate", "Basic realm=\"origin\""))
+        .build();
+    let proxy = Proxy::start("");
+
+    let req = Request::get(upstream.url("/"))
+        .header("proxy-authorization", "Basic YWxpY2U6c2VjcmV0")
+        .header("proxy-connection", "keep-alive")
+        .body(Body::empty()).unwrap();
+    let answer = client::request(proxy.addr, req).await;
+
+    assert_eq!(answer.status, 200);
+    assert_eq!(answer.header("proxy-authenticate"), None);
+    let recorded = upstream.last();
+    assert_eq!(recorded.header("proxy-authorization"), None);
+    assert_eq!(recorded.header("proxy-connection"), None);
+}
+
+#[tokio::test]
+async fn proxy_credentials_are_relayed_to_parent_proxy() {
+    let parent = MockUpstream::new().on_connect("example.com:443", Echo).build();
+    let proxy = Proxy::start(&format!("upstream_proxy: {}\n", parent.authority()));
+
+    let (status, _tunnel) = client::connect(proxy.addr, "example.com:443",
+                                            &[("Proxy-Authorization", "Basic YWxpY2U6c2VjcmV0")]).await;
+
+    assert_eq!(status, 200);
+    assert_eq!(parent.last().header("proxy-authorization"), Some("Basic YWxpY2U6c2VjcmV0"));
+}
+
+/// Sends a GET of `url` with the extra header lines over `stream` and reads the answer
+async fn exchange(stream: &mut TcpStream, url: &str, headers: &str) -> String {
+    let req = format!("GET {} HTTP/1.1\r\nHost: {}\r\n{}\r\n", url, url.split('/').nth(2).unwrap(), headers);
+    stream.write_all(req.as_bytes()).await.unwrap();
+    let (head, _) = client::read_response(stream).await;
+    head
+}
+
+fn has_close(head: &str) -> bool {
+    head.to_ascii_lowercase().lines().any(|l| l.starts_with("connection:") && l.contains("close"))
+}
+
+#[tokio::test]
+async fn client_close_closes_only_the_client_connection() {
+    let upstream = MockUpstream::new().on_get("/", 200, "ok").build();
+    let proxy = Proxy::start("");
+
+    let mut stream = TcpStream::connect(proxy.addr).await.unwrap();
+    let head = exchange(&mut stream, &upstream.url("/"), "Connection: close\r\n").await;
+    assert_eq!(client::status_of(&head), 200);
+    assert!(client::is_eof(&mut stream).await, "client connection left open");
+
+    // the upstream connection stayed in the pool
+    let mut stream = TcpStream::connect(proxy.addr).await.unwrap();
+    exchange(&mut stream, &upstream.url("/"), "").await;
+    assert_eq!(upstream.connections(), 1);
+    assert!(!upstream.last().headers.contains_key("connection"));
+}
+
+#[tokio::test]
+async fn upstream_close_keeps_the_client_connection() {
+    let upstream = MockUpstream::new()
+        .on(hyper::Method::GET, "/", Reply::text(200, "ok").header("connection", "close"))
+        .build();
+    let proxy = Proxy::start("");
+
+    let mut stream = TcpStream::connect(proxy.addr).await.unwrap();
+    let head = exchange(&mut stream, &upstream.url("/"), "").await;
+    assert_eq!(client::status_of(&head), 200);
+    assert!(!has_close(&head), "{}", head);
+    assert!(!client::is_eof(&mut stream).await, "client connection closed with the upstream one");
+
+    let head = exchange(&mut stream, &upstream.url("/"), "").await;
+    assert_eq!(client::status_of(&head), 200);
+    assert_eq!(upstream.connections(), 2);
+}
+
+#[tokio::test]
+async fn keep_alive_on_both_hops() {
+    let upstream = MockUpstream::new().on_get("/", 200, "ok").build();
+    let proxy = Proxy::start("");
+
+    let mut stream = TcpStream::connect(proxy.addr).await.unwrap();
+    for _ in 0..3 {
+        let head = exchange(&mut stream, &upstream.url("/"), "Connection: keep-alive\r\n").await;
+        assert_eq!(client::status_of(&head), 200);
+        assert!(!has_close(&head), "{}", head);
+    }
+    assert!(!client::is_eof(&mut stream).await);
+    assert_eq!(upstream.connections(), 1);
+}
+
+#[tokio::test]
+async fn close_policy_of_the_proxy_closes_the_client_connection() {
+    let upstream = MockUpstream::new().on_get("/", 503, "unavailable").build();
+    let proxy = Proxy::start("close_connection_on_status: [503]\n");
+
+    let mut stream = TcpStream::connect(proxy.addr).await.unwrap();
+    let head = exchange(&mut stream, &upstream.url("/"), "Connection: keep-alive\r\n").await;
+
+    assert_eq!(client::status_of(&head), 503);
+    assert!(has_close(&head), "{}", head);
+    assert!(client::is_eof(&mut stream).await);
+}
+
+#[tokio::test]
+async fn request_limit_closes_the_connection_after_its_last_response() {
+    let upstream = MockUpstream::new().on_get("/", 200, "ok").build();
+    let proxy = Proxy::start("limits:\n  max_requests_per_connection: 3\n");
+
+    let mut stream = TcpStream::connect(proxy.addr).await.unwrap();
+    for _ in 0..2 {
+        let head = exchange(&mut stream, &upstream.url("/"), "").await;
+        assert!(!has_close(&head), "{}", head);
+    }
+    let head = exchange(&mut stream, &upstream.url("/"), "").await;
+
+    assert_eq!(client::status_of(&head), 200);
+    assert!(has_close(&head), "{}", head);
+    assert!(client::is_eof(&mut stream).await);
+}
+
+#[tokio::test]
+async fn tunnels_are_not_counted_as_requests() {
+    let server = helpers::RawServer::echo();
+    let proxy = Proxy::start("limits:\n  max_requests_per_connection: 1\n");
+
+    let (status, mut tunnel) = client::connect(proxy.addr, &server.addr.to_string(), &[]).await;
+
+    assert_eq!(status, 200);
+    assert!(!client::is_eof(&mut tunnel).await, "tunnel closed");
+}
+
+#[tokio::test]
+async fn old_connection_is_closed_after_its_next_response() {
+    let upstream = MockUpstream::new().on_get("/", 200, "ok").build();
+    let proxy = Proxy::start("limits:\n  max_connection_age: 1\n");
+
+    let mut stream = TcpStream::connect(proxy.addr).await.unwrap();
+    let head = exchange(&mut stream, &upstream.url("/"), "").await;
+    assert!(!has_close(&head), "{}", head);
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    let head = exchange(&mut stream, &upstream.url("/"), "").await;
+
+    assert_eq!(client::status_of(&head), 200);
+    assert!(has_close(&head), "{}", head);
+    assert!(client::is_eof(&mut stream).await);
+}
+
+#[tokio::test]
+async fn tunnel_is_closed_at_the_connection_age() {
+    let server = helpers::RawServer::echo();
+    let proxy = Proxy::start("limits:\n  max_connection_age: 1\n");
+
+    let (status, mut tunnel) = client::connect(proxy.addr, &server.addr.to_string(), &[]).await;
+    assert_eq!(status, 200);
+    assert!(!client::is_eof(&mut tunnel).await, "tunnel closed early");
+    tokio::time::sleep(Duration::from_millis(700)).await;
+
+    assert!(client::is_eof(&mut tunnel).await, "tunnel left open");
+    assert!(proxy.log().contains("reached max connection age 1s, closing it"), "{}", proxy.log());
+}
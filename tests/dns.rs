@@ -0,0 +1,116 @@
+//! Resolution of destinations: `hosts`, the address family of `dns` and retries of failed resolutions
+mod helpers;
+
+use hyper::{Body, Request};
+use helpers::{client, MockUpstream, Proxy, Reply, Upstream};
+
+
+/// Config naming `v6only.test` with an IPv6 address only, as an AAAA-only host
+fn v6_only_host(family: &str) -> String {
+    format!("hosts:\n  v6only.test: \"::1\"\ndns:\n  family: {}\n", family)
+}
+
+#[tokio::test]
+async fn ipv4_only_never_dials_ipv6_address() {
+    let upstream = MockUpstream::new().on_get("/", 200, "ok").listen("[::1]:0").build();
+    let port = upstream.addr.port();
+    let proxy = Proxy::start(&v6_only_host("ipv4_only"));
+
+    // failed upstream requests close the client connection
+    let url = format!("http://v6only.test:{}/", port);
+    let forwarded = client::Conn::open(proxy.addr).await.send(Request::get(url).body(Body::empty()).unwrap()).await;
+    let (status, _) = client::connect(proxy.addr, &format!("v6only.test:{}", port), &[]).await;
+    // the IPv6 literal is refused as well instead of being dialed
+    let literal = client::get(proxy.addr, &format!("http://[::1]:{}/", port)).await;
+
+    assert!(forwarded.is_err(), "{:?}", forwarded);
+    assert!(proxy.log().contains("no Ipv4Only addresses found for v6only.test"), "{}", proxy.log());
+    assert_eq!(status, 502);
+    assert_eq!(literal.status, 502, "{}", literal.text());
+    assert!(literal.text().contains("address family of [::1]"), "{}", literal.text());
+    assert_eq!(upstream.connections(), 0, "an IPv6 address was dialed");
+}
+
+#[tokio::test]
+async fn any_family_dials_ipv6_address() {
+    let upstream = MockUpstream::new().on_get("/", 200, "ok").listen("[::1]:0").build();
+    let port = upstream.addr.port();
+    let proxy = Proxy::start(&v6_only_host("any"));
+
+    let answer = client::get(proxy.addr, &format!("http://v6only.test:{}/", port)).await;
+
+    assert_eq!(answer.status, 200, "{}", answer.text());
+    assert_eq!(upstream.connections(), 1);
+}
+
+#[tokio::test]
+async fn ipv6_literal_is_forwarded_with_brackets() {
+    let upstream = MockUpstream::new().on_get("/", 200, "ok").listen("[::1]:0").build();
+    let proxy = Proxy::start("dns:\n  family: ipv6_only\n");
+
+    let url = format!("http://[0:0::1]:{}/", upstream.addr.port());
+    let answer = client::get(proxy.addr, &url).await;
+    // an IPv4 literal is refused instead of being dialed
+    let literal = client::get(proxy.addr, "http://127.0.0.1:9/").await;
+
+    assert_eq!(answer.status, 200, "{}", answer.text());
+    assert_eq!(upstream.last().header("host"), Some(&*format!("[0:0::1]:{}", upstream.addr.port())));
+    assert_eq!(literal.status, 502);
+    assert!(literal.text().contains("address family of 127.0.0.1:9"), "{}", literal.text());
+}
+
+/// Config resolving with a DoH server answering every query with `rcode`
+fn doh_answering(rcode: u16) -> (Upstream, String) {
+    let reply = Reply::text(200, &format!("{{\"Status\": {}}}", rcode))
+        .header("content-type", "application/dns-json");
+    let doh = MockUpstream::new().on(hyper::Method::GET, "/dns-query", reply).tls().build();
+    let config = format!("dns:
+  resolver: doh
+  doh_url: https://localhost:{}/dns-query
+  doh_ca_pem: \"{{fixtures}}/ca.pem\"
+  retries: 2
+  retry_backoff_ms: 10
+", doh.addr.port());
+    (doh, config)
+}
+
+async fn tunnel_refusal(proxy: &Proxy) -> String {
+    client::raw(proxy.addr, b"CONNECT missing.test:443 HTTP/1.1\r\nHost: missing.test:443\r\n\r\n").await
+}
+
+#[tokio::test]
+async fn missing_name_is_not_retried() {
+    let (doh, config) = doh_answering(3);
+    let proxy = Proxy::start(&config);
+
+    let answer = tunnel_refusal(&proxy).await;
+
+    assert_eq!(client::status_of(&answer), 502, "{}", answer);
+    assert!(answer.contains("host not found: missing.test"), "{}", answer);
+    // an A and an AAAA query
+    assert_eq!(doh.requests().len(), 2);
+    assert_eq!(proxy.metric(r#"dns_resolutions_total{result="not_found"}"#).await, Some(1.0));
+    assert_eq!(proxy.metric("dns_retries_total").await, None);
+}
+
+#[tokio::test]
+async fn status_of_missing_name_is_configurable() {
+    let (_doh, config) = doh_answering(3);
+    let proxy = Proxy::start(&format!("{}  not_found_status: 404\n", config));
+
+    assert_eq!(client::status_of(&tunnel_refusal(&proxy).await), 404);
+}
+
+#[tokio::test]
+async fn server_failure_is_retried() {
+    let (doh, config) = doh_answering(2);
+    let proxy = Proxy::start(&config);
+
+    let answer = tunnel_refusal(&proxy).await;
+
+    assert_eq!(client::status_of(&answer), 502, "{}", answer);
+    assert!(answer.contains("temporary failure resolving remote host missing.test"), "{}", answer);
+    assert_eq!(doh.requests().len(), 6);
+    assert_eq!(proxy.metric("dns_retries_total").await, Some(2.0));
+    assert_eq!(proxy.metric(r#"dns_resolutions_total{result="temporary"}"#).await, Some(1.0));
+}
@@ -0,0 +1,193 @@
+//! Errors the proxy answers itself: their codes in `x-proxy-error`, JSON bodies and metrics, and `error_pages`
+mod helpers;
+
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use helpers::proxy::TempDir;
+use helpers::{client, Connect, MockUpstream, Proxy, RawServer, Reply};
+
+
+/// Sends the request line and header lines with `Accept: application/json`, returns the status,
+/// `x-proxy-error` and the JSON body of the answer
+async fn error_of(proxy: SocketAddr, request: &str) -> (u16, String, serde_json::Value) {
+    let mut stream = TcpStream::connect(proxy).await.unwrap();
+    let req = format!("{}\r\nAccept: application/json\r\nConnection: close\r\n\r\n", request);
+    stream.write_all(req.as_bytes()).await.unwrap();
+    let (head, body) = client::read_response(&mut stream).await;
+    let code = head.lines()
+        .filter_map(|l| l.split_once(':'))
+        .find(|(name, _)| name.eq_ignore_ascii_case("x-proxy-error"))
+        .map(|(_, v)| v.trim().to_string())
+        .unwrap_or_default();
+    let json = serde_json::from_slice(&body).unwrap_or_else(|e| panic!("{}; {}", e, head));
+    (client::status_of(&head), code, json)
+}
+
+#[tokio::test]
+async fn failures_map_to_their_codes() {
+    let upstream = MockUpstream::new()
+        .on_get("/", 200, "ok")
+        .on(hyper::Method::GET, "/slow", Reply::text(200, "late").delay(Duration::from_secs(3)))
+        .build();
+    let closed = helpers::proxy::free_port();
+    let proxy = Proxy::start("\
+acl:
+  deny: [blocked.example]
+via_pseudonym: edge-1
+request_timeout_ms: 500
+");
+    let host = upstream.authority();
+    // request, status, code and whether a retry hint is given
+    let cases = [
+        (String::from("CONNECT blocked.example:443 HTTP/1.1\r\nHost: blocked.example:443"), 403,
+         "policy_blocked", false),
+        (format!("TRACE http://{}/ HTTP/1.1\r\nHost: {}", host, host), 405, "policy_blocked", false),
+        (String::from("GET http://example.com/%zz HTTP/1.1\r\nHost: example.com"), 400, "bad_request", false),
+        // a name that does not exist is not retried
+        (String::from("CONNECT nonexistent.invalid:443 HTTP/1.1\r\nHost: nonexistent.invalid:443"), 502,
+         "dns_failure", false),
+        (format!("CONNECT 127.0.0.1:{} HTTP/1.1\r\nHost: 127.0.0.1:{}", closed, closed), 502,
+         "upstream_connect_failed", true),
+        (format!("GET http://{}/slow HTTP/1.1\r\nHost: {}", host, host), 504, "upstream_timeout", true),
+        (format!("GET http://{}/ HTTP/1.1\r\nHost: {}\r\nVia: 1.1 edge-1", host, host), 403, "loop_detected",
+         false),
+    ];
+    for (request, status, code, retryable) in &cases {
+        let (answered, header, json) = error_of(proxy.addr, request).await;
+        assert_eq!((answered, header.as_str()), (*status, *code), "{}", request);
+        assert_eq!(json["code"], *code, "{}", request);
+        assert!(json["message"].as_str().map(|m| !m.is_empty()).unwrap_or(false), "{}", json);
+        assert_eq!(json["request_id"].as_str().map(|v| v.len()), Some(16), "{}", json);
+        assert_eq!(json.get("retry_after_ms").is_some(), *retryable, "{}", json);
+    }
+
+    assert_eq!(proxy.metric(r#"proxy_errors_total{code="policy_blocked"}"#).await, Some(2.0));
+    assert_eq!(proxy.metric(r#"proxy_errors_total{code="loop_detected"}"#).await, Some(1.0));
+}
+
+#[tokio::test]
+async fn challenge_of_parent_is_auth_required() {
+    let parent = MockUpstream::new().on_connect("example.com:443", Connect::Refuse(407)).build();
+    let proxy = Proxy::start(&format!("upstream_proxy: {}\n", parent.authority()));
+
+    let (status, code, json) = error_of(proxy.addr, "CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443").await;
+
+    assert_eq!((status, code.as_str()), (407, "auth_required"));
+    assert_eq!(json["code"], "auth_required");
+}
+
+#[tokio::test]
+async fn tunnel_limit_is_rate_limited() {
+    let server = RawServer::echo();
+    let proxy = Proxy::start("limits:\n  max_tunnels: 1\n");
+    let (status, _open) = client::connect(proxy.addr, &server.addr.to_string(), &[]).await;
+    assert_eq!(status, 200);
+
+    let request = format!("CONNECT {} HTTP/1.1\r\nHost: {}", server.addr, server.addr);
+    let (status, code, json) = error_of(proxy.addr, &request).await;
+
+    assert_eq!((status, code.as_str()), (503, "rate_limited"));
+    assert_eq!(json["retry_after_ms"], 1000);
+}
+
+#[tokio::test]
+async fn failing_webhook_is_internal_error() {
+    let upstream = MockUpstream::new().on_get("/", 200, "ok").build();
+    let proxy = Proxy::start(&format!("webhook:\n  url: http://127.0.0.1:{}/check\n", helpers::proxy::free_port()));
+
+    let host = upstream.authority();
+    let (status, code, json) = error_of(proxy.addr, &format!("GET http://{}/ HTTP/1.1\r\nHost: {}", host, host)).await;
+
+    assert_eq!((status, code.as_str()), (502, "internal_error"));
+    assert!(json["message"].as_str().unwrap().starts_with("request webhook failed"), "{}", json);
+    assert_eq!(upstream.connections(), 0);
+}
+
+#[tokio::test]
+async fn answers_of_servers_have_no_code() {
+    let upstream = MockUpstream::new().on_get("/", 503, "down").build();
+    let proxy = Proxy::start("");
+
+    let answer = client::get(proxy.addr, &upstream.url("/")).await;
+
+    assert_eq!(answer.status, 503);
+    assert_eq!(answer.header("x-proxy-error"), None);
+    assert_eq!(answer.text(), "down");
+}
+
+/// Starts the proxy with pages for 403 and 502 and a default one, each naming its template
+fn with_error_pages(more: &str) -> Proxy {
+    let dir = TempDir::new();
+    for name in ["403", "502", "default"].iter() {
+        dir.write(&format!("{}.html", name), format!("{} page: {{{{status}}}} {{{{reason}}}} for {{{{host}}}}, \
+                                                      quote {{{{request_id}}}}", name));
+    }
+    let yaml = format!("error_pages:
+  403: \"{{dir}}/403.html\"
+  502: \"{{dir}}/502.html\"
+  default: \"{{dir}}/default.html\"
+acl:
+  deny: [blocked.example]
+{}", more);
+    Proxy::start_in(dir, &yaml, &[], &[])
+}
+
+/// Sends the request line and header lines, returns the head and body of the answer
+async fn page_of(proxy: SocketAddr, request: &str) -> (String, String) {
+    let mut stream = TcpStream::connect(proxy).await.unwrap();
+    stream.write_all(format!("{}\r\nConnection: close\r\n\r\n", request).as_bytes()).await.unwrap();
+    let (head, body) = client::read_response(&mut stream).await;
+    (head, String::from_utf8(body).unwrap())
+}
+
+#[tokio::test]
+async fn configured_pages_are_rendered() {
+    let upstream = MockUpstream::new()
+        .on(hyper::Method::GET, "/slow", Reply::text(200, "late").delay(Duration::from_secs(3)))
+        .build();
+    let closed = helpers::proxy::free_port();
+    let proxy = with_error_pages("request_timeout_ms: 500\n");
+    let host = upstream.authority();
+    let cases = [
+        (String::from("CONNECT blocked.example:443 HTTP/1.1\r\nHost: blocked.example:443"),
+         "403 page: 403 Forbidden for blocked.example"),
+        (format!("CONNECT 127.0.0.1:{} HTTP/1.1\r\nHost: 127.0.0.1:{}", closed, closed),
+         "502 page: 502 Bad Gateway for 127.0.0.1"),
+        (format!("GET http://{}/slow HTTP/1.1\r\nHost: {}", host, host),
+         "default page: 504 Gateway Timeout for 127.0.0.1"),
+    ];
+    for (request, expected) in &cases {
+        let (head, body) = page_of(proxy.addr, request).await;
+
+        assert!(body.starts_with(expected), "{}: {}", request, body);
+        assert!(head.to_ascii_lowercase().contains("content-type: text/html; charset=utf-8"), "{}", head);
+        let request_id = body.rsplit(' ').next().unwrap();
+        assert_eq!(request_id.len(), 16, "{}", body);
+        assert!(proxy.log().contains(&format!("with request id {}", request_id)), "{}", proxy.log());
+    }
+}
+
+#[tokio::test]
+async fn json_is_answered_instead_of_pages_when_asked_for() {
+    let proxy = with_error_pages("");
+
+    let request = "CONNECT blocked.example:443 HTTP/1.1\r\nHost: blocked.example:443";
+    let (status, code, json) = error_of(proxy.addr, request).await;
+
+    assert_eq!((status, code.as_str()), (403, "policy_blocked"));
+    assert_eq!(json["error"], "Forbidden");
+    assert_eq!(json["request_id"].as_str().map(|v| v.len()), Some(16), "{}", json);
+}
+
+#[tokio::test]
+async fn answers_of_servers_get_no_page() {
+    let upstream = MockUpstream::new().on_get("/", 502, "from the server").build();
+    let proxy = with_error_pages("");
+
+    let answer = client::get(proxy.addr, &upstream.url("/")).await;
+
+    assert_eq!(answer.status, 502);
+    assert_eq!(answer.text(), "from the server");
+}
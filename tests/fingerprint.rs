@@ -0,0 +1,71 @@
+//! `Server` and other headers revealing the software of upstreams, on forwarded responses and on
+//! the ones of the proxy
+mod helpers;
+
+use helpers::{client, MockUpstream, Proxy, Reply, Upstream};
+
+
+fn fingerprinted() -> Upstream {
+    let reply = Reply::text(200, "ok")
+        .header("server", "nginx/1.18.0")
+        .header("x-powered-by", "PHP/7.4.3")
+        .header("x-aspnet-version", "4.0.30319");
+    MockUpstream::new().on(hyper::Method::GET, "/", reply).build()
+}
+
+const DENY: &str = "acl:\n  deny: [blocked.example]\n";
+
+/// `Server` headers of a forwarded response, a refused request and an established tunnel
+async fn server_headers(proxy: &Proxy, upstream: &Upstream) -> [Option<String>; 3] {
+    let forwarded = client::get(proxy.addr, &upstream.url("/")).await;
+    let refused = client::get(proxy.addr, "http://blocked.example/").await;
+    assert_eq!(refused.status, 403);
+    let connect = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\n", upstream.authority(), upstream.authority());
+    let tunnel = client::raw(proxy.addr, connect.as_bytes()).await;
+    assert_eq!(client::status_of(&tunnel), 200, "{}", tunnel);
+    let tunnel = tunnel.lines()
+        .filter_map(|l| l.split_once(':'))
+        .find(|(name, _)| name.eq_ignore_ascii_case("server"))
+        .map(|(_, v)| v.trim().to_string());
+    [forwarded.header("server").map(String::from), refused.header("server").map(String::from), tunnel]
+}
+
+#[tokio::test]
+async fn passthrough_keeps_the_server_of_upstreams() {
+    let upstream = fingerprinted();
+    let proxy = Proxy::start(DENY);
+
+    assert_eq!(server_headers(&proxy, &upstream).await, [Some(String::from("nginx/1.18.0")), None, None]);
+}
+
+#[tokio::test]
+async fn remove_drops_every_server_header() {
+    let upstream = fingerprinted();
+    let proxy = Proxy::start(&format!("{}response:\n  server_header: remove\n", DENY));
+
+    assert_eq!(server_headers(&proxy, &upstream).await, [None, None, None]);
+}
+
+#[tokio::test]
+async fn override_is_sent_with_every_response() {
+    let upstream = fingerprinted();
+    let proxy = Proxy::start(&format!("{}response:\n  server_header: \"override:edge\"\n", DENY));
+
+    let edge = Some(String::from("edge"));
+    assert_eq!(server_headers(&proxy, &upstream).await, [edge.clone(), edge.clone(), edge]);
+}
+
+#[tokio::test]
+async fn fingerprint_headers_are_stripped_when_asked_to() {
+    let upstream = fingerprinted();
+    let kept = Proxy::start("");
+    let stripped = Proxy::start("response:\n  strip_fingerprint_headers: true\n");
+
+    let answer = client::get(kept.addr, &upstream.url("/")).await;
+    assert_eq!(answer.header("x-powered-by"), Some("PHP/7.4.3"));
+    assert_eq!(answer.header("x-aspnet-version"), Some("4.0.30319"));
+    let answer = client::get(stripped.addr, &upstream.url("/")).await;
+    assert_eq!(answer.header("x-powered-by"), None);
+    assert_eq!(answer.header("x-aspnet-version"), None);
+    assert_eq!(answer.header("server"), Some("nginx/1.18.0"));
+}
@@ -0,0 +1,58 @@
+#!/bin/sh
+# Generates the certificates of the integration tests, valid for 100 years:
+#   ca.pem            root of the test PKI
+#   server.pem        localhost and 127.0.0.1, signed by ca.pem
+#   server2.pem       the same names with another key, for certificate reloads
+#   client.pem        client certificate of alice, signed by ca.pem
+#   revoked.pem       client certificate of mallory, revoked by crl.pem
+#   other-ca.pem      root nothing trusts, other-client.pem is signed by it
+#   server.pin        sha256/<base64> SPKI pin of server.pem
+#   expired.pem       localhost and 127.0.0.1 like server.pem, expired in 2001
+# Keys are next to their certificates as *-key.pem. Run it from this directory.
+set -e
+DAYS=36500
+key() { openssl genpkey -algorithm EC -pkeyopt ec_paramgen_curve:P-256 -out "$1" 2>/dev/null; }
+ext() { printf '%s\n' "$@" > ext.cnf; }
+
+key ca-key.pem
+openssl req -x509 -new -key ca-key.pem -days $DAYS -subj "/CN=mirror-proxy test CA" \
+    -addext "basicConstraints=critical,CA:TRUE" -addext "keyUsage=critical,keyCertSign,cRLSign" -out ca.pem
+key other-ca-key.pem
+openssl req -x509 -new -key other-ca-key.pem -days $DAYS -subj "/CN=mirror-proxy other CA" \
+    -addext "basicConstraints=critical,CA:TRUE" -addext "keyUsage=critical,keyCertSign,cRLSign" -out other-ca.pem
+
+sign() { # name cn ca extensions...
+    name=$1 cn=$2 ca=$3; shift 3
+    key $name-key.pem
+    openssl req -new -key $name-key.pem -subj "/CN=$cn" -out $name.csr
+    ext "$@"
+    openssl x509 -req -in $name.csr -CA $ca.pem -CAkey $ca-key.pem -set_serial 0x$(openssl rand -hex 8) \
+        ${VALIDITY:--days $DAYS} -extfile ext.cnf -out $name.pem 2>/dev/null
+    rm $name.csr
+}
+sign server localhost ca "subjectAltName=DNS:localhost,IP:127.0.0.1" "extendedKeyUsage=serverAuth"
+sign server2 localhost ca "subjectAltName=DNS:localhost,IP:127.0.0.1" "extendedKeyUsage=serverAuth"
+sign client alice ca "subjectAltName=DNS:alice" "extendedKeyUsage=clientAuth"
+sign revoked mallory ca "subjectAltName=DNS:mallory" "extendedKeyUsage=clientAuth"
+sign other-client eve other-ca "subjectAltName=DNS:eve" "extendedKeyUsage=clientAuth"
+VALIDITY="-not_before 20000101000000Z -not_after 20010101000000Z" \
+    sign expired localhost ca "subjectAltName=DNS:localhost,IP:127.0.0.1" "extendedKeyUsage=serverAuth"
+rm ext.cnf
+
+# revocation list of ca.pem naming revoked.pem
+mkdir -p crl.d && : > crl.d/index.txt && echo 01 > crl.d/crlnumber
+cat > crl.d/ca.cnf <<CNF
+[ca]
+default_ca = test
+[test]
+database = crl.d/index.txt
+crlnumber = crl.d/crlnumber
+default_md = sha256
+default_crl_days = $DAYS
+CNF
+openssl ca -config crl.d/ca.cnf -keyfile ca-key.pem -cert ca.pem -revoke revoked.pem 2>/dev/null
+openssl ca -config crl.d/ca.cnf -keyfile ca-key.pem -cert ca.pem -gencrl -out crl.pem 2>/dev/null
+rm -r crl.d
+
+printf 'sha256/%s\n' "$(openssl x509 -in server.pem -pubkey -noout | openssl pkey -pubin -outform DER \
+    | openssl dgst -sha256 -binary | openssl base64)" > server.pin
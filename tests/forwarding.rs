@@ -0,0 +1,210 @@
+//! Requests and tunnels through the proxy to mock upstreams
+mod helpers;
+
+use std::time::{Duration, Instant};
+use hyper::{Body, Request};
+use hyper::body::HttpBody;
+use tokio::io::AsyncWriteExt;
+use helpers::{client, MockUpstream, Proxy, RawServer, Reply};
+use helpers::mock_upstream::SimulateTls;
+
+
+#[tokio::test]
+async fn forwards_get_to_upstream() {
+    let upstream = MockUpstream::new().on_get("/ok", 200, "hello").build();
+    let proxy = Proxy::start("");
+
+    let answer = client::get(proxy.addr, &upstream.url("/ok")).await;
+
+    assert_eq!(answer.status, 200);
+    assert_eq!(answer.text(), "hello");
+    let recorded = upstream.last();
+    assert_eq!(recorded.uri, "/ok");
+    assert!(recorded.header("via").is_some(), "no Via header in {:?}", recorded.headers);
+}
+
+#[tokio::test]
+async fn rewrites_paths_per_host() {
+    let upstream = MockUpstream::new().fallback(Reply::text(200, "ok")).build();
+    let proxy = Proxy::start("\
+hosts:
+  api.test: 127.0.0.1
+path_rewrites:
+  - host: api.test
+    strip_prefix: /api
+  - host: api.test
+    regex: \"^/v([0-9]+)/\"
+    replacement: \"/version/$1/\"
+");
+
+    let port = upstream.addr.port();
+    for path in &["/api/users?id=1", "/v2/users", "/apis"] {
+        let answer = client::get(proxy.addr, &format!("http://api.test:{}{}", port, path)).await;
+        assert_eq!(answer.status, 200);
+    }
+    // other hosts are forwarded as they are
+    client::get(proxy.addr, &upstream.url("/api/users")).await;
+
+    let uris: Vec<_> = upstream.requests().into_iter().map(|r| r.uri).collect();
+    assert_eq!(uris, ["/users?id=1", "/version/2/users", "/apis", "/api/users"]);
+    assert!(proxy.log().contains("path of http://api.test:"), "{}", proxy.log());
+}
+
+/// gRPC message with the length-prefixed framing of gRPC
+fn grpc_message(payload: &[u8]) -> Vec<u8> {
+    [&[0][..], &(payload.len() as u32).to_be_bytes(), payload].concat()
+}
+
+#[tokio::test]
+async fn forwards_grpc_stream_with_trailers() {
+    let reply = Reply::new(200)
+        .header("content-type", "application/grpc")
+        .chunk(Duration::ZERO, grpc_message(b"first"))
+        .chunk(Duration::from_millis(300), grpc_message(b"second"))
+        .trailer("grpc-status", "0")
+        .trailer("grpc-message", "done");
+    let upstream = MockUpstream::new().on(hyper::Method::POST, "/echo.Echo/Stream", reply).build();
+    let proxy = Proxy::start("grpc_proxy: true\n");
+
+    let (mut sending, body) = Body::channel();
+    let req = Request::post(upstream.url("/echo.Echo/Stream"))
+        .header("content-type", "application/grpc")
+        .header("te", "trailers")
+        .body(body)
+        .unwrap();
+    let mut conn = client::Conn::open_h2(proxy.addr).await;
+    let responding = tokio::spawn(async move { conn.send(req).await });
+    sending.send_data(grpc_message(b"hello").into()).await.unwrap();
+    drop(sending);
+    let mut resp = responding.await.unwrap().unwrap();
+
+    let started = Instant::now();
+    let first = resp.body_mut().data().await.unwrap().unwrap();
+    assert_eq!(first, grpc_message(b"first"));
+    // streamed, not buffered until the end
+    assert!(started.elapsed() < Duration::from_millis(200), "{:?}", started.elapsed());
+    assert_eq!(resp.body_mut().data().await.unwrap().unwrap(), grpc_message(b"second"));
+    // an empty DATA frame may end the stream before the trailers
+    while let Some(rest) = resp.body_mut().data().await {
+        assert_eq!(rest.unwrap(), "");
+    }
+    let trailers = resp.body_mut().trailers().await.unwrap().unwrap();
+    assert_eq!(trailers["grpc-status"], "0");
+    assert_eq!(trailers["grpc-message"], "done");
+
+    let recorded = upstream.last();
+    assert_eq!(recorded.version, hyper::Version::HTTP_2);
+    assert_eq!(recorded.body, grpc_message(b"hello"));
+    assert_eq!(recorded.header("te"), Some("trailers"));
+    assert_eq!(recorded.header("content-length"), None);
+}
+
+/// Camera stream of `frames` JPEG parts sent `gap` apart
+fn camera_stream(frames: u8, gap: Duration) -> Reply {
+    let mut reply = Reply::new(200).header("content-type", "multipart/x-mixed-replace; boundary=frame");
+    for i in 0..frames {
+        let jpeg = [&[0xff, 0xd8, 0xff, 0xe0][..], &[i; 64], &[0xff, 0xd9]].concat();
+        let head = format!("--frame\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\n\r\n", jpeg.len());
+        reply = reply.chunk(gap, [head.as_bytes(), &jpeg, b"\r\n"].concat());
+    }
+    reply
+}
+
+#[tokio::test]
+async fn streams_multipart_parts_as_they_arrive() {
+    let reply = camera_stream(5, Duration::from_millis(100));
+    let upstream = MockUpstream::new().on(hyper::Method::GET, "/stream", reply).build();
+    let proxy = Proxy::start("");
+
+    let mut conn = client::Conn::open(proxy.addr).await;
+    let started = Instant::now();
+    let resp = conn.send(Request::get(upstream.url("/stream")).body(Body::empty()).unwrap()).await.unwrap();
+    assert_eq!(resp.headers()["content-type"], "multipart/x-mixed-replace; boundary=frame");
+    let mut body = resp.into_body();
+    let mut arrivals = Vec::new();
+    let mut received = Vec::new();
+    while let Some(chunk) = body.data().await {
+        received.extend_from_slice(&chunk.unwrap());
+        let parts = received.windows(7).filter(|w| w == b"--frame").count();
+        arrivals.resize(parts, started.elapsed());
+    }
+
+    assert_eq!(arrivals.len(), 5, "{:?}", arrivals);
+    assert_eq!(received.windows(2).filter(|w| w == &[0xff, 0xd9]).count(), 5);
+    // every part reaches the client on its own instead of the body as a whole
+    for (i, pair) in arrivals.windows(2).enumerate() {
+        assert!(pair[1] - pair[0] >= Duration::from_millis(50), "part {} arrived with the one before, {:?}",
+                i + 2, arrivals);
+    }
+    assert!(arrivals[0] < Duration::from_millis(300), "first part after {:?}", arrivals[0]);
+}
+
+#[tokio::test]
+async fn mirror_compares_stream_before_its_end() {
+    let upstream = MockUpstream::new()
+        .on(hyper::Method::GET, "/stream", camera_stream(10, Duration::from_millis(200)))
+        .build();
+    let mirror = MockUpstream::new().fallback(Reply::text(500, "down")).build();
+    let proxy = Proxy::start(&format!("mirror:\n  target: {}\n  compare: true\nmirror_max_body_bytes: 100\n",
+                                      mirror.url("").trim_end_matches('/')));
+
+    let mut conn = client::Conn::open(proxy.addr).await;
+    let started = Instant::now();
+    let resp = conn.send(Request::get(upstream.url("/stream")).body(Body::empty()).unwrap()).await.unwrap();
+    let mut body = resp.into_body();
+    body.data().await.unwrap().unwrap();
+
+    // the stream lasts 2s, the capture limit is passed with its first part
+    assert!(proxy.wait_log("mirror_mismatch").await, "{}", proxy.log());
+    assert!(started.elapsed() < Duration::from_millis(1500), "compared after {:?}", started.elapsed());
+}
+
+#[tokio::test]
+async fn early_hints_are_passed_on_as_link_headers() {
+    // answers the request with 103 Early Hints before the final response
+    let server = RawServer::start(|mut stream| async move {
+        client::read_head(&mut stream).await;
+        let _ = stream.write_all(b"HTTP/1.1 103 Early Hints\r\nLink: </a.css>; rel=preload\r\n\r\n\
+                                   HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello").await;
+    });
+    let proxy = Proxy::start("");
+
+    let answer = client::get(proxy.addr, &format!("http://{}/", server.addr)).await;
+
+    assert_eq!(answer.status, 200);
+    assert_eq!(answer.header("link"), Some("</a.css>; rel=preload"));
+    assert_eq!(answer.text(), "hello");
+    assert_eq!(proxy.metric(r#"upstream_interim_responses_total{status="103"}"#).await, Some(1.0));
+}
+
+#[tokio::test]
+async fn tunnels_through_parent_proxy() {
+    let parent = MockUpstream::new()
+        .on_get("/ok", 200, "hello")
+        .on_connect("example.com:443", SimulateTls)
+        .build();
+    let proxy = Proxy::start(&format!("upstream_proxy: {}\n", parent.authority()));
+
+    let (status, stream) = client::connect(proxy.addr, "example.com:443", &[]).await;
+    assert_eq!(status, 200);
+    let stream = client::tls(stream, None).await.unwrap();
+    let mut conn = client::Conn::over(stream, false).await;
+    let answer = conn.request(Request::get("/ok").header("host", "example.com").body(Body::empty()).unwrap()).await;
+
+    assert_eq!(answer.text(), "hello");
+    let requests = parent.requests();
+    assert_eq!(requests[0].method, hyper::Method::CONNECT);
+    assert_eq!(requests[0].uri, "example.com:443");
+    assert!(requests[1].tls);
+}
+
+#[tokio::test]
+async fn refused_parent_tunnel_is_passed_on() {
+    let parent = MockUpstream::new().on_connect("example.com:443", helpers::Connect::Refuse(407)).build();
+    let proxy = Proxy::start(&format!("upstream_proxy: {}\n", parent.authority()));
+
+    let (status, mut stream) = client::connect(proxy.addr, "example.com:443", &[]).await;
+
+    assert_eq!(status, 407);
+    stream.shutdown().await.unwrap();
+}
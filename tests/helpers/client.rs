@@ -0,0 +1,200 @@
+//! Clients of the proxy: requests in absolute-form over hyper, raw bytes for what hyper would not
+//! send, CONNECT tunnels and TLS
+use std::convert::TryFrom;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use hyper::{Body, HeaderMap, Request, Response, StatusCode, Version};
+use hyper::client::conn::{self, SendRequest};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+use tokio_rustls::client::TlsStream;
+
+use super::{load_certs, fixture, provider, roots};
+
+
+/// Response with its whole body
+#[derive(Debug)]
+pub struct Answer {
+    pub status: StatusCode,
+    pub version: Version,
+    pub headers: HeaderMap,
+    pub body: Vec<u8>,
+    pub trailers: Option<HeaderMap>,
+}
+
+impl Answer {
+    pub async fn read(resp: Response<Body>) -> Answer {
+        let (parts, mut body) = resp.into_parts();
+        let mut bytes = Vec::new();
+        while let Some(chunk) = hyper::body::HttpBody::data(&mut body).await {
+            bytes.extend_from_slice(&chunk.unwrap());
+        }
+        let trailers = hyper::body::HttpBody::trailers(&mut body).await.unwrap();
+        Answer { status: parts.status, version: parts.version, headers: parts.headers, body: bytes, trailers }
+    }
+
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).and_then(|v| v.to_str().ok())
+    }
+}
+
+/// Client connection to the proxy, requests are sent as they are, in absolute-form for plain HTTP
+pub struct Conn {
+    sender: SendRequest<Body>,
+}
+
+impl Conn {
+    pub async fn open(addr: SocketAddr) -> Conn {
+        Conn::over(TcpStream::connect(addr).await.unwrap(), false).await
+    }
+
+    /// HTTP/2 with prior knowledge
+    pub async fn open_h2(addr: SocketAddr) -> Conn {
+        Conn::over(TcpStream::connect(addr).await.unwrap(), true).await
+    }
+
+    pub async fn over<S>(stream: S, http2: bool) -> Conn where S: AsyncRead + AsyncWrite + Unpin + Send + 'static {
+        let (sender, connection) = conn::Builder::new().http2_only(http2).handshake(stream).await.unwrap();
+        tokio::task::spawn(async move {
+            let _ = connection.await;
+        });
+        Conn { sender }
+    }
+
+    pub async fn send(&mut self, req: Request<Body>) -> hyper::Result<Response<Body>> {
+        futures_util::future::poll_fn(|cx| self.sender.poll_ready(cx)).await?;
+        self.sender.send_request(req).await
+    }
+
+    pub async fn request(&mut self, req: Request<Body>) -> Answer {
+        Answer::read(self.send(req).await.unwrap()).await
+    }
+
+    pub async fn get(&mut self, url: &str) -> Answer {
+        self.request(Request::get(url).body(Body::empty()).unwrap()).await
+    }
+
+    /// Tells whether the proxy closed the connection, after giving it a moment to do so
+    pub async fn is_closed(&mut self) -> bool {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        futures_util::future::poll_fn(|cx| self.sender.poll_ready(cx)).await.is_err()
+    }
+}
+
+/// Sends one request on a connection of its own
+pub async fn request(proxy: SocketAddr, req: Request<Body>) -> Answer {
+    Conn::open(proxy).await.request(req).await
+}
+
+pub async fn get(proxy: SocketAddr, url: &str) -> Answer {
+    Conn::open(proxy).await.get(url).await
+}
+
+/// Request to the proxy itself, e.g. of `/metrics`
+pub async fn get_origin(proxy: SocketAddr, path: &str) -> Answer {
+    let req = Request::get(path).header("host", proxy.to_string()).body(Body::empty()).unwrap();
+    request(proxy, req).await
+}
+
+/// Writes `bytes` and reads until the proxy closes the connection or is silent for a second
+pub async fn raw(proxy: SocketAddr, bytes: &[u8]) -> String {
+    let mut stream = TcpStream::connect(proxy).await.unwrap();
+    stream.write_all(bytes).await.unwrap();
+    read_for(&mut stream, Duration::from_secs(1)).await
+}
+
+/// Reads what arrives until the stream ends or nothing more comes within `quiet`
+pub async fn read_for<S: AsyncRead + Unpin>(stream: &mut S, quiet: Duration) -> String {
+    let mut out = Vec::new();
+    let mut buf = [0u8; 8192];
+    while let Ok(Ok(n)) = tokio::time::timeout(quiet, stream.read(&mut buf)).await {
+        if n == 0 {
+            break;
+        }
+        out.extend_from_slice(&buf[..n]);
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Reads a response head up to its empty line
+pub async fn read_head<S: AsyncRead + Unpin>(stream: &mut S) -> String {
+    let mut head = Vec::new();
+    let mut byte = [0u8; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        match stream.read(&mut byte).await {
+            Ok(1) => head.push(byte[0]),
+            _ => break,
+        }
+    }
+    String::from_utf8_lossy(&head).into_owned()
+}
+
+/// Status of a response head, 0 when there is none
+pub fn status_of(head: &str) -> u16 {
+    head.split_whitespace().nth(1).and_then(|v| v.parse().ok()).unwrap_or(0)
+}
+
+/// Sends CONNECT with the extra header lines, returns the status and the stream, which is the
+/// tunnel once the status is 200
+pub async fn connect(proxy: SocketAddr, authority: &str, headers: &[(&str, &str)]) -> (u16, TcpStream) {
+    let mut stream = TcpStream::connect(proxy).await.unwrap();
+    let mut req = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n", authority, authority);
+    for (name, value) in headers {
+        req.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    req.push_str("\r\n");
+    stream.write_all(req.as_bytes()).await.unwrap();
+    let head = read_head(&mut stream).await;
+    (status_of(&head), stream)
+}
+
+/// TLS client trusting the CA of the fixtures, presenting the client certificate `name` (e.g.
+/// `client` for `client.pem` and `client-key.pem`) when given
+pub fn tls_connector(client_cert: Option<&str>, alpn: &[&[u8]]) -> TlsConnector {
+    let builder = rustls::ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions().unwrap()
+        .with_root_certificates(roots("ca.pem"));
+    let mut config = match client_cert {
+        Some(name) => {
+            let certs = load_certs(&fixture(&format!("{}.pem", name)));
+            let key = std::fs::File::open(fixture(&format!("{}-key.pem", name))).unwrap();
+            let key = rustls_pemfile::private_key(&mut std::io::BufReader::new(key)).unwrap().unwrap();
+            builder.with_client_auth_cert(certs, key).unwrap()
+        },
+        None => builder.with_no_client_auth(),
+    };
+    config.alpn_protocols = alpn.iter().map(|v| v.to_vec()).collect();
+    TlsConnector::from(Arc::new(config))
+}
+
+/// TLS handshake as a client of `localhost` over `stream`
+pub async fn tls<S>(stream: S, client_cert: Option<&str>) -> std::io::Result<TlsStream<S>>
+    where S: AsyncRead + AsyncWrite + Unpin {
+    let name = rustls::pki_types::ServerName::try_from("localhost").unwrap();
+    tls_connector(client_cert, &[]).connect(name, stream).await
+}
+
+/// Reads a response head and a body delimited by its `Content-Length`
+pub async fn read_response<S: AsyncRead + Unpin>(stream: &mut S) -> (String, Vec<u8>) {
+    let head = read_head(stream).await;
+    let length = head.lines()
+        .filter_map(|l| l.split_once(':'))
+        .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, v)| v.trim().parse::<usize>().ok())
+        .unwrap_or(0);
+    let mut body = vec![0u8; length];
+    stream.read_exact(&mut body).await.unwrap();
+    (head, body)
+}
+
+/// Tells whether the peer closed the stream, waiting up to 500ms for it
+pub async fn is_eof<S: AsyncRead + Unpin>(stream: &mut S) -> bool {
+    let mut byte = [0u8; 1];
+    matches!(tokio::time::timeout(Duration::from_millis(500), stream.read(&mut byte)).await, Ok(Ok(0)) | Ok(Err(_)))
+}
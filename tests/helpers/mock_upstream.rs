@@ -0,0 +1,462 @@
+//! Servers the proxy forwards to: `MockUpstream` answers HTTP/1 and HTTP/2 requests with canned
+//! replies, over TLS as well, and opens CONNECT tunnels as a parent proxy; `RawServer` runs a
+//! handler on every accepted connection for tunnel tests.
+//!
+//! ```ignore
+//! let upstream = MockUpstream::new()
+//!     .on_get("/ok", 200, "hello")
+//!     .on_connect("example.com:443", SimulateTls)
+//!     .build();
+//! ```
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use hyper::{Body, HeaderMap, Method, Request, Response, Version};
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::oneshot;
+use tokio_rustls::TlsAcceptor;
+
+use super::fixture;
+
+pub use Connect::{Echo, Refuse, SimulateTls};
+
+
+/// What the mock does with a CONNECT request to an authority, as a parent proxy would
+#[derive(Clone, Copy, Debug)]
+pub enum Connect {
+    /// Answers 200 and sends every byte of the tunnel back
+    Echo,
+    /// Answers 200 and serves the routes of the mock over TLS with the `localhost` certificate
+    SimulateTls,
+    /// Answers with the status and opens no tunnel
+    Refuse(u16),
+}
+
+/// Canned answer of a route
+#[derive(Clone, Debug)]
+pub struct Reply {
+    status: u16,
+    headers: Vec<(String, String)>,
+    /// Each chunk is sent after its delay
+    chunks: Vec<(Duration, Vec<u8>)>,
+    trailers: Vec<(String, String)>,
+    /// Before the response head is sent
+    delay: Duration,
+}
+
+impl Reply {
+    pub fn new(status: u16) -> Reply {
+        Reply { status, headers: Vec::new(), chunks: Vec::new(), trailers: Vec::new(), delay: Duration::ZERO }
+    }
+
+    pub fn text(status: u16, body: &str) -> Reply {
+        Reply::new(status).body(body)
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Reply {
+        self.headers.push((String::from(name), String::from(value)));
+        self
+    }
+
+    /// Body sent at once with a `Content-Length`
+    pub fn body(mut self, body: impl AsRef<[u8]>) -> Reply {
+        self.chunks = vec![(Duration::ZERO, body.as_ref().to_vec())];
+        self
+    }
+
+    /// Appends a chunk of a streamed body, sent `after` the previous one
+    pub fn chunk(mut self, after: Duration, chunk: impl AsRef<[u8]>) -> Reply {
+        self.chunks.push((after, chunk.as_ref().to_vec()));
+        self
+    }
+
+    pub fn trailer(mut self, name: &str, value: &str) -> Reply {
+        self.trailers.push((String::from(name), String::from(value)));
+        self
+    }
+
+    /// Waits before the response head
+    pub fn delay(mut self, delay: Duration) -> Reply {
+        self.delay = delay;
+        self
+    }
+
+    async fn into_response(self) -> Response<Body> {
+        tokio::time::sleep(self.delay).await;
+        let mut builder = Response::builder().status(self.status);
+        for (name, value) in &self.headers {
+            builder = builder.header(name.as_str(), value.as_str());
+        }
+        let streamed = self.chunks.len() > 1 || !self.trailers.is_empty()
+            || self.chunks.iter().any(|(after, _)| !after.is_zero());
+        if !streamed {
+            let body = self.chunks.into_iter().next().map(|(_, v)| v).unwrap_or_default();
+            return builder.body(Body::from(body)).unwrap();
+        }
+        let (mut sender, body) = Body::channel();
+        tokio::task::spawn(async move {
+            for (after, chunk) in self.chunks {
+                tokio::time::sleep(after).await;
+                if sender.send_data(chunk.into()).await.is_err() {
+                    return;
+                }
+            }
+            if !self.trailers.is_empty() {
+                let mut trailers = HeaderMap::new();
+                for (name, value) in &self.trailers {
+                    trailers.append(hyper::header::HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                                    value.parse().unwrap());
+                }
+                let _ = sender.send_trailers(trailers).await;
+            }
+        });
+        builder.body(body).unwrap()
+    }
+}
+
+/// Request as the mock received it
+#[derive(Clone, Debug)]
+pub struct Recorded {
+    pub method: Method,
+    pub uri: String,
+    pub version: Version,
+    pub headers: HeaderMap,
+    pub body: Vec<u8>,
+    /// Number of the TCP connection it came on, from 1
+    pub connection: usize,
+    /// Received over TLS, within a tunnel as well
+    pub tls: bool,
+    /// DNS name of the client certificate
+    pub client_cert: Option<String>,
+}
+
+impl Recorded {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).and_then(|v| v.to_str().ok())
+    }
+}
+
+/// Builder of a mock upstream server
+#[derive(Default)]
+pub struct MockUpstream {
+    routes: HashMap<(Method, String), Reply>,
+    fallback: Option<Reply>,
+    connects: HashMap<String, Connect>,
+    tls: Option<(String, String)>,
+    client_auth: bool,
+    alpn: Vec<Vec<u8>>,
+    listen: Option<String>,
+}
+
+impl MockUpstream {
+    pub fn new() -> MockUpstream {
+        MockUpstream::default()
+    }
+
+    pub fn on_get(self, path: &str, status: u16, body: &str) -> MockUpstream {
+        self.on(Method::GET, path, Reply::text(status, body))
+    }
+
+    pub fn on(mut self, method: Method, path: &str, reply: Reply) -> MockUpstream {
+        self.routes.insert((method, String::from(path)), reply);
+        self
+    }
+
+    /// Answers requests no route matches, 404 otherwise
+    pub fn fallback(mut self, reply: Reply) -> MockUpstream {
+        self.fallback = Some(reply);
+        self
+    }
+
+    pub fn on_connect(mut self, authority: &str, connect: Connect) -> MockUpstream {
+        self.connects.insert(String::from(authority), connect);
+        self
+    }
+
+    /// Serves TLS with the `localhost` certificate of the fixtures besides plain HTTP, told apart by
+    /// the first byte of a connection
+    pub fn tls(self) -> MockUpstream {
+        self.tls_cert("server.pem", "server-key.pem")
+    }
+
+    pub fn tls_cert(mut self, cert: &str, key: &str) -> MockUpstream {
+        self.tls = Some((fixture(cert), fixture(key)));
+        self
+    }
+
+    /// Requires TLS clients to present a certificate signed by the CA of the fixtures
+    pub fn client_auth(mut self) -> MockUpstream {
+        self.client_auth = true;
+        self
+    }
+
+    /// Offers `h2` and `http/1.1` by ALPN
+    pub fn alpn_h2(mut self) -> MockUpstream {
+        self.alpn = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        self
+    }
+
+    /// Address to listen on instead of `127.0.0.1:0`, e.g. `[::1]:0`
+    pub fn listen(mut self, addr: &str) -> MockUpstream {
+        self.listen = Some(String::from(addr));
+        self
+    }
+
+    pub fn build(self) -> Upstream {
+        let listener_acceptor = self.tls.as_ref()
+            .map(|(cert, key)| acceptor(cert, key, self.client_auth, &self.alpn));
+        // a tunnel simulating TLS always has a certificate
+        let tunnel_acceptor = listener_acceptor.clone()
+            .unwrap_or_else(|| acceptor(&fixture("server.pem"), &fixture("server-key.pem"), false, &self.alpn));
+        let shared = Arc::new(Shared {
+            routes: self.routes,
+            fallback: self.fallback,
+            connects: self.connects,
+            recorded: Mutex::new(Vec::new()),
+            connections: AtomicUsize::new(0),
+            tunnel_acceptor,
+        });
+        let listen = self.listen.as_deref().unwrap_or("127.0.0.1:0");
+        let serving = shared.clone();
+        let (addr, shutdown) = spawn_server(listen, move |stream| {
+            let shared = serving.clone();
+            let acceptor = listener_acceptor.clone();
+            async move {
+                let connection = shared.connections.fetch_add(1, Ordering::SeqCst) + 1;
+                let mut first = [0u8; 1];
+                let is_tls = matches!(stream.peek(&mut first).await, Ok(1) if first[0] == 0x16);
+                match acceptor {
+                    Some(acceptor) if is_tls => {
+                        if let Ok(stream) = acceptor.accept(stream).await {
+                            let cert = client_cert(stream.get_ref().1.peer_certificates());
+                            serve(shared, stream, connection, true, cert).await;
+                        }
+                    },
+                    _ => serve(shared, stream, connection, false, None).await,
+                }
+            }
+        });
+        Upstream { addr, shared, _shutdown: shutdown }
+    }
+}
+
+/// Running mock upstream, stopped when dropped
+pub struct Upstream {
+    pub addr: SocketAddr,
+    shared: Arc<Shared>,
+    _shutdown: oneshot::Sender<()>,
+}
+
+impl Upstream {
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}{}", self.addr, path)
+    }
+
+    pub fn https_url(&self, path: &str) -> String {
+        format!("https://{}{}", self.addr, path)
+    }
+
+    pub fn authority(&self) -> String {
+        self.addr.to_string()
+    }
+
+    pub fn requests(&self) -> Vec<Recorded> {
+        self.shared.recorded.lock().unwrap().clone()
+    }
+
+    /// Last request received, panics when there was none
+    pub fn last(&self) -> Recorded {
+        self.requests().pop().expect("the upstream received no request")
+    }
+
+    /// TCP connections accepted so far
+    pub fn connections(&self) -> usize {
+        self.shared.connections.load(Ordering::SeqCst)
+    }
+
+    /// Waits up to 5 seconds until `n` requests were received
+    pub async fn wait_requests(&self, n: usize) -> Vec<Recorded> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while self.requests().len() < n && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        self.requests()
+    }
+}
+
+struct Shared {
+    routes: HashMap<(Method, String), Reply>,
+    fallback: Option<Reply>,
+    connects: HashMap<String, Connect>,
+    recorded: Mutex<Vec<Recorded>>,
+    connections: AtomicUsize,
+    tunnel_acceptor: TlsAcceptor,
+}
+
+fn serve<S>(shared: Arc<Shared>, stream: S, connection: usize, tls: bool, cert: Option<String>)
+    -> Pin<Box<dyn Future<Output = ()> + Send>>
+    where S: AsyncRead + AsyncWrite + Unpin + Send + 'static {
+    Box::pin(async move {
+        let service = service_fn(move |req: Request<Body>| {
+            let shared = shared.clone();
+            let cert = cert.clone();
+            async move { Ok::<_, hyper::Error>(answer(shared, req, connection, tls, cert).await) }
+        });
+        let _ = Http::new().serve_connection(stream, service).with_upgrades().await;
+    })
+}
+
+async fn answer(shared: Arc<Shared>, mut req: Request<Body>, connection: usize, tls: bool, cert: Option<String>)
+    -> Response<Body> {
+    let body = if req.method() == Method::CONNECT {
+        Vec::new()
+    } else {
+        hyper::body::to_bytes(req.body_mut()).await.map(|b| b.to_vec()).unwrap_or_default()
+    };
+    shared.recorded.lock().unwrap().push(Recorded {
+        method: req.method().clone(),
+        uri: req.uri().to_string(),
+        version: req.version(),
+        headers: req.headers().clone(),
+        body,
+        connection,
+        tls,
+        client_cert: cert,
+    });
+    if req.method() == Method::CONNECT {
+        let authority = req.uri().authority().map(|a| a.to_string()).unwrap_or_default();
+        let connect = match shared.connects.get(&authority) {
+            Some(v) => *v,
+            None => Refuse(403),
+        };
+        if let Refuse(status) = connect {
+            return Reply::new(status).into_response().await;
+        }
+        let shared = shared.clone();
+        tokio::task::spawn(async move {
+            let upgraded = match hyper::upgrade::on(req).await {
+                Ok(v) => v,
+                Err(_) => return,
+            };
+            match connect {
+                Echo => {
+                    let (mut rd, mut wr) = tokio::io::split(upgraded);
+                    let _ = tokio::io::copy(&mut rd, &mut wr).await;
+                    let _ = wr.shutdown().await;
+                },
+                _ => {
+                    if let Ok(stream) = shared.tunnel_acceptor.clone().accept(upgraded).await {
+                        serve(shared, stream, connection, true, None).await;
+                    }
+                }
+            }
+        });
+        return Response::new(Body::empty());
+    }
+    let path = req.uri().path().to_string();
+    let reply = shared.routes.get(&(req.method().clone(), path)).cloned()
+        .or_else(|| shared.fallback.clone())
+        .unwrap_or_else(|| Reply::text(404, "not found"));
+    reply.into_response().await
+}
+
+/// DNS name of a client certificate, the fixtures name their clients by it
+fn client_cert(certs: Option<&[rustls::pki_types::CertificateDer<'_>]>) -> Option<String> {
+    let cert = webpki::EndEntityCert::try_from(certs?.first()?).ok()?;
+    let name = cert.valid_dns_names().next().map(String::from);
+    name
+}
+
+fn acceptor(cert: &str, key: &str, client_auth: bool, alpn: &[Vec<u8>]) -> TlsAcceptor {
+    let certs = super::load_certs(cert);
+    let key = rustls_pemfile::private_key(&mut std::io::BufReader::new(std::fs::File::open(key).unwrap()))
+        .unwrap().unwrap();
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = rustls::ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions().unwrap();
+    let builder = if client_auth {
+        let verifier = rustls::server::WebPkiClientVerifier::builder_with_provider(
+            Arc::new(super::roots("ca.pem")), provider).build().unwrap();
+        builder.with_client_cert_verifier(verifier)
+    } else {
+        builder.with_no_client_auth()
+    };
+    let mut config = builder.with_single_cert(certs, key).unwrap();
+    config.alpn_protocols = alpn.to_vec();
+    TlsAcceptor::from(Arc::new(config))
+}
+
+
+/// Server running a handler on every accepted TCP connection, stopped when dropped
+pub struct RawServer {
+    pub addr: SocketAddr,
+    connections: Arc<AtomicUsize>,
+    _shutdown: oneshot::Sender<()>,
+}
+
+impl RawServer {
+    pub fn start<F, Fut>(handler: F) -> RawServer
+        where F: Fn(TcpStream) -> Fut + Send + Sync + 'static, Fut: Future<Output = ()> + Send + 'static {
+        RawServer::listen("127.0.0.1:0", handler)
+    }
+
+    pub fn listen<F, Fut>(addr: &str, handler: F) -> RawServer
+        where F: Fn(TcpStream) -> Fut + Send + Sync + 'static, Fut: Future<Output = ()> + Send + 'static {
+        let connections = Arc::new(AtomicUsize::new(0));
+        let counted = connections.clone();
+        let (addr, shutdown) = spawn_server(addr, move |stream| {
+            counted.fetch_add(1, Ordering::SeqCst);
+            handler(stream)
+        });
+        RawServer { addr, connections, _shutdown: shutdown }
+    }
+
+    /// Sends every byte back until the client closes its side
+    pub fn echo() -> RawServer {
+        RawServer::start(|stream| async move {
+            let (mut rd, mut wr) = stream.into_split();
+            let _ = tokio::io::copy(&mut rd, &mut wr).await;
+            let _ = wr.shutdown().await;
+        })
+    }
+
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::SeqCst)
+    }
+}
+
+/// Accepts connections on a thread with a runtime of its own, so servers outlive the runtime of
+/// the test which started them and serve tests without one
+fn spawn_server<F, Fut>(listen: &str, handler: F) -> (SocketAddr, oneshot::Sender<()>)
+    where F: Fn(TcpStream) -> Fut + Send + Sync + 'static, Fut: Future<Output = ()> + Send + 'static {
+    let listener = std::net::TcpListener::bind(listen).unwrap();
+    listener.set_nonblocking(true).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (shutdown, stopped) = oneshot::channel::<()>();
+    std::thread::spawn(move || {
+        let runtime = tokio::runtime::Builder::new_multi_thread().worker_threads(2).enable_all().build().unwrap();
+        runtime.block_on(async move {
+            let listener = TcpListener::from_std(listener).unwrap();
+            let handler = Arc::new(handler);
+            let accepting = async {
+                while let Ok((stream, _)) = listener.accept().await {
+                    tokio::task::spawn(handler(stream));
+                }
+            };
+            tokio::select! {
+                _ = accepting => (),
+                _ = stopped => (),
+            }
+        });
+        runtime.shutdown_background();
+    });
+    (addr, shutdown)
+}
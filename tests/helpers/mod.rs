@@ -0,0 +1,35 @@
+//! Helpers of the integration tests, which run the built binary against servers of their own.
+//! Every test file includes them with `mod helpers;`, so some go unused in each.
+#![allow(dead_code, unused_imports)]
+use std::sync::Arc;
+
+pub mod client;
+pub mod mock_upstream;
+pub mod proxy;
+
+pub use mock_upstream::{Connect, MockUpstream, RawServer, Recorded, Reply, Upstream};
+pub use proxy::Proxy;
+
+
+/// Path of a file of `tests/fixtures`, see `generate.sh` there
+pub fn fixture(name: &str) -> String {
+    format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name)
+}
+
+pub fn load_certs(path: &str) -> Vec<rustls::pki_types::CertificateDer<'static>> {
+    let file = std::fs::File::open(path).unwrap_or_else(|e| panic!("can not open {}; {}", path, e));
+    rustls_pemfile::certs(&mut std::io::BufReader::new(file)).collect::<Result<_, _>>().unwrap()
+}
+
+/// Trust anchors of a CA file of the fixtures
+pub fn roots(name: &str) -> rustls::RootCertStore {
+    let mut roots = rustls::RootCertStore::empty();
+    for cert in load_certs(&fixture(name)) {
+        roots.add(cert).unwrap();
+    }
+    roots
+}
+
+pub fn provider() -> Arc<rustls::crypto::CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
@@ -0,0 +1,200 @@
+//! Runs the built proxy as a child process with a config of the test
+use std::net::{SocketAddr, TcpListener};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Output, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use super::client;
+
+
+const BINARY: &str = env!("CARGO_BIN_EXE_mirror-proxy");
+
+/// Keys the harness sets unless the config of a test does: the proxy listens on a free port of
+/// the loopback address and may reach the servers of the test there
+const DEFAULTS: [&str; 3] = ["ip", "port", "allow_localhost"];
+
+/// Directory of the files of one test, removed when dropped
+pub struct TempDir {
+    pub path: PathBuf,
+}
+
+impl TempDir {
+    pub fn new() -> TempDir {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!("mirror-proxy-test-{}-{}", std::process::id(),
+                                                     NEXT.fetch_add(1, Ordering::SeqCst)));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        TempDir { path }
+    }
+
+    /// Writes a file of the directory and returns its path
+    pub fn write(&self, name: &str, contents: impl AsRef<[u8]>) -> String {
+        let path = self.path.join(name);
+        std::fs::write(&path, contents).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    pub fn file(&self, name: &str) -> String {
+        self.path.join(name).to_string_lossy().into_owned()
+    }
+
+    pub fn read(&self, name: &str) -> String {
+        std::fs::read_to_string(self.path.join(name)).unwrap_or_default()
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+/// Running proxy, killed when dropped
+pub struct Proxy {
+    pub addr: SocketAddr,
+    pub dir: TempDir,
+    pub config: String,
+    child: Child,
+}
+
+impl Proxy {
+    /// Starts the proxy with `yaml`, in which `{dir}` is the directory of the test and `{fixtures}`
+    /// that of the fixtures, and waits until it listens
+    pub fn start(yaml: &str) -> Proxy {
+        Proxy::start_with(yaml, &[], &[])
+    }
+
+    pub fn start_with(yaml: &str, args: &[&str], envs: &[(&str, &str)]) -> Proxy {
+        Proxy::start_in(TempDir::new(), yaml, args, envs)
+    }
+
+    /// Starts the proxy in a directory the test already put files in
+    pub fn start_in(dir: TempDir, yaml: &str, args: &[&str], envs: &[(&str, &str)]) -> Proxy {
+        let port = free_port();
+        let config = dir.write("config.yaml", with_defaults(&expand(yaml, &dir.path), port));
+        let log = std::fs::File::create(dir.path.join("proxy.log")).unwrap();
+        let child = Command::new(BINARY)
+            .arg("--config").arg(&config)
+            .args(args)
+            .env_remove("MIRROR_PROXY_IP").env_remove("MIRROR_PROXY_PORT")
+            .envs(envs.iter().copied())
+            .current_dir(&dir.path)
+            .stdout(Stdio::null())
+            .stderr(log)
+            .spawn()
+            .unwrap();
+        let mut proxy = Proxy { addr: SocketAddr::from(([127, 0, 0, 1], port)), dir, config, child };
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !proxy.log().contains("server listening at") {
+            if let Ok(Some(status)) = proxy.child.try_wait() {
+                panic!("proxy exited with {} before listening:\n{}", status, proxy.log());
+            }
+            if Instant::now() > deadline {
+                panic!("proxy does not listen after 10s:\n{}", proxy.log());
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        proxy
+    }
+
+    /// What the proxy logged so far
+    pub fn log(&self) -> String {
+        self.dir.read("proxy.log")
+    }
+
+    /// Waits up to 5 seconds until the log contains `needle`
+    pub async fn wait_log(&self, needle: &str) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !self.log().contains(needle) {
+            if Instant::now() > deadline {
+                return false;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        true
+    }
+
+    /// Rewrites the config file, `{dir}` and `{fixtures}` are replaced as by `start`
+    pub fn rewrite_config(&self, yaml: &str) {
+        std::fs::write(&self.config, with_defaults(&expand(yaml, &self.dir.path), self.addr.port())).unwrap();
+    }
+
+    /// Sends SIGHUP and waits until the outcome of the reload is logged after its start
+    pub async fn hup(&self) {
+        let before = self.log().matches("reload").count() + 2;
+        let status = Command::new("kill").arg("-HUP").arg(self.child.id().to_string()).status().unwrap();
+        assert!(status.success());
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while self.log().matches("reload").count() < before && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    }
+
+    /// `/metrics` as served by the proxy listener
+    pub async fn metrics(&self) -> String {
+        client::get_origin(self.addr, "/metrics").await.text()
+    }
+
+    /// Value of a metric line starting with `series`, e.g. `tunnels_total` or
+    /// `tunnel_timeouts_total{operation="read"`
+    pub async fn metric(&self, series: &str) -> Option<f64> {
+        self.metrics().await.lines()
+            .filter(|l| l.starts_with(series))
+            .filter_map(|l| l.rsplit(' ').next()?.parse::<f64>().ok())
+            .reduce(|a, b| a + b)
+    }
+
+    /// Exit status once it exits by itself within `timeout`
+    pub fn wait_exit(&mut self, timeout: Duration) -> Option<std::process::ExitStatus> {
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline {
+            if let Ok(Some(status)) = self.child.try_wait() {
+                return Some(status);
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        None
+    }
+}
+
+impl Drop for Proxy {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Runs the binary to its exit, e.g. for subcommands and startup errors
+pub fn run(args: &[&str], envs: &[(&str, &str)], dir: &Path) -> Output {
+    Command::new(BINARY)
+        .args(args)
+        .env_remove("MIRROR_PROXY_IP").env_remove("MIRROR_PROXY_PORT")
+        .envs(envs.iter().copied())
+        .current_dir(dir)
+        .output()
+        .unwrap()
+}
+
+/// Port nothing listens on right now
+pub fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+fn expand(yaml: &str, dir: &Path) -> String {
+    yaml.replace("{dir}", &dir.to_string_lossy())
+        .replace("{fixtures}", &format!("{}/tests/fixtures", env!("CARGO_MANIFEST_DIR")))
+}
+
+fn with_defaults(yaml: &str, port: u16) -> String {
+    let mut config = String::new();
+    for key in DEFAULTS.iter().filter(|k| !yaml.lines().any(|l| l.starts_with(&format!("{}:", k)))) {
+        match *key {
+            "ip" => config.push_str("ip: 127.0.0.1\n"),
+            "port" => config.push_str(&format!("port: {}\n", port)),
+            _ => config.push_str("allow_localhost: true\n"),
+        }
+    }
+    config + yaml
+}
@@ -0,0 +1,117 @@
+//! HTTP/1.0 clients: bodies delimited by closing instead of chunks, keep-alive only when asked for and
+//! CONNECT
+mod helpers;
+
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use helpers::{client, MockUpstream, Proxy, RawServer, Reply};
+
+
+/// Sends an HTTP/1.0 GET of `url` with the extra header lines, returns the head and the body, read up
+/// to its `Content-Length` or else to the end of the stream
+async fn get10(stream: &mut TcpStream, url: &str, headers: &str) -> (String, String) {
+    stream.write_all(format!("GET {} HTTP/1.0\r\n{}\r\n", url, headers).as_bytes()).await.unwrap();
+    let head = client::read_head(stream).await;
+    let body = match header_of(&head, "content-length").map(|v| v.parse::<usize>().unwrap()) {
+        Some(length) => {
+            let mut body = vec![0u8; length];
+            stream.read_exact(&mut body).await.unwrap();
+            String::from_utf8(body).unwrap()
+        },
+        None => client::read_for(stream, Duration::from_secs(2)).await
+    };
+    (head, body)
+}
+
+fn header_of<'a>(head: &'a str, name: &str) -> Option<&'a str> {
+    head.lines()
+        .filter_map(|l| l.split_once(':'))
+        .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.trim())
+}
+
+#[tokio::test]
+async fn streamed_body_is_delimited_by_closing() {
+    let reply = Reply::new(200).chunk(Duration::ZERO, "first ").chunk(Duration::from_millis(50), "second");
+    let upstream = MockUpstream::new().on(hyper::Method::GET, "/", reply).build();
+    let proxy = Proxy::start("");
+
+    let mut stream = TcpStream::connect(proxy.addr).await.unwrap();
+    let (head, body) = get10(&mut stream, &upstream.url("/"), "Connection: keep-alive\r\n").await;
+
+    assert_eq!(client::status_of(&head), 200, "{}", head);
+    assert_eq!(header_of(&head, "transfer-encoding"), None, "{}", head);
+    assert_eq!(header_of(&head, "connection"), Some("close"), "{}", head);
+    assert_eq!(body, "first second");
+    assert!(client::is_eof(&mut stream).await);
+}
+
+#[tokio::test]
+async fn keep_alive_is_kept_only_when_asked_for() {
+    let upstream = MockUpstream::new().on_get("/", 200, "ok").build();
+    let proxy = Proxy::start("");
+
+    let mut asking = TcpStream::connect(proxy.addr).await.unwrap();
+    let (head, body) = get10(&mut asking, &upstream.url("/"), "Connection: keep-alive\r\n").await;
+    assert_eq!(body, "ok", "{}", head);
+    assert!(head.to_ascii_lowercase().contains("connection: keep-alive"), "{}", head);
+    let (head, body) = get10(&mut asking, &upstream.url("/"), "Connection: keep-alive\r\n").await;
+    assert_eq!(body, "ok", "{}", head);
+
+    let mut other = TcpStream::connect(proxy.addr).await.unwrap();
+    let (head, body) = get10(&mut other, &upstream.url("/"), "").await;
+    assert_eq!(body, "ok", "{}", head);
+    assert!(client::is_eof(&mut other).await);
+    assert!(!client::is_eof(&mut asking).await);
+}
+
+#[tokio::test]
+async fn transformed_body_is_sent_with_its_length() {
+    let reply = Reply::new(200).header("content-type", "text/plain")
+        .chunk(Duration::ZERO, "hello ").chunk(Duration::from_millis(50), "world");
+    let upstream = MockUpstream::new().on(hyper::Method::GET, "/", reply).build();
+    let proxy = Proxy::start("\
+body_transforms:
+  - content_type_match: text/plain
+    find: world
+    replace: there
+");
+
+    let mut stream = TcpStream::connect(proxy.addr).await.unwrap();
+    let (head, body) = get10(&mut stream, &upstream.url("/"), "Connection: keep-alive\r\n").await;
+
+    assert_eq!(body, "hello there", "{}", head);
+    assert_eq!(header_of(&head, "content-length"), Some("11"), "{}", head);
+    assert!(!client::is_eof(&mut stream).await);
+}
+
+#[tokio::test]
+async fn own_answers_are_not_chunked() {
+    let proxy = Proxy::start("acl:\n  deny: [blocked.example]\n");
+
+    let mut stream = TcpStream::connect(proxy.addr).await.unwrap();
+    let (head, body) = get10(&mut stream, "http://blocked.example/", "").await;
+
+    assert_eq!(client::status_of(&head), 403, "{}", head);
+    assert_eq!(header_of(&head, "transfer-encoding"), None, "{}", head);
+    assert!(!body.is_empty());
+    assert!(client::is_eof(&mut stream).await);
+}
+
+#[tokio::test]
+async fn connect_of_http10_client_is_tunneled() {
+    let server = RawServer::echo();
+    let proxy = Proxy::start("");
+
+    let mut stream = TcpStream::connect(proxy.addr).await.unwrap();
+    stream.write_all(format!("CONNECT {} HTTP/1.0\r\n\r\n", server.addr).as_bytes()).await.unwrap();
+    let head = client::read_head(&mut stream).await;
+    assert_eq!(client::status_of(&head), 200, "{}", head);
+    stream.write_all(b"ping").await.unwrap();
+    let mut echoed = [0u8; 4];
+    stream.read_exact(&mut echoed).await.unwrap();
+
+    assert_eq!(&echoed, b"ping");
+    assert_eq!(server.connections(), 1);
+}
@@ -0,0 +1,156 @@
+//! Proxy listener served over TLS: client certificates, their revocation and the certificate of the proxy
+mod helpers;
+
+use std::convert::TryFrom;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use hyper::{Body, Request};
+use tokio::net::TcpStream;
+use helpers::proxy::TempDir;
+use helpers::{client, fixture, MockUpstream, Proxy};
+
+
+/// Listener with the `localhost` certificate of the fixtures, client certificates signed by their
+/// CA as `client_auth`, followed by `more` keys of `tls`; access log lines name the users
+fn listener(client_auth: &str, more: &str) -> String {
+    format!("log_format: squid
+access_log: \"{{dir}}/access.log\"
+tls:
+  cert_pem: \"{{fixtures}}/server.pem\"
+  key_pem: \"{{fixtures}}/server-key.pem\"
+  client_auth: {}
+  client_ca: \"{{fixtures}}/ca.pem\"
+{}", client_auth, more)
+}
+
+/// Requests `url` over TLS presenting the client certificate `cert`, returns the status or why
+/// the handshake failed; TLS 1.3 clients learn of a rejected certificate on their first read
+async fn get(proxy: SocketAddr, cert: Option<&str>, url: &str) -> Result<u16, String> {
+    let stream = TcpStream::connect(proxy).await.unwrap();
+    let stream = client::tls(stream, cert).await.map_err(|e| e.to_string())?;
+    let mut conn = client::Conn::over(stream, false).await;
+    let resp = conn.send(Request::get(url).body(Body::empty()).unwrap()).await.map_err(|e| e.to_string())?;
+    Ok(resp.status().as_u16())
+}
+
+/// User field of the access log line of every request
+async fn users(proxy: &Proxy) -> Vec<String> {
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+    proxy.dir.read("access.log").lines().map(|l| l.split(' ').filter(|f| !f.is_empty()).nth(7).unwrap().to_string())
+        .collect()
+}
+
+#[tokio::test]
+async fn required_client_certificate_is_the_identity() {
+    let upstream = MockUpstream::new().on_get("/", 200, "ok").build();
+    let proxy = Proxy::start(&listener("required", ""));
+
+    assert_eq!(get(proxy.addr, Some("client"), &upstream.url("/")).await, Ok(200));
+    assert!(get(proxy.addr, None, &upstream.url("/")).await.is_err());
+    assert!(get(proxy.addr, Some("other-client"), &upstream.url("/")).await.is_err());
+
+    assert_eq!(users(&proxy).await, ["alice"]);
+    assert_eq!(upstream.requests().len(), 1);
+    assert_eq!(proxy.log().matches("TLS handshake failed").count(), 2, "{}", proxy.log());
+}
+
+#[tokio::test]
+async fn optional_client_certificate_allows_anonymous_clients() {
+    let upstream = MockUpstream::new().on_get("/", 200, "ok").build();
+    let proxy = Proxy::start(&listener("optional", ""));
+
+    assert_eq!(get(proxy.addr, None, &upstream.url("/")).await, Ok(200));
+    assert_eq!(get(proxy.addr, Some("client"), &upstream.url("/")).await, Ok(200));
+    // an invalid certificate is no anonymous client
+    assert!(get(proxy.addr, Some("other-client"), &upstream.url("/")).await.is_err());
+
+    assert_eq!(users(&proxy).await, ["-", "alice"]);
+}
+
+#[tokio::test]
+async fn certificate_revoked_by_crl_is_rejected() {
+    let upstream = MockUpstream::new().on_get("/", 200, "ok").build();
+    let proxy = Proxy::start(&listener("required", "  crl_pem: [\"{fixtures}/crl.pem\"]\n"));
+
+    assert!(get(proxy.addr, Some("revoked"), &upstream.url("/")).await.is_err());
+    assert_eq!(get(proxy.addr, Some("client"), &upstream.url("/")).await, Ok(200));
+    assert_eq!(users(&proxy).await, ["alice"]);
+}
+
+#[tokio::test]
+async fn certificate_of_revoked_serial_is_rejected() {
+    let upstream = MockUpstream::new().on_get("/", 200, "ok").build();
+    let der = helpers::load_certs(&fixture("client.pem")).remove(0);
+    let serial: Vec<String> = webpki::EndEntityCert::try_from(&der).unwrap().serial().iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    let proxy = Proxy::start(&listener("required", &format!("  revoked_serials: [\"{}\"]\n", serial.join(":"))));
+
+    assert!(get(proxy.addr, Some("client"), &upstream.url("/")).await.is_err());
+    assert_eq!(get(proxy.addr, Some("revoked"), &upstream.url("/")).await, Ok(200));
+    assert_eq!(users(&proxy).await, ["mallory"]);
+}
+
+/// Copies the certificate `name` of the fixtures and its key to where the listener of
+/// `reloaded_listener` reads them
+fn install_cert(dir: &TempDir, name: &str) {
+    dir.write("cert.pem", std::fs::read(fixture(&format!("{}.pem", name))).unwrap());
+    dir.write("key.pem", std::fs::read(fixture(&format!("{}-key.pem", name))).unwrap());
+}
+
+/// Starts a listener with the `server` certificate of the fixtures copied to its directory
+fn reloaded_listener() -> Proxy {
+    let dir = TempDir::new();
+    install_cert(&dir, "server");
+    Proxy::start_in(dir, "tls:\n  cert_pem: \"{dir}/cert.pem\"\n  key_pem: \"{dir}/key.pem\"\n", &[], &[])
+}
+
+/// Whether the next handshake with the proxy presents the certificate `name` of the fixtures
+async fn presents(proxy: &Proxy, name: &str) -> bool {
+    let stream = TcpStream::connect(proxy.addr).await.unwrap();
+    let stream = client::tls(stream, None).await.unwrap();
+    let expected = helpers::load_certs(&fixture(&format!("{}.pem", name)));
+    stream.get_ref().1.peer_certificates() == Some(&expected[..])
+}
+
+#[tokio::test]
+async fn changed_certificate_files_are_reloaded() {
+    let proxy = reloaded_listener();
+    let established = client::tls(TcpStream::connect(proxy.addr).await.unwrap(), None).await.unwrap();
+    assert!(presents(&proxy, "server").await);
+
+    install_cert(&proxy.dir, "server2");
+    // the files are checked every 10 seconds
+    let deadline = Instant::now() + Duration::from_secs(15);
+    while !proxy.log().contains("loaded new certificate") && Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    assert!(presents(&proxy, "server2").await, "{}", proxy.log());
+    // connections established before are untouched
+    let upstream = MockUpstream::new().on_get("/", 200, "ok").build();
+    let mut conn = client::Conn::over(established, false).await;
+    assert_eq!(conn.request(Request::get(upstream.url("/")).body(Body::empty()).unwrap()).await.status, 200);
+}
+
+#[tokio::test]
+async fn certificates_are_reloaded_on_sighup() {
+    let proxy = reloaded_listener();
+
+    install_cert(&proxy.dir, "server2");
+    proxy.hup().await;
+
+    assert!(presents(&proxy, "server2").await, "{}", proxy.log());
+}
+
+#[tokio::test]
+async fn mismatching_key_keeps_the_certificate() {
+    let proxy = reloaded_listener();
+
+    proxy.dir.write("cert.pem", std::fs::read(fixture("server2.pem")).unwrap());
+    proxy.hup().await;
+
+    assert!(proxy.log().contains("can not reload certificate"), "{}", proxy.log());
+    assert!(proxy.log().contains("does not match certificate"), "{}", proxy.log());
+    assert!(presents(&proxy, "server").await);
+}
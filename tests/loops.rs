@@ -0,0 +1,51 @@
+//! Requests and tunnels pointing back at the proxy, found by their destination or `Via` header
+mod helpers;
+
+use helpers::{client, MockUpstream, Proxy, RawServer};
+
+
+#[tokio::test]
+async fn tunnel_to_own_port_is_refused() {
+    let proxy = Proxy::start("");
+    let port = proxy.addr.port();
+
+    for authority in [format!("127.0.0.1:{}", port), format!("localhost:{}", port)].iter() {
+        let request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\n", authority, authority);
+        let answer = client::raw(proxy.addr, request.as_bytes()).await;
+
+        assert_eq!(client::status_of(&answer), 403, "{}", answer);
+        assert!(answer.contains("refusing to proxy to myself"), "{}", answer);
+    }
+    assert_eq!(proxy.metric(r#"loops_refused_total{reason="address"}"#).await, Some(2.0));
+}
+
+#[tokio::test]
+async fn request_to_own_address_is_refused() {
+    let proxy = Proxy::start("");
+
+    let answer = client::get(proxy.addr, &format!("http://{}/", proxy.addr)).await;
+
+    assert_eq!(answer.status, 403);
+    assert_eq!(answer.text(), "refusing to proxy to myself");
+}
+
+#[tokio::test]
+async fn strict_refuses_other_local_ports() {
+    let server = RawServer::echo();
+    let listen = Proxy::start("");
+    let strict = Proxy::start("loop_detection: strict\n");
+
+    assert_eq!(client::connect(listen.addr, &server.addr.to_string(), &[]).await.0, 200);
+    assert_eq!(client::connect(strict.addr, &server.addr.to_string(), &[]).await.0, 403);
+}
+
+#[tokio::test]
+async fn forwarded_requests_name_the_proxy_in_via() {
+    let upstream = MockUpstream::new().on_get("/", 200, "ok").build();
+    let proxy = Proxy::start("via_pseudonym: edge-1\n");
+
+    let answer = client::get(proxy.addr, &upstream.url("/")).await;
+
+    assert_eq!(answer.status, 200);
+    assert_eq!(upstream.last().header("via"), Some("1.1 edge-1"));
+}
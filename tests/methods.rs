@@ -0,0 +1,88 @@
+//! Methods the proxy accepts: `allowed_methods`, `denied_methods` with TRACE by default, their route
+//! overrides and `OPTIONS *`
+mod helpers;
+
+use hyper::{Body, Method, Request};
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use helpers::{client, MockUpstream, Proxy, RawServer, Reply};
+
+
+fn request(method: Method, url: &str) -> Request<Body> {
+    Request::builder().method(method).uri(url).body(Body::empty()).unwrap()
+}
+
+#[tokio::test]
+async fn trace_is_refused_by_default() {
+    let upstream = MockUpstream::new().fallback(Reply::text(200, "echo")).build();
+    let proxy = Proxy::start("");
+
+    let answer = client::request(proxy.addr, request(Method::TRACE, &upstream.url("/"))).await;
+
+    assert_eq!(answer.status, 405, "{}", answer.text());
+    assert_eq!(answer.header("x-proxy-error"), Some("policy_blocked"));
+    let allow = answer.header("allow").unwrap();
+    assert!(allow.contains("GET") && !allow.contains("TRACE"), "{}", allow);
+    assert_eq!(upstream.connections(), 0);
+}
+
+#[tokio::test]
+async fn allowlist_permits_only_its_methods() {
+    let upstream = MockUpstream::new().fallback(Reply::text(200, "ok")).build();
+    let server = RawServer::echo();
+    let proxy = Proxy::start("allowed_methods: [GET, CONNECT]\n");
+
+    let get = client::get(proxy.addr, &upstream.url("/")).await;
+    let post = client::request(proxy.addr, request(Method::POST, &upstream.url("/"))).await;
+    let (tunnel, _) = client::connect(proxy.addr, &server.addr.to_string(), &[]).await;
+
+    assert_eq!(get.status, 200);
+    assert_eq!(post.status, 405);
+    assert_eq!(post.header("allow"), Some("GET, CONNECT"));
+    assert_eq!(tunnel, 200);
+    assert_eq!(upstream.requests().len(), 1);
+}
+
+#[tokio::test]
+async fn route_overrides_the_methods() {
+    let upstream = MockUpstream::new().fallback(Reply::text(200, "ok")).build();
+    let proxy = Proxy::start("\
+hosts:
+  debug.test: 127.0.0.1
+  readonly.test: 127.0.0.1
+routes:
+  - name: debug
+    host: debug.test
+    denied_methods: []
+  - name: readonly
+    host: readonly.test
+    allowed_methods: [GET, HEAD]
+");
+    let port = upstream.addr.port();
+
+    let trace = client::request(proxy.addr, request(Method::TRACE, &format!("http://debug.test:{}/", port))).await;
+    let post = client::request(proxy.addr, request(Method::POST, &format!("http://readonly.test:{}/", port))).await;
+    let other = client::request(proxy.addr, request(Method::TRACE, &upstream.url("/"))).await;
+
+    assert_eq!(trace.status, 200, "{}", trace.text());
+    assert_eq!(upstream.last().method, Method::TRACE);
+    assert_eq!(post.status, 405);
+    assert_eq!(post.header("allow"), Some("GET, HEAD"));
+    assert_eq!(other.status, 405);
+    assert_eq!(upstream.requests().len(), 1);
+}
+
+#[tokio::test]
+async fn options_asterisk_is_answered_by_the_proxy() {
+    let proxy = Proxy::start("allowed_methods: [GET, HEAD, OPTIONS, TRACE]\n");
+
+    let mut stream = TcpStream::connect(proxy.addr).await.unwrap();
+    // both on one connection, the first answer leaves it usable
+    for _ in 0..2 {
+        stream.write_all(format!("OPTIONS * HTTP/1.1\r\nHost: {}\r\n\r\n", proxy.addr).as_bytes()).await.unwrap();
+        let (head, _) = client::read_response(&mut stream).await;
+
+        assert_eq!(client::status_of(&head), 200, "{}", head);
+        assert!(head.contains("allow: GET, HEAD, OPTIONS\r\n"), "{}", head);
+    }
+}
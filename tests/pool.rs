@@ -0,0 +1,115 @@
+//! Reuse of upstream connections as set by the `client` pool options, and the log of whether a
+//! request went over a new or a reused one
+mod helpers;
+
+use std::time::Duration;
+use helpers::{client, MockUpstream, Proxy, Reply, Upstream};
+
+
+/// Sends `n` requests one after the other, each over a client connection of its own
+async fn sequential(proxy: &Proxy, upstream: &Upstream, n: usize) {
+    for _ in 0..n {
+        assert_eq!(client::get(proxy.addr, &upstream.url("/")).await.status, 200);
+    }
+}
+
+#[tokio::test]
+async fn idle_connections_are_reused() {
+    let upstream = MockUpstream::new().on_get("/", 200, "ok").build();
+    let proxy = Proxy::start("client:\n  pool_idle_timeout: 300\n");
+
+    sequential(&proxy, &upstream, 3).await;
+
+    assert_eq!(upstream.connections(), 1);
+    assert_eq!(proxy.metric("upstream_connections_opened_total{").await, Some(1.0));
+    assert_eq!(proxy.metric("upstream_connections_reused_total").await, Some(2.0));
+    assert!(proxy.log().contains("upstream pool: idle timeout Some(300s), max idle per host None"), "{}", proxy.log());
+}
+
+#[tokio::test]
+async fn zero_idle_timeout_disables_reuse() {
+    let upstream = MockUpstream::new().on_get("/", 200, "ok").build();
+    let proxy = Proxy::start("client:\n  pool_idle_timeout: 0\n");
+
+    sequential(&proxy, &upstream, 3).await;
+
+    assert_eq!(upstream.connections(), 3);
+    assert_eq!(proxy.metric("upstream_connections_opened_total{").await, Some(3.0));
+    assert_eq!(proxy.metric("upstream_connections_reused_total").await, None);
+}
+
+#[tokio::test]
+async fn idle_connections_per_host_are_limited() {
+    let upstream = MockUpstream::new()
+        .fallback(Reply::text(200, "ok").delay(Duration::from_millis(300)))
+        .build();
+    let proxy = Proxy::start("client:\n  pool_max_idle_per_host: 1\n");
+    let url = upstream.url("/");
+    let concurrent = || async {
+        let (a, b) = tokio::join!(client::get(proxy.addr, &url), client::get(proxy.addr, &url));
+        assert_eq!(a.status, 200);
+        assert_eq!(b.status, 200);
+    };
+
+    concurrent().await;
+    // one of the two connections was kept, the other one is opened again
+    concurrent().await;
+
+    assert_eq!(upstream.connections(), 3);
+}
+
+#[tokio::test]
+async fn http2_is_spoken_unless_http1_only() {
+    let upstream = MockUpstream::new().on_get("/", 200, "ok").build();
+    let proxy = Proxy::start("client:\n  http1_only: false\n");
+
+    sequential(&proxy, &upstream, 2).await;
+
+    assert_eq!(upstream.last().version, hyper::Version::HTTP_2);
+    assert_eq!(upstream.connections(), 1);
+}
+
+/// `conn=` field of the log line of every forwarded request
+fn conn_fields(proxy: &Proxy) -> Vec<String> {
+    proxy.log().lines()
+        .filter_map(|l| l.split(" conn=").nth(1))
+        .map(|v| v.split(' ').next().unwrap().to_string())
+        .collect()
+}
+
+#[tokio::test]
+async fn keep_alive_upstream_is_reused() {
+    let upstream = MockUpstream::new().on_get("/", 200, "ok").build();
+    let proxy = Proxy::start("");
+
+    sequential(&proxy, &upstream, 3).await;
+
+    assert_eq!(conn_fields(&proxy), ["new", "reused", "reused"]);
+    let logged = format!("GET {} 200; conn=new connect_ms=", upstream.url("/"));
+    assert!(proxy.log().contains(&logged), "{}", proxy.log());
+    assert!(proxy.log().contains(&format!(" upstream={}", upstream.addr)), "{}", proxy.log());
+}
+
+#[tokio::test]
+async fn closing_upstream_is_never_reused() {
+    let upstream = MockUpstream::new()
+        .on(hyper::Method::GET, "/", Reply::text(200, "ok").header("connection", "close"))
+        .build();
+    let proxy = Proxy::start("");
+
+    sequential(&proxy, &upstream, 3).await;
+
+    assert_eq!(conn_fields(&proxy), ["new", "new", "new"]);
+    assert_eq!(upstream.connections(), 3);
+}
+
+#[tokio::test]
+async fn connections_are_bound_to_the_local_address() {
+    let upstream = MockUpstream::new().on_get("/", 200, "ok").build();
+    // the upstream listens on 127.0.0.1, which the OS would pick otherwise
+    let proxy = Proxy::start("client:\n  local_address: 127.0.0.2\n");
+
+    sequential(&proxy, &upstream, 1).await;
+
+    assert!(proxy.log().contains(" local=127.0.0.2:"), "{}", proxy.log());
+}
@@ -0,0 +1,100 @@
+//! `--print-config`: the effective config, where every value came from, secrets masked
+mod helpers;
+
+use helpers::proxy::{run, TempDir};
+
+
+/// Line of `key` in the printed config of `config.yaml` in `dir`
+fn printed(dir: &TempDir, key: &str, args: &[&str], envs: &[(&str, &str)]) -> String {
+    let args = [&["--print-config"][..], args].concat();
+    let output = run(&args, envs, &dir.path);
+    assert!(output.status.success(), "{:?}", output);
+    let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+    let prefix = format!("{}: ", key);
+    stdout.lines().find(|l| l.starts_with(&prefix)).unwrap_or_else(|| panic!("no {} in {}", key, stdout)).to_string()
+}
+
+#[test]
+fn port_comes_from_the_source_of_highest_precedence() {
+    // file, env and cli set the port or not, the last of them wins
+    let cases = [
+        (false, false, false, "port: 8080  # default"),
+        (true, false, false, "port: 9000  # file config.yaml"),
+        (false, true, false, "port: 9100  # env"),
+        (false, false, true, "port: 9200  # cli"),
+        (true, true, false, "port: 9100  # env"),
+        (true, false, true, "port: 9200  # cli"),
+        (false, true, true, "port: 9200  # cli"),
+        (true, true, true, "port: 9200  # cli"),
+    ];
+    for (file, env, cli, expected) in cases {
+        let dir = TempDir::new();
+        dir.write("config.yaml", if file { "port: 9000\n" } else { "log_level: info\n" });
+        let envs: &[(&str, &str)] = if env { &[("MIRROR_PROXY_PORT", "9100")] } else { &[] };
+        let args: &[&str] = if cli { &["--port", "9200"] } else { &[] };
+        assert_eq!(printed(&dir, "port", args, envs), expected, "file {} env {} cli {}", file, env, cli);
+    }
+}
+
+#[test]
+fn ip_comes_from_the_source_of_highest_precedence() {
+    let dir = TempDir::new();
+    dir.write("config.yaml", "ip: 127.0.0.2\n");
+
+    assert_eq!(printed(&dir, "ip", &[], &[]), "ip: 127.0.0.2  # file config.yaml");
+    assert_eq!(printed(&dir, "ip", &[], &[("MIRROR_PROXY_IP", "127.0.0.3")]), "ip: 127.0.0.3  # env");
+    assert_eq!(printed(&dir, "ip", &["--ip", "127.0.0.4"], &[("MIRROR_PROXY_IP", "127.0.0.3")]),
+               "ip: 127.0.0.4  # cli");
+}
+
+#[test]
+fn included_file_is_named_as_source() {
+    let dir = TempDir::new();
+    dir.write("config.yaml", "include: base.yaml\nlog_level: info\n");
+    dir.write("base.yaml", "port: 9000\nlog_level: warn\n");
+
+    assert_eq!(printed(&dir, "port", &[], &[]), "port: 9000  # file base.yaml");
+    assert_eq!(printed(&dir, "log_level", &[], &[]), "log_level: info  # file config.yaml");
+}
+
+#[test]
+fn secrets_are_masked() {
+    let dir = TempDir::new();
+    dir.write("config.yaml", "\
+admin_token: s3cret-token
+privacy:
+  anonymize_client_ip: hash
+  hash_key: s3cret-key
+upstream_proxy: alice:s3cret-password@parent.example.com:3128
+");
+
+    let output = run(&["--print-config"], &[], &dir.path);
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{:?}", output);
+    assert!(!stdout.contains("s3cret"), "{}", stdout);
+    assert!(stdout.contains("admin_token: ********  # file config.yaml"), "{}", stdout);
+    assert!(stdout.contains("hash_key: ********  # file config.yaml"), "{}", stdout);
+}
+
+#[test]
+fn interpolated_values_come_from_the_environment() {
+    let dir = TempDir::new();
+    dir.write("config.yaml", "\
+port: ${PROXY_PORT}
+upstream_proxy: alice:${PARENT_PASSWORD}@parent.example.com:3128
+via_pseudonym: ${PSEUDONYM:-edge-1}
+");
+    let envs = [("PROXY_PORT", "9300"), ("PARENT_PASSWORD", "s3cret")];
+
+    // interpolated values count as those of the file
+    assert_eq!(printed(&dir, "port", &[], &envs), "port: 9300  # file config.yaml");
+    assert_eq!(printed(&dir, "via_pseudonym", &[], &envs), "via_pseudonym: edge-1  # file config.yaml");
+    assert_eq!(printed(&dir, "upstream_proxy", &[], &envs),
+               "upstream_proxy: \"********@parent.example.com:3128\"  # file config.yaml");
+
+    let output = run(&["--print-config"], &envs[..1], &dir.path);
+    assert_eq!(output.status.code(), Some(78), "{:?}", output);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("upstream_proxy: environment variable PARENT_PASSWORD is not set"), "{}", stderr);
+}
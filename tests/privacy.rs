@@ -0,0 +1,110 @@
+//! Anonymized client addresses in everything the proxy writes: its log, the access and audit
+//! logs and the metrics
+mod helpers;
+
+use std::net::SocketAddr;
+use std::time::Duration;
+use hyper::{Body, Request};
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpSocket, TcpStream};
+use helpers::{client, MockUpstream, Proxy, RawServer};
+
+
+/// Address the clients of the tests connect from, the upstreams listen on 127.0.0.1
+const CLIENT_IP: &str = "127.0.0.2";
+
+const CONFIG: &str = "\
+log_format: squid
+access_log: \"{dir}/access.log\"
+audit_log:
+  path: \"{dir}/audit.log\"
+acl:
+  deny: [blocked.example]
+max_outgoing_per_host: 1
+outgoing_queue_timeout_ms: 5000
+";
+
+async fn connect_from_client_ip(proxy: SocketAddr) -> TcpStream {
+    let socket = TcpSocket::new_v4().unwrap();
+    socket.bind(format!("{}:0", CLIENT_IP).parse().unwrap()).unwrap();
+    socket.connect(proxy).await.unwrap()
+}
+
+async fn tunnel(proxy: SocketAddr, authority: &str) -> (u16, TcpStream) {
+    let mut stream = connect_from_client_ip(proxy).await;
+    let req = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\n", authority, authority);
+    stream.write_all(req.as_bytes()).await.unwrap();
+    let head = client::read_head(&mut stream).await;
+    (client::status_of(&head), stream)
+}
+
+/// Makes the proxy write about the client everywhere: a forwarded request, a denied tunnel and a
+/// tunnel queued for a slot of its host; returns everything it wrote
+async fn everything_written(proxy: &Proxy) -> String {
+    let upstream = MockUpstream::new().on_get("/", 200, "ok").build();
+    let server = RawServer::echo();
+
+    let mut conn = client::Conn::over(connect_from_client_ip(proxy.addr).await, false).await;
+    let answer = conn.request(Request::get(upstream.url("/")).body(Body::empty()).unwrap()).await;
+    assert_eq!(answer.status, 200);
+    let (status, _) = tunnel(proxy.addr, "blocked.example:443").await;
+    assert_eq!(status, 403);
+    // the second tunnel waits for the slot of the first one
+    let (status, first) = tunnel(proxy.addr, &server.addr.to_string()).await;
+    assert_eq!(status, 200);
+    let (addr, authority) = (proxy.addr, server.addr.to_string());
+    let queued = tokio::spawn(async move { tunnel(addr, &authority).await });
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    drop(first);
+    assert_eq!(queued.await.unwrap().0, 200);
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let metrics = proxy.metrics().await;
+    assert!(metrics.contains("outgoing_queue_wait_ms_count{client="), "{}", metrics);
+    [proxy.log(), proxy.dir.read("access.log"), proxy.dir.read("audit.log"), metrics].join("\n")
+}
+
+#[tokio::test]
+async fn truncated_client_address_is_written() {
+    let proxy = Proxy::start(&format!("{}privacy:\n  anonymize_client_ip: truncate\n", CONFIG));
+
+    let written = everything_written(&proxy).await;
+
+    assert!(!written.contains(CLIENT_IP), "{}", written);
+    assert!(proxy.log().contains("client 127.0.0.0:"), "{}", proxy.log());
+    assert!(proxy.dir.read("access.log").contains("127.0.0.0"), "{}", proxy.dir.read("access.log"));
+    assert!(proxy.dir.read("audit.log").contains("\"client\":\"127.0.0.0:"), "{}", proxy.dir.read("audit.log"));
+    assert!(written.contains("outgoing_queue_wait_ms_count{client=\"127.0.0.0\"}"), "{}", written);
+}
+
+#[tokio::test]
+async fn hashed_client_address_is_written() {
+    let proxy = Proxy::start(&format!("{}privacy:\n  anonymize_client_ip: hash\n  hash_key: s3cret\n", CONFIG));
+
+    let written = everything_written(&proxy).await;
+
+    assert!(!written.contains(CLIENT_IP), "{}", written);
+    assert!(!written.contains("127.0.0.0"), "{}", written);
+    // first 8 bytes of the HMAC of the address
+    let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, b"s3cret");
+    let hash: String = ring::hmac::sign(&key, &[127, 0, 0, 2]).as_ref()[..8].iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    assert!(proxy.log().contains(&format!("client {}:", hash)), "{}", proxy.log());
+    assert!(proxy.dir.read("access.log").contains(&hash), "{}", proxy.dir.read("access.log"));
+    assert!(proxy.dir.read("audit.log").contains(&hash), "{}", proxy.dir.read("audit.log"));
+    assert!(written.contains(&format!("outgoing_queue_wait_ms_count{{client=\"{}\"}}", hash)), "{}", written);
+}
+
+#[tokio::test]
+async fn client_address_is_written_without_privacy() {
+    let proxy = Proxy::start(CONFIG);
+
+    let written = everything_written(&proxy).await;
+
+    // the other tests would pass as well if the address were not written at all
+    assert!(proxy.log().contains(CLIENT_IP), "{}", proxy.log());
+    assert!(proxy.dir.read("access.log").contains(CLIENT_IP), "{}", proxy.dir.read("access.log"));
+    assert!(proxy.dir.read("audit.log").contains(CLIENT_IP), "{}", proxy.dir.read("audit.log"));
+    assert!(written.contains(&format!("outgoing_queue_wait_ms_count{{client=\"{}\"}}", CLIENT_IP)), "{}", written);
+}
@@ -0,0 +1,101 @@
+//! Bodies of requests the proxy answers without forwarding them: drained up to `limits.reject_drain_max`
+//! so the connection stays usable, or left unread with `Connection: close`
+mod helpers;
+
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use helpers::{client, Proxy};
+
+
+const DENY: &str = "acl:\n  deny: [blocked.example]\n";
+
+/// POST of `body` to a host the proxy denies, `chunked` or with its `Content-Length`
+fn post_denied(body: &[u8], chunked: bool) -> Vec<u8> {
+    let mut req = b"POST http://blocked.example/upload HTTP/1.1\r\nHost: blocked.example\r\n".to_vec();
+    match chunked {
+        true => {
+            req.extend_from_slice(b"Transfer-Encoding: chunked\r\n\r\n");
+            for chunk in body.chunks(1024) {
+                req.extend_from_slice(format!("{:x}\r\n", chunk.len()).as_bytes());
+                req.extend_from_slice(chunk);
+                req.extend_from_slice(b"\r\n");
+            }
+            req.extend_from_slice(b"0\r\n\r\n");
+        },
+        false => {
+            req.extend_from_slice(format!("Content-Length: {}\r\n\r\n", body.len()).as_bytes());
+            req.extend_from_slice(body);
+        }
+    }
+    req
+}
+
+/// Sends `req` from a task of its own, which gives up once the proxy stops reading, and returns the
+/// head of the answer and whether the proxy closed the connection after it
+async fn refused(proxy: &Proxy, req: Vec<u8>) -> (String, bool) {
+    let (mut rd, mut wr) = TcpStream::connect(proxy.addr).await.unwrap().into_split();
+    tokio::spawn(async move {
+        let _ = wr.write_all(&req).await;
+        // the write half stays open until the proxy is done
+        tokio::time::sleep(Duration::from_secs(5)).await;
+    });
+    let (head, _) = client::read_response(&mut rd).await;
+    (head, client::is_eof(&mut rd).await)
+}
+
+#[tokio::test]
+async fn small_bodies_are_drained_and_the_connection_reused() {
+    let proxy = Proxy::start(DENY);
+
+    let mut stream = TcpStream::connect(proxy.addr).await.unwrap();
+    for (length, chunked) in [(50_000, false), (60_000, true)].iter() {
+        stream.write_all(&post_denied(&vec![b'x'; *length], *chunked)).await.unwrap();
+        let (head, _) = client::read_response(&mut stream).await;
+        assert_eq!(client::status_of(&head), 403, "{}", head);
+        assert!(!head.to_ascii_lowercase().contains("connection: close"), "{}", head);
+    }
+
+    assert!(!client::is_eof(&mut stream).await);
+    assert_eq!(proxy.metric(r#"rejected_bodies_total{outcome="drained"}"#).await, Some(2.0));
+}
+
+#[tokio::test]
+async fn announced_large_body_closes_the_connection() {
+    let proxy = Proxy::start(DENY);
+
+    let (head, closed) = refused(&proxy, post_denied(&vec![b'x'; 5 * 1024 * 1024], false)).await;
+
+    assert_eq!(client::status_of(&head), 403, "{}", head);
+    assert!(head.to_ascii_lowercase().contains("connection: close"), "{}", head);
+    assert!(closed);
+    assert_eq!(proxy.metric(r#"rejected_bodies_total{outcome="closed"}"#).await, Some(1.0));
+}
+
+#[tokio::test]
+async fn streamed_body_over_the_limit_closes_the_connection() {
+    let proxy = Proxy::start(&format!("{}limits:\n  reject_drain_max: 4096\n", DENY));
+
+    let (head, closed) = refused(&proxy, post_denied(&vec![b'x'; 64 * 1024], true)).await;
+
+    assert_eq!(client::status_of(&head), 403, "{}", head);
+    assert!(head.to_ascii_lowercase().contains("connection: close"), "{}", head);
+    assert!(closed);
+    assert_eq!(proxy.metric(r#"rejected_bodies_total{outcome="closed"}"#).await, Some(1.0));
+    assert_eq!(proxy.metric(r#"rejected_bodies_total{outcome="drained"}"#).await, None);
+}
+
+#[tokio::test]
+async fn zero_drain_max_closes_on_every_body() {
+    let proxy = Proxy::start(&format!("{}limits:\n  reject_drain_max: 0\n", DENY));
+
+    let (head, closed) = refused(&proxy, post_denied(b"x", false)).await;
+    // a request without a body keeps the connection
+    let get = b"GET http://blocked.example/ HTTP/1.1\r\nHost: blocked.example\r\n\r\n".to_vec();
+    let (bodiless, bodiless_closed) = refused(&proxy, get).await;
+
+    assert_eq!(client::status_of(&head), 403, "{}", head);
+    assert!(closed);
+    assert_eq!(client::status_of(&bodiless), 403, "{}", bodiless);
+    assert!(!bodiless_closed);
+}
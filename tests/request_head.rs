@@ -0,0 +1,48 @@
+//! Limits of HTTP/1 request heads: `http1_max_headers`, the header lines hyper parses at most and
+//! `http1_max_buf_size_kb`
+mod helpers;
+
+use helpers::{client, MockUpstream, Proxy, Upstream};
+
+
+/// GET of `/` on `upstream` with `Host` and `extra` more header lines of `size` bytes each
+fn get_with_headers(upstream: &Upstream, extra: usize, size: usize) -> Vec<u8> {
+    let mut req = format!("GET {} HTTP/1.1\r\nHost: {}\r\n", upstream.url("/"), upstream.authority());
+    for i in 0..extra {
+        req.push_str(&format!("X-Header-{}: {}\r\n", i, "a".repeat(size)));
+    }
+    req.push_str("Connection: close\r\n\r\n");
+    req.into_bytes()
+}
+
+#[tokio::test]
+async fn too_many_header_lines_are_refused() {
+    let upstream = MockUpstream::new().on_get("/", 200, "ok").build();
+    let proxy = Proxy::start("http1_max_headers: 10\n");
+
+    // Host and Connection count as well
+    let within = client::raw(proxy.addr, &get_with_headers(&upstream, 8, 1)).await;
+    let over_limit = client::raw(proxy.addr, &get_with_headers(&upstream, 9, 1)).await;
+    // more than hyper parses, refused before the proxy sees the request
+    let over_hyper = client::raw(proxy.addr, &get_with_headers(&upstream, 99, 1)).await;
+
+    assert_eq!(client::status_of(&within), 200, "{}", within);
+    assert_eq!(client::status_of(&over_limit), 431, "{}", over_limit);
+    assert!(over_limit.contains("at most 10 are allowed"), "{}", over_limit);
+    assert_eq!(client::status_of(&over_hyper), 431, "{}", over_hyper);
+    assert_eq!(upstream.requests().len(), 1);
+    assert_eq!(proxy.metric(r#"malformed_requests_total{reason="too_many_headers"}"#).await, Some(2.0));
+}
+
+#[tokio::test]
+async fn head_over_the_buffer_is_refused() {
+    let upstream = MockUpstream::new().on_get("/", 200, "ok").build();
+    let proxy = Proxy::start("http1_max_buf_size_kb: 8\n");
+
+    let within = client::raw(proxy.addr, &get_with_headers(&upstream, 1, 4 * 1024)).await;
+    let over = client::raw(proxy.addr, &get_with_headers(&upstream, 4, 4 * 1024)).await;
+
+    assert_eq!(client::status_of(&within), 200, "{}", within);
+    assert_eq!(client::status_of(&over), 431, "{}", over);
+    assert_eq!(upstream.requests().len(), 1);
+}
@@ -0,0 +1,78 @@
+//! Request ids adopted from clients or generated, forwarded upstream and echoed to clients
+mod helpers;
+
+use hyper::{Body, Request};
+use helpers::{client, MockUpstream, Proxy};
+
+
+fn with_id(url: &str, id: &str) -> Request<Body> {
+    Request::get(url).header("x-request-id", id).body(Body::empty()).unwrap()
+}
+
+#[tokio::test]
+async fn id_of_client_is_adopted() {
+    let upstream = MockUpstream::new().on_get("/", 200, "ok").build();
+    let proxy = Proxy::start("");
+
+    let answer = client::request(proxy.addr, with_id(&upstream.url("/"), "f81d4fae-7dec-11d0-a765-00a0c91e6bf6")).await;
+
+    assert_eq!(answer.status, 200);
+    assert_eq!(answer.header("x-request-id"), Some("f81d4fae-7dec-11d0-a765-00a0c91e6bf6"));
+    assert_eq!(upstream.last().header("x-request-id"), Some("f81d4fae-7dec-11d0-a765-00a0c91e6bf6"));
+}
+
+#[tokio::test]
+async fn missing_id_is_generated() {
+    let upstream = MockUpstream::new().on_get("/", 200, "ok").build();
+    let proxy = Proxy::start("");
+
+    let first = client::get(proxy.addr, &upstream.url("/")).await;
+    let second = client::get(proxy.addr, &upstream.url("/")).await;
+
+    let id = first.header("x-request-id").unwrap().to_string();
+    assert_eq!(id.len(), 16);
+    assert!(id.chars().all(|c| c.is_ascii_hexdigit()), "{}", id);
+    assert_eq!(upstream.requests()[0].header("x-request-id"), Some(id.as_str()));
+    assert_ne!(second.header("x-request-id"), Some(id.as_str()));
+}
+
+#[tokio::test]
+async fn garbage_id_is_replaced() {
+    let upstream = MockUpstream::new().on_get("/", 200, "ok").build();
+    let proxy = Proxy::start("");
+
+    let garbage = "x".repeat(5 * 1024);
+    let answer = client::request(proxy.addr, with_id(&upstream.url("/"), &garbage)).await;
+
+    let id = answer.header("x-request-id").unwrap();
+    assert_eq!(id.len(), 16);
+    assert_eq!(upstream.last().header("x-request-id"), Some(id));
+}
+
+#[tokio::test]
+async fn untrusted_client_can_not_choose_the_id() {
+    let upstream = MockUpstream::new().on_get("/", 200, "ok").build();
+    let proxy = Proxy::start("request_id:\n  header: x-correlation-id\n  trust_client: false\n");
+
+    let req = Request::get(upstream.url("/")).header("x-correlation-id", "forged").body(Body::empty()).unwrap();
+    let answer = client::request(proxy.addr, req).await;
+
+    let id = answer.header("x-correlation-id").unwrap();
+    assert_ne!(id, "forged");
+    assert_eq!(upstream.last().header("x-correlation-id"), Some(id));
+    assert_eq!(upstream.last().header("x-request-id"), None);
+}
+
+#[tokio::test]
+async fn refusals_of_the_proxy_carry_the_id() {
+    let proxy = Proxy::start("acl:\n  deny: [blocked.example]\n");
+
+    let answer = client::request(proxy.addr, with_id("http://blocked.example/", "edge-1:42")).await;
+    let connect = b"CONNECT blocked.example:443 HTTP/1.1\r\nHost: blocked.example:443\r\n\r\n";
+    let generated = client::raw(proxy.addr, connect).await;
+
+    assert_eq!(answer.status, 403);
+    assert_eq!(answer.header("x-request-id"), Some("edge-1:42"));
+    assert_eq!(client::status_of(&generated), 403);
+    assert!(generated.to_ascii_lowercase().contains("x-request-id: "), "{}", generated);
+}
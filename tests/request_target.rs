@@ -0,0 +1,50 @@
+//! Normalization of request targets before policies and upstreams see them
+mod helpers;
+
+use helpers::{client, MockUpstream, Proxy};
+
+
+#[tokio::test]
+async fn userinfo_is_refused() {
+    let upstream = MockUpstream::new().on_get("/", 200, "ok").build();
+    let proxy = Proxy::start("");
+
+    let authority = upstream.authority();
+    let answer = client::raw(proxy.addr, format!("GET http://alice:s3cret@{}/ HTTP/1.1\r\nHost: {}\r\n\r\n",
+                                                 authority, authority).as_bytes()).await;
+
+    assert_eq!(client::status_of(&answer), 400, "{}", answer);
+    assert_eq!(upstream.connections(), 0);
+    assert_eq!(proxy.metric(r#"malformed_requests_total{reason="userinfo"}"#).await, Some(1.0));
+    assert!(!proxy.log().contains("s3cret"), "{}", proxy.log());
+}
+
+/// Sends a GET of `path` on `authority` through the proxy as raw bytes, so the path reaches it as written
+async fn forward(proxy: &Proxy, authority: &str, path: &str) {
+    let req = format!("GET http://{}{} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n", authority, path, authority);
+    let answer = client::raw(proxy.addr, req.as_bytes()).await;
+    assert_eq!(client::status_of(&answer), 200, "{}", answer);
+}
+
+#[tokio::test]
+async fn targets_are_normalized_by_default() {
+    let upstream = MockUpstream::new().fallback(helpers::Reply::text(200, "ok")).build();
+    let proxy = Proxy::start("");
+
+    forward(&proxy, &upstream.authority(), "/a/%2e%2e//b/?x=%2e%2e").await;
+
+    assert_eq!(upstream.last().uri, "/b/?x=%2e%2e");
+}
+
+#[tokio::test]
+async fn off_passes_targets_through() {
+    let upstream = MockUpstream::new().fallback(helpers::Reply::text(200, "ok")).build();
+    let proxy = Proxy::start("uri_normalization: off\n");
+
+    forward(&proxy, &upstream.authority(), "/a/%2e%2e//b/?x=%2e%2e").await;
+    forward(&proxy, &upstream.authority(), "/%7Euser/%c0%ae").await;
+
+    let requests = upstream.requests();
+    assert_eq!(requests[0].uri, "/a/%2e%2e//b/?x=%2e%2e");
+    assert_eq!(requests[1].uri, "/%7Euser/%c0%ae");
+}
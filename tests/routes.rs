@@ -0,0 +1,64 @@
+//! Named routes overriding the settings of the requests and tunnels they match
+mod helpers;
+
+use std::time::Duration;
+use helpers::{client, MockUpstream, Proxy, Reply};
+
+
+#[tokio::test]
+async fn timeout_override_applies_only_to_the_matching_host() {
+    let upstream = MockUpstream::new()
+        .fallback(Reply::text(200, "late").delay(Duration::from_millis(800)))
+        .build();
+    let proxy = Proxy::start("\
+hosts:
+  artifacts.test: 127.0.0.1
+  api.test: 127.0.0.1
+request_timeout_ms: 300
+log_format: squid
+access_log: \"{dir}/access.log\"
+routes:
+  - name: artifacts
+    host: artifacts.test
+    request_timeout_ms: 5000
+");
+    let port = upstream.addr.port();
+
+    let artifacts = client::get(proxy.addr, &format!("http://artifacts.test:{}/", port)).await;
+    let api = client::get(proxy.addr, &format!("http://api.test:{}/", port)).await;
+
+    assert_eq!(artifacts.status, 200);
+    assert_eq!(api.status, 504);
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let routes: Vec<String> = proxy.dir.read("access.log").lines()
+        .map(|l| l.rsplit(' ').next().unwrap().to_string())
+        .collect();
+    assert_eq!(routes, ["artifacts", "default"]);
+    assert_eq!(proxy.metric(r#"requests_total{method="GET",status="200",route="artifacts"}"#).await, Some(1.0));
+    assert_eq!(proxy.metric(r#"requests_total{method="GET",status="504",route="default"}"#).await, Some(1.0));
+}
+
+#[tokio::test]
+async fn tunnel_timeout_override_applies_only_to_the_matching_host() {
+    let server = helpers::RawServer::echo();
+    let proxy = Proxy::start("\
+hosts:
+  stream.test: 127.0.0.1
+  other.test: 127.0.0.1
+tunnel_read_timeout_secs: 1
+routes:
+  - name: streaming
+    host: stream.test
+    tunnel_read_timeout_secs: 0
+");
+    let port = server.addr.port();
+
+    let (status, mut streaming) = client::connect(proxy.addr, &format!("stream.test:{}", port), &[]).await;
+    assert_eq!(status, 200);
+    let (status, mut other) = client::connect(proxy.addr, &format!("other.test:{}", port), &[]).await;
+    assert_eq!(status, 200);
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+
+    assert!(client::is_eof(&mut other).await, "tunnel of other.test left open");
+    assert!(!client::is_eof(&mut streaming).await, "tunnel of stream.test closed");
+}
@@ -0,0 +1,103 @@
+//! Clients which send request headers or accept response bytes too slowly
+mod helpers;
+
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpSocket, TcpStream};
+use helpers::{client, MockUpstream, Proxy, RawServer, Reply};
+
+
+/// Connects with a receive buffer as small as the system allows, so a client which does not read
+/// stalls the writes of the proxy early
+async fn small_window(proxy: &Proxy) -> TcpStream {
+    let socket = TcpSocket::new_v4().unwrap();
+    socket.set_recv_buffer_size(4096).unwrap();
+    socket.connect(proxy.addr).await.unwrap()
+}
+
+#[tokio::test]
+async fn never_completed_headers_are_timed_out() {
+    let upstream = MockUpstream::new().on_get("/", 200, "ok").build();
+    let proxy = Proxy::start("limits:\n  header_timeout: 1\n");
+
+    let mut stream = TcpStream::connect(proxy.addr).await.unwrap();
+    let started = Instant::now();
+    // one byte every 500ms while the connection is open, the headers never end
+    let request = format!("GET {} HTTP/1.1\r\nHost: {}\r\nX-Slow: {}", upstream.url("/"), upstream.authority(),
+                          "a".repeat(100));
+    for byte in request.as_bytes() {
+        if stream.write_all(&[*byte]).await.is_err() {
+            break;
+        }
+        if client::is_eof(&mut stream).await {
+            break;
+        }
+        assert!(started.elapsed() < Duration::from_secs(5), "connection left open");
+    }
+
+    assert!(started.elapsed() >= Duration::from_secs(1), "closed after {:?}", started.elapsed());
+    assert!(proxy.wait_log("request headers not received in 1s, closing connection; reason=slow_client").await,
+            "{}", proxy.log());
+    assert_eq!(proxy.metric(r#"slow_clients_total{reason="header_timeout"}"#).await, Some(1.0));
+    assert_eq!(upstream.connections(), 0);
+}
+
+#[tokio::test]
+async fn headers_sent_in_time_are_served() {
+    let upstream = MockUpstream::new().on_get("/", 200, "ok").build();
+    let proxy = Proxy::start("limits:\n  header_timeout: 1\n");
+
+    let mut stream = TcpStream::connect(proxy.addr).await.unwrap();
+    stream.write_all(format!("GET {} HTTP/1.1\r\n", upstream.url("/")).as_bytes()).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    stream.write_all(format!("Host: {}\r\n\r\n", upstream.authority()).as_bytes()).await.unwrap();
+    let (head, body) = client::read_response(&mut stream).await;
+
+    assert_eq!(client::status_of(&head), 200);
+    assert_eq!(body, b"ok");
+}
+
+#[tokio::test]
+async fn client_not_reading_the_response_is_aborted() {
+    let upstream = MockUpstream::new()
+        .on(hyper::Method::GET, "/large", Reply::new(200).body(vec![b'x'; 16 * 1024 * 1024]))
+        .build();
+    let proxy = Proxy::start("limits:\n  write_timeout: 1\n");
+
+    let mut stream = small_window(&proxy).await;
+    let request = format!("GET {} HTTP/1.1\r\nHost: {}\r\n\r\n", upstream.url("/large"), upstream.authority());
+    stream.write_all(request.as_bytes()).await.unwrap();
+
+    assert!(proxy.wait_log("no bytes accepted in 1s, aborting connection; reason=slow_client").await,
+            "{}", proxy.log());
+    assert_eq!(proxy.metric(r#"slow_clients_total{reason="write_timeout"}"#).await, Some(1.0));
+}
+
+#[tokio::test]
+async fn uploading_client_not_reading_its_tunnel_is_aborted() {
+    // reads whatever the client uploads and floods it with bytes
+    let server = RawServer::start(|stream| async move {
+        let (mut rd, mut wr) = stream.into_split();
+        tokio::spawn(async move { tokio::io::copy(&mut rd, &mut tokio::io::sink()).await });
+        let chunk = vec![b'x'; 64 * 1024];
+        while wr.write_all(&chunk).await.is_ok() {}
+    });
+    let proxy = Proxy::start("limits:\n  write_timeout: 1\n");
+
+    let mut stream = small_window(&proxy).await;
+    let request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\n", server.addr, server.addr);
+    stream.write_all(request.as_bytes()).await.unwrap();
+    assert_eq!(client::status_of(&client::read_head(&mut stream).await), 200);
+    // the upload keeps going while the download side is stalled
+    let started = Instant::now();
+    while !proxy.log().contains("reason=slow_client") && started.elapsed() < Duration::from_secs(5) {
+        if stream.write_all(b"upload").await.is_err() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+
+    assert!(proxy.wait_log("no bytes accepted in 1s, aborting connection; reason=slow_client").await,
+            "{}", proxy.log());
+    assert_eq!(proxy.metric(r#"slow_clients_total{reason="write_timeout"}"#).await, Some(1.0));
+}
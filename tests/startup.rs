@@ -0,0 +1,94 @@
+//! Exit codes and messages of failures to start
+mod helpers;
+
+use std::net::TcpListener;
+use std::process::Command;
+use helpers::proxy::{free_port, run, TempDir};
+
+
+/// Starts the proxy with `config.yaml` of `yaml`, returns its exit code and the last line it logged
+fn start(yaml: &str, args: &[&str]) -> (Option<i32>, String) {
+    let dir = TempDir::new();
+    dir.write("config.yaml", yaml);
+    let output = run(args, &[], &dir.path);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    (output.status.code(), stderr.lines().last().unwrap_or("").to_string())
+}
+
+#[test]
+fn invalid_arguments_exit_with_64() {
+    let (code, _) = start("", &["--unknown"]);
+    assert_eq!(code, Some(64));
+}
+
+#[test]
+fn missing_config_exits_with_66() {
+    let (code, message) = start("", &["--config", "missing.yaml"]);
+    assert_eq!(code, Some(66));
+    assert!(message.contains("can not open config file \"missing.yaml\""), "{}", message);
+    assert!(message.ends_with("exit code 66"), "{}", message);
+}
+
+#[test]
+fn invalid_config_exits_with_78() {
+    let (code, message) = start("port: [\n", &[]);
+    assert_eq!(code, Some(78));
+    assert!(message.contains("invalid yaml"), "{}", message);
+
+    let (code, message) = start("port: 70000\n", &[]);
+    assert_eq!(code, Some(78));
+    assert!(message.contains("/port: 70000 is greater than the maximum of 65535"), "{}", message);
+
+    let (code, message) = start(&format!("port: {}\nerror_pages:\n  502: missing.html\n", free_port()), &[]);
+    assert_eq!(code, Some(78));
+    assert!(message.contains("can not read error_pages template \"missing.html\" of 502"), "{}", message);
+}
+
+#[test]
+fn unresolvable_address_exits_with_68() {
+    let (code, message) = start(&format!("ip: nonexistent.invalid\nport: {}\n", free_port()), &[]);
+    assert_eq!(code, Some(68));
+    assert!(message.contains("can not resolve server address nonexistent.invalid"), "{}", message);
+}
+
+#[test]
+fn address_in_use_exits_with_75() {
+    let taken = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = taken.local_addr().unwrap().port();
+
+    let dir = TempDir::new();
+    dir.write("config.yaml", format!("port: {}\n", port));
+    let output = run(&[], &[], &dir.path);
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(75), "{}", stderr);
+    assert!(stderr.contains(&format!("can not bind server address 127.0.0.1:{}", port)), "{}", stderr);
+    // told at once, not as a crash of the running server
+    assert!(stderr.contains(&format!("another process listens on port {}", port)), "{}", stderr);
+    assert!(!stderr.contains("server crashed"), "{}", stderr);
+}
+
+#[test]
+fn invalid_tls_files_exit_with_76() {
+    let yaml = format!("port: {}\ntls:\n  cert_pem: missing.pem\n  key_pem: missing-key.pem\n", free_port());
+    let (code, message) = start(&yaml, &[]);
+    assert_eq!(code, Some(76));
+    assert!(message.contains("TLS error"), "{}", message);
+}
+
+#[test]
+fn invalid_activated_socket_exits_with_71() {
+    let dir = TempDir::new();
+    dir.write("config.yaml", "log_level: info\n");
+    // the socket passed by systemd is a file here
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(format!("LISTEN_PID=$$ LISTEN_FDS=1 exec {} 3<config.yaml", env!("CARGO_BIN_EXE_mirror-proxy")))
+        .current_dir(&dir.path)
+        .output()
+        .unwrap();
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(71), "{}", stderr);
+    assert!(stderr.contains("file descriptor 3 of socket activation is no socket"), "{}", stderr);
+}
@@ -0,0 +1,224 @@
+//! CONNECT tunnels and their limits
+mod helpers;
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use helpers::{client, Proxy, RawServer};
+
+
+/// Echo server telling whether the proxy closed its side of a tunnel
+fn echo_noting_close() -> (RawServer, Arc<AtomicBool>) {
+    let closed = Arc::new(AtomicBool::new(false));
+    let noted = closed.clone();
+    let server = RawServer::start(move |stream| {
+        let closed = noted.clone();
+        async move {
+            let (mut rd, mut wr) = stream.into_split();
+            let _ = tokio::io::copy(&mut rd, &mut wr).await;
+            closed.store(true, Ordering::SeqCst);
+        }
+    });
+    (server, closed)
+}
+
+#[tokio::test]
+async fn max_connection_age_closes_live_tunnel() {
+    let (server, closed) = echo_noting_close();
+    let proxy = Proxy::start("limits:\n  max_connection_age: 1\n");
+
+    let (status, mut tunnel) = client::connect(proxy.addr, &server.addr.to_string(), &[]).await;
+    assert_eq!(status, 200);
+    let started = Instant::now();
+    // the tunnel carries bytes until the watchdog closes it
+    let mut buf = [0u8; 4];
+    let ended = loop {
+        if tunnel.write_all(b"ping").await.is_err() {
+            break started.elapsed();
+        }
+        match tokio::time::timeout(Duration::from_secs(3), tunnel.read_exact(&mut buf)).await {
+            Ok(Ok(_)) => assert_eq!(&buf, b"ping"),
+            Ok(Err(_)) => break started.elapsed(),
+            Err(_) => panic!("tunnel neither echoed nor closed"),
+        }
+        assert!(started.elapsed() < Duration::from_secs(5), "tunnel still open after its max age");
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    };
+
+    assert!(ended >= Duration::from_millis(900), "closed after {:?}", ended);
+    assert!(proxy.wait_log("reached max connection age").await);
+    // the upstream side is closed as well, not left to the pipe tasks
+    let deadline = Instant::now() + Duration::from_secs(2);
+    while !closed.load(Ordering::SeqCst) && Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert!(closed.load(Ordering::SeqCst), "upstream connection outlived the tunnel");
+}
+
+#[tokio::test]
+async fn read_timeout_spares_one_way_stream() {
+    // sends a tick every 300ms for 2.5s and never reads
+    let server = RawServer::start(|mut stream| async move {
+        for _ in 0..8 {
+            if stream.write_all(b"tick").await.is_err() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(300)).await;
+        }
+        tokio::time::sleep(Duration::from_secs(10)).await;
+    });
+    let proxy = Proxy::start("tunnel_read_timeout_secs: 1\n");
+
+    let (status, mut tunnel) = client::connect(proxy.addr, &server.addr.to_string(), &[]).await;
+    assert_eq!(status, 200);
+    let started = Instant::now();
+    let mut received = 0;
+    let mut buf = [0u8; 64];
+    // the client sends nothing, yet the tunnel stays open while the server streams
+    let ended = loop {
+        match tokio::time::timeout(Duration::from_secs(5), tunnel.read(&mut buf)).await {
+            Ok(Ok(0)) | Ok(Err(_)) => break started.elapsed(),
+            Ok(Ok(n)) => received += n,
+            Err(_) => panic!("tunnel not closed after the stream stopped"),
+        }
+    };
+
+    assert_eq!(received, 8 * 4);
+    assert!(ended >= Duration::from_millis(3000), "closed after {:?}", ended);
+    assert!(proxy.wait_log("sent no bytes in 1s, closing tunnel").await);
+    assert_eq!(proxy.metric("tunnel_timeouts_total{").await, Some(1.0));
+}
+
+#[tokio::test]
+async fn write_timeout_closes_tunnel_of_slow_consumer() {
+    let server = RawServer::start(|mut stream| async move {
+        let chunk = vec![b'x'; 64 * 1024];
+        while stream.write_all(&chunk).await.is_ok() {}
+    });
+    let proxy = Proxy::start("tunnel_write_timeout_secs: 1\n");
+
+    // reads nothing, the buffers fill up until writes to the client stall
+    let (status, _tunnel) = client::connect(proxy.addr, &server.addr.to_string(), &[]).await;
+    assert_eq!(status, 200);
+
+    assert!(proxy.wait_log("client accepted no bytes in 1s, closing tunnel").await, "{}", proxy.log());
+    assert_eq!(proxy.metric(r#"tunnel_timeouts_total{side="client",operation="write"}"#).await, Some(1.0));
+}
+
+#[tokio::test]
+async fn connect_authority_defaults_port_and_refuses_malformed_ones() {
+    let server = RawServer::echo();
+    let v6 = RawServer::listen("[::1]:0", |stream| async move {
+        let (mut rd, mut wr) = stream.into_split();
+        let _ = tokio::io::copy(&mut rd, &mut wr).await;
+    });
+    let proxy = Proxy::start(&format!("connect_default_port: {}\n", server.addr.port()));
+
+    let (status, mut tunnel) = client::connect(proxy.addr, "127.0.0.1", &[]).await;
+    assert_eq!(status, 200);
+    tunnel.write_all(b"ping").await.unwrap();
+    assert_eq!(client::read_for(&mut tunnel, Duration::from_millis(300)).await, "ping");
+    let (status, mut tunnel) = client::connect(proxy.addr, &format!("[::1]:{}", v6.addr.port()), &[]).await;
+    assert_eq!(status, 200);
+    tunnel.write_all(b"ping").await.unwrap();
+    assert_eq!(client::read_for(&mut tunnel, Duration::from_millis(300)).await, "ping");
+
+    let (status, mut stream) = client::connect(proxy.addr, "alice@127.0.0.1:443", &[]).await;
+    assert_eq!(status, 400);
+    assert!(client::read_for(&mut stream, Duration::from_millis(300)).await.contains("userinfo"));
+    let (status, mut stream) = client::connect(proxy.addr, "[example.com]:443", &[]).await;
+    assert_eq!(status, 400);
+    assert!(client::read_for(&mut stream, Duration::from_millis(300)).await.contains("host is invalid"));
+    // names which do not exist are no malformed requests
+    let (status, _) = client::connect(proxy.addr, "nonexistent.invalid:443", &[]).await;
+    assert_eq!(status, 502);
+}
+
+/// Config naming `two.test` with `addrs`, tried in `order`, and logging tunnels in squid format
+fn two_addresses(addrs: &[&str], order: &str) -> String {
+    format!("hosts:\n  two.test: [{}]\ndns:\n  address_order: {}\n\
+             log_format: squid\naccess_log: \"{{dir}}/access.log\"\n",
+            addrs.iter().map(|a| format!("\"{}\"", a)).collect::<Vec<_>>().join(", "), order)
+}
+
+#[tokio::test]
+async fn tunnel_falls_back_to_the_accepting_address() {
+    let server = RawServer::echo();
+    let port = server.addr.port();
+    // nothing listens on the port of 127.0.0.3
+    let proxy = Proxy::start(&two_addresses(&["127.0.0.3", "127.0.0.1"], "as_returned"));
+
+    let (status, mut stream) = client::connect(proxy.addr, &format!("two.test:{}", port), &[]).await;
+    assert_eq!(status, 200);
+    stream.write_all(b"ping").await.unwrap();
+    let mut buf = [0; 4];
+    stream.read_exact(&mut buf).await.unwrap();
+    drop(stream);
+
+    assert_eq!(&buf, b"ping");
+    proxy.wait_log(&format!("tunnel to two.test:{} connected to 127.0.0.1:{}", port, port)).await;
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let line = proxy.dir.read("access.log");
+    assert!(line.contains(&format!("CONNECT two.test:{} - HIER_DIRECT/127.0.0.1 ", port)), "{}", line);
+}
+
+#[tokio::test]
+async fn refusal_lists_every_address_in_order() {
+    let port = helpers::proxy::free_port();
+    let proxy = Proxy::start(&two_addresses(&["127.0.0.3", "::1"], "prefer_ipv6"));
+
+    let answer = client::raw(proxy.addr, format!("CONNECT two.test:{} HTTP/1.1\r\nHost: two.test\r\n\r\n", port)
+        .as_bytes()).await;
+
+    assert_eq!(client::status_of(&answer), 502, "{}", answer);
+    let tried = format!("can not connect to remote host two.test:{}; tried [::1]:{} (", port, port);
+    assert!(answer.contains(&tried), "{}", answer);
+    assert!(answer.contains(&format!("), 127.0.0.3:{} (", port)), "{}", answer);
+}
+
+#[tokio::test]
+async fn tunnels_use_source_ports_of_the_range() {
+    let ports = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let seen = ports.clone();
+    let server = RawServer::start(move |stream| {
+        seen.lock().unwrap().push(stream.peer_addr().unwrap().port());
+        async move {
+            let (mut rd, mut wr) = stream.into_split();
+            let _ = tokio::io::copy(&mut rd, &mut wr).await;
+        }
+    });
+    // below the ephemeral ports of Linux, which other tests are given
+    let proxy = Proxy::start("outbound:\n  port_range: [31010, 31012]\n");
+
+    let mut open = Vec::new();
+    for _ in 0..3 {
+        let (status, stream) = client::connect(proxy.addr, &server.addr.to_string(), &[]).await;
+        assert_eq!(status, 200);
+        open.push(stream);
+    }
+    let refused = client::raw(proxy.addr, format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\n", server.addr, server.addr)
+        .as_bytes()).await;
+
+    let mut ports = ports.lock().unwrap().clone();
+    ports.sort_unstable();
+    assert_eq!(ports, [31010, 31011, 31012]);
+    assert_eq!(client::status_of(&refused), 503, "{}", refused);
+    assert!(refused.contains(&format!("no free source port to connect to {}", server.addr)), "{}", refused);
+    assert_eq!(proxy.metric("source_ports_exhausted_total").await, Some(1.0));
+}
+
+#[tokio::test]
+async fn forwarding_without_free_source_port_is_unavailable() {
+    let upstream = helpers::MockUpstream::new().on_get("/", 200, "ok").build();
+    let proxy = Proxy::start("outbound:\n  port_range: [31020, 31020]\n");
+
+    // the tunnel holds the only source port to the upstream
+    let (status, _open) = client::connect(proxy.addr, &upstream.authority(), &[]).await;
+    assert_eq!(status, 200);
+    let answer = client::get(proxy.addr, &upstream.url("/")).await;
+
+    assert_eq!(answer.status, 503, "{}", answer.text());
+    assert!(answer.text().contains("no free source port to connect to"), "{}", answer.text());
+    assert_eq!(proxy.metric("source_ports_exhausted_total").await, Some(1.0));
+}
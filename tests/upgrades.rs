@@ -0,0 +1,76 @@
+//! CONNECT requests waiting for the upgrade of their client connection: `limits.upgrade_timeout` and
+//! `limits.max_pending_upgrades`
+mod helpers;
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use helpers::{client, Proxy, RawServer};
+
+
+/// Echo server noting when the proxy closes the connection of a tunnel
+fn echo_noting_close() -> (RawServer, Arc<AtomicBool>) {
+    let closed = Arc::new(AtomicBool::new(false));
+    let noted = closed.clone();
+    let server = RawServer::start(move |stream| {
+        let closed = noted.clone();
+        async move {
+            let (mut rd, mut wr) = stream.into_split();
+            let _ = tokio::io::copy(&mut rd, &mut wr).await;
+            closed.store(true, Ordering::SeqCst);
+        }
+    });
+    (server, closed)
+}
+
+#[tokio::test]
+async fn pending_upgrades_beyond_the_limit_are_refused() {
+    let server = RawServer::echo();
+    // the first CONNECT holds the only slot while it is delayed
+    let proxy = Proxy::start("mode: development\nsimulate_latency_ms: 1000\nlimits:\n  max_pending_upgrades: 1\n");
+
+    let (addr, authority) = (proxy.addr, server.addr.to_string());
+    let first = tokio::spawn(async move { client::connect(addr, &authority, &[]).await });
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    let (refused, _) = client::connect(proxy.addr, &server.addr.to_string(), &[]).await;
+
+    assert_eq!(refused, 503);
+    assert_eq!(first.await.unwrap().0, 200);
+    assert_eq!(proxy.metric(r#"upgrades_aborted_total{reason="pending_limit"}"#).await, Some(1.0));
+    // the slot is free again once the first tunnel is upgraded
+    assert_eq!(client::connect(proxy.addr, &server.addr.to_string(), &[]).await.0, 200);
+}
+
+#[tokio::test]
+async fn stalled_client_is_upgraded_and_cleaned_up() {
+    let (server, closed) = echo_noting_close();
+    let proxy = Proxy::start("limits:\n  upgrade_timeout: 1\n  max_pending_upgrades: 1\n");
+
+    // sends the CONNECT headers, then neither reads the answer nor sends anything
+    let mut stalled = TcpStream::connect(proxy.addr).await.unwrap();
+    let request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\n", server.addr, server.addr);
+    stalled.write_all(request.as_bytes()).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+
+    // the stalled tunnel holds no slot and was not timed out
+    let (status, mut tunnel) = client::connect(proxy.addr, &server.addr.to_string(), &[]).await;
+    assert_eq!(status, 200);
+    tunnel.write_all(b"ping").await.unwrap();
+    let mut echoed = [0u8; 4];
+    tunnel.read_exact(&mut echoed).await.unwrap();
+    assert_eq!(&echoed, b"ping");
+    assert_eq!(proxy.metric("tunnels_total").await, Some(2.0));
+    assert_eq!(proxy.metric(r#"upgrades_aborted_total{reason="upgrade_timeout"}"#).await, None);
+
+    // the upstream connection of the stalled tunnel goes with its client
+    drop(stalled);
+    let deadline = Instant::now() + Duration::from_secs(2);
+    while !closed.load(Ordering::SeqCst) && Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert!(closed.load(Ordering::SeqCst), "upstream connection outlived its client");
+    assert_eq!(server.connections(), 2);
+    drop(tunnel);
+}
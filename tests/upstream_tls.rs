@@ -0,0 +1,177 @@
+//! TLS of connections to `https` upstreams: trusted roots, pins and client certificates
+mod helpers;
+
+use helpers::{client, fixture, MockUpstream, Proxy};
+
+
+/// `upstream_tls` trusting the CA of the fixtures only, followed by `more` of its keys
+fn upstream_tls(more: &str) -> String {
+    format!("upstream_tls:\n  use_system_roots: false\n  ca_bundle: \"{{fixtures}}/ca.pem\"\n{}", more)
+}
+
+/// SPKI pin of the certificate `MockUpstream::tls` serves
+fn server_pin() -> String {
+    std::fs::read_to_string(fixture("server.pin")).unwrap().trim().to_string()
+}
+
+const WRONG_PIN: &str = "sha256/AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=";
+
+fn pins(pins: &[&str]) -> String {
+    upstream_tls(&format!("  pins:\n    localhost: [{}]\n", pins.join(", ")))
+}
+
+#[tokio::test]
+async fn private_ca_needs_the_bundle() {
+    let upstream = MockUpstream::new().on_get("/", 200, "ok").tls().build();
+    let without = Proxy::start("");
+    let with = Proxy::start(&upstream_tls(""));
+    let url = format!("https://localhost:{}/", upstream.addr.port());
+
+    let refused = client::get(without.addr, &url).await;
+    let answer = client::get(with.addr, &url).await;
+
+    assert_eq!(refused.status, 502);
+    let expected = format!("TLS handshake with localhost:{} failed", upstream.addr.port());
+    assert!(refused.text().contains(&expected), "{}", refused.text());
+    assert!(refused.text().contains("UnknownIssuer"), "{}", refused.text());
+    assert_eq!(answer.status, 200, "{}", answer.text());
+    assert_eq!(upstream.requests().len(), 1);
+}
+
+#[tokio::test]
+async fn insecure_hosts_are_not_verified() {
+    let upstream = MockUpstream::new().on_get("/", 200, "ok").tls().build();
+    let proxy = Proxy::start("upstream_tls:\n  insecure_hosts: [localhost]\n");
+
+    let answer = client::get(proxy.addr, &format!("https://localhost:{}/", upstream.addr.port())).await;
+    let other = client::get(proxy.addr, &upstream.https_url("/")).await;
+
+    assert_eq!(answer.status, 200, "{}", answer.text());
+    assert_eq!(other.status, 502, "{}", other.text());
+    assert!(proxy.log().contains("TLS certificates of upstream hosts matching \"localhost\" are NOT verified"),
+            "{}", proxy.log());
+}
+
+#[tokio::test]
+async fn settings_of_the_host_take_precedence() {
+    let upstream = MockUpstream::new().on_get("/", 200, "ok").tls().build();
+    let proxy = Proxy::start("\
+upstream_tls:
+  verify: false
+  hosts:
+    localhost: {verify: true}
+");
+
+    let verified = client::get(proxy.addr, &format!("https://localhost:{}/", upstream.addr.port())).await;
+    let unverified = client::get(proxy.addr, &upstream.https_url("/")).await;
+
+    assert_eq!(verified.status, 502, "{}", verified.text());
+    assert_eq!(unverified.status, 200, "{}", unverified.text());
+}
+
+#[tokio::test]
+async fn sni_of_the_host_is_sent() {
+    let upstream = MockUpstream::new().on_get("/", 200, "ok").tls().build();
+    // the certificate is valid for the name sent, not for the host of the URL
+    let config = upstream_tls("  hosts:\n    \"127.0.0.1\": {sni: localhost}\n");
+    let proxy = Proxy::start(&config);
+
+    let answer = client::get(proxy.addr, &upstream.https_url("/")).await;
+
+    assert_eq!(answer.status, 200, "{}", answer.text());
+}
+
+#[tokio::test]
+async fn matching_pin_is_accepted() {
+    let upstream = MockUpstream::new().on_get("/", 200, "ok").tls().build();
+    let proxy = Proxy::start(&pins(&[WRONG_PIN, &server_pin()]));
+
+    let answer = client::get(proxy.addr, &format!("https://localhost:{}/", upstream.addr.port())).await;
+
+    assert_eq!(answer.status, 200, "{}", answer.text());
+    assert_eq!(answer.text(), "ok");
+}
+
+#[tokio::test]
+async fn pin_mismatch_fails_the_handshake() {
+    let upstream = MockUpstream::new().on_get("/", 200, "ok").tls().build();
+    let proxy = Proxy::start(&pins(&[WRONG_PIN]));
+
+    let answer = client::get(proxy.addr, &format!("https://localhost:{}/", upstream.addr.port())).await;
+
+    assert_eq!(answer.status, 502);
+    assert!(answer.text().contains("certificate pin mismatch"), "{}", answer.text());
+    assert!(upstream.requests().is_empty());
+    // other hosts have no pins
+    let unpinned = client::get(proxy.addr, &upstream.https_url("/")).await;
+    assert_eq!(unpinned.status, 200, "{}", unpinned.text());
+}
+
+#[tokio::test]
+async fn report_only_logs_pin_mismatch() {
+    let upstream = MockUpstream::new().on_get("/", 200, "ok").tls().build();
+    let proxy = Proxy::start(&format!("{}  pins_report_only: true\n", pins(&[WRONG_PIN])));
+
+    let answer = client::get(proxy.addr, &format!("https://localhost:{}/", upstream.addr.port())).await;
+
+    assert_eq!(answer.status, 200, "{}", answer.text());
+    let logged = format!("certificate of localhost matches none of its pins, its key is {}; allowed in report-only \
+                          mode", server_pin());
+    assert!(proxy.log().contains(&logged), "{}", proxy.log());
+}
+
+#[tokio::test]
+async fn pins_are_reloaded() {
+    let upstream = MockUpstream::new().on_get("/", 200, "ok").tls().build();
+    let proxy = Proxy::start(&pins(&[WRONG_PIN]));
+    let url = format!("https://localhost:{}/", upstream.addr.port());
+    assert_eq!(client::get(proxy.addr, &url).await.status, 502);
+
+    proxy.rewrite_config(&pins(&[&server_pin()]));
+    proxy.hup().await;
+
+    assert_eq!(client::get(proxy.addr, &url).await.status, 200);
+}
+
+/// `upstream_tls` presenting the client certificate `name` of the fixtures to `localhost`
+fn client_cert(name: &str) -> String {
+    upstream_tls(&format!("  client_certs:
+    localhost:
+      cert_pem: \"{{fixtures}}/{name}.pem\"
+      key_pem: \"{{fixtures}}/{name}-key.pem\"
+", name = name))
+}
+
+#[tokio::test]
+async fn client_certificate_is_presented_to_matching_hosts() {
+    let upstream = MockUpstream::new().on_get("/", 200, "ok").tls().client_auth().build();
+    let proxy = Proxy::start(&client_cert("client"));
+
+    let answer = client::get(proxy.addr, &format!("https://localhost:{}/", upstream.addr.port())).await;
+    assert_eq!(answer.status, 200, "{}", answer.text());
+    assert_eq!(upstream.last().client_cert.as_deref(), Some("alice"));
+
+    // 127.0.0.1 is not localhost to the patterns
+    let refused = client::get(proxy.addr, &upstream.https_url("/")).await;
+    assert_eq!(refused.status, 502);
+    assert!(refused.text().contains(&format!("TLS handshake with {} failed: client certificate rejected",
+                                             upstream.authority())), "{}", refused.text());
+    assert_eq!(upstream.requests().len(), 1);
+    let key = std::fs::read_to_string(fixture("client-key.pem")).unwrap();
+    assert!(!proxy.log().contains(key.lines().nth(1).unwrap()), "{}", proxy.log());
+}
+
+#[tokio::test]
+async fn client_certificates_are_reloaded() {
+    let upstream = MockUpstream::new().on_get("/", 200, "ok").tls().client_auth().build();
+    // signed by a CA the upstream does not trust
+    let proxy = Proxy::start(&client_cert("other-client"));
+    let url = format!("https://localhost:{}/", upstream.addr.port());
+    assert_eq!(client::get(proxy.addr, &url).await.status, 502);
+
+    proxy.rewrite_config(&client_cert("client"));
+    proxy.hup().await;
+
+    assert_eq!(client::get(proxy.addr, &url).await.status, 200);
+    assert_eq!(upstream.last().client_cert.as_deref(), Some("alice"));
+}